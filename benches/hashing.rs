@@ -0,0 +1,130 @@
+//! Hashing throughput of the scan pipeline on synthetic files
+//!
+//! There's no io_uring backend yet, `mmap` is measured as a baseline for the
+//! `pread` loop of the hash workers (and the async reads used elsewhere).
+
+use archlinux_userland_fs_cmp::digest::Algorithm;
+use archlinux_userland_fs_cmp::disk;
+use archlinux_userland_fs_cmp::Event;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+const LARGE_FILE_SIZE: usize = 16 * 1024 * 1024;
+const SMALL_FILE_SIZE: usize = 64 * 1024;
+const NUM_SMALL_FILES: usize = 256;
+
+fn fixture_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "archlinux-userland-fs-cmp-bench-{}",
+        std::process::id()
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn write_file(path: &Path, size: usize) -> String {
+    let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(path, &data).unwrap();
+    hex::encode(Sha256::digest(&data))
+}
+
+/// Hash all files with the given number of hash workers, like the main loop would
+async fn hash_all(files: &[(PathBuf, String)], workers: usize) {
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    disk::spawn_hashers(&event_tx, workers, None, &CancellationToken::new());
+    drop(event_tx);
+
+    let mut queue = files.iter();
+    let mut pending = files.len();
+    while pending > 0 {
+        match event_rx.recv().await.unwrap() {
+            Event::AvailableHasher(tx) => {
+                if let Some((path, sha256)) = queue.next() {
+                    tx.send(vec![(path.clone(), Some(sha256.clone()), None)])
+                        .unwrap();
+                }
+            }
+            Event::CompletedHashing(disk::HashVerify::Passed(..), _) => pending -= 1,
+            event => panic!("Unexpected event: {event:?}"),
+        }
+    }
+}
+
+fn bench_buffer_size(c: &mut Criterion, rt: &Runtime, path: &Path) {
+    let mut group = c.benchmark_group("buffer_size");
+    group.throughput(Throughput::Bytes(LARGE_FILE_SIZE as u64));
+    for size in [2048, 8192, 65536, 1024 * 1024] {
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.to_async(rt).iter(|| async move {
+                let mut file = tokio::fs::File::open(path).await.unwrap();
+                disk::hash_reader_sized(&mut file, size).await.unwrap()
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_backend(c: &mut Criterion, rt: &Runtime, path: &Path) {
+    let mut group = c.benchmark_group("backend");
+    group.throughput(Throughput::Bytes(LARGE_FILE_SIZE as u64));
+    group.bench_function("async-read", |b| {
+        b.to_async(rt)
+            .iter(|| async { disk::hash_file(path).await.unwrap() });
+    });
+    group.bench_function("pread", |b| {
+        let shutdown = CancellationToken::new();
+        b.iter(|| disk::hash_file_blocking(path, &[Algorithm::Sha256], &shutdown).unwrap());
+    });
+    group.bench_function("mmap", |b| {
+        b.iter(|| {
+            let file = fs::File::open(path).unwrap();
+            let map = unsafe { memmap2::Mmap::map(&file) }.unwrap();
+            Sha256::digest(&map[..])
+        });
+    });
+    group.finish();
+}
+
+fn bench_workers(c: &mut Criterion, rt: &Runtime, files: &[(PathBuf, String)]) {
+    let mut group = c.benchmark_group("workers");
+    group.throughput(Throughput::Bytes((SMALL_FILE_SIZE * files.len()) as u64));
+    for workers in [1, 2, 4, 8] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(workers),
+            &workers,
+            |b, &workers| {
+                b.to_async(rt).iter(|| hash_all(files, workers));
+            },
+        );
+    }
+    group.finish();
+}
+
+fn hashing(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let dir = fixture_dir();
+
+    let large = dir.join("large");
+    write_file(&large, LARGE_FILE_SIZE);
+    let small = (0..NUM_SMALL_FILES)
+        .map(|i| {
+            let path = dir.join(format!("small-{i}"));
+            let sha256 = write_file(&path, SMALL_FILE_SIZE);
+            (path, sha256)
+        })
+        .collect::<Vec<_>>();
+
+    bench_buffer_size(c, &rt, &large);
+    bench_backend(c, &rt, &large);
+    bench_workers(c, &rt, &small);
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+criterion_group!(benches, hashing);
+criterion_main!(benches);
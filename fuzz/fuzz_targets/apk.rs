@@ -0,0 +1,8 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::apk;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let _ = apk::parse_installed(data);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::cpio;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = cpio::parse(data);
+});
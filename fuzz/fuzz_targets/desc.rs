@@ -0,0 +1,10 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::pkg;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    if let Some(pkg) = pkg::parse_desc(data) {
+        let _ = pkg.to_url(pkg::ARCHIVE_URL, "zst");
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::dpkg;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = dpkg::parse_status(text);
+        let _ = dpkg::parse_md5sums(text);
+    }
+    let _ = dpkg::deb_hashes(data);
+});
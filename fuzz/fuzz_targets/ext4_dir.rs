@@ -0,0 +1,8 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::ext4;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ext4::parse_dir(data);
+});
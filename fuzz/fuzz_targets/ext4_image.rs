@@ -0,0 +1,12 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::ext4;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // images are read from a file, like a disk image with a partition table
+    let path = std::env::temp_dir().join(format!("fuzz-ext4-{}.img", std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    let _ = ext4::Filesystem::open(&path, None);
+    let _ = ext4::Filesystem::open(&path, Some(1));
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::pkg;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let _ = pkg::parse_files(data);
+});
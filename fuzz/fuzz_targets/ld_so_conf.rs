@@ -0,0 +1,8 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::ldso;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let _ = ldso::parse_conf(data);
+});
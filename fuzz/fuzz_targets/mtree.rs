@@ -0,0 +1,11 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::mtree;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    let mut parser = mtree::Parser::default();
+    for line in data.lines() {
+        let _ = parser.parse(line);
+    }
+});
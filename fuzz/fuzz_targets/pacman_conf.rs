@@ -0,0 +1,11 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::pacman_conf;
+use libfuzzer_sys::fuzz_target;
+use std::path::Path;
+
+fuzz_target!(|data: &str| {
+    let conf = pacman_conf::parse(data);
+    let _ = conf.is_no_extract(Path::new("usr/share/locale/de/LC_MESSAGES/foo.mo"));
+    let _ = conf.is_no_upgrade(Path::new("etc/pacman.conf"));
+});
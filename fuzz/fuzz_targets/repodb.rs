@@ -0,0 +1,8 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::repodb;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = repodb::parse_db(data);
+});
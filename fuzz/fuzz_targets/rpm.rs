@@ -0,0 +1,8 @@
+#![no_main]
+
+use archlinux_userland_fs_cmp::rpm;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = rpm::parse_header(data);
+});
@@ -0,0 +1,83 @@
+use crate::errors::*;
+use crate::pkg::{self, Package};
+use crate::vercmp::vercmp;
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+pub const TRACKER_URL: &str = "https://security.archlinux.org/all.json";
+
+/// A vulnerability group of the Arch Linux security tracker
+#[derive(Debug, Clone, Deserialize)]
+pub struct Group {
+    pub name: String,
+    pub packages: Vec<String>,
+    pub status: String,
+    pub severity: String,
+    pub affected: String,
+    pub fixed: Option<String>,
+    pub issues: Vec<String>,
+}
+
+impl Group {
+    /// Check if the given package is affected and not fixed yet
+    pub fn affects(&self, pkg: &Package) -> bool {
+        if self.status == "Not affected" || !self.packages.contains(&pkg.name) {
+            return false;
+        }
+        if let Some(fixed) = &self.fixed {
+            if vercmp(&pkg.version, fixed) != Ordering::Less {
+                return false;
+            }
+        }
+        vercmp(&pkg.version, &self.affected) != Ordering::Less
+    }
+}
+
+pub async fn fetch(client: &reqwest::Client) -> Result<Vec<Group>> {
+    info!("Fetching vulnerability groups from {TRACKER_URL:?}");
+    let res = client
+        .get(TRACKER_URL)
+        .send()
+        .await
+        .with_context(|| anyhow!("Failed to send http request ({TRACKER_URL:?})"))?
+        .error_for_status()?;
+    let body = res.bytes().await.context("Failed to read http response")?;
+    let groups = serde_json::from_slice(&body).context("Failed to parse security tracker data")?;
+    Ok(groups)
+}
+
+/// Open CVEs of the packages owning the flagged files, as a short annotation
+pub async fn annotate(
+    client: &reqwest::Client,
+    root: &Path,
+    dbpath: &Path,
+    flagged: &BTreeMap<PathBuf, String>,
+) -> Result<HashMap<PathBuf, String>> {
+    let groups = fetch(client).await?;
+    let owners = pkg::list_file_owners(dbpath).await?;
+
+    let mut annotations = HashMap::new();
+    for path in flagged.keys() {
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        let Some(pkg) = owners.get(rel) else { continue };
+
+        let mut cves = groups
+            .iter()
+            .filter(|group| group.affects(pkg))
+            .flat_map(|group| &group.issues)
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        if cves.is_empty() {
+            continue;
+        }
+        cves.sort_unstable();
+        cves.dedup();
+        annotations.insert(
+            path.clone(),
+            format!("{} {}: {}", pkg.name, pkg.version, cves.join(", ")),
+        );
+    }
+    Ok(annotations)
+}
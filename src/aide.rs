@@ -0,0 +1,138 @@
+use crate::errors::*;
+use async_compression::tokio::write::GzipEncoder;
+use base64::prelude::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+
+// attribute bits of the `attr` field, as defined by aide
+const DB_FILENAME: u64 = 1 << 0;
+const DB_PERM: u64 = 1 << 2;
+const DB_UID: u64 = 1 << 3;
+const DB_GID: u64 = 1 << 4;
+const DB_SIZE: u64 = 1 << 5;
+const DB_SHA256: u64 = 1 << 30;
+
+const DB_SPEC: &str = "@@db_spec name attr perm uid gid size sha256";
+
+/// Metadata of the installed file, if it's still present on disk
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stat {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+}
+
+/// Escape a filename the way aide does, everything except a few safe characters is percent-encoded
+fn encode_name(path: &Path) -> String {
+    let mut out = String::new();
+    for b in path.as_os_str().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/._-+@:,".contains(b) {
+            out.push(*b as char);
+        } else {
+            out.push_str(&format!("%{b:02X}"));
+        }
+    }
+    out
+}
+
+/// Format one database entry, the path is absolute on the investigated system
+pub fn format_entry(path: &Path, sha256: &str, stat: Option<Stat>) -> Result<String> {
+    let sha256 = hex::decode(sha256).context("Invalid sha256 digest")?;
+    let sha256 = BASE64_STANDARD.encode(sha256);
+    let name = encode_name(path);
+
+    let line = if let Some(stat) = stat {
+        let attr = DB_FILENAME | DB_PERM | DB_UID | DB_GID | DB_SIZE | DB_SHA256;
+        format!(
+            "{name} {attr} {:o} {} {} {} {sha256}\n",
+            stat.mode, stat.uid, stat.gid, stat.size
+        )
+    } else {
+        let attr = DB_FILENAME | DB_SHA256;
+        format!("{name} {attr} 0 0 0 0 {sha256}\n")
+    };
+    Ok(line)
+}
+
+async fn write_db<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    root: &Path,
+    hashes: &HashMap<PathBuf, String>,
+    modified: &BTreeSet<&Path>,
+) -> Result<()> {
+    let sorted = hashes.iter().collect::<BTreeMap<_, _>>();
+
+    let header = format!(
+        "@@begin_db\n# This file was generated by archlinux-userland-fs-cmp, version {}\n{DB_SPEC}\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    writer.write_all(header.as_bytes()).await?;
+
+    for (path, sha256) in sorted {
+        // the metadata of modified files can't be trusted
+        let stat = fs::symlink_metadata(path)
+            .await
+            .ok()
+            .filter(|_| !modified.contains(path.as_path()))
+            .filter(|md| md.is_file())
+            .map(|md| Stat {
+                mode: md.mode(),
+                uid: md.uid(),
+                gid: md.gid(),
+                size: md.size(),
+            });
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        let line = format_entry(&Path::new("/").join(rel), sha256, stat)?;
+        writer.write_all(line.as_bytes()).await?;
+    }
+
+    writer.write_all(b"@@end_db\n").await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Write all trusted hashes as an aide database, compressed with gzip if the filename ends with `.gz`.
+/// The stat of `modified` files is left out, it can't be trusted
+pub async fn export(
+    path: &Path,
+    root: &Path,
+    hashes: &HashMap<PathBuf, String>,
+    modified: &BTreeSet<&Path>,
+) -> Result<()> {
+    let file = File::create(path)
+        .await
+        .with_context(|| anyhow!("Failed to open file: {path:?}"))?;
+    let writer = BufWriter::new(file);
+
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        write_db(GzipEncoder::new(writer), root, hashes, modified).await
+    } else {
+        write_db(writer, root, hashes, modified).await
+    }
+    .context("Failed to write aide database")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_aide_entry() {
+        let sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let stat = Stat {
+            mode: 0o100644,
+            uid: 0,
+            gid: 0,
+            size: 0,
+        };
+        assert_eq!(
+            format_entry(Path::new("/usr/share/empty file"), sha256, Some(stat)).unwrap(),
+            "/usr/share/empty%20file 1073741885 100644 0 0 0 47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=\n"
+        );
+    }
+}
@@ -0,0 +1,70 @@
+use crate::errors::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Parse an allowlist of `path sha256` pairs, paths are relative to the scan root
+pub fn parse(root: &Path, content: &str) -> Result<HashMap<PathBuf, String>> {
+    let mut allowlist = HashMap::new();
+
+    for (num, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let Some((path, sha256)) = line.rsplit_once(char::is_whitespace) else {
+            bail!("Invalid allowlist entry in line {}: {line:?}", num + 1);
+        };
+        if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("Invalid sha256 in allowlist line {}: {sha256:?}", num + 1);
+        }
+
+        let path = crate::resolve_target_path(root, Path::new(path.trim_end()));
+        allowlist.insert(path, sha256.to_ascii_lowercase());
+    }
+
+    Ok(allowlist)
+}
+
+pub async fn load(root: &Path, path: &Path) -> Result<HashMap<PathBuf, String>> {
+    let content = fs::read_to_string(path)
+        .await
+        .with_context(|| anyhow!("Failed to read allowlist: {path:?}"))?;
+    let allowlist =
+        parse(root, &content).with_context(|| anyhow!("Failed to parse allowlist: {path:?}"))?;
+    info!("Loaded {} pinned hashes from {path:?}", allowlist.len());
+    Ok(allowlist)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_allowlist() {
+        let allowlist = parse(
+            Path::new("/mnt"),
+            "# accepted local changes\n/etc/sudoers d2e3c1a6ea0a8c53bd2e4a5c2d6de1ac8bb8e9d4a8d8c2e1d79eb5b40fe1e1f0\nusr/share/my file.txt  E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855\n",
+        )
+        .unwrap();
+        assert_eq!(
+            allowlist,
+            HashMap::from([
+                (
+                    PathBuf::from("/mnt/etc/sudoers"),
+                    "d2e3c1a6ea0a8c53bd2e4a5c2d6de1ac8bb8e9d4a8d8c2e1d79eb5b40fe1e1f0".to_string()
+                ),
+                (
+                    PathBuf::from("/mnt/usr/share/my file.txt"),
+                    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string()
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_invalid_hash() {
+        assert!(parse(Path::new("/"), "/etc/sudoers abc\n").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use crate::digest::{Algorithm, Checksum};
+use crate::errors::*;
+use crate::pkg::Package;
+use crate::{resolve_merged_usr, Event};
+use base64::prelude::*;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::fs;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Locations of the apk database relative to the root, newer versions of apk-tools moved it into /usr
+pub const DB_PATHS: &[&str] = &["lib/apk/db/installed", "usr/lib/apk/db/installed"];
+
+/// An installed package from the apk database
+#[derive(Debug, Clone)]
+pub struct Installed {
+    pub pkg: Package,
+    /// Files relative to the root and their checksum
+    pub files: Vec<(String, Checksum)>,
+}
+
+/// Parse a `Z:` checksum, `Q1` is a base64 encoded sha1 and `Q2` a base64 encoded sha256
+fn parse_checksum(value: &str) -> Option<Checksum> {
+    let (algorithm, encoded) = if let Some(encoded) = value.strip_prefix("Q1") {
+        (Algorithm::Sha1, encoded)
+    } else if let Some(encoded) = value.strip_prefix("Q2") {
+        (Algorithm::Sha256, encoded)
+    } else {
+        return None;
+    };
+    let digest = BASE64_STANDARD.decode(encoded).ok()?;
+    Checksum::new(algorithm, &hex::encode(digest)).ok()
+}
+
+/// Parse the `installed` file of the apk database
+pub fn parse_installed(db: &str) -> Vec<Installed> {
+    let mut installed = Vec::new();
+    for paragraph in db.split("\n\n") {
+        let mut name = None;
+        let mut version = None;
+        let mut arch = None;
+        let mut files = Vec::new();
+
+        let mut dir = "";
+        let mut file = None;
+        for line in paragraph.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            match key {
+                "P" => name = Some(value),
+                "V" => version = Some(value),
+                "A" => arch = Some(value),
+                "F" => {
+                    dir = value;
+                    file = None;
+                }
+                "R" if dir.is_empty() => file = Some(value.to_string()),
+                "R" => file = Some(format!("{dir}/{value}")),
+                // the checksum belongs to the file that was listed before
+                "Z" => {
+                    if let (Some(path), Some(checksum)) = (file.take(), parse_checksum(value)) {
+                        files.push((path, checksum));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let (Some(name), Some(version), Some(arch)) = (name, version, arch) else {
+            continue;
+        };
+        installed.push(Installed {
+            pkg: Package {
+                name: name.to_string(),
+                version: version.to_string(),
+                arch: arch.to_string(),
+            },
+            files,
+        });
+    }
+    installed
+}
+
+/// Read the installed packages from the apk database and send the trusted hashes of their files
+pub fn spawn_list_installed(
+    event_tx: mpsc::UnboundedSender<Event>,
+    root: PathBuf,
+    shutdown: CancellationToken,
+) {
+    tokio::spawn(async move {
+        let mut installed = None;
+        for path in DB_PATHS {
+            let path = root.join(path);
+            match fs::read_to_string(&path).await {
+                Ok(db) => {
+                    installed = Some(parse_installed(&db));
+                    break;
+                }
+                Err(err) => debug!("Failed to read apk database {path:?}: {err:#}"),
+            }
+        }
+        let Some(installed) = installed else {
+            warn!("Failed to find apk database in {root:?}");
+            event_tx.send(Event::CompletedListInstalled).ok();
+            return;
+        };
+
+        for installed in &installed {
+            debug!(
+                "Found installed package: {:?} {:?}",
+                installed.pkg.name, installed.pkg.version
+            );
+            if event_tx.send(Event::PkgQueued).is_err() {
+                return;
+            }
+        }
+        for installed in installed {
+            if shutdown.is_cancelled() {
+                return;
+            }
+            let pkg = Arc::new(installed.pkg);
+            for (path, checksum) in installed.files {
+                let path = resolve_merged_usr(&root, &path);
+                let event = Event::TrustedFile(path, checksum.to_string(), Some(pkg.clone()));
+                if event_tx.send(event).is_err() {
+                    return;
+                }
+            }
+            if event_tx.send(Event::PkgCompleted).is_err() {
+                return;
+            }
+        }
+
+        event_tx.send(Event::CompletedListInstalled).ok();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_apk_installed() {
+        let db = "C:Q1/0hE2cJvAkEaaP8uXYV6LdGd6W+o=
+P:musl
+V:1.2.5-r0
+A:x86_64
+S:411605
+F:lib
+R:ld-musl-x86_64.so.1
+a:0:0:755
+Z:Q1iJ3sd2OgPpJRGiIrU1rwGKfYH+o=
+R:libc.musl-x86_64.so.1
+a:0:0:777
+Z:Q17yJ3JFNypA4mxhJJr0ou6CzsJVI=
+F:usr/share/doc
+R:README
+Z:Q2LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=
+
+P:alpine-baselayout-data
+V:3.6.5-r0
+A:x86_64
+F:etc
+";
+        let installed = parse_installed(db);
+        assert_eq!(installed.len(), 2);
+        assert_eq!(installed[0].pkg.name, "musl");
+        assert_eq!(installed[0].pkg.version, "1.2.5-r0");
+        let files = installed[0]
+            .files
+            .iter()
+            .map(|(path, checksum)| (path.as_str(), checksum.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "lib/ld-musl-x86_64.so.1",
+                    "sha1:889dec7763a03e92511a222b535af018a7d81fea".to_string()
+                ),
+                (
+                    "lib/libc.musl-x86_64.so.1",
+                    "sha1:ef2277245372a40e26c61249af4a2ee82cec2552".to_string()
+                ),
+                (
+                    "usr/share/doc/README",
+                    "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824".to_string()
+                ),
+            ]
+        );
+        assert!(installed[1].files.is_empty());
+    }
+}
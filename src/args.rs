@@ -1,25 +1,515 @@
-use clap::{ArgAction, Parser};
-use std::path::PathBuf;
+use crate::backend;
+use crate::digest;
+use crate::disk;
+use crate::download;
+use crate::dpkg;
+use crate::errors::*;
+use crate::fetch;
+use crate::filter;
+use crate::intel;
+use crate::mounts;
+use crate::pacman_conf;
+use crate::pkg;
+use crate::profile;
+use crate::repodb;
+use crate::report;
+use crate::state;
+use crate::throttle;
+use crate::trust;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
 
 #[derive(Debug, Parser)]
-#[command(version)]
+#[command(version, args_conflicts_with_subcommands = true)]
 pub struct Args {
     /// Increase logging output (can be used multiple times)
     #[arg(short, long, global = true, action(ArgAction::Count))]
     pub verbose: u8,
-    pub path: PathBuf,
-    #[arg(short = 'b', long, default_value = "var/lib/pacman")]
-    pub dbpath: PathBuf,
+    /// Don't draw progress bars or log the status line while scanning
+    #[arg(short, long, global = true, conflicts_with = "progress_json")]
+    pub quiet: bool,
+    /// When to use colors for the progress and logs on stderr, `auto` only colors a terminal and honors `NO_COLOR`
+    #[arg(long, value_enum, default_value_t, global = true)]
+    pub color: ColorChoice,
+    #[arg(required_unless_present_any = ["input_tar", "squashfs", "ext4", "image", "lvm_snapshot", "roots", "print_schema"])]
+    pub path: Option<PathBuf>,
+    /// Snapshots of the same system in chronological order, the last one is scanned and flagged files are traced through the others
+    #[arg(long = "root", conflicts_with_all = ["path", "input_tar", "squashfs", "ext4", "image", "lvm_snapshot"])]
+    pub roots: Vec<PathBuf>,
+    /// Location of the pacman database (defaults to the `DBPath` of pacman.conf, or var/lib/pacman)
+    #[arg(short = 'b', long)]
+    pub dbpath: Option<PathBuf>,
+    /// Location of pacman.conf in the investigated system, a missing file is ignored
+    #[arg(long, default_value = pacman_conf::PATH)]
+    pub pacman_conf: PathBuf,
+    /// Package manager of the investigated system
+    #[arg(long, value_enum, default_value_t, conflicts_with_all = ["input_tar", "squashfs", "ext4"])]
+    pub backend: backend::Backend,
+    /// Download the packages of `--backend dpkg` from snapshot.debian.org to verify with sha256 instead of md5
+    #[arg(long)]
+    pub debian_snapshot: bool,
+    /// Base url of snapshot.debian.org (or a mirror of it)
+    #[arg(long, default_value = dpkg::SNAPSHOT_URL)]
+    pub debian_snapshot_url: String,
+    /// Base url of the Arch Linux Archive (or a mirror of it) to download packages from, or a
+    /// template like `https://mirror/{arch}/{name}-{version}-{arch}.pkg.tar.{ext}` (also `{first}`).
+    /// Can be repeated, the mirrors are tried in order
+    #[arg(long, visible_alias = "mirror", default_value = pkg::ARCHIVE_URL, global = true)]
+    pub archive_url: Vec<String>,
+    /// Verify against the repositories of the Arch Linux Archive at this date (like `2024/01/31`),
+    /// installed packages that weren't in the repositories at that date can't be verified
+    #[arg(long, value_name = "YYYY/MM/DD", value_parser = repodb::parse_date)]
+    pub archive_date: Option<String>,
     /// Files and folder to exclude (won't be traversed)
-    #[arg(short = 'x', long)]
+    #[arg(short = 'x', long, global = true)]
     pub exclude: Vec<PathBuf>,
-    /// How many files to hash concurrently
-    #[arg(short = 'n', long)]
+    /// Only verify the files of this package (can be repeated), the rest of the filesystem isn't scanned
+    #[arg(long = "pkg", value_name = "NAME", conflicts_with_all = ["input_tar", "squashfs", "ext4", "hashes_from"])]
+    pub pkgs: Vec<String>,
+    /// File with names of packages to verify like `--pkg`, one per line
+    #[arg(long, conflicts_with_all = ["input_tar", "squashfs", "ext4", "hashes_from"])]
+    pub pkg_file: Option<PathBuf>,
+    /// Only verify the files listed in this file (or `-` for stdin), one path per line. Only the
+    /// packages owning them are fetched
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["input_tar", "squashfs", "ext4", "hashes_from", "pkgs", "pkg_file"])]
+    pub paths_from: Option<PathBuf>,
+    /// Only scan files matching this glob (like `/usr/bin/*` or `/etc/**`), can be repeated
+    #[arg(long, value_parser = filter::Glob::new, conflicts_with_all = ["input_tar", "squashfs", "ext4"])]
+    pub include_glob: Vec<filter::Glob>,
+    /// Don't scan files matching this glob (like `/home/**`), can be repeated
+    #[arg(long, value_parser = filter::Glob::new, conflicts_with_all = ["input_tar", "squashfs", "ext4"])]
+    pub exclude_glob: Vec<filter::Glob>,
+    /// A directory of the investigated system that is mounted somewhere else, like `/usr=/mnt/usr_part`
+    /// (can be repeated). Paths in the report are the paths on this host
+    #[arg(long, value_name = "TARGET=HOST", value_parser = mounts::Map::parse, conflicts_with_all = ["input_tar", "squashfs", "ext4"])]
+    pub map: Vec<mounts::Map>,
+    /// How many files to hash concurrently (scaled by the measured throughput if not set)
+    #[arg(short = 'n', long, global = true)]
     pub concurrency: Option<usize>,
-    /// Read the pacman database and print URLs for all installed packages
+    /// Number of threads that walk the filesystem in parallel (defaults to the number of CPUs)
+    #[arg(long)]
+    pub walk_threads: Option<usize>,
+    /// Read buffer size of the hash workers (like `4M`)
+    #[arg(long, value_parser = throttle::parse_size, default_value = "1M", global = true)]
+    pub hash_buffer_size: u64,
+    /// Memory map files of at least this size for hashing (like `64M`), a file that is truncated
+    /// during the scan crashes it
+    #[arg(long, value_name = "SIZE", value_parser = throttle::parse_size, global = true)]
+    pub mmap_threshold: Option<u64>,
+    /// Read the pacman database and print URLs for all installed packages, as text, json or jsonl
     #[arg(short = 'L', long)]
     pub list_pkgs: bool,
+    /// Download all installed packages (or those selected with --pkg) into this directory instead of scanning,
+    /// with a SHA256SUMS manifest. The directory can be used as --bundle later
+    #[arg(long, value_name = "DIR", conflicts_with = "list_pkgs")]
+    pub download_pkgs: Option<PathBuf>,
+    /// Download the signatures of the packages with --download-pkgs and verify them with gpgv
+    #[arg(long, requires = "download_pkgs")]
+    pub verify_sigs: bool,
+    /// The keyring that --verify-sigs verifies the packages with
+    #[arg(long, value_name = "PATH", default_value = download::ARCHLINUX_KEYRING)]
+    pub sig_keyring: PathBuf,
     /// Where to write the report to
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     pub output: Option<PathBuf>,
+    /// Write the packages owning flagged files to this file, as `name=version` for `pacman -S --overwrite`
+    #[arg(long)]
+    pub remediation_out: Option<PathBuf>,
+    /// Copy flagged and untracked files into this directory, recorded in a hash-chained custody log
+    #[arg(long)]
+    pub quarantine: Option<PathBuf>,
+    /// Name of the operator recorded in the custody log (defaults to $SUDO_USER or $USER)
+    #[arg(long)]
+    pub operator: Option<String>,
+    /// Format of the report
+    #[arg(long, value_enum, default_value_t, global = true)]
+    pub format: report::Format,
+    /// Write flagged files and disk errors as soon as they're found, so they survive an interrupted scan
+    #[arg(long)]
+    pub stream: bool,
+    /// Add every file that passed verification to the report as PASSED, as evidence that it was verified
+    #[arg(long)]
+    pub print_passed: bool,
+    /// Write every file that passed verification to this ndjson log, with its package, sha256 and the time of verification
+    #[arg(long, value_name = "PATH")]
+    pub log_passed: Option<PathBuf>,
+    /// Store the results of every run in this sqlite database
+    #[arg(long, value_name = "PATH")]
+    pub db: Option<PathBuf>,
+    /// Only report findings that are new since the previous run of the same root in `--db`
+    #[arg(long, requires = "db", conflicts_with = "stream")]
+    pub diff_last: bool,
+    /// Don't end the report with the statistics of the scan and the command line
+    #[arg(long)]
+    pub no_summary: bool,
+    /// Write the progress as json lines to stderr instead of drawing progress bars, for frontends and automation
+    #[arg(long)]
+    pub progress_json: bool,
+    /// Print the JSON Schema of the json report and exit
+    #[arg(long)]
+    pub print_schema: bool,
+    /// Scan a read-only btrfs snapshot for a consistent view, `auto` creates (and deletes) one, otherwise the path of an existing snapshot
+    #[arg(long, value_name = "auto|PATH", conflicts_with_all = ["input_tar", "squashfs", "ext4", "image"])]
+    pub snapshot: Option<PathBuf>,
+    /// Mount a raw or qcow2 disk image read-only (optionally a partition of it) and scan it
+    #[arg(long, value_name = "IMAGE[:PARTITION]", conflicts_with_all = ["input_tar", "squashfs", "ext4"])]
+    pub image: Option<String>,
+    /// Create a temporary snapshot of a logical volume (given as VG/LV) and scan it
+    #[arg(long, value_name = "VG/LV", conflicts_with_all = ["input_tar", "squashfs", "ext4", "image", "snapshot"])]
+    pub lvm_snapshot: Option<String>,
+    /// Read the filesystem from a (compressed) tarball instead of a mounted directory
+    #[arg(long, conflicts_with_all = ["squashfs", "ext4"])]
+    pub input_tar: Option<PathBuf>,
+    /// Read the filesystem from a squashfs image instead of a mounted directory
+    #[arg(long, conflicts_with = "ext4")]
+    pub squashfs: Option<PathBuf>,
+    /// Read the filesystem from an ext2/3/4 image (optionally a partition of a raw disk image) in userspace, without mounting it
+    #[arg(long, value_name = "IMAGE[:PARTITION]")]
+    pub ext4: Option<String>,
+    /// Pause the disk walker or the trust sources while the state of the scan gets close to this size (like `1G`)
+    #[arg(long, value_parser = throttle::parse_size)]
+    pub max_memory: Option<u64>,
+    /// Report modified config files that are in the backup list of their package as WRONG SHA256, instead of MODIFIED CONFIG.
+    /// NoUpgrade and NoExtract of pacman.conf are ignored too
+    #[arg(long)]
+    pub strict: bool,
+    /// Fail the scan (exit code 4) if files couldn't be read because of these errors, like `io,unreadable-symlink`.
+    /// Permission denied and vanished files are normal on a live system, errors on evidence media aren't
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub fail_on_errors: Vec<disk::ErrorClass>,
+    /// Stop the scan at the first modified file, the report only contains the files flagged so far
+    #[arg(long)]
+    pub fail_fast: bool,
+    /// Only hash files that changed since the previous (incremental) run
+    #[arg(long)]
+    pub incremental: bool,
+    /// Share of unchanged files that are verified anyway with --incremental
+    #[arg(long, default_value = "0.01")]
+    pub incremental_sample: f64,
+    /// Don't hash files that have the size and modification time of their package and didn't change
+    /// before this date (`YYYY-MM-DD`, `@<unix timestamp>` or `@last-run`). The modification time
+    /// can be forged by anybody who can write the file, use this only for quick triage
+    #[arg(long, value_parser = state::Since::parse, conflicts_with_all = ["input_tar", "squashfs", "ext4"])]
+    pub since: Option<state::Since>,
+    /// Hash every file, even if --since or --incremental would skip it
+    #[arg(long, conflicts_with = "resume")]
+    pub paranoid: bool,
+    /// Periodically write the progress of the scan to this file, to continue it with --resume after an interruption
+    #[arg(long, conflicts_with_all = ["input_tar", "squashfs", "ext4"])]
+    pub state_file: Option<PathBuf>,
+    /// Continue an interrupted scan from --state-file, files that already passed and didn't change are skipped
+    #[arg(long, requires = "state_file", conflicts_with = "incremental")]
+    pub resume: bool,
+    /// Verify against a `sha256sum` manifest instead of the pacman database (or with `--trust-source manifest`, per package),
+    /// like one written by `--export-hashes`
+    #[arg(long, visible_alias = "import-hashes")]
+    pub hashes_from: Option<PathBuf>,
+    /// Hash algorithm of untagged `--hashes-from` entries (like `b3sum` output), sha512 is detected by length
+    #[arg(long, value_enum, default_value_t)]
+    pub hashes_algorithm: digest::Algorithm,
+    /// Sources of trusted hashes for installed packages, in order of priority
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = [trust::Kind::Cache, trust::Kind::Archive])]
+    pub trust_source: Vec<trust::Kind>,
+    /// Directory of package files for `--trust-source bundle` (like an offline bundle or a pacman cache)
+    #[arg(long)]
+    pub bundle: Option<PathBuf>,
+    /// Directory of the `cache` trust source, the hashes are stored per name-version-arch (defaults to mtree/ in the state directory)
+    #[arg(long)]
+    pub mtree_cache: Option<PathBuf>,
+    /// Read the `.MTREE` copies of the local pacman database before the other trust sources (only as trustworthy as the investigated system)
+    #[arg(long)]
+    pub trust_local_db: bool,
+    /// Read packages from the `CacheDir` of the investigated pacman.conf before downloading them (only as trustworthy as the investigated system)
+    #[arg(long)]
+    pub trust_cache_dir: bool,
+    /// Read packages from a pacman cache (like /var/cache/pacman/pkg) before downloading them from the archive, can be repeated
+    #[arg(long)]
+    pub pkg_cache: Vec<PathBuf>,
+    /// Number of workers that decompress packages and read their `.MTREE` (defaults to the number of CPUs)
+    #[arg(long)]
+    pub decompress_workers: Option<usize>,
+    /// Read buffer size of the decompression workers (like `256K`)
+    #[arg(long, value_parser = throttle::parse_size, default_value = "64K")]
+    pub decompress_buffer_size: u64,
+    /// Number of packages that are looked up (and downloaded) concurrently
+    #[arg(long, default_value_t = fetch::NUM_HTTP_WORKERS)]
+    pub http_concurrency: usize,
+    /// Timeout of a whole http request in seconds, including the download of the package
+    #[arg(long, value_name = "SECONDS")]
+    pub http_timeout: Option<u64>,
+    /// Timeout for establishing a connection in seconds
+    #[arg(long, value_name = "SECONDS")]
+    pub connect_timeout: Option<u64>,
+    /// Send all http requests through this proxy, like `socks5h://127.0.0.1:9050` for Tor
+    /// (`socks5h` also resolves hostnames through the proxy). Defaults to `ALL_PROXY` and friends
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+    /// How often a download is retried (or resumed) after a transient error
+    #[arg(long, default_value_t = 3)]
+    pub http_retries: u32,
+    /// Delay in seconds before the first retry, doubled for each further retry
+    #[arg(long, value_name = "SECONDS", default_value_t = 1)]
+    pub http_backoff: u64,
+    /// Limit the aggregate download rate of all http workers, in bytes per second (like `2M`)
+    #[arg(long, value_name = "RATE", value_parser = throttle::parse_size)]
+    pub limit_rate: Option<u64>,
+    /// Write all trusted hashes to a `sha256sum -c` compatible manifest, with comments for the owning packages
+    #[arg(long, global = true)]
+    pub export_hashes: Option<PathBuf>,
+    /// Write all trusted hashes as an AIDE database (gzip compressed if the name ends with `.gz`)
+    #[arg(long, global = true)]
+    pub export_aide: Option<PathBuf>,
+    /// File of `path sha256` pairs for accepted local modifications
+    #[arg(long, global = true)]
+    pub allowlist: Option<PathBuf>,
+    /// Known-good hash sets (sha256 lists or NSRL csv), matching untracked files are downgraded
+    #[arg(long, global = true)]
+    pub known_good: Vec<PathBuf>,
+    /// Verify kernel images, microcode and initramfs contents in /boot
+    #[arg(long)]
+    pub verify_boot: bool,
+    /// Location of the mounted /boot partition (defaults to <path>/boot)
+    #[arg(long)]
+    pub boot_dir: Option<PathBuf>,
+    /// Verify bootloader EFI binaries against the packaged originals
+    #[arg(long)]
+    pub verify_efi: bool,
+    /// Location of a mounted EFI system partition (defaults to <path>/efi and <path>/boot)
+    #[arg(long)]
+    pub esp: Vec<PathBuf>,
+    /// Verify kernel module trees in /usr/lib/modules belong to installed kernels
+    #[arg(long)]
+    pub check_modules: bool,
+    /// Verify systemd units and drop-ins, and report overridden ExecStart= commands
+    #[arg(long)]
+    pub check_systemd: bool,
+    /// Check for dynamic linker hijacking (ld.so.preload, untracked libraries, ld.so.cache)
+    #[arg(long)]
+    pub check_ld: bool,
+    /// Report files with setuid/setgid bits or file capabilities that are untracked or more
+    /// privileged than their package calls for
+    #[arg(long, conflicts_with_all = ["input_tar", "squashfs", "ext4"])]
+    pub check_privileges: bool,
+    /// Don't restrict filesystem access and syscalls with Landlock and seccomp after startup
+    #[arg(long)]
+    pub no_sandbox: bool,
+    /// Keep CAP_DAC_READ_SEARCH to read all files under the scan root without running as root,
+    /// filesystem access is confined to the scan with Landlock
+    #[arg(long, conflicts_with_all = ["no_sandbox", "image", "lvm_snapshot"])]
+    pub keep_read_cap: bool,
+    /// Directory for caches and state between runs (defaults to $XDG_STATE_HOME/archlinux-userland-fs-cmp)
+    #[arg(long, global = true)]
+    pub state_dir: Option<PathBuf>,
+    /// Don't report files that are expected to change after install (like python bytecode)
+    #[arg(long, global = true)]
+    pub hide_generated: bool,
+    /// Report untracked files everywhere, by default only the ones in package-managed
+    /// directories (/usr, /opt, /boot and /etc) are listed and the rest is counted
+    #[arg(long, global = true)]
+    pub show_all_untracked: bool,
+    /// Don't use the built-in list of files that are expected to change after install, like caches written by hooks
+    #[arg(long)]
+    pub no_default_ignores: bool,
+    /// Files that are expected to change after install, one glob per line (like `/opt/app/cache/**`)
+    #[arg(long)]
+    pub ignore_file: Vec<PathBuf>,
+    /// Audit profile, `sensitive` scans high-value locations first and reports them separately
+    #[arg(long, value_enum)]
+    pub profile: Option<profile::Profile>,
+    /// Compare modified ELF binaries section by section with the originals from their packages
+    #[arg(long)]
+    pub elf_diff: bool,
+    /// Download the full packages and compare their files with the disk, instead of only trusting
+    /// their `.MTREE`. Also shows the changed lines of small text files
+    #[arg(long)]
+    pub deep_compare: bool,
+    /// Include a unified diff against the file of the package for modified text files
+    #[arg(long)]
+    pub text_diff: bool,
+    /// Text files up to this size are diffed with --text-diff (like `256K`)
+    #[arg(long, value_parser = throttle::parse_size, default_value = "64K")]
+    pub text_diff_max_size: u64,
+    /// Write the diffs of modified text files into this directory instead of the report, implies --text-diff
+    #[arg(long)]
+    pub diff_dir: Option<PathBuf>,
+    /// Replace flagged and missing files with the verified files of their packages, including their
+    /// mode and ownership. Asks for every file unless --yes is set
+    #[arg(long, conflicts_with_all = ["input_tar", "squashfs", "ext4", "image", "lvm_snapshot", "snapshot", "keep_read_cap"])]
+    pub restore: bool,
+    /// Only report which files --restore would replace, after verifying them in their packages
+    #[arg(long, requires = "restore")]
+    pub dry_run: bool,
+    /// Restore all files without asking
+    #[arg(short = 'y', long, requires = "restore")]
+    pub yes: bool,
+    /// Annotate flagged files with open CVEs of their package from the Arch Linux security tracker
+    #[arg(long)]
+    pub cve: bool,
+    /// Lookup the sha256 of flagged and untracked files with a threat intel service
+    #[arg(long, value_enum, global = true)]
+    pub lookup_hashes: Option<intel::Provider>,
+    /// API key for the threat intel service (defaults to $VT_API_KEY or $MALWAREBAZAAR_API_KEY)
+    #[arg(long, global = true)]
+    pub lookup_api_key: Option<String>,
+    /// Maximum number of threat intel lookups per minute
+    #[arg(long, default_value = "4", global = true)]
+    pub lookup_rate: u32,
+    /// Maximum number of threat intel lookups, flagged files and untracked executables are looked up first
+    #[arg(long, default_value = "100", global = true)]
+    pub lookup_limit: usize,
+    #[command(subcommand)]
+    pub subcommand: Option<SubCommand>,
+}
+
+impl Args {
+    /// If the output on stderr should be colored
+    pub fn use_color(&self) -> bool {
+        match self.color {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+        }
+    }
+
+    /// The pacman database, relative to the root
+    pub fn dbpath(&self) -> &Path {
+        self.dbpath.as_deref().unwrap_or(Path::new(pkg::DBPATH))
+    }
+
+    /// The http client with the configured timeouts
+    pub fn http_client(&self) -> Result<reqwest::Client> {
+        fetch::http_client(
+            self.http_timeout.map(Duration::from_secs),
+            self.connect_timeout.map(Duration::from_secs),
+            self.proxy.as_deref(),
+        )
+    }
+
+    pub fn http_retry(&self) -> fetch::Retry {
+        fetch::Retry {
+            retries: self.http_retries,
+            backoff: Duration::from_secs(self.http_backoff),
+        }
+    }
+
+    /// The filesystem is read from a tarball or image file, instead of a mounted directory
+    pub fn reads_archive(&self) -> bool {
+        self.input_tar.is_some() || self.squashfs.is_some() || self.ext4.is_some()
+    }
+
+    /// The root of the filesystem that is investigated
+    pub fn root(&self) -> &Path {
+        match &self.subcommand {
+            Some(SubCommand::Compare(compare)) => &compare.path,
+            Some(SubCommand::Baseline(baseline)) => match &baseline.action {
+                BaselineAction::Create(args) | BaselineAction::Check(args) => &args.path,
+            },
+            // inputs like tarballs and images are scanned as if they were mounted at /
+            Some(SubCommand::CompareReports(_))
+            | Some(SubCommand::VerifyCustody(_))
+            | Some(SubCommand::Cache(_))
+            | None => self
+                .path
+                .as_deref()
+                .or(self.roots.last().map(PathBuf::as_path))
+                .unwrap_or(Path::new("/")),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SubCommand {
+    /// Compare a directory tree against a known-good tree instead of pacman packages
+    Compare(Compare),
+    /// Record and verify hashes of files that aren't owned by any package
+    Baseline(Baseline),
+    /// Report findings that only show up on one of two hosts, based on their json reports
+    CompareReports(CompareReports),
+    /// Verify the hash chain of a chain-of-custody log
+    VerifyCustody(VerifyCustody),
+    /// Inspect and prune the state directory
+    Cache(Cache),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Compare {
+    /// The known-good tree, like a clean clone or an older snapshot
+    pub trusted: PathBuf,
+    /// The tree that is investigated
+    pub path: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct CompareReports {
+    /// Report of the first host (written with --format json)
+    pub a: PathBuf,
+    /// Report of the second host
+    pub b: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct VerifyCustody {
+    /// The custody.log in the quarantine directory
+    pub log: PathBuf,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Baseline {
+    #[command(subcommand)]
+    pub action: BaselineAction,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum BaselineAction {
+    /// Hash untracked files and write them into a signed baseline
+    Create(BaselineArgs),
+    /// Report untracked files that changed since the baseline was created
+    Check(BaselineArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct BaselineArgs {
+    /// The root of the filesystem
+    pub path: PathBuf,
+    #[arg(short = 'b', long, default_value = "var/lib/pacman")]
+    pub dbpath: PathBuf,
+    /// Location of the baseline database (defaults to baselines/baseline.db in the state directory)
+    #[arg(long)]
+    pub baseline: Option<PathBuf>,
+    /// File with the secret key used to sign the baseline
+    #[arg(long)]
+    pub key: PathBuf,
+    /// Directories to record files from (only used when creating a baseline)
+    #[arg(long, default_values = ["/usr/local", "/opt"])]
+    pub prefix: Vec<PathBuf>,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct Cache {
+    #[command(subcommand)]
+    pub action: CacheAction,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheAction {
+    /// Show the number of files and their size for each kind of data
+    Stats,
+    /// Delete data from the state directory
+    Clear {
+        /// Only delete these kinds of data (defaults to everything)
+        #[arg(value_enum)]
+        kinds: Vec<state::Kind>,
+    },
 }
@@ -0,0 +1,123 @@
+use crate::errors::*;
+use std::time::{Duration, Instant};
+
+/// How long the throughput is measured before the number of workers is changed
+pub const WINDOW: Duration = Duration::from_secs(2);
+/// Changes in throughput below this ratio are considered noise
+const THRESHOLD: f64 = 0.05;
+
+/// Scales the number of hash workers by hill climbing on the measured throughput,
+/// the best concurrency is very different for spinning disks, NVMe and network block devices
+#[derive(Debug)]
+pub struct Autoscale {
+    min: usize,
+    max: usize,
+    started: Instant,
+    hashed: u64,
+    /// The queue of files didn't run empty during the current window
+    backlog: bool,
+    /// Throughput of the previous window, in files per second
+    previous: Option<f64>,
+    growing: bool,
+}
+
+impl Autoscale {
+    pub fn new(min: usize, max: usize) -> Self {
+        Autoscale {
+            min: min.max(1),
+            max: max.max(min),
+            started: Instant::now(),
+            hashed: 0,
+            backlog: true,
+            previous: None,
+            growing: true,
+        }
+    }
+
+    /// Called periodically with the total number of hashed files, the current number of
+    /// workers and the length of the queue. Returns the new number of workers once a
+    /// measurement window is complete.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        hashed: u64,
+        workers: usize,
+        queued: usize,
+    ) -> Option<usize> {
+        self.backlog &= queued > 0;
+        let elapsed = now.duration_since(self.started);
+        if elapsed < WINDOW {
+            return None;
+        }
+
+        let throughput = hashed.saturating_sub(self.hashed) as f64 / elapsed.as_secs_f64();
+        let backlog = self.backlog;
+        self.started = now;
+        self.hashed = hashed;
+        self.backlog = true;
+
+        // without a backlog the workers are waiting for files, the throughput says nothing
+        if !backlog {
+            self.previous = None;
+            return None;
+        }
+
+        let step = (workers / 4).max(1);
+        match self.previous {
+            Some(previous) if throughput < previous * (1.0 - THRESHOLD) => {
+                self.growing = !self.growing
+            }
+            Some(previous) if throughput <= previous * (1.0 + THRESHOLD) => {
+                self.previous = Some(throughput);
+                return None;
+            }
+            // keep going in the same direction, the first window probes upwards
+            _ => (),
+        }
+        self.previous = Some(throughput);
+
+        let target = if self.growing {
+            workers + step
+        } else {
+            workers.saturating_sub(step)
+        }
+        .clamp(self.min, self.max);
+        if target == workers {
+            return None;
+        }
+        debug!(
+            "Hashed {throughput:.1} files/s with {workers} workers ({:.1} per worker), scaling to {target}",
+            throughput / workers.max(1) as f64
+        );
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hill_climbing() {
+        let start = Instant::now();
+        let mut autoscale = Autoscale::new(1, 16);
+        autoscale.started = start;
+
+        // first window probes upwards
+        let t = start + WINDOW;
+        assert_eq!(autoscale.update(t, 200, 4, 10), Some(5));
+        // more throughput, keep growing
+        let t = t + WINDOW;
+        assert_eq!(autoscale.update(t, 500, 5, 10), Some(6));
+        // throughput dropped, step back
+        let t = t + WINDOW;
+        assert_eq!(autoscale.update(t, 700, 6, 10), Some(5));
+        // same throughput, hold
+        let t = t + WINDOW;
+        assert_eq!(autoscale.update(t, 900, 5, 10), None);
+
+        // the queue ran empty, no decision
+        assert_eq!(autoscale.update(t + WINDOW / 2, 950, 5, 0), None);
+        assert_eq!(autoscale.update(t + WINDOW, 1500, 5, 10), None);
+    }
+}
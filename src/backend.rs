@@ -0,0 +1,15 @@
+use clap::ValueEnum;
+
+/// The package manager of the investigated system
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Arch Linux, the local pacman database and the Arch Linux Archive
+    #[default]
+    Pacman,
+    /// Debian and derivatives, md5sums of the dpkg database (optionally sha256 from snapshot.debian.org)
+    Dpkg,
+    /// Alpine, sha1 (or sha256) of the apk database
+    Apk,
+    /// Fedora and RHEL, file digests of the sqlite rpmdb
+    Rpm,
+}
@@ -0,0 +1,258 @@
+use crate::args::{BaselineAction, BaselineArgs};
+use crate::disk;
+use crate::errors::*;
+use crate::manifest;
+use crate::pkg;
+use crate::state::{Kind, StateDir};
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio::task;
+use walkdir::WalkDir;
+
+const HEADER: &str = "# archlinux-userland-fs-cmp baseline v1\n";
+const PREFIX: &str = "# prefix ";
+const SIGNATURE: &str = "# hmac-sha256 ";
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts keys of any length")
+}
+
+/// Hashes of files that aren't owned by any package, paths are relative to the root
+#[derive(Debug, PartialEq)]
+pub struct Baseline {
+    pub prefixes: Vec<PathBuf>,
+    pub hashes: BTreeMap<PathBuf, String>,
+}
+
+impl Baseline {
+    /// Serialize the baseline and append a signature for the given key
+    pub fn sign(&self, key: &[u8]) -> String {
+        let mut out = HEADER.to_string();
+        for prefix in &self.prefixes {
+            out.push_str(&format!("{PREFIX}{}\n", prefix.display()));
+        }
+        for (path, sha256) in &self.hashes {
+            out.push_str(&String::from_utf8_lossy(&manifest::format_line(
+                sha256, path,
+            )));
+        }
+
+        let mut mac = hmac(key);
+        mac.update(out.as_bytes());
+        let signature = hex::encode(mac.finalize().into_bytes());
+        out.push_str(&format!("{SIGNATURE}{signature}\n"));
+        out
+    }
+
+    /// Verify the signature with the given key and parse the baseline
+    pub fn verify(content: &str, key: &[u8]) -> Result<Self> {
+        let body = content.trim_end_matches('\n');
+        let (body, signature) = body
+            .rsplit_once('\n')
+            .context("Baseline is missing a signature")?;
+        let signature = signature
+            .strip_prefix(SIGNATURE)
+            .context("Baseline is missing a signature")?;
+        let signature = hex::decode(signature).context("Baseline signature is invalid hex")?;
+
+        let body = format!("{body}\n");
+        let mut mac = hmac(key);
+        mac.update(body.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| {
+            anyhow!("Baseline signature does not match, it was modified or the key is wrong")
+        })?;
+
+        let Some(body) = body.strip_prefix(HEADER) else {
+            bail!("Unsupported baseline format");
+        };
+        let mut baseline = Baseline {
+            prefixes: Vec::new(),
+            hashes: BTreeMap::new(),
+        };
+        for line in body.lines() {
+            if let Some(prefix) = line.strip_prefix(PREFIX) {
+                baseline.prefixes.push(PathBuf::from(prefix));
+            } else {
+                let (sha256, path) = manifest::parse_line(line)
+                    .with_context(|| anyhow!("Invalid baseline entry: {line:?}"))?;
+                baseline.hashes.insert(path, sha256);
+            }
+        }
+        Ok(baseline)
+    }
+}
+
+/// Hash all files below the prefixes that aren't owned by an installed package
+pub async fn collect(
+    args: &BaselineArgs,
+    prefixes: &[PathBuf],
+    num_hash_workers: usize,
+) -> Result<Baseline> {
+    let root = &args.path;
+    let owned = pkg::list_file_owners(&root.join(&args.dbpath)).await?;
+    debug!("Found {} files owned by packages", owned.len());
+
+    let mut files = Vec::new();
+    for prefix in prefixes {
+        let dir = crate::resolve_target_path(root, prefix);
+        if !dir.is_dir() {
+            debug!("Skipping missing directory: {dir:?}");
+            continue;
+        }
+        let root = root.clone();
+        let found = task::spawn_blocking(move || {
+            let mut files = Vec::new();
+            for entry in WalkDir::new(&dir) {
+                let entry = entry.with_context(|| anyhow!("Failed to walk directory: {dir:?}"))?;
+                if !entry.file_type().is_file() {
+                    continue;
+                }
+                if let Ok(rel) = entry.path().strip_prefix(&root) {
+                    files.push(rel.to_owned());
+                }
+            }
+            Ok::<_, Error>(files)
+        })
+        .await??;
+        files.extend(found.into_iter().filter(|rel| !owned.contains_key(rel)));
+    }
+
+    let stream = futures::stream::iter(files)
+        .map(|rel| async move {
+            let path = root.join(&rel);
+            let sha256 = disk::hash_file(&path)
+                .await
+                .with_context(|| anyhow!("Failed to read file from disk {path:?}"))?;
+            Ok::<_, Error>((rel, hex::encode(sha256)))
+        })
+        .buffer_unordered(num_hash_workers);
+    futures_util::pin_mut!(stream);
+
+    let mut hashes = BTreeMap::new();
+    while let Some(result) = stream.next().await {
+        let (rel, sha256) = result?;
+        hashes.insert(rel, sha256);
+    }
+    info!("Hashed {} files not owned by any package", hashes.len());
+
+    Ok(Baseline {
+        prefixes: prefixes.to_vec(),
+        hashes,
+    })
+}
+
+/// Describe how the files changed since the baseline was created
+pub fn diff(root: &Path, old: &Baseline, new: &Baseline) -> Vec<String> {
+    let mut changes = Vec::new();
+    let paths = old
+        .hashes
+        .keys()
+        .chain(new.hashes.keys())
+        .collect::<HashSet<_>>();
+    let mut paths = paths.into_iter().collect::<Vec<_>>();
+    paths.sort();
+
+    for rel in paths {
+        let path = root.join(rel);
+        match (old.hashes.get(rel), new.hashes.get(rel)) {
+            (Some(old), Some(new)) if old != new => {
+                changes.push(format!("[BASELINE MODIFIED] {path:?}"));
+            }
+            (Some(_), None) => changes.push(format!("[BASELINE REMOVED] {path:?}")),
+            (None, Some(_)) => changes.push(format!("[BASELINE ADDED] {path:?}")),
+            _ => (),
+        }
+    }
+    changes
+}
+
+#[tokio::main]
+pub async fn run(
+    action: BaselineAction,
+    output: Option<PathBuf>,
+    num_hash_workers: usize,
+    state: StateDir,
+) -> Result<()> {
+    let default_path = state.dir(Kind::Baselines).join("baseline.db");
+
+    let mut writer = if let Some(path) = output {
+        Box::new(
+            fs::File::create(&path)
+                .await
+                .with_context(|| anyhow!("Failed to open file: {path:?}"))?,
+        ) as Box<dyn AsyncWrite + Unpin>
+    } else {
+        Box::new(io::stdout()) as Box<dyn AsyncWrite + Unpin>
+    };
+
+    match action {
+        BaselineAction::Create(args) => {
+            let key = fs::read(&args.key)
+                .await
+                .with_context(|| anyhow!("Failed to read key: {:?}", args.key))?;
+            let baseline = collect(&args, &args.prefix, num_hash_workers).await?;
+            let path = args.baseline.unwrap_or(default_path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .await
+                    .with_context(|| anyhow!("Failed to create directory: {parent:?}"))?;
+            }
+            fs::write(&path, baseline.sign(&key))
+                .await
+                .with_context(|| anyhow!("Failed to write baseline: {path:?}"))?;
+            info!("Wrote baseline to {path:?}");
+        }
+        BaselineAction::Check(args) => {
+            let key = fs::read(&args.key)
+                .await
+                .with_context(|| anyhow!("Failed to read key: {:?}", args.key))?;
+            let path = args.baseline.as_ref().unwrap_or(&default_path);
+            let content = fs::read_to_string(path)
+                .await
+                .with_context(|| anyhow!("Failed to read baseline: {path:?}"))?;
+            let old = Baseline::verify(&content, &key)?;
+            let new = collect(&args, &old.prefixes, num_hash_workers).await?;
+            for change in diff(&args.path, &old, &new) {
+                writer.write_all(format!("{change}\n").as_bytes()).await?;
+            }
+        }
+    }
+    writer.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn baseline() -> Baseline {
+        Baseline {
+            prefixes: vec![PathBuf::from("/usr/local")],
+            hashes: [(
+                PathBuf::from("usr/local/bin/foo"),
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string(),
+            )]
+            .into_iter()
+            .collect(),
+        }
+    }
+
+    #[test]
+    fn sign_verify_roundtrip() {
+        let signed = baseline().sign(b"secret");
+        assert_eq!(Baseline::verify(&signed, b"secret").unwrap(), baseline());
+        assert!(Baseline::verify(&signed, b"wrong key").is_err());
+    }
+
+    #[test]
+    fn verify_tampered() {
+        let signed = baseline().sign(b"secret").replace("e3b0", "0000");
+        assert!(Baseline::verify(&signed, b"secret").is_err());
+    }
+}
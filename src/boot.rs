@@ -0,0 +1,540 @@
+use crate::cpio;
+use crate::disk::sha256;
+use crate::errors::*;
+use crate::hashes::TrustedHashes;
+use crate::report::Entry;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+const MODULE_COMPRESSION_EXTS: &[&str] = &["zst", "xz", "gz"];
+const MICROCODE_IMAGES: &[&str] = &["amd-ucode.img", "intel-ucode.img"];
+/// The only paths that are expected in an early microcode cpio
+const MICROCODE_PATHS: &[&str] = &[
+    "kernel",
+    "kernel/x86",
+    "kernel/x86/microcode",
+    "kernel/x86/microcode/AuthenticAMD.bin",
+    "kernel/x86/microcode/GenuineIntel.bin",
+];
+
+#[derive(Debug)]
+pub enum Finding {
+    /// A file in /boot does not match the file shipped by its package
+    Mismatch(PathBuf),
+    /// No trusted source was found for a file in /boot
+    Unverified(PathBuf),
+    /// A file embedded in an initramfs doesn't match its packaged original
+    InitramfsMismatch(PathBuf, String),
+    /// A file embedded in an initramfs that isn't owned by any package
+    InitramfsUntracked(PathBuf, String),
+    /// An EFI binary that differs from the packaged original with the same name
+    EfiMismatch(PathBuf),
+    /// An EFI binary that isn't a copy of any packaged EFI binary
+    EfiUntracked(PathBuf),
+    /// An EFI binary that is generated on install (like grub) and can't be verified
+    EfiUnverifiable(PathBuf),
+    /// A microcode image that isn't a well-formed early cpio
+    MicrocodeInvalid(PathBuf, Error),
+    Error(PathBuf, Error),
+}
+
+impl From<Finding> for Entry {
+    fn from(finding: Finding) -> Self {
+        match finding {
+            Finding::Mismatch(path) => Entry::path("BOOT WRONG SHA256", path),
+            Finding::Unverified(path) => Entry::path("BOOT UNVERIFIED", path),
+            Finding::InitramfsMismatch(image, name) => {
+                Entry::path("INITRAMFS WRONG SHA256", image).detail(name)
+            }
+            Finding::InitramfsUntracked(image, name) => {
+                Entry::path("INITRAMFS NO SHA256", image).detail(name)
+            }
+            Finding::EfiMismatch(path) => Entry::path("EFI WRONG SHA256", path),
+            Finding::EfiUntracked(path) => Entry::path("EFI NO SHA256", path),
+            Finding::EfiUnverifiable(path) => Entry::path("EFI UNVERIFIABLE", path),
+            Finding::MicrocodeInvalid(path, err) => {
+                Entry::path("MICROCODE INVALID", path).detail(format!("{err:#}"))
+            }
+            Finding::Error(path, err) => Entry::path("BOOT ERROR", path).detail(format!("{err:#}")),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Files in /boot that have been covered by this pass
+    pub covered: Vec<PathBuf>,
+    pub findings: Vec<Finding>,
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hex::encode(hasher.finalize()))
+}
+
+fn decompress(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut out = Vec::new();
+    if data.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        zstd::stream::read::Decoder::new(data)?.read_to_end(&mut out)?;
+    } else if data.starts_with(&[0x1f, 0x8b]) {
+        flate2::read::MultiGzDecoder::new(data).read_to_end(&mut out)?;
+    } else if data.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        liblzma::read::XzDecoder::new_multi_decoder(data).read_to_end(&mut out)?;
+    } else {
+        return Ok(None);
+    }
+    Ok(Some(out))
+}
+
+/// Read concatenated cpio archives, returns the remaining data that isn't a cpio archive
+fn read_archives<'a>(
+    mut data: &'a [u8],
+    files: &mut Vec<(String, u32, Vec<u8>)>,
+) -> Result<&'a [u8]> {
+    loop {
+        // skip padding between concatenated archives
+        while let Some(rest) = data.strip_prefix(&[0]) {
+            data = rest;
+        }
+        if !cpio::is_newc(data) {
+            return Ok(data);
+        }
+
+        let (entries, consumed) = cpio::parse(data)?;
+        for entry in entries.into_iter().filter(|e| e.is_file()) {
+            files.push((entry.name, entry.mode, entry.data.to_vec()));
+        }
+        data = &data[consumed..];
+    }
+}
+
+/// Read all files from an initramfs, this may be a concatenation of
+/// uncompressed cpio archives (like early microcode) and one compressed archive
+pub fn read_initramfs(data: &[u8]) -> Result<Vec<(String, u32, Vec<u8>)>> {
+    let mut files = Vec::new();
+
+    let rest = read_archives(data, &mut files)?;
+    if !rest.is_empty() {
+        let Some(inner) = decompress(rest).context("Failed to decompress initramfs")? else {
+            bail!("Unsupported initramfs compression");
+        };
+        if !read_archives(&inner, &mut files)?.is_empty() {
+            bail!("Unexpected trailing data in initramfs");
+        }
+    }
+
+    Ok(files)
+}
+
+/// Map a path inside of the initramfs to the packaged file it was copied from
+fn packaged_source(name: &str) -> Option<String> {
+    let name = name.trim_start_matches("./").trim_start_matches('/');
+    match name {
+        "init" | "init_functions" => Some(format!("usr/lib/initcpio/{name}")),
+        // generated by mkinitcpio
+        "config" | "buildconfig" | "VERSION" | "early_cpio" => None,
+        _ if name.starts_with("etc/") => None,
+        _ if name.starts_with("usr/lib/modules/") && name.contains("/modules.") => None,
+        _ => {
+            if let Some(hook) = name.strip_prefix("hooks/") {
+                Some(format!("usr/lib/initcpio/hooks/{hook}"))
+            } else {
+                Some(name.to_string())
+            }
+        }
+    }
+}
+
+/// Modules may be decompressed when copied into the initramfs, verify the
+/// compressed original and compare with its decompressed content instead
+fn verify_decompressed_module(
+    root: &Path,
+    name: &str,
+    trusted: &TrustedHashes,
+) -> Result<Option<String>> {
+    for ext in MODULE_COMPRESSION_EXTS {
+        let path = root.join(format!("{name}.{ext}"));
+        let Some(expected) = trusted.get(&path) else {
+            continue;
+        };
+        let compressed = fs::read(&path)?;
+        if sha256(&compressed) != *expected {
+            bail!("Compressed module does not match trusted sha256: {path:?}");
+        }
+        let data = decompress(&compressed)?.context("Unsupported module compression")?;
+        return Ok(Some(sha256(&data)));
+    }
+    Ok(None)
+}
+
+/// Validate the structure of an early microcode image, it must be an uncompressed
+/// cpio that only contains microcode, returns the sha256 of the microcode files
+pub fn read_microcode(data: &[u8]) -> Result<Vec<String>> {
+    let mut hashes = Vec::new();
+    let mut data = data;
+    while !data.is_empty() {
+        if !cpio::is_newc(data) {
+            bail!("Microcode image is not an uncompressed cpio archive");
+        }
+        let (entries, consumed) = cpio::parse(data)?;
+        for entry in entries {
+            let name = entry.name.trim_start_matches("./");
+            if !MICROCODE_PATHS.contains(&name) {
+                bail!("Unexpected entry in microcode image: {:?}", entry.name);
+            }
+            if entry.is_file() {
+                hashes.push(sha256(entry.data));
+            } else if !entry.data.is_empty() {
+                bail!("Unexpected data for non-file entry: {:?}", entry.name);
+            }
+        }
+        data = &data[consumed..];
+        while let Some(rest) = data.strip_prefix(&[0]) {
+            data = rest;
+        }
+    }
+    if hashes.is_empty() {
+        bail!("Microcode image does not contain any microcode");
+    }
+    Ok(hashes)
+}
+
+/// Verify the early microcode images, returns the hashes of the trusted microcode
+fn verify_microcode(
+    root: &Path,
+    boot: &Path,
+    trusted: &TrustedHashes,
+    report: &mut Report,
+) -> HashSet<String> {
+    let mut microcode = HashSet::new();
+    for name in MICROCODE_IMAGES {
+        let path = boot.join(name);
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                report.findings.push(Finding::Error(path, err.into()));
+                continue;
+            }
+        };
+
+        report.covered.push(path.clone());
+        match trusted.get(&root.join("boot").join(name)) {
+            Some(expected) if sha256(&data) == *expected => {
+                debug!("Microcode image verified: {path:?}")
+            }
+            Some(_) => report.findings.push(Finding::Mismatch(path.clone())),
+            None => report.findings.push(Finding::Unverified(path.clone())),
+        }
+
+        match read_microcode(&data) {
+            Ok(hashes) => microcode.extend(hashes),
+            Err(err) => report.findings.push(Finding::MicrocodeInvalid(path, err)),
+        }
+    }
+    microcode
+}
+
+fn verify_initramfs(
+    root: &Path,
+    image: &Path,
+    trusted: &TrustedHashes,
+    microcode: &HashSet<String>,
+    report: &mut Report,
+) -> Result<()> {
+    let data = fs::read(image)?;
+    let files = read_initramfs(&data)?;
+    debug!("Found {} files in initramfs {image:?}", files.len());
+
+    for (name, _mode, content) in files {
+        // early microcode is copied from the microcode images
+        if name
+            .trim_start_matches("./")
+            .starts_with("kernel/x86/microcode/")
+        {
+            if !microcode.contains(&sha256(&content)) {
+                report
+                    .findings
+                    .push(Finding::InitramfsUntracked(image.to_owned(), name));
+            }
+            continue;
+        }
+
+        let Some(source) = packaged_source(&name) else {
+            continue;
+        };
+
+        let expected = if let Some(sha256) = trusted.get(&root.join(&source)) {
+            Some(sha256.clone())
+        } else if source.ends_with(".ko") {
+            verify_decompressed_module(root, &source, trusted)?
+        } else {
+            None
+        };
+
+        match expected {
+            Some(expected) if sha256(&content) == expected => (),
+            Some(_) => report
+                .findings
+                .push(Finding::InitramfsMismatch(image.to_owned(), name)),
+            None => report
+                .findings
+                .push(Finding::InitramfsUntracked(image.to_owned(), name)),
+        }
+    }
+
+    Ok(())
+}
+
+/// The kernel image is copied from /usr/lib/modules/<version>/vmlinuz by mkinitcpio
+fn installed_kernels(root: &Path) -> Result<Vec<(String, PathBuf)>> {
+    let mut kernels = Vec::new();
+    let modules = root.join("usr/lib/modules");
+    for entry in
+        fs::read_dir(&modules).with_context(|| anyhow!("Failed to read directory: {modules:?}"))?
+    {
+        let path = entry?.path();
+        let Ok(pkgbase) = fs::read_to_string(path.join("pkgbase")) else {
+            continue;
+        };
+        kernels.push((pkgbase.trim().to_string(), path.join("vmlinuz")));
+    }
+    Ok(kernels)
+}
+
+fn verify_kernels(
+    root: &Path,
+    boot: &Path,
+    trusted: &TrustedHashes,
+    report: &mut Report,
+) -> Result<()> {
+    let mut expected = HashMap::<_, Vec<_>>::new();
+    for (pkgbase, vmlinuz) in installed_kernels(root)? {
+        if let Some(sha256) = trusted.get(&vmlinuz) {
+            expected.entry(pkgbase).or_default().push(sha256.clone());
+        }
+    }
+
+    for entry in
+        fs::read_dir(boot).with_context(|| anyhow!("Failed to read directory: {boot:?}"))?
+    {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        let Some(pkgbase) = name.strip_prefix("vmlinuz-") else {
+            continue;
+        };
+
+        report.covered.push(path.clone());
+        let Some(expected) = expected.get(pkgbase) else {
+            report.findings.push(Finding::Unverified(path));
+            continue;
+        };
+        match sha256_file(&path) {
+            Ok(sha256) if expected.contains(&sha256) => debug!("Kernel image verified: {path:?}"),
+            Ok(_) => report.findings.push(Finding::Mismatch(path)),
+            Err(err) => report.findings.push(Finding::Error(path, err)),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn verify(root: &Path, boot: &Path, trusted: &TrustedHashes) -> Result<Report> {
+    let mut report = Report::default();
+
+    verify_kernels(root, boot, trusted, &mut report)?;
+    let microcode = verify_microcode(root, boot, trusted, &mut report);
+
+    for entry in
+        fs::read_dir(boot).with_context(|| anyhow!("Failed to read directory: {boot:?}"))?
+    {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !name.starts_with("initramfs-") || !name.ends_with(".img") {
+            continue;
+        }
+
+        info!("Verifying initramfs: {path:?}");
+        report.covered.push(path.clone());
+        if let Err(err) = verify_initramfs(root, &path, trusted, &microcode, &mut report) {
+            report.findings.push(Finding::Error(path, err));
+        }
+    }
+
+    Ok(report)
+}
+
+fn is_efi_binary(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+    let name = name.to_ascii_lowercase();
+    name.ends_with(".efi") || name.ends_with(".efi.signed")
+}
+
+/// The name of a packaged EFI binary once it's been installed into the ESP
+fn efi_install_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    let name = name.strip_suffix(".signed").unwrap_or(&name);
+    Some(name.to_string())
+}
+
+/// Grub images are assembled by grub-install and never shipped by a package
+fn is_generated_efi(name: &str) -> bool {
+    name.starts_with("grub") || name.starts_with("core.efi")
+}
+
+/// Verify EFI binaries in the EFI system partitions against packaged EFI binaries
+pub fn verify_efi(esps: &[PathBuf], trusted: &TrustedHashes) -> Report {
+    let mut report = Report::default();
+
+    let mut packaged = HashMap::<_, Vec<_>>::new();
+    for (path, sha256) in trusted.iter() {
+        if is_efi_binary(&path) {
+            if let Some(name) = efi_install_name(&path) {
+                packaged.entry(name).or_default().push(sha256);
+            }
+        }
+    }
+    let known = packaged
+        .values()
+        .flatten()
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+
+    for esp in esps {
+        for entry in walkdir::WalkDir::new(esp) {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    report
+                        .findings
+                        .push(Finding::Error(esp.clone(), err.into()));
+                    continue;
+                }
+            };
+            let path = entry.path();
+            if !entry.file_type().is_file() || !is_efi_binary(path) {
+                continue;
+            }
+            report.covered.push(path.to_owned());
+
+            let sha256 = match sha256_file(path) {
+                Ok(sha256) => sha256,
+                Err(err) => {
+                    report.findings.push(Finding::Error(path.to_owned(), err));
+                    continue;
+                }
+            };
+            if known.contains(&sha256.as_str()) {
+                debug!("EFI binary matches packaged original: {path:?}");
+                continue;
+            }
+
+            let name = efi_install_name(path).unwrap_or_default();
+            let finding = if packaged.contains_key(&name) {
+                Finding::EfiMismatch(path.to_owned())
+            } else if is_generated_efi(&name) {
+                Finding::EfiUnverifiable(path.to_owned())
+            } else {
+                Finding::EfiUntracked(path.to_owned())
+            };
+            report.findings.push(finding);
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn read_compressed_initramfs() {
+        let early = cpio::build(&[("kernel/x86/microcode/GenuineIntel.bin", 0o100644, b"ucode")]);
+        let main = cpio::build(&[
+            ("usr/bin", 0o040755, b""),
+            ("usr/bin/kmod", 0o100755, b"kmod"),
+        ]);
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(&main).unwrap();
+
+        let mut image = early;
+        image.extend(gz.finish().unwrap());
+
+        let files = read_initramfs(&image).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                (
+                    "kernel/x86/microcode/GenuineIntel.bin".to_string(),
+                    0o100644,
+                    b"ucode".to_vec()
+                ),
+                ("usr/bin/kmod".to_string(), 0o100755, b"kmod".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn validate_microcode() {
+        let image = cpio::build(&[
+            ("kernel", 0o040755, b""),
+            ("kernel/x86", 0o040755, b""),
+            ("kernel/x86/microcode", 0o040755, b""),
+            ("kernel/x86/microcode/AuthenticAMD.bin", 0o100644, b"ucode"),
+        ]);
+        assert_eq!(read_microcode(&image).unwrap(), vec![sha256(b"ucode")]);
+
+        let image = cpio::build(&[
+            ("kernel/x86/microcode/AuthenticAMD.bin", 0o100644, b"ucode"),
+            ("init", 0o100755, b"#!/bin/sh"),
+        ]);
+        assert!(read_microcode(&image).is_err());
+    }
+
+    #[test]
+    fn efi_names() {
+        assert_eq!(
+            efi_install_name(Path::new(
+                "/mnt/usr/lib/systemd/boot/efi/systemd-bootx64.efi.signed"
+            ))
+            .as_deref(),
+            Some("systemd-bootx64.efi")
+        );
+        assert_eq!(
+            efi_install_name(Path::new("/mnt/efi/EFI/BOOT/BOOTX64.EFI")).as_deref(),
+            Some("bootx64.efi")
+        );
+        assert!(is_efi_binary(Path::new("/mnt/efi/EFI/BOOT/BOOTX64.EFI")));
+        assert!(!is_efi_binary(Path::new("/mnt/efi/loader/loader.conf")));
+    }
+
+    #[test]
+    fn map_packaged_source() {
+        assert_eq!(
+            packaged_source("hooks/udev").as_deref(),
+            Some("usr/lib/initcpio/hooks/udev")
+        );
+        assert_eq!(
+            packaged_source("./usr/bin/kmod").as_deref(),
+            Some("usr/bin/kmod")
+        );
+        assert_eq!(packaged_source("etc/fstab"), None);
+        assert_eq!(
+            packaged_source("usr/lib/modules/6.7.0-arch1-1/modules.dep"),
+            None
+        );
+    }
+}
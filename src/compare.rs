@@ -0,0 +1,156 @@
+use crate::disk;
+use crate::errors::*;
+use crate::Event;
+use futures_util::StreamExt;
+use std::collections::HashSet;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+use walkdir::WalkDir;
+
+/// Describe differences in permissions, ownership and symlink targets
+pub fn metadata_diff(trusted: &Path, path: &Path) -> Result<Option<String>> {
+    let expected = fs::symlink_metadata(trusted)?;
+    let actual = fs::symlink_metadata(path)?;
+
+    let mut diff = Vec::new();
+    if expected.mode() & 0o7777 != actual.mode() & 0o7777 {
+        diff.push(format!(
+            "mode: {:04o} -> {:04o}",
+            expected.mode() & 0o7777,
+            actual.mode() & 0o7777
+        ));
+    }
+    if expected.uid() != actual.uid() {
+        diff.push(format!("uid: {} -> {}", expected.uid(), actual.uid()));
+    }
+    if expected.gid() != actual.gid() {
+        diff.push(format!("gid: {} -> {}", expected.gid(), actual.gid()));
+    }
+    if expected.is_symlink() && actual.is_symlink() {
+        let expected = fs::read_link(trusted)?;
+        let actual = fs::read_link(path)?;
+        if expected != actual {
+            diff.push(format!("link: {expected:?} -> {actual:?}"));
+        }
+    }
+
+    if diff.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(diff.join(", ")))
+    }
+}
+
+fn walk_trusted(trusted: &Path, excluded: &HashSet<PathBuf>) -> Vec<Result<(PathBuf, bool)>> {
+    let mut entries = Vec::new();
+    let mut walkdir = WalkDir::new(trusted).into_iter();
+    while let Some(entry) = walkdir.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                entries.push(Err(anyhow!("Failed to access trusted tree: {err:#}")));
+                continue;
+            }
+        };
+        let Ok(rel) = entry.path().strip_prefix(trusted) else {
+            continue;
+        };
+        if excluded.contains(rel) {
+            walkdir.skip_current_dir();
+            continue;
+        }
+        let file_type = entry.file_type();
+        if file_type.is_file() || file_type.is_symlink() {
+            entries.push(Ok((rel.to_owned(), file_type.is_file())));
+        }
+    }
+    entries
+}
+
+/// Hash a known-good tree and provide its files as trusted hashes for the investigated tree
+pub fn spawn_trusted_tree(
+    event_tx: mpsc::UnboundedSender<Event>,
+    trusted: PathBuf,
+    root: PathBuf,
+    excluded: HashSet<PathBuf>,
+    num_hash_workers: usize,
+    shutdown: CancellationToken,
+) {
+    tokio::spawn(async move {
+        let entries = {
+            let trusted = trusted.clone();
+            task::spawn_blocking(move || walk_trusted(&trusted, &excluded)).await
+        };
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(err) => {
+                event_tx
+                    .send(Event::DiskError(Error::from(err).into()))
+                    .ok();
+                event_tx.send(Event::CompletedListInstalled).ok();
+                return;
+            }
+        };
+
+        let stream = futures::stream::iter(entries)
+            .map(|entry| {
+                let trusted = trusted.clone();
+                let root = root.clone();
+                async move {
+                    let (rel, is_file) = entry?;
+                    let src = trusted.join(&rel);
+                    let path = root.join(&rel);
+
+                    let metadata = {
+                        let (src, path) = (src.clone(), path.clone());
+                        task::spawn_blocking(move || metadata_diff(&src, &path)).await?
+                    };
+                    let sha256 = if is_file {
+                        let sha256 = disk::hash_file(&src)
+                            .await
+                            .with_context(|| anyhow!("Failed to read trusted file {src:?}"))?;
+                        Some(hex::encode(sha256))
+                    } else {
+                        None
+                    };
+
+                    // the file missing in the investigated tree is not an error here
+                    Ok::<_, Error>((path, sha256, metadata.ok().flatten()))
+                }
+            })
+            .buffer_unordered(num_hash_workers);
+        futures_util::pin_mut!(stream);
+
+        loop {
+            let result = tokio::select! {
+                _ = shutdown.cancelled() => return,
+                result = stream.next() => result,
+            };
+            let Some(result) = result else { break };
+            let events = match result {
+                Ok((path, sha256, metadata)) => {
+                    let mut events = Vec::new();
+                    if let Some(diff) = metadata {
+                        events.push(Event::WrongMetadata(path.clone(), diff));
+                    }
+                    if let Some(sha256) = sha256 {
+                        events.push(Event::TrustedFile(path, sha256, None));
+                    }
+                    events
+                }
+                Err(err) => vec![Event::DiskError(err.into())],
+            };
+            for event in events {
+                if event_tx.send(event).is_err() {
+                    return;
+                }
+            }
+        }
+
+        event_tx.send(Event::CompletedListInstalled).ok();
+    });
+}
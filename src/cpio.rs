@@ -0,0 +1,149 @@
+use crate::errors::*;
+
+const HEADER_LEN: usize = 110;
+const TRAILER: &str = "TRAILER!!!";
+
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFREG: u32 = 0o100000;
+
+#[derive(Debug, PartialEq)]
+pub struct Entry<'a> {
+    pub name: String,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub data: &'a [u8],
+}
+
+impl Entry<'_> {
+    pub fn is_file(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+fn hex_field(header: &[u8], idx: usize) -> Result<u32> {
+    let start = 6 + idx * 8;
+    let field = std::str::from_utf8(&header[start..start + 8])
+        .context("cpio header field is not valid utf-8")?;
+    u32::from_str_radix(field, 16).with_context(|| anyhow!("Invalid hex in cpio header: {field:?}"))
+}
+
+pub fn is_newc(data: &[u8]) -> bool {
+    data.starts_with(b"070701") || data.starts_with(b"070702")
+}
+
+/// Parse a single newc archive, returns the entries and the number of bytes consumed
+pub fn parse(data: &[u8]) -> Result<(Vec<Entry<'_>>, usize)> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    loop {
+        let header = data
+            .get(offset..offset + HEADER_LEN)
+            .context("Unexpected end of cpio archive")?;
+        if !is_newc(header) {
+            bail!("Unsupported cpio header magic at offset {offset}");
+        }
+
+        let mode = hex_field(header, 1)?;
+        let uid = hex_field(header, 2)?;
+        let gid = hex_field(header, 3)?;
+        let filesize = hex_field(header, 6)? as usize;
+        let namesize = hex_field(header, 11)? as usize;
+
+        let name_start = offset + HEADER_LEN;
+        let name = data
+            .get(name_start..name_start + namesize)
+            .context("Unexpected end of cpio archive in filename")?;
+        let name = name.strip_suffix(b"\0").unwrap_or(name);
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        let data_start = align4(name_start + namesize);
+        let content = data
+            .get(data_start..data_start + filesize)
+            .context("Unexpected end of cpio archive in file content")?;
+        offset = align4(data_start + filesize);
+
+        if name == TRAILER {
+            break;
+        }
+
+        entries.push(Entry {
+            name,
+            mode,
+            uid,
+            gid,
+            data: content,
+        });
+    }
+
+    Ok((entries, offset.min(data.len())))
+}
+
+#[cfg(test)]
+pub fn build(entries: &[(&str, u32, &[u8])]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let trailer = (TRAILER, 0, &b""[..]);
+    for (name, mode, data) in entries.iter().chain([&trailer]) {
+        let namesize = name.len() + 1;
+        out.extend(
+            format!(
+                "070701{:08x}{mode:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{namesize:08x}{:08x}",
+                0, 0, 0, 1, 0, data.len(), 0, 0, 0, 0, 0
+            )
+            .as_bytes(),
+        );
+        out.extend(name.as_bytes());
+        out.push(0);
+        out.resize(align4(out.len()), 0);
+        out.extend(*data);
+        out.resize(align4(out.len()), 0);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_archive() {
+        let archive = build(&[
+            ("usr", 0o040755, b""),
+            ("usr/bin/busybox", 0o100755, b"hello world"),
+        ]);
+        let (entries, consumed) = parse(&archive).unwrap();
+        assert_eq!(consumed, archive.len());
+        assert_eq!(
+            entries,
+            vec![
+                Entry {
+                    name: "usr".to_string(),
+                    mode: 0o040755,
+                    uid: 0,
+                    gid: 0,
+                    data: b"",
+                },
+                Entry {
+                    name: "usr/bin/busybox".to_string(),
+                    mode: 0o100755,
+                    uid: 0,
+                    gid: 0,
+                    data: b"hello world",
+                },
+            ]
+        );
+        assert!(!entries[0].is_file());
+        assert!(entries[1].is_file());
+    }
+
+    #[test]
+    fn parse_truncated() {
+        let archive = build(&[("usr/bin/busybox", 0o100755, b"hello world")]);
+        assert!(parse(&archive[..120]).is_err());
+    }
+}
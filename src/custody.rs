@@ -0,0 +1,194 @@
+use crate::disk::sha256;
+use crate::errors::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const LOG_NAME: &str = "custody.log";
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// A single record of the chain-of-custody log, serialized as one json line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    pub seq: u64,
+    pub time: u64,
+    pub operator: String,
+    pub action: String,
+    pub path: PathBuf,
+    pub sha256: String,
+    /// Hash of the previous line in the log
+    pub prev: String,
+}
+
+/// The operator that is recorded, the user that invoked sudo if available
+pub fn operator() -> String {
+    ["SUDO_USER", "USER", "LOGNAME"]
+        .iter()
+        .find_map(|key| std::env::var(key).ok())
+        .unwrap_or_else(|| format!("uid={}", unsafe { libc::getuid() }))
+}
+
+/// Verify the hash chain of a log, returns the number of records and the hash of the last line
+pub fn verify(content: &str) -> Result<(u64, String)> {
+    let mut prev = GENESIS.to_string();
+    let mut seq = 0;
+    for (num, line) in content.lines().enumerate() {
+        let record = serde_json::from_str::<Record>(line)
+            .with_context(|| anyhow!("Failed to parse custody record in line {}", num + 1))?;
+        if record.seq != seq {
+            bail!(
+                "Unexpected sequence number in line {}: {}",
+                num + 1,
+                record.seq
+            );
+        }
+        if record.prev != prev {
+            bail!("Broken hash chain in line {}", num + 1);
+        }
+        prev = sha256(line.as_bytes());
+        seq += 1;
+    }
+    Ok((seq, prev))
+}
+
+/// An append-only, hash-chained log of the evidence that was collected
+pub struct Log {
+    file: fs::File,
+    operator: String,
+    seq: u64,
+    prev: String,
+}
+
+impl Log {
+    pub fn open(dir: &Path, operator: String) -> Result<Self> {
+        let path = dir.join(LOG_NAME);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&path)
+            .with_context(|| anyhow!("Failed to open custody log: {path:?}"))?;
+
+        // continue the existing chain, refuse to append to a log that was tampered with
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let (seq, prev) =
+            verify(&content).with_context(|| anyhow!("Failed to verify custody log: {path:?}"))?;
+
+        Ok(Log {
+            file,
+            operator,
+            seq,
+            prev,
+        })
+    }
+
+    pub fn append(&mut self, action: &str, path: &Path, digest: &str) -> Result<()> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let record = Record {
+            seq: self.seq,
+            time,
+            operator: self.operator.clone(),
+            action: action.to_string(),
+            path: path.to_owned(),
+            sha256: digest.to_string(),
+            prev: self.prev.clone(),
+        };
+        let line = serde_json::to_string(&record)?;
+        self.file
+            .write_all(format!("{line}\n").as_bytes())
+            .context("Failed to write custody log")?;
+        self.file.sync_data()?;
+
+        self.seq += 1;
+        self.prev = sha256(line.as_bytes());
+        Ok(())
+    }
+}
+
+/// Copy a file into the evidence directory, named by its sha256
+fn copy_evidence(dir: &Path, path: &Path) -> Result<String> {
+    let mut src = fs::File::open(path)?;
+    let tmp = dir.join(format!(".tmp-{}", std::process::id()));
+    let mut dest = fs::File::create(&tmp)?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = src.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        dest.write_all(&buf[..n])?;
+    }
+    dest.sync_all()?;
+    let sha256 = hex::encode(hasher.finalize());
+
+    let target = dir.join(&sha256);
+    fs::set_permissions(&tmp, fs::Permissions::from_mode(0o400))?;
+    match fs::rename(&tmp, &target) {
+        Ok(()) => Ok(sha256),
+        Err(err) => {
+            fs::remove_file(&tmp).ok();
+            Err(err.into())
+        }
+    }
+}
+
+/// Copy flagged files into the quarantine directory and record them in the custody log
+pub fn quarantine(dir: &Path, operator: String, paths: &[&PathBuf]) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| anyhow!("Failed to create directory: {dir:?}"))?;
+    let mut log = Log::open(dir, operator)?;
+
+    for path in paths {
+        match copy_evidence(dir, path) {
+            Ok(sha256) => {
+                info!("Quarantined {path:?} as {sha256}");
+                log.append("quarantine", path, &sha256)?;
+            }
+            Err(err) => warn!("Failed to quarantine {path:?}: {err:#}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verify_hash_chain() {
+        let first = Record {
+            seq: 0,
+            time: 1700000000,
+            operator: "root".to_string(),
+            action: "quarantine".to_string(),
+            path: PathBuf::from("/mnt/usr/bin/foo"),
+            sha256: GENESIS.to_string(),
+            prev: GENESIS.to_string(),
+        };
+        let first = serde_json::to_string(&first).unwrap();
+        let second = Record {
+            seq: 1,
+            time: 1700000001,
+            operator: "root".to_string(),
+            action: "quarantine".to_string(),
+            path: PathBuf::from("/mnt/usr/bin/bar"),
+            sha256: GENESIS.to_string(),
+            prev: sha256(first.as_bytes()),
+        };
+        let second = serde_json::to_string(&second).unwrap();
+
+        let log = format!("{first}\n{second}\n");
+        assert_eq!(verify(&log).unwrap(), (2, sha256(second.as_bytes())));
+
+        let tampered = log.replace("/mnt/usr/bin/foo", "/mnt/usr/bin/baz");
+        assert!(verify(&tampered).is_err());
+    }
+}
@@ -0,0 +1,329 @@
+use crate::digest::{Algorithm, Checksum, MultiHasher};
+use crate::disk;
+use crate::errors::*;
+use crate::fetch;
+use crate::hashes::TrustedHashes;
+use crate::pkg::Package;
+use crate::report::Entry;
+use futures_util::StreamExt;
+use similar::{ChangeTag, TextDiff};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_tar as tar;
+
+/// Files up to this size are kept in memory to show a diff of text files
+pub const MAX_DIFF_SIZE: u64 = 64 * 1024;
+/// Changed lines that are shown per file
+const MAX_DIFF_LINES: usize = 20;
+
+#[derive(Debug, Default)]
+pub struct Comparison {
+    pub findings: Vec<Finding>,
+    /// The changed lines of small text files that were flagged by the scan already
+    pub diffs: HashMap<PathBuf, Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum Finding {
+    /// The `.MTREE` of the package has a different hash for a file than the file in the package
+    MtreeMismatch {
+        path: PathBuf,
+        package: Package,
+        mtree: String,
+        content: String,
+    },
+    /// The file on disk matches the `.MTREE` but isn't the file in the package, with the
+    /// changed lines of small text files
+    Differs(PathBuf, Vec<String>),
+    Error(Option<PathBuf>, Error),
+}
+
+impl From<Finding> for Entry {
+    fn from(finding: Finding) -> Self {
+        match finding {
+            Finding::MtreeMismatch {
+                path,
+                package,
+                mtree,
+                content,
+            } => {
+                let mut entry = Entry::path("MTREE MISMATCH", path);
+                entry.package = Some(package.name);
+                entry.package_version = Some(package.version);
+                entry.expected = Some(mtree);
+                entry.sha256 = Some(content);
+                entry
+            }
+            Finding::Differs(path, diff) => {
+                let mut entry = Entry::path("WRONG CONTENT", path);
+                entry.details = diff;
+                entry
+            }
+            Finding::Error(path, err) => {
+                Entry::new("DEEP COMPARE ERROR", path).detail(format!("{err:#}"))
+            }
+        }
+    }
+}
+
+/// The changed lines between the packaged and the installed content of a text file,
+/// binary files have no diff
+pub fn diff_lines(original: &[u8], installed: &[u8]) -> Vec<String> {
+    let (Ok(original), Ok(installed)) = (
+        std::str::from_utf8(original),
+        std::str::from_utf8(installed),
+    ) else {
+        return Vec::new();
+    };
+    let diff = TextDiff::from_lines(original, installed);
+    let changes = diff
+        .iter_all_changes()
+        .filter_map(|change| match change.tag() {
+            ChangeTag::Delete => Some(format!("diff: -{:?}", change.value().trim_end())),
+            ChangeTag::Insert => Some(format!("diff: +{:?}", change.value().trim_end())),
+            ChangeTag::Equal => None,
+        })
+        .collect::<Vec<_>>();
+    let more = changes.len().saturating_sub(MAX_DIFF_LINES);
+    let mut lines = changes.into_iter().take(MAX_DIFF_LINES).collect::<Vec<_>>();
+    if more > 0 {
+        lines.push(format!("diff: {more} more changed lines"));
+    }
+    lines
+}
+
+/// The path of a package member in the root, the metadata files of the package are skipped
+fn member_path(root: &Path, path: &Path) -> Option<PathBuf> {
+    if path.to_str().is_some_and(|path| path.starts_with('.')) {
+        return None;
+    }
+    if !path.components().all(|c| matches!(c, Component::Normal(_))) {
+        warn!("Found malformed path in package: {path:?}");
+        return None;
+    }
+    Some(root.join(path))
+}
+
+/// Compare every file of a package with its `.MTREE` hash and with the file on disk
+async fn compare_members<R: AsyncRead + Unpin>(
+    reader: R,
+    root: &Path,
+    pkg: &Package,
+    trusted: &TrustedHashes,
+) -> Result<Comparison> {
+    let mut comparison = Comparison::default();
+    let mut tar = tar::Archive::new(reader);
+    let mut entries = tar.entries()?;
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.context("Failed to read entry from package")?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let Some(path) = member_path(root, &entry.path()?) else {
+            continue;
+        };
+        // only files that are part of the scan, excluded files have no trusted hash
+        let Some(mtree) = trusted.get(&path) else {
+            continue;
+        };
+        let mtree = Checksum::parse(&mtree)?;
+
+        let keep = entry.header().size()? <= MAX_DIFF_SIZE;
+        let mut content = Vec::new();
+        let mut hasher = MultiHasher::new(&[mtree.algorithm, Algorithm::Sha256]);
+        let mut buf = vec![0; fetch::DECOMPRESS_BUFFER_SIZE];
+        loop {
+            let n = entry
+                .read(&mut buf)
+                .await
+                .with_context(|| anyhow!("Failed to read {path:?} from package"))?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            if keep {
+                content.extend_from_slice(&buf[..n]);
+            }
+        }
+        let checksums = hasher.finalize();
+        let sha256 = checksums
+            .iter()
+            .find(|c| c.algorithm == Algorithm::Sha256)
+            .map(|c| c.hex.clone())
+            .context("Missing sha256 digest")?;
+        if !checksums.iter().any(|c| c.matches(&mtree)) {
+            comparison.findings.push(Finding::MtreeMismatch {
+                path: path.clone(),
+                package: pkg.clone(),
+                mtree: mtree.to_string(),
+                content: sha256.clone(),
+            });
+        }
+
+        let installed =
+            match disk::hash_file_with(&path, &[mtree.algorithm, Algorithm::Sha256]).await {
+                Ok(installed) => installed,
+                // reported by the scan already
+                Err(err) => {
+                    debug!("Failed to read {path:?} for deep compare: {err:#}");
+                    continue;
+                }
+            };
+        if installed
+            .iter()
+            .any(|c| c.algorithm == Algorithm::Sha256 && c.hex == sha256)
+        {
+            continue;
+        }
+        let diff = match keep {
+            true => match tokio::fs::read(&path).await {
+                Ok(installed) => diff_lines(&content, &installed),
+                Err(_) => Vec::new(),
+            },
+            false => Vec::new(),
+        };
+        if installed.iter().any(|c| c.matches(&mtree)) {
+            // the scan passed this file
+            comparison.findings.push(Finding::Differs(path, diff));
+        } else if !diff.is_empty() {
+            comparison.diffs.insert(path, diff);
+        }
+    }
+    Ok(comparison)
+}
+
+/// Compare the content of a package with the disk, the package is read from the first
+/// directory (like `--bundle` or `--pkg-cache`) or mirror that has it
+pub async fn compare_package(
+    client: &reqwest::Client,
+    dirs: &[PathBuf],
+    mirrors: &[String],
+    root: &Path,
+    pkg: &Package,
+    trusted: &TrustedHashes,
+) -> Result<Comparison> {
+    let Some((source, reader)) = fetch::open_package(client, dirs, mirrors, pkg).await? else {
+        bail!("Failed to find package {}-{}", pkg.name, pkg.version)
+    };
+    compare_members(reader, root, pkg, trusted)
+        .await
+        .with_context(|| anyhow!("Failed to read package: {source}"))
+}
+
+/// Compare the content of all packages with the disk, `concurrency` packages at a time
+pub async fn compare(
+    client: &reqwest::Client,
+    dirs: &[PathBuf],
+    mirrors: &[String],
+    root: &Path,
+    pkgs: &[Package],
+    trusted: &TrustedHashes,
+    concurrency: usize,
+) -> Comparison {
+    let mut results = futures_util::stream::iter(pkgs)
+        .map(|pkg| async move {
+            info!("Comparing content of {}-{}", pkg.name, pkg.version);
+            compare_package(client, dirs, mirrors, root, pkg, trusted).await
+        })
+        .buffer_unordered(concurrency.max(1));
+
+    let mut comparison = Comparison::default();
+    while let Some(result) = results.next().await {
+        match result {
+            Ok(result) => {
+                comparison.findings.extend(result.findings);
+                comparison.diffs.extend(result.diffs);
+            }
+            Err(err) => comparison.findings.push(Finding::Error(None, err)),
+        }
+    }
+    comparison
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn compare_package_content() {
+        let dir = std::env::temp_dir().join(format!("deep-test-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(root.join("etc/motd"), b"hello\nworld\n").unwrap();
+        std::fs::write(root.join("etc/issue"), b"Arch Linux\n").unwrap();
+        std::fs::write(root.join("etc/hostname"), b"foo\n").unwrap();
+
+        let mut tar = ::tar::Builder::new(Vec::new());
+        for (path, data) in [
+            (".MTREE", &b""[..]),
+            ("etc/motd", b"hello\nthere\n"),
+            ("etc/issue", b"Arch Linux\n"),
+            ("etc/hostname", b"bar\n"),
+        ] {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, data).unwrap();
+        }
+        let pkg = Package {
+            name: "filesystem".to_string(),
+            version: "2024.04.07-1".to_string(),
+            arch: "any".to_string(),
+        };
+        std::fs::write(dir.join(pkg.file_name("")), tar.into_inner().unwrap()).unwrap();
+
+        let trusted = [
+            (root.join("etc/motd"), disk::sha256(b"hello\nthere\n")),
+            // the .MTREE lies about this file
+            (root.join("etc/issue"), disk::sha256(b"Ubuntu\n")),
+            // and about this one, but the installed file matches it
+            (root.join("etc/hostname"), disk::sha256(b"foo\n")),
+        ]
+        .into_iter()
+        .collect::<TrustedHashes>();
+        let comparison = compare(
+            &reqwest::Client::new(),
+            std::slice::from_ref(&dir),
+            &[],
+            &root,
+            std::slice::from_ref(&pkg),
+            &trusted,
+            1,
+        )
+        .await;
+        let mut findings = comparison
+            .findings
+            .into_iter()
+            .map(Entry::from)
+            .map(|entry| (entry.kind, entry.path.unwrap(), entry.details))
+            .collect::<Vec<_>>();
+        findings.sort();
+        assert_eq!(
+            findings,
+            vec![
+                (
+                    "MTREE MISMATCH".to_string(),
+                    root.join("etc/hostname"),
+                    vec![]
+                ),
+                ("MTREE MISMATCH".to_string(), root.join("etc/issue"), vec![]),
+                (
+                    "WRONG CONTENT".to_string(),
+                    root.join("etc/hostname"),
+                    vec!["diff: -\"bar\"".to_string(), "diff: +\"foo\"".to_string()]
+                ),
+            ]
+        );
+        // flagged by the scan, with the changed lines
+        assert_eq!(
+            comparison.diffs[&root.join("etc/motd")],
+            vec![
+                "diff: -\"there\"".to_string(),
+                "diff: +\"world\"".to_string()
+            ]
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,203 @@
+use crate::errors::*;
+use clap::ValueEnum;
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+
+/// Hash algorithms supported for trusted hashes, md5 and sha1 are only
+/// used if a package manager doesn't record anything better
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, ValueEnum)]
+pub enum Algorithm {
+    #[default]
+    Sha256,
+    Sha512,
+    Blake3,
+    Sha1,
+    Md5,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Md5 => "md5",
+        }
+    }
+
+    /// Parse the tag of a BSD-style line, like `SHA512 (path) = hash`
+    pub fn from_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "SHA256" => Some(Algorithm::Sha256),
+            "SHA512" => Some(Algorithm::Sha512),
+            "BLAKE3" => Some(Algorithm::Blake3),
+            "SHA1" => Some(Algorithm::Sha1),
+            "MD5" => Some(Algorithm::Md5),
+            _ => None,
+        }
+    }
+
+    /// Length of the digest in hex
+    pub fn hex_len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 | Algorithm::Blake3 => 64,
+            Algorithm::Sha512 => 128,
+            Algorithm::Sha1 => 40,
+            Algorithm::Md5 => 32,
+        }
+    }
+
+    pub fn hasher(&self) -> Box<dyn Hasher> {
+        match self {
+            Algorithm::Sha256 => Box::new(Sha256::new()),
+            Algorithm::Sha512 => Box::new(Sha512::new()),
+            Algorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            Algorithm::Sha1 => Box::new(Sha1::new()),
+            Algorithm::Md5 => Box::new(Md5::new()),
+        }
+    }
+}
+
+/// An incremental hash function
+pub trait Hasher: Send {
+    fn update(&mut self, data: &[u8]);
+
+    fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+macro_rules! impl_hasher {
+    ($($ty:ty),*) => {
+        $(
+            impl Hasher for $ty {
+                fn update(&mut self, data: &[u8]) {
+                    Digest::update(self, data);
+                }
+
+                fn finalize(self: Box<Self>) -> Vec<u8> {
+                    Digest::finalize(*self).to_vec()
+                }
+            }
+        )*
+    };
+}
+
+impl_hasher!(Sha256, Sha512, Sha1, Md5);
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finalize(self: Box<Self>) -> Vec<u8> {
+        blake3::Hasher::finalize(&self).as_bytes().to_vec()
+    }
+}
+
+/// Compute the digests of multiple algorithms while reading the data once
+pub struct MultiHasher {
+    hashers: Vec<(Algorithm, Box<dyn Hasher>)>,
+}
+
+impl MultiHasher {
+    pub fn new(algorithms: &[Algorithm]) -> Self {
+        let mut hashers = Vec::<(Algorithm, Box<dyn Hasher>)>::new();
+        for algorithm in algorithms {
+            if !hashers.iter().any(|(a, _)| a == algorithm) {
+                hashers.push((*algorithm, algorithm.hasher()));
+            }
+        }
+        MultiHasher { hashers }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for (_, hasher) in &mut self.hashers {
+            hasher.update(data);
+        }
+    }
+
+    pub fn finalize(self) -> Vec<Checksum> {
+        self.hashers
+            .into_iter()
+            .map(|(algorithm, hasher)| Checksum {
+                algorithm,
+                hex: hex::encode(hasher.finalize()),
+            })
+            .collect()
+    }
+}
+
+/// A hash together with its algorithm, sha256 is written as plain hex and
+/// everything else as `<algorithm>:<hex>`, like `blake3:af13...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub hex: String,
+}
+
+impl Checksum {
+    pub fn new(algorithm: Algorithm, hex: &str) -> Result<Self> {
+        if hex.len() != algorithm.hex_len() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("Invalid {} digest: {hex:?}", algorithm.name());
+        }
+        Ok(Checksum {
+            algorithm,
+            hex: hex.to_ascii_lowercase(),
+        })
+    }
+
+    pub fn parse(s: &str) -> Result<Self> {
+        let (algorithm, hex) = match s.split_once(':') {
+            Some((name, hex)) => {
+                let algorithm = Algorithm::from_str(name, true)
+                    .map_err(|_| anyhow!("Unknown hash algorithm: {name:?}"))?;
+                (algorithm, hex)
+            }
+            None => (Algorithm::Sha256, s),
+        };
+        Self::new(algorithm, hex)
+    }
+
+    pub fn matches(&self, other: &Checksum) -> bool {
+        self.algorithm == other.algorithm && self.hex.eq_ignore_ascii_case(&other.hex)
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        match self.algorithm {
+            Algorithm::Sha256 => write!(w, "{}", self.hex),
+            algorithm => write!(w, "{}:{}", algorithm.name(), self.hex),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multi_hasher_single_pass() {
+        let mut hasher =
+            MultiHasher::new(&[Algorithm::Blake3, Algorithm::Sha256, Algorithm::Blake3]);
+        hasher.update(b"");
+        let checksums = hasher
+            .finalize()
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            checksums,
+            vec![
+                "blake3:af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ]
+        );
+        assert_eq!(
+            Checksum::parse(&checksums[0]).unwrap().algorithm,
+            Algorithm::Blake3
+        );
+    }
+}
@@ -1,31 +1,223 @@
+use crate::digest::{Algorithm, Checksum, MultiHasher};
 use crate::errors::*;
+use crate::filter::PathFilter;
+use crate::mtree;
+use crate::state::{Incremental, Stamp};
+use crate::throttle::Pause;
 use crate::Event;
+use clap::ValueEnum;
 use sha2::{Digest, Sha256};
-use std::collections::HashSet;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs::FileType;
+use std::fs::Metadata;
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
 use tokio::task;
-use walkdir::{DirEntry, WalkDir};
+use tokio_util::sync::CancellationToken;
+
+/// Default size of the read buffer of the hash workers, large files are syscall-bound with small buffers
+pub const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+/// Upper bound of hash workers per CPU, this is also the size of the thread pool they read files with
+pub const MAX_HASH_WORKERS_PER_CPU: usize = 4;
 
 #[derive(Debug)]
 pub enum HashVerify {
-    Passed(PathBuf),
-    Flagged(PathBuf),
+    /// The sha256 is included if the file was verified with a different algorithm
+    Passed(PathBuf, Option<Stamp>, Option<String>),
+    /// The file didn't change since a previous run (or `--since`) and wasn't read
+    Unchanged(PathBuf, Stamp),
+    Flagged(PathBuf, String),
+    Computed(PathBuf, String),
+    /// The size on disk differs from the trusted size, as expected and actual size. The file isn't read
+    WrongSize(PathBuf, u64, u64),
+}
+
+/// The classes of [`ScanError`], like for `--fail-on-errors`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ValueEnum)]
+pub enum ErrorClass {
+    PermissionDenied,
+    Vanished,
+    Io,
+    UnreadableSymlink,
+    HashDecode,
+    WorkerCrash,
+    Other,
+}
+
+impl ErrorClass {
+    /// Errors that are normal on a live system, like files of running processes
+    /// that are deleted while scanning. Everything else may point to damaged media
+    pub fn is_expected(&self) -> bool {
+        matches!(self, ErrorClass::PermissionDenied | ErrorClass::Vanished)
+    }
+}
+
+/// Errors while reading the investigated filesystem
+#[derive(Debug)]
+pub enum ScanError {
+    PermissionDenied(PathBuf),
+    /// The file was removed between listing and reading it
+    Vanished(PathBuf),
+    Io(PathBuf, io::Error),
+    /// The target of a symlink couldn't be read to compare it
+    UnreadableSymlink(PathBuf, io::Error),
+    /// The trusted hash of the file couldn't be decoded
+    HashDecode(PathBuf, Error),
+    /// A hash worker crashed while reading the file and was restarted
+    WorkerCrash(Option<PathBuf>, String),
+    Other(Option<PathBuf>, Error),
+}
+
+impl ScanError {
+    pub fn from_io(path: PathBuf, err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => ScanError::PermissionDenied(path),
+            io::ErrorKind::NotFound => ScanError::Vanished(path),
+            _ => ScanError::Io(path, err),
+        }
+    }
+
+    /// Classify an error that happened while reading a file
+    pub fn read(path: PathBuf, err: Error) -> Self {
+        match err.downcast::<io::Error>() {
+            Ok(err) => Self::from_io(path, err),
+            Err(err) => ScanError::Other(Some(path), err),
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            ScanError::PermissionDenied(_) => ErrorClass::PermissionDenied,
+            ScanError::Vanished(_) => ErrorClass::Vanished,
+            ScanError::Io(..) => ErrorClass::Io,
+            ScanError::UnreadableSymlink(..) => ErrorClass::UnreadableSymlink,
+            ScanError::HashDecode(..) => ErrorClass::HashDecode,
+            ScanError::WorkerCrash(..) => ErrorClass::WorkerCrash,
+            ScanError::Other(..) => ErrorClass::Other,
+        }
+    }
+
+    /// The error class, used as kind in the report
+    pub fn kind(&self) -> &'static str {
+        match self.class() {
+            ErrorClass::PermissionDenied => "PERMISSION DENIED",
+            ErrorClass::Vanished => "VANISHED",
+            ErrorClass::Io => "IO ERROR",
+            ErrorClass::UnreadableSymlink => "UNREADABLE SYMLINK",
+            ErrorClass::HashDecode => "HASH DECODE ERROR",
+            ErrorClass::WorkerCrash => "WORKER CRASH",
+            ErrorClass::Other => "DISK ERROR",
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            ScanError::PermissionDenied(path)
+            | ScanError::Vanished(path)
+            | ScanError::Io(path, _)
+            | ScanError::UnreadableSymlink(path, _)
+            | ScanError::HashDecode(path, _) => Some(path),
+            ScanError::WorkerCrash(path, _) | ScanError::Other(path, _) => path.as_deref(),
+        }
+    }
+
+    /// Description of the error without the path
+    pub fn detail(&self) -> Option<String> {
+        match self {
+            ScanError::PermissionDenied(_) | ScanError::Vanished(_) => None,
+            ScanError::Io(_, err) | ScanError::UnreadableSymlink(_, err) => Some(err.to_string()),
+            ScanError::WorkerCrash(_, msg) => Some(msg.clone()),
+            ScanError::HashDecode(_, err) | ScanError::Other(_, err) => Some(format!("{err:#}")),
+        }
+    }
 }
 
-async fn verify_file(path: &Path, sha256: &str) -> Result<bool> {
+impl fmt::Display for ScanError {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScanError::PermissionDenied(path) => write!(w, "Permission denied: {path:?}"),
+            ScanError::Vanished(path) => write!(w, "File vanished during scan: {path:?}"),
+            ScanError::Io(path, err) => write!(w, "Failed to read {path:?}: {err}"),
+            ScanError::UnreadableSymlink(path, err) => {
+                write!(w, "Failed to read symlink {path:?}: {err}")
+            }
+            ScanError::HashDecode(path, err) => {
+                write!(w, "Failed to decode trusted hash of {path:?}: {err:#}")
+            }
+            ScanError::WorkerCrash(Some(path), msg) => {
+                write!(w, "Hash worker crashed while reading {path:?}: {msg}")
+            }
+            ScanError::WorkerCrash(None, msg) => write!(w, "Hash worker crashed: {msg}"),
+            ScanError::Other(Some(path), err) => write!(w, "Failed to read {path:?}: {err:#}"),
+            ScanError::Other(None, err) => write!(w, "{err:#}"),
+        }
+    }
+}
+
+impl std::error::Error for ScanError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ScanError::Io(_, err) | ScanError::UnreadableSymlink(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for ScanError {
+    fn from(err: Error) -> Self {
+        ScanError::Other(None, err)
+    }
+}
+
+pub fn sha256(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+pub async fn hash_file(path: &Path) -> Result<Vec<u8>> {
     let mut file = File::open(path).await?;
+    hash_reader(&mut file).await
+}
+
+pub async fn hash_reader<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>> {
+    hash_reader_sized(reader, read_options().buffer_size).await
+}
+
+/// Hash with a custom read buffer size, used to tune [`HASH_BUFFER_SIZE`]
+pub async fn hash_reader_sized<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf_size: usize,
+) -> Result<Vec<u8>> {
     let mut hasher = Sha256::new();
 
-    let expected = hex::decode(sha256)
-        .with_context(|| anyhow!("Failed to decode sha256 as hex: {sha256:?}"))?;
+    let mut buf = vec![0u8; buf_size];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    let calculated = hasher.finalize();
+
+    Ok(calculated.to_vec())
+}
+
+/// Hash a file with multiple algorithms in a single pass
+pub async fn hash_file_with(path: &Path, algorithms: &[Algorithm]) -> Result<Vec<Checksum>> {
+    let mut file = File::open(path).await?;
+    let mut hasher = MultiHasher::new(algorithms);
 
-    let mut buf = [0u8; 2048];
+    let mut buf = vec![0u8; read_options().buffer_size];
     loop {
         let n = file.read(&mut buf).await?;
         if n == 0 {
@@ -33,102 +225,717 @@ async fn verify_file(path: &Path, sha256: &str) -> Result<bool> {
         }
         hasher.update(&buf[..n]);
     }
-    let calculated = hasher.finalize();
 
-    if expected == calculated[..] {
-        Ok(true)
+    Ok(hasher.finalize())
+}
+
+/// How the hash workers read files
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReadOptions {
+    pub buffer_size: usize,
+    /// Files of at least this size are memory mapped instead of read. A file that is
+    /// truncated while it's mapped crashes the scan with SIGBUS, so this is off by default
+    pub mmap_threshold: Option<u64>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        ReadOptions {
+            buffer_size: HASH_BUFFER_SIZE,
+            mmap_threshold: None,
+        }
+    }
+}
+
+static READ_OPTIONS: OnceLock<ReadOptions> = OnceLock::new();
+
+/// Configure how files are read by the hash workers, like the [`hash_pool`] this applies to
+/// the whole process and can only be set once, before the first file is hashed
+pub fn set_read_options(options: ReadOptions) -> Result<()> {
+    if options.buffer_size == 0 {
+        bail!("The read buffer of the hash workers can't be empty");
+    }
+    READ_OPTIONS
+        .set(options)
+        .map_err(|_| anyhow!("Read options of the hash workers have already been set"))
+}
+
+fn read_options() -> ReadOptions {
+    READ_OPTIONS.get().copied().unwrap_or_default()
+}
+
+thread_local! {
+    /// The read buffer of each thread of the [`hash_pool`], so it's not allocated for every file
+    static READ_BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Hash a file with multiple algorithms in a single pass of positioned reads (or of a memory
+/// map, see [`ReadOptions`]), this blocks and is meant for the [`hash_pool`]
+pub fn hash_file_blocking(
+    path: &Path,
+    algorithms: &[Algorithm],
+    shutdown: &CancellationToken,
+) -> Result<Vec<Checksum>> {
+    hash_file_blocking_with(path, algorithms, read_options(), shutdown)
+}
+
+fn hash_file_blocking_with(
+    path: &Path,
+    algorithms: &[Algorithm],
+    options: ReadOptions,
+    shutdown: &CancellationToken,
+) -> Result<Vec<Checksum>> {
+    let file = std::fs::File::open(path)?;
+    let mut hasher = MultiHasher::new(algorithms);
+
+    if let Some(threshold) = options.mmap_threshold {
+        let len = file.metadata()?.len();
+        // empty files can't be mapped
+        if len > 0 && len >= threshold {
+            let map = unsafe { memmap2::Mmap::map(&file) }?;
+            map.advise(memmap2::Advice::Sequential).ok();
+            for chunk in map.chunks(options.buffer_size) {
+                if shutdown.is_cancelled() {
+                    bail!("Hashing was cancelled");
+                }
+                hasher.update(chunk);
+            }
+            return Ok(hasher.finalize());
+        }
+    }
+
+    READ_BUFFER.with_borrow_mut(|buf| {
+        buf.resize(options.buffer_size, 0);
+        let mut offset = 0;
+        loop {
+            if shutdown.is_cancelled() {
+                bail!("Hashing was cancelled");
+            }
+            let n = match file.read_at(buf, offset) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            };
+            hasher.update(&buf[..n]);
+            offset += n as u64;
+        }
+        Ok(())
+    })?;
+
+    Ok(hasher.finalize())
+}
+
+/// The thread pool of the hash workers, the async workers only hand out the files
+/// and the reading and hashing happens here without the overhead of the async runtime
+fn hash_pool() -> &'static rayon::ThreadPool {
+    static POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+    POOL.get_or_init(|| {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(num_cpus::get() * MAX_HASH_WORKERS_PER_CPU)
+            .thread_name(|idx| format!("hash-worker-{idx}"))
+            .build()
+            .expect("Failed to create thread pool for hash workers")
+    })
+}
+
+/// Run a blocking function on the [`hash_pool`], a panic is resumed in the calling task
+async fn run_on_hash_pool<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = oneshot::channel();
+    hash_pool().spawn(move || {
+        tx.send(panic::catch_unwind(AssertUnwindSafe(f))).ok();
+    });
+    match rx.await.expect("Hash pool dropped a task") {
+        Ok(value) => value,
+        Err(panic) => panic::resume_unwind(panic),
+    }
+}
+
+/// Identifies the content of a file with multiple hard links, the change time tells apart
+/// a file that was replaced and got the same inode number
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Inode {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    ctime: (i64, i64),
+}
+
+impl From<&Metadata> for Inode {
+    fn from(metadata: &Metadata) -> Self {
+        Inode {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.len(),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+        }
+    }
+}
+
+/// The digests of a hard linked file, `None` if it couldn't be read
+type LinkDigests = Arc<OnceLock<Option<Vec<Checksum>>>>;
+
+/// Digests of files with multiple hard links, so their content is only read once for all paths.
+/// An inode is forgotten once all of its links have been hashed, or by [`forget_hard_links`]
+fn hard_links() -> &'static std::sync::Mutex<HashMap<Inode, (LinkDigests, u64)>> {
+    static LINKS: OnceLock<std::sync::Mutex<HashMap<Inode, (LinkDigests, u64)>>> = OnceLock::new();
+    LINKS.get_or_init(Default::default)
+}
+
+/// Forget the hard links of a finished scan, inodes with links outside of the scan (like in
+/// excluded directories or on other mounts) are never complete and would be kept otherwise
+pub fn forget_hard_links() {
+    hard_links().lock().unwrap().clear();
+}
+
+/// Hash a file like [`hash_file_blocking`], a file with multiple hard links is only read for
+/// the first of its paths and the others wait for its digests
+fn hash_linked_file(
+    path: &Path,
+    metadata: Option<&Metadata>,
+    algorithms: &[Algorithm],
+    shutdown: &CancellationToken,
+) -> Result<Vec<Checksum>> {
+    let Some(metadata) = metadata.filter(|metadata| metadata.nlink() > 1) else {
+        return hash_file_blocking(path, algorithms, shutdown);
+    };
+    let digests = {
+        let mut links = hard_links().lock().unwrap();
+        let inode = Inode::from(metadata);
+        let (digests, remaining) = links
+            .entry(inode)
+            .or_insert_with(|| (Default::default(), metadata.nlink()));
+        let digests = digests.clone();
+        *remaining -= 1;
+        if *remaining == 0 {
+            links.remove(&inode);
+        }
+        digests
+    };
+
+    let mut err = None;
+    let cached = digests.get_or_init(|| match hash_file_blocking(path, algorithms, shutdown) {
+        Ok(checksums) => Some(checksums),
+        Err(e) => {
+            err = Some(e);
+            None
+        }
+    });
+    if let Some(err) = err {
+        return Err(err);
+    }
+    let reused = cached.as_ref().and_then(|checksums| {
+        algorithms
+            .iter()
+            .map(|algorithm| {
+                checksums
+                    .iter()
+                    .find(|c| c.algorithm == *algorithm)
+                    .cloned()
+            })
+            .collect::<Option<Vec<_>>>()
+    });
+    match reused {
+        Some(checksums) => {
+            trace!("Reusing digests of hard link: {path:?}");
+            Ok(checksums)
+        }
+        // the first path failed or was hashed with other algorithms
+        None => hash_file_blocking(path, algorithms, shutdown),
+    }
+}
+
+fn verify_file(
+    path: PathBuf,
+    metadata: Option<&Metadata>,
+    expected: Option<String>,
+    stamp: Option<Stamp>,
+    shutdown: &CancellationToken,
+) -> Result<HashVerify, ScanError> {
+    let Some(expected) = expected else {
+        let calculated = match hash_linked_file(&path, metadata, &[Algorithm::Sha256], shutdown) {
+            Ok(mut calculated) => calculated.remove(0),
+            Err(err) => return Err(ScanError::read(path, err)),
+        };
+        return Ok(HashVerify::Computed(path, calculated.hex));
+    };
+    let expected = match Checksum::parse(&expected) {
+        Ok(expected) => expected,
+        Err(err) => return Err(ScanError::HashDecode(path, err)),
+    };
+
+    // the sha256 is always needed for reports and exports
+    let algorithms = [expected.algorithm, Algorithm::Sha256];
+    let checksums = match hash_linked_file(&path, metadata, &algorithms, shutdown) {
+        Ok(checksums) => checksums,
+        Err(err) => return Err(ScanError::read(path, err)),
+    };
+    let sha256 = checksums
+        .iter()
+        .find(|c| c.algorithm == Algorithm::Sha256)
+        .map(|c| c.hex.clone())
+        .context("Missing sha256 digest")?;
+
+    if checksums.iter().any(|c| c.matches(&expected)) {
+        let sha256 = Some(sha256).filter(|_| expected.algorithm != Algorithm::Sha256);
+        Ok(HashVerify::Passed(path, stamp, sha256))
     } else {
-        Ok(false)
+        Ok(HashVerify::Flagged(path, sha256))
     }
 }
 
-pub async fn read_disk(
-    walkdir: &std::sync::Mutex<walkdir::IntoIter>,
-    entry: std::result::Result<DirEntry, walkdir::Error>,
-    excluded: &HashSet<PathBuf>,
-) -> Result<Option<(PathBuf, FileType)>> {
-    let entry = entry.context("Failed to access disk")?;
+/// Verify a single file on the [`hash_pool`], a file with a different size than
+/// in its `expected` metadata is flagged without reading it
+fn hash_task(
+    path: PathBuf,
+    sha256: Option<String>,
+    expected: Option<mtree::Metadata>,
+    previous: Option<Arc<Incremental>>,
+    shutdown: &CancellationToken,
+) -> Event {
+    // the stamp is taken before reading, so concurrent writes cause a re-hash next time
+    let metadata = std::fs::symlink_metadata(&path).ok();
+    let size = metadata.as_ref().map(|m| m.len()).unwrap_or_default();
+    let expected_size = expected.as_ref().and_then(|expected| expected.size);
+    if let Some(expected) = expected_size.filter(|_| sha256.is_some()) {
+        if metadata
+            .as_ref()
+            .is_some_and(|m| m.is_file() && m.len() != expected)
+        {
+            return Event::CompletedHashing(HashVerify::WrongSize(path, expected, size), 0);
+        }
+    }
+    let stamp = metadata
+        .as_ref()
+        .filter(|_| previous.is_some())
+        .map(Stamp::from_metadata);
 
-    let path = entry.path().to_owned();
-    if excluded.contains(&path) {
-        let mut lock = walkdir.lock().unwrap();
-        lock.skip_current_dir();
+    match (&previous, stamp, &sha256) {
+        (Some(previous), Some(stamp), Some(_)) if previous.is_unchanged(&path, &stamp) => {
+            trace!("Skipping unchanged file: {path:?}");
+            Event::CompletedHashing(HashVerify::Unchanged(path, stamp), size)
+        }
+        (Some(previous), Some(stamp), Some(_))
+            if expected
+                .as_ref()
+                .is_some_and(|expected| previous.is_older(&stamp, size, expected)) =>
+        {
+            trace!("Skipping file with the modification time of its package: {path:?}");
+            Event::CompletedHashing(HashVerify::Unchanged(path, stamp), size)
+        }
+        _ => match verify_file(path, metadata.as_ref(), sha256, stamp, shutdown) {
+            Ok(verified) => Event::CompletedHashing(verified, size),
+            Err(err) => Event::DiskError(err),
+        },
+    }
+}
+
+/// The type of a file on disk, `None` for devices, sockets and fifos
+pub fn file_kind(file_type: FileType) -> Option<mtree::Kind> {
+    if file_type.is_symlink() {
+        Some(mtree::Kind::Link)
+    } else if file_type.is_dir() {
+        Some(mtree::Kind::Dir)
+    } else if file_type.is_file() {
+        Some(mtree::Kind::File)
+    } else {
+        None
+    }
+}
+
+/// Directories that are replaced with symlinks on purpose, like `/lib -> usr/lib` on merged-/usr systems
+fn is_merged_dir(path: &Path, target: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    crate::MERGED_USR_DIRS.contains(&name)
+        && [Path::new("usr").join(name), Path::new("/usr").join(name)].contains(&target.to_owned())
+}
+
+/// Compare the type of a file with its trusted type, `None` if they match. The target of a
+/// symlink that replaced a file or directory is part of the diff
+pub fn type_diff(path: &Path, expected: &mtree::Metadata) -> Result<Option<String>, ScanError> {
+    let actual =
+        std::fs::symlink_metadata(path).map_err(|err| ScanError::from_io(path.to_owned(), err))?;
+    let expected = expected.kind();
+    let kind = file_kind(actual.file_type());
+    if kind == Some(expected) {
         return Ok(None);
     }
 
-    let stat = task::spawn_blocking(move || entry.file_type())
-        .await
-        .with_context(|| anyhow!("Failed to stat path {path:?}"))?;
+    let mut diff = format!(
+        "type: {} -> {}",
+        expected.name(),
+        kind.map_or("special", |kind| kind.name())
+    );
+    if actual.is_symlink() {
+        let target = std::fs::read_link(path)
+            .map_err(|err| ScanError::UnreadableSymlink(path.to_owned(), err))?;
+        if expected == mtree::Kind::Dir && is_merged_dir(path, &target) {
+            return Ok(None);
+        }
+        diff.push_str(&format!(", link: {target:?}"));
+    }
+    Ok(Some(diff))
+}
 
-    Ok(Some((path, stat)))
+/// Compare the permissions, ownership and symlink target of a file with its trusted
+/// metadata, `None` if they match. Files of another type are reported by [`type_diff`]
+pub fn metadata_diff(path: &Path, expected: &mtree::Metadata) -> Result<Option<String>, ScanError> {
+    let actual =
+        std::fs::symlink_metadata(path).map_err(|err| ScanError::from_io(path.to_owned(), err))?;
+    if file_kind(actual.file_type()) != Some(expected.kind()) {
+        return Ok(None);
+    }
+
+    let mut diff = Vec::new();
+    if let Some(link) = &expected.link {
+        let expected = Path::new(link);
+        let actual = std::fs::read_link(path)
+            .map_err(|err| ScanError::UnreadableSymlink(path.to_owned(), err))?;
+        if expected != actual {
+            diff.push(format!("link: {expected:?} -> {actual:?}"));
+        }
+    } else if let Some(mode) = expected.mode {
+        // the mode of symlinks is meaningless on linux
+        if mode & 0o7777 != actual.mode() & 0o7777 {
+            diff.push(format!(
+                "mode: {:04o} -> {:04o}",
+                mode & 0o7777,
+                actual.mode() & 0o7777
+            ));
+        }
+    }
+    if let Some(uid) = expected.uid.filter(|uid| *uid != actual.uid()) {
+        diff.push(format!("uid: {uid} -> {}", actual.uid()));
+    }
+    if let Some(gid) = expected.gid.filter(|gid| *gid != actual.gid()) {
+        diff.push(format!("gid: {gid} -> {}", actual.gid()));
+    }
+
+    if diff.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(diff.join(", ")))
+    }
 }
 
-pub fn spawn_scan(
+/// Verify the trusted type and metadata of packaged files, this blocks. Files that don't exist
+/// (or are excluded) are skipped.
+pub fn verify_metadata(
+    trusted: impl IntoIterator<Item = (PathBuf, mtree::Metadata)>,
+    excluded: &HashSet<PathBuf>,
+) -> Vec<Event> {
+    let mut events = Vec::new();
+    for (path, expected) in trusted {
+        if path.ancestors().any(|dir| excluded.contains(dir)) {
+            continue;
+        }
+        match type_diff(&path, &expected) {
+            Ok(Some(diff)) => {
+                events.push(Event::TypeChanged(path, diff));
+                continue;
+            }
+            Ok(None) => (),
+            Err(ScanError::Vanished(_)) => continue,
+            Err(err) => {
+                events.push(Event::DiskError(err));
+                continue;
+            }
+        }
+        match metadata_diff(&path, &expected) {
+            Ok(Some(diff)) => events.push(Event::WrongMetadata(path, diff)),
+            Ok(None) => (),
+            Err(ScanError::Vanished(_)) => (),
+            Err(err) => events.push(Event::DiskError(err)),
+        }
+    }
+    events
+}
+
+/// Files to hash together with their expected hash and metadata (if any)
+pub type HashBatch = Vec<(PathBuf, Option<String>, Option<mtree::Metadata>)>;
+
+/// State of a hash worker that survives a crash, so a restarted worker continues with the rest of the batch
+#[derive(Debug, Default)]
+struct WorkerState {
+    current: Option<PathBuf>,
+    batch: VecDeque<(PathBuf, Option<String>, Option<mtree::Metadata>)>,
+}
+
+/// Wait for batches of paths and their expected hash, then verify with disk content,
+/// files that passed in a previous run and didn't change since are skipped
+async fn hash_worker(
     event_tx: mpsc::UnboundedSender<Event>,
-    path: PathBuf,
-    excluded: HashSet<PathBuf>,
+    previous: Option<Arc<Incremental>>,
+    shutdown: CancellationToken,
+    state: Arc<std::sync::Mutex<WorkerState>>,
+) {
+    loop {
+        let task = state.lock().unwrap().batch.pop_front();
+        let Some((path, sha256, expected)) = task else {
+            let (tx, rx) = oneshot::channel();
+            if event_tx.send(Event::AvailableHasher(tx)).is_err() {
+                break;
+            }
+            let batch = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                batch = rx => batch,
+            };
+            let Ok(batch) = batch else { break };
+            state.lock().unwrap().batch.extend(batch);
+            continue;
+        };
+        state.lock().unwrap().current = Some(path.clone());
+
+        let task = {
+            let previous = previous.clone();
+            let shutdown = shutdown.clone();
+            run_on_hash_pool(move || hash_task(path, sha256, expected, previous, &shutdown))
+        };
+        let event = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            event = task => event,
+        };
+        state.lock().unwrap().current = None;
+
+        if event_tx.send(event).is_err() {
+            break;
+        }
+    }
+}
+
+fn panic_message(err: task::JoinError) -> String {
+    let panic = err.into_panic();
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// Spawn the hash workers, a worker that crashed is reported with the file it
+/// was working on and restarted with the rest of its batch, so the pool never shrinks
+pub fn spawn_hashers(
+    event_tx: &mpsc::UnboundedSender<Event>,
     num_hash_workers: usize,
+    previous: Option<Arc<Incremental>>,
+    shutdown: &CancellationToken,
 ) {
-    // wait for paths and their expected hash, then verify with disk content
     for _ in 0..num_hash_workers {
         let event_tx = event_tx.clone();
+        let previous = previous.clone();
+        let shutdown = shutdown.clone();
         tokio::spawn(async move {
+            let state = Arc::new(std::sync::Mutex::new(WorkerState::default()));
             loop {
-                let (tx, rx) = oneshot::channel();
-                if event_tx.send(Event::AvailableHasher(tx)).is_err() {
-                    break;
-                }
-                let Ok((path, sha256)) = rx.await else { break };
-
-                let event = match verify_file(&path, &sha256).await {
-                    Ok(verified) => Event::CompletedHashing(if verified {
-                        HashVerify::Passed(path)
-                    } else {
-                        HashVerify::Flagged(path)
-                    }),
-                    Err(err) => {
-                        Event::DiskError(anyhow!("Failed to read file from disk {path:?}: {err:#}"))
-                    }
+                let worker = tokio::spawn(hash_worker(
+                    event_tx.clone(),
+                    previous.clone(),
+                    shutdown.clone(),
+                    state.clone(),
+                ));
+                let err = match worker.await {
+                    Ok(()) => break,
+                    Err(err) if err.is_panic() => err,
+                    Err(_) => break,
                 };
 
-                if event_tx.send(event).is_err() {
+                let path = state
+                    .lock()
+                    .unwrap_or_else(|err| err.into_inner())
+                    .current
+                    .take();
+                let msg = panic_message(err);
+                error!("Hash worker crashed while reading {path:?}, restarting: {msg}");
+                if event_tx
+                    .send(Event::DiskError(ScanError::WorkerCrash(path, msg)))
+                    .is_err()
+                {
                     break;
                 }
             }
         });
     }
+}
 
-    // walk the filesystem and report to main thread
-    tokio::spawn(async move {
-        let walkdir = Arc::new(std::sync::Mutex::new(WalkDir::new(path).into_iter()));
+/// The state that is shared by the threads of a parallel walk
+struct Walker<'a> {
+    event_tx: &'a mpsc::UnboundedSender<Event>,
+    excluded: &'a HashSet<PathBuf>,
+    filter: &'a PathFilter,
+    /// Device and inode of the directories that were walked, so a directory that shows up
+    /// under another path (like a bind mount) is skipped
+    seen_dirs: &'a Mutex<HashSet<(u64, u64)>>,
+    pause: &'a Pause,
+    runtime: &'a tokio::runtime::Handle,
+    shutdown: &'a CancellationToken,
+    /// Set once the main thread stopped receiving events
+    stopped: AtomicBool,
+}
 
-        loop {
-            let Ok(Some(entry)) = ({
-                let walkdir = walkdir.clone();
-                task::spawn_blocking(move || {
-                    let mut lock = walkdir.lock().unwrap();
-                    lock.next()
-                })
-                .await
-            }) else {
-                break;
-            };
+impl<'a> Walker<'a> {
+    fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed) || self.shutdown.is_cancelled()
+    }
 
-            let event = match read_disk(&walkdir, entry, &excluded).await {
-                Ok(Some((path, stat))) => {
-                    if stat.is_dir() {
-                        Event::DiskPwd(path)
-                    } else if stat.is_symlink() {
-                        // ignore this for now
-                        continue;
-                    } else {
-                        Event::DiskFile(path)
-                    }
+    fn send(&self, event: Event) {
+        if self.event_tx.send(event).is_err() {
+            self.stopped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Report an entry of the walk, directories are read on another thread of the pool
+    fn visit<'s>(&'s self, scope: &rayon::Scope<'s>, path: PathBuf, file_type: FileType)
+    where
+        'a: 's,
+    {
+        let is_dir = file_type.is_dir();
+        if self.excluded.contains(&path) || (is_dir && !self.filter.is_walked(&path)) {
+            return;
+        }
+        if !is_dir && !self.filter.is_included(&path) {
+            return;
+        }
+        if file_type.is_symlink() {
+            // ignore this for now
+            return;
+        }
+        if !is_dir {
+            // the size is only used for the progress, errors are reported once the file is read
+            let size = std::fs::symlink_metadata(&path)
+                .ok()
+                .filter(|m| m.is_file())
+                .map(|m| m.len())
+                .unwrap_or_default();
+            self.send(Event::DiskFile(path, size));
+            return;
+        }
+
+        if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+            let dir = (metadata.dev(), metadata.ino());
+            if !self.seen_dirs.lock().unwrap().insert(dir) {
+                info!("Skipping {path:?}, the same directory has been scanned under another path");
+                return;
+            }
+        }
+        self.send(Event::DiskPwd(path.clone()));
+        scope.spawn(move |scope| self.read_dir(scope, path));
+    }
+
+    fn read_dir<'s>(&'s self, scope: &rayon::Scope<'s>, path: PathBuf)
+    where
+        'a: 's,
+    {
+        if self.pause.is_paused() {
+            self.runtime.block_on(async {
+                tokio::select! {
+                    _ = self.shutdown.cancelled() => (),
+                    _ = self.pause.wait() => (),
+                }
+            });
+        }
+        if self.is_stopped() {
+            return;
+        }
+
+        let dir = match std::fs::read_dir(&path) {
+            Ok(dir) => dir,
+            Err(err) => return self.send(Event::DiskError(ScanError::from_io(path, err))),
+        };
+        for entry in dir {
+            if self.is_stopped() {
+                return;
+            }
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    self.send(Event::DiskError(ScanError::from_io(path.clone(), err)));
+                    continue;
                 }
-                Ok(None) => continue,
-                Err(err) => Event::DiskError(err),
             };
+            match entry.file_type() {
+                Ok(file_type) => self.visit(scope, entry.path(), file_type),
+                Err(err) => self.send(Event::DiskError(ScanError::from_io(entry.path(), err))),
+            }
+        }
+    }
+}
+
+/// Walk a directory on the threads of `pool` and report its content to the main thread,
+/// returns false on shutdown. This blocks
+#[allow(clippy::too_many_arguments)]
+fn walk(
+    pool: &rayon::ThreadPool,
+    event_tx: &mpsc::UnboundedSender<Event>,
+    path: PathBuf,
+    excluded: &HashSet<PathBuf>,
+    filter: &PathFilter,
+    seen_dirs: &Mutex<HashSet<(u64, u64)>>,
+    pause: &Pause,
+    runtime: &tokio::runtime::Handle,
+    shutdown: &CancellationToken,
+) -> bool {
+    let walker = Walker {
+        event_tx,
+        excluded,
+        filter,
+        seen_dirs,
+        pause,
+        runtime,
+        shutdown,
+        stopped: AtomicBool::new(false),
+    };
+    // like `find -H`, a symlink that is walked directly is followed
+    match std::fs::metadata(&path) {
+        Ok(metadata) => pool.scope(|scope| walker.visit(scope, path, metadata.file_type())),
+        Err(err) => walker.send(Event::DiskError(ScanError::from_io(path, err))),
+    }
+    !walker.is_stopped()
+}
+
+/// Check only the files that are sent over `paths` instead of walking the filesystem,
+/// like the trusted files of selected packages. The scan completes once the sender is dropped
+pub fn spawn_targeted_scan(
+    event_tx: mpsc::UnboundedSender<Event>,
+    mut paths: mpsc::UnboundedReceiver<PathBuf>,
+    excluded: HashSet<PathBuf>,
+    filter: PathFilter,
+    num_hash_workers: usize,
+    previous: Option<Arc<Incremental>>,
+    shutdown: CancellationToken,
+) {
+    spawn_hashers(&event_tx, num_hash_workers, previous, &shutdown);
 
+    tokio::spawn(async move {
+        loop {
+            let path = tokio::select! {
+                _ = shutdown.cancelled() => return,
+                path = paths.recv() => path,
+            };
+            let Some(path) = path else { break };
+            if path.ancestors().any(|dir| excluded.contains(dir)) || !filter.is_included(&path) {
+                continue;
+            }
+            let stat = {
+                let path = path.clone();
+                task::spawn_blocking(move || std::fs::symlink_metadata(path)).await
+            };
+            let event = match stat {
+                Ok(Ok(stat)) if stat.is_file() => Event::DiskFile(path, stat.len()),
+                // like the walker, anything else is left to the metadata check
+                Ok(Ok(_)) => continue,
+                // reported as missing file
+                Ok(Err(err)) if err.kind() == io::ErrorKind::NotFound => continue,
+                Ok(Err(err)) => Event::DiskError(ScanError::from_io(path, err)),
+                Err(err) => Event::DiskError(ScanError::Other(Some(path), err.into())),
+            };
             if event_tx.send(event).is_err() {
                 return;
             }
@@ -137,3 +944,337 @@ pub fn spawn_scan(
         event_tx.send(Event::CompletedDiskScan).ok();
     });
 }
+
+/// Scan the filesystem, the `priority` directories are walked before everything else.
+/// `paths` are the root and any directories that are mounted separately, walked in order.
+/// Each of them is walked by `walk_threads` threads in parallel
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_scan(
+    event_tx: mpsc::UnboundedSender<Event>,
+    paths: Vec<PathBuf>,
+    mut excluded: HashSet<PathBuf>,
+    filter: PathFilter,
+    priority: Vec<PathBuf>,
+    num_hash_workers: usize,
+    walk_threads: usize,
+    previous: Option<Arc<Incremental>>,
+    pause: Pause,
+    shutdown: CancellationToken,
+) {
+    spawn_hashers(&event_tx, num_hash_workers, previous, &shutdown);
+
+    let runtime = tokio::runtime::Handle::current();
+    task::spawn_blocking(move || {
+        let pool = match rayon::ThreadPoolBuilder::new()
+            .num_threads(walk_threads.max(1))
+            .thread_name(|i| format!("walk-{i}"))
+            .build()
+        {
+            Ok(pool) => pool,
+            Err(err) => {
+                let err = anyhow!("Failed to start threads of the disk walk: {err:#}");
+                event_tx
+                    .send(Event::DiskError(ScanError::Other(None, err)))
+                    .ok();
+                return;
+            }
+        };
+        let seen_dirs = Mutex::new(HashSet::new());
+        let walk = |path: PathBuf, excluded: &HashSet<PathBuf>| {
+            walk(
+                &pool, &event_tx, path, excluded, &filter, &seen_dirs, &pause, &runtime, &shutdown,
+            )
+        };
+        for dir in priority {
+            if !walk(dir.clone(), &excluded) {
+                return;
+            }
+            // don't report these files twice
+            excluded.insert(dir);
+        }
+        for path in paths {
+            if !walk(path, &excluded) {
+                return;
+            }
+        }
+
+        event_tx.send(Event::CompletedDiskScan).ok();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn classify_read_errors() {
+        let path = PathBuf::from("/nonexistent/archlinux-userland-fs-cmp");
+        let err = hash_file(&path).await.unwrap_err();
+        let err = ScanError::read(path.clone(), err);
+        assert!(matches!(&err, ScanError::Vanished(p) if *p == path));
+        assert_eq!(err.kind(), "VANISHED");
+
+        let err = io::Error::from(io::ErrorKind::PermissionDenied);
+        let err = ScanError::from_io(path.clone(), err);
+        assert_eq!(err.path(), Some(path.as_path()));
+        assert_eq!(err.kind(), "PERMISSION DENIED");
+        assert!(err.class().is_expected());
+
+        let metadata = mtree::Metadata {
+            link: Some("/usr/bin/true".to_string()),
+            ..Default::default()
+        };
+        let diff = type_diff(&std::env::temp_dir(), &metadata).unwrap();
+        assert_eq!(diff.as_deref(), Some("type: link -> dir"));
+        let diff = metadata_diff(&std::env::temp_dir(), &metadata).unwrap();
+        assert_eq!(diff, None);
+        let err = metadata_diff(&path, &metadata).unwrap_err();
+        assert_eq!(err.class(), ErrorClass::Vanished);
+    }
+
+    #[test]
+    fn detect_type_changes() {
+        let dir = std::env::temp_dir().join(format!("type-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("usr/lib")).unwrap();
+        // a packaged binary that was replaced with a symlink
+        let sudo = dir.join("sudo");
+        std::os::unix::fs::symlink("/tmp/rogue", &sudo).unwrap();
+        std::os::unix::fs::symlink("usr/lib", dir.join("lib")).unwrap();
+        std::os::unix::fs::symlink("/tmp", dir.join("share")).unwrap();
+
+        let file = mtree::Metadata {
+            mode: Some(0o4755),
+            ..Default::default()
+        };
+        let dir_entry = mtree::Metadata {
+            kind: Some(mtree::Kind::Dir),
+            ..Default::default()
+        };
+        assert_eq!(
+            type_diff(&sudo, &file).unwrap().as_deref(),
+            Some("type: file -> link, link: \"/tmp/rogue\"")
+        );
+        assert_eq!(metadata_diff(&sudo, &file).unwrap(), None);
+        assert_eq!(
+            type_diff(&dir.join("usr"), &file).unwrap().as_deref(),
+            Some("type: file -> dir")
+        );
+        assert_eq!(type_diff(&dir.join("usr"), &dir_entry).unwrap(), None);
+        // merged-/usr
+        assert_eq!(type_diff(&dir.join("lib"), &dir_entry).unwrap(), None);
+        assert_eq!(
+            type_diff(&dir.join("share"), &dir_entry)
+                .unwrap()
+                .as_deref(),
+            Some("type: dir -> link, link: \"/tmp\"")
+        );
+
+        let events = verify_metadata(
+            [(sudo.clone(), file), (dir.join("lib"), dir_entry)],
+            &HashSet::new(),
+        );
+        assert!(matches!(&events[..], [Event::TypeChanged(path, _)] if *path == sudo));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn blocking_hash_matches_async() {
+        let path = std::env::temp_dir().join(format!(
+            "archlinux-userland-fs-cmp-test-{}",
+            std::process::id()
+        ));
+        let data = (0..HASH_BUFFER_SIZE * 3 + 17)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        std::fs::write(&path, &data).unwrap();
+
+        let shutdown = CancellationToken::new();
+        let checksums = run_on_hash_pool({
+            let path = path.clone();
+            move || hash_file_blocking(&path, &[Algorithm::Sha256], &shutdown).unwrap()
+        })
+        .await;
+        assert_eq!(checksums[0].hex, sha256(&data));
+        assert_eq!(
+            checksums[0].hex,
+            hex::encode(hash_file(&path).await.unwrap())
+        );
+
+        let mapped = ReadOptions {
+            buffer_size: 4096,
+            mmap_threshold: Some(0),
+        };
+        let checksums = hash_file_blocking_with(
+            &path,
+            &[Algorithm::Sha256],
+            mapped,
+            &CancellationToken::new(),
+        )
+        .unwrap();
+        assert_eq!(checksums[0].hex, sha256(&data));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hard_links_are_hashed_once() {
+        let dir = std::env::temp_dir().join(format!("disk-links-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a");
+        let b = dir.join("b");
+        std::fs::write(&a, b"hello\n").unwrap();
+        std::fs::hard_link(&a, &b).unwrap();
+
+        let metadata = std::fs::symlink_metadata(&a).unwrap();
+        let inode = Inode::from(&metadata);
+        let shutdown = CancellationToken::new();
+        let algorithms = [Algorithm::Md5, Algorithm::Sha256];
+        let first = hash_linked_file(&a, Some(&metadata), &algorithms, &shutdown).unwrap();
+        assert!(hard_links().lock().unwrap().contains_key(&inode));
+
+        // the content isn't read again, the digests of the first path are reused
+        std::fs::write(&b, b"pwned\n").unwrap();
+        let verified = verify_file(
+            b.clone(),
+            Some(&metadata),
+            Some(sha256(b"hello\n")),
+            None,
+            &shutdown,
+        )
+        .unwrap();
+        assert!(matches!(verified, HashVerify::Passed(ref path, None, None) if *path == b));
+        assert_eq!(first[1].hex, sha256(b"hello\n"));
+        assert!(!hard_links().lock().unwrap().contains_key(&inode));
+
+        // a link that is never hashed, like in an excluded directory
+        std::fs::hard_link(&a, dir.join("c")).unwrap();
+        let metadata = std::fs::symlink_metadata(&a).unwrap();
+        let inode = Inode::from(&metadata);
+        hash_linked_file(&a, Some(&metadata), &algorithms, &shutdown).unwrap();
+        assert!(hard_links().lock().unwrap().contains_key(&inode));
+        forget_hard_links();
+        assert!(!hard_links().lock().unwrap().contains_key(&inode));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn wrong_size_is_flagged_without_hashing() {
+        let path = std::env::temp_dir().join(format!("disk-size-test-{}", std::process::id()));
+        std::fs::write(&path, b"truncated").unwrap();
+        let shutdown = CancellationToken::new();
+        let size = |size| {
+            Some(mtree::Metadata {
+                size: Some(size),
+                ..Default::default()
+            })
+        };
+
+        let event = hash_task(
+            path.clone(),
+            Some(sha256(b"x")),
+            size(1024),
+            None,
+            &shutdown,
+        );
+        assert!(matches!(
+            event,
+            Event::CompletedHashing(HashVerify::WrongSize(_, 1024, 9), 0)
+        ));
+        // the content is compared if the size matches
+        let event = hash_task(path.clone(), Some(sha256(b"x")), size(9), None, &shutdown);
+        assert!(matches!(
+            event,
+            Event::CompletedHashing(HashVerify::Flagged(..), 9)
+        ));
+        // untracked files are always hashed
+        let event = hash_task(path.clone(), None, size(1024), None, &shutdown);
+        assert!(matches!(
+            event,
+            Event::CompletedHashing(HashVerify::Computed(..), 9)
+        ));
+
+        // with --since, files with the modification time of their package aren't read
+        let mtime = std::fs::metadata(&path).unwrap().mtime();
+        let previous = Arc::new(Incremental {
+            since: Some(mtime + 60),
+            ..Default::default()
+        });
+        let expected = |mtime| {
+            Some(mtree::Metadata {
+                size: Some(9),
+                mtime: Some(mtime),
+                ..Default::default()
+            })
+        };
+        let event = hash_task(
+            path.clone(),
+            Some(sha256(b"x")),
+            expected(mtime),
+            Some(previous.clone()),
+            &shutdown,
+        );
+        assert!(matches!(
+            event,
+            Event::CompletedHashing(HashVerify::Unchanged(..), 9)
+        ));
+        let event = hash_task(
+            path.clone(),
+            Some(sha256(b"x")),
+            expected(mtime - 1),
+            Some(previous),
+            &shutdown,
+        );
+        assert!(matches!(
+            event,
+            Event::CompletedHashing(HashVerify::Flagged(..), 9)
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn parallel_walk() {
+        let dir = std::env::temp_dir().join(format!("disk-walk-test-{}", std::process::id()));
+        for sub in ["a/b/c", "a/d", "skip"] {
+            std::fs::create_dir_all(dir.join(sub)).unwrap();
+        }
+        for file in ["x", "a/y", "a/b/c/z", "a/d/w", "skip/v"] {
+            std::fs::write(dir.join(file), b"hello\n").unwrap();
+        }
+
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let filter = PathFilter::new(dir.clone(), Vec::new(), Vec::new());
+        spawn_scan(
+            event_tx,
+            vec![dir.clone()],
+            HashSet::from([dir.join("skip")]),
+            filter,
+            vec![dir.join("a/d")],
+            0,
+            4,
+            None,
+            Pause::default(),
+            CancellationToken::new(),
+        );
+        let mut files = Vec::new();
+        while let Some(event) = event_rx.recv().await {
+            match event {
+                Event::DiskFile(path, size) => {
+                    assert_eq!(size, 6);
+                    files.push(path);
+                }
+                Event::DiskPwd(_) => (),
+                Event::CompletedDiskScan => break,
+                event => panic!("Unexpected event: {event:?}"),
+            }
+        }
+        // the priority directory is walked first
+        assert_eq!(files[0], dir.join("a/d/w"));
+        files.sort();
+        assert_eq!(
+            files,
+            ["a/b/c/z", "a/d/w", "a/y", "x"].map(|file| dir.join(file))
+        );
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,233 @@
+use crate::disk;
+use crate::errors::*;
+use crate::fetch::{self, Retry};
+use crate::pkg::Package;
+use futures_util::StreamExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio::task;
+
+/// The keyring that signs the official Arch Linux packages
+pub const ARCHLINUX_KEYRING: &str = "/usr/share/pacman/keyrings/archlinux.gpg";
+/// The sha256sum manifest of the downloaded packages
+pub const MANIFEST: &str = "SHA256SUMS";
+
+/// A package in the download directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Downloaded {
+    pub file: String,
+    pub sha256: String,
+}
+
+/// A download that is written to `name.part` until it's complete
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+/// The compression of a package url, like `zst`
+fn url_ext(url: &str) -> &'static str {
+    fetch::PKG_COMPRESSION_EXTS
+        .iter()
+        .find(|ext| !ext.is_empty() && url.ends_with(&format!(".pkg.tar.{ext}")))
+        .copied()
+        .unwrap_or("")
+}
+
+/// Download a file, a partial download of a previous run is continued. `false` if the file doesn't exist
+async fn download_file(
+    client: &reqwest::Client,
+    url: &str,
+    path: &Path,
+    retry: &Retry,
+) -> Result<bool> {
+    let part = part_path(path);
+    let offset = fs::metadata(&part).await.map(|m| m.len()).unwrap_or(0);
+    let Some((offset, mut body)) = fetch::download_package_at(client, url, offset, retry).await?
+    else {
+        return Ok(false);
+    };
+    if offset > 0 {
+        info!("Continuing download of {url:?} at {offset} bytes");
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(offset > 0)
+        .write(true)
+        .truncate(offset == 0)
+        .open(&part)
+        .await
+        .with_context(|| anyhow!("Failed to open file: {part:?}"))?;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.with_context(|| anyhow!("Failed to download {url:?}"))?;
+        file.write_all(&chunk)
+            .await
+            .with_context(|| anyhow!("Failed to write file: {part:?}"))?;
+    }
+    file.sync_all()
+        .await
+        .with_context(|| anyhow!("Failed to write file: {part:?}"))?;
+    fs::rename(&part, path)
+        .await
+        .with_context(|| anyhow!("Failed to rename {part:?} to {path:?}"))?;
+    Ok(true)
+}
+
+/// Verify the detached signature of a package with `gpgv`
+async fn verify_signature(keyring: &Path, sig: &Path, path: &Path) -> Result<()> {
+    let mut cmd = Command::new("gpgv");
+    cmd.arg("--keyring").arg(keyring).arg(sig).arg(path);
+    let output = task::spawn_blocking(move || cmd.output())
+        .await?
+        .context("Failed to execute gpgv")?;
+    if !output.status.success() {
+        bail!(
+            "Invalid signature of {path:?}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Download a package into `dir`, like `name-version-arch.pkg.tar.zst` from the first mirror
+/// that has it. A package that is already in the directory isn't downloaded again, with a
+/// keyring its signature is downloaded and verified too. `None` if no mirror has the package
+pub async fn download(
+    client: &reqwest::Client,
+    mirrors: &[String],
+    dir: &Path,
+    pkg: &Package,
+    retry: &Retry,
+    keyring: Option<&Path>,
+) -> Result<Option<Downloaded>> {
+    let mut found = None;
+    for ext in fetch::PKG_COMPRESSION_EXTS {
+        let file = pkg.file_name(ext);
+        let path = dir.join(&file);
+        if fs::try_exists(&path).await.unwrap_or(false) {
+            debug!("Package was downloaded already: {path:?}");
+            found = Some((file, path, None));
+            break;
+        }
+    }
+    let (file, path, url) = match found {
+        Some(found) => found,
+        None => {
+            let located = fetch::locate_package(client, mirrors, pkg).await?;
+            let Some(url) = located.url.filter(|_| located.exists) else {
+                return Ok(None);
+            };
+            let file = pkg.file_name(url_ext(&url));
+            let path = dir.join(&file);
+            if !download_file(client, &url, &path, retry).await? {
+                return Ok(None);
+            }
+            (file, path, Some(url))
+        }
+    };
+
+    if let Some(keyring) = keyring {
+        let sig = dir.join(format!("{file}.sig"));
+        if !fs::try_exists(&sig).await.unwrap_or(false) {
+            let url = match url {
+                Some(url) => url,
+                None => {
+                    let located = fetch::locate_package(client, mirrors, pkg).await?;
+                    located
+                        .url
+                        .filter(|_| located.exists)
+                        .context("Failed to find package for signature")?
+                }
+            };
+            let sig_url = format!("{url}.sig");
+            if !download_file(client, &sig_url, &sig, retry).await? {
+                bail!("Failed to find signature: {sig_url:?}");
+            }
+        }
+        verify_signature(keyring, &sig, &path).await?;
+    }
+
+    let sha256 = hex::encode(
+        disk::hash_file(&path)
+            .await
+            .with_context(|| anyhow!("Failed to hash {path:?}"))?,
+    );
+    Ok(Some(Downloaded { file, sha256 }))
+}
+
+/// Write the sha256sum manifest of the downloaded packages, it can be checked with `sha256sum -c`
+pub async fn write_manifest(dir: &Path, downloaded: &mut [Downloaded]) -> Result<()> {
+    downloaded.sort_by(|a, b| a.file.cmp(&b.file));
+    let manifest = downloaded
+        .iter()
+        .map(|pkg| format!("{}  {}\n", pkg.sha256, pkg.file))
+        .collect::<String>();
+    let path = dir.join(MANIFEST);
+    fs::write(&path, manifest)
+        .await
+        .with_context(|| anyhow!("Failed to write manifest: {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn downloaded_packages_are_kept() {
+        let dir = std::env::temp_dir().join(format!("download-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pkg = |name: &str| Package {
+            name: name.to_string(),
+            version: "1.0-1".to_string(),
+            arch: "x86_64".to_string(),
+        };
+        std::fs::write(dir.join("foo-1.0-1-x86_64.pkg.tar.xz"), b"foo").unwrap();
+
+        // no mirror is needed for packages that are in the directory already
+        let client = reqwest::Client::new();
+        let retry = Retry::default();
+        let foo = download(&client, &[], &dir, &pkg("foo"), &retry, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            foo,
+            Downloaded {
+                file: "foo-1.0-1-x86_64.pkg.tar.xz".to_string(),
+                sha256: disk::sha256(b"foo"),
+            }
+        );
+        let bar = download(&client, &[], &dir, &pkg("bar"), &retry, None)
+            .await
+            .unwrap();
+        assert_eq!(bar, None);
+
+        let baz = Downloaded {
+            file: "baz-1.0-1-any.pkg.tar.zst".to_string(),
+            sha256: disk::sha256(b"baz"),
+        };
+        write_manifest(&dir, &mut [foo, baz]).await.unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join(MANIFEST)).unwrap(),
+            format!(
+                "{}  baz-1.0-1-any.pkg.tar.zst\n{}  foo-1.0-1-x86_64.pkg.tar.xz\n",
+                disk::sha256(b"baz"),
+                disk::sha256(b"foo")
+            )
+        );
+        assert_eq!(
+            part_path(Path::new("/tmp/foo.pkg.tar.zst")),
+            Path::new("/tmp/foo.pkg.tar.zst.part")
+        );
+        assert_eq!(
+            url_ext("https://example.com/foo-1.0-1-any.pkg.tar.zst"),
+            "zst"
+        );
+        assert_eq!(url_ext("https://example.com/foo-1.0-1-any.pkg.tar"), "");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
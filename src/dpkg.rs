@@ -0,0 +1,392 @@
+use crate::digest::{Algorithm, Checksum};
+use crate::errors::*;
+use crate::manifest;
+use crate::pkg::Package;
+use crate::{resolve_merged_usr, Event};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Location of the dpkg database, relative to the root
+pub const DBPATH: &str = "var/lib/dpkg";
+/// snapshot.debian.org, it keeps every package that was ever published to Debian
+pub const SNAPSHOT_URL: &str = "https://snapshot.debian.org";
+
+const NUM_HTTP_WORKERS: usize = 4;
+
+/// An installed package from the dpkg `status` file
+#[derive(Debug, Clone)]
+pub struct Installed {
+    pub pkg: Package,
+    /// Configuration files (absolute paths) and the md5 of the version that was shipped
+    pub conffiles: Vec<(String, String)>,
+}
+
+/// Parse the dpkg `status` file, only packages that are fully installed are returned
+pub fn parse_status(status: &str) -> Vec<Installed> {
+    let mut installed = Vec::new();
+    for paragraph in status.split("\n\n") {
+        let mut name = None;
+        let mut version = None;
+        let mut arch = None;
+        let mut state = None;
+        let mut conffiles = Vec::new();
+
+        let mut field = "";
+        for line in paragraph.lines() {
+            if let Some(value) = line.strip_prefix(' ') {
+                if field == "Conffiles" {
+                    let mut parts = value.split_whitespace();
+                    // obsolete conffiles are no longer shipped by the package
+                    if let (Some(path), Some(md5), None | Some("remove-on-upgrade")) =
+                        (parts.next(), parts.next(), parts.next())
+                    {
+                        conffiles.push((path.to_string(), md5.to_string()));
+                    }
+                }
+                continue;
+            }
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            field = key;
+            let value = value.trim();
+            match key {
+                "Package" => name = Some(value),
+                "Version" => version = Some(value),
+                "Architecture" => arch = Some(value),
+                "Status" => state = Some(value),
+                _ => (),
+            }
+        }
+
+        if state.and_then(|state| state.split_whitespace().nth(2)) != Some("installed") {
+            continue;
+        }
+        let (Some(name), Some(version), Some(arch)) = (name, version, arch) else {
+            continue;
+        };
+        installed.push(Installed {
+            pkg: Package {
+                name: name.to_string(),
+                version: version.to_string(),
+                arch: arch.to_string(),
+            },
+            conffiles,
+        });
+    }
+    installed
+}
+
+/// Parse a `<pkg>.md5sums` file of the dpkg database, paths are relative to the root
+pub fn parse_md5sums(md5sums: &str) -> Vec<(String, Checksum)> {
+    md5sums
+        .lines()
+        .filter_map(|line| manifest::parse_checksum_line(line.as_bytes(), Algorithm::Md5))
+        .filter(|(checksum, _)| checksum.algorithm == Algorithm::Md5)
+        .map(|(checksum, path)| (path.to_string_lossy().into_owned(), checksum))
+        .collect()
+}
+
+async fn read_md5sums(dbpath: &Path, pkg: &Package) -> Result<Vec<(String, Checksum)>> {
+    let info = dbpath.join("info");
+    // packages that are `Multi-Arch: same` are qualified with their architecture
+    for name in [
+        format!("{}:{}.md5sums", pkg.name, pkg.arch),
+        format!("{}.md5sums", pkg.name),
+    ] {
+        let path = info.join(name);
+        match fs::read_to_string(&path).await {
+            Ok(md5sums) => return Ok(parse_md5sums(&md5sums)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+            Err(err) => {
+                return Err(err).with_context(|| anyhow!("Failed to read file: {path:?}"));
+            }
+        }
+    }
+    // some packages only ship directories or conffiles
+    Ok(Vec::new())
+}
+
+#[derive(Debug, Deserialize)]
+struct BinFiles {
+    result: Vec<BinFile>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BinFile {
+    architecture: String,
+    hash: String,
+}
+
+/// Download the .deb of a package from snapshot.debian.org, the content is verified with the sha1 of the index
+pub async fn fetch_snapshot(client: &reqwest::Client, url: &str, pkg: &Package) -> Result<Vec<u8>> {
+    let url = url.trim_end_matches('/');
+    let index = format!("{url}/mr/binary/{}/{}/binfiles", pkg.name, pkg.version);
+    let binfiles = client
+        .get(&index)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await
+        .with_context(|| anyhow!("Failed to download package index: {index:?}"))?;
+    let binfiles = serde_json::from_slice::<BinFiles>(&binfiles)
+        .with_context(|| anyhow!("Failed to parse package index: {index:?}"))?;
+    let Some(binfile) = binfiles
+        .result
+        .into_iter()
+        .find(|binfile| binfile.architecture == pkg.arch)
+    else {
+        bail!("Package is not available for architecture {:?}", pkg.arch);
+    };
+
+    let url = format!("{url}/file/{}", binfile.hash);
+    let deb = client
+        .get(&url)
+        .send()
+        .await?
+        .error_for_status()?
+        .bytes()
+        .await
+        .with_context(|| anyhow!("Failed to download package: {url:?}"))?;
+    let sha1 = hex::encode(Sha1::digest(&deb));
+    if !sha1.eq_ignore_ascii_case(&binfile.hash) {
+        bail!(
+            "Downloaded package has wrong sha1 (expected={:?}, got={sha1:?})",
+            binfile.hash
+        );
+    }
+    Ok(deb.to_vec())
+}
+
+/// Find a member of an `ar` archive by its name prefix
+fn ar_member<'a>(mut data: &'a [u8], prefix: &str) -> Result<Option<(&'a str, &'a [u8])>> {
+    data = data
+        .strip_prefix(b"!<arch>\n")
+        .context("Package is not an ar archive")?;
+    while data.len() >= 60 {
+        let (header, rest) = data.split_at(60);
+        let name = std::str::from_utf8(&header[..16])
+            .context("Invalid member name in ar archive")?
+            .trim_end()
+            .trim_end_matches('/');
+        let size = std::str::from_utf8(&header[48..58])
+            .ok()
+            .and_then(|size| size.trim().parse::<usize>().ok())
+            .context("Invalid member size in ar archive")?;
+        if size > rest.len() {
+            bail!("Truncated ar archive");
+        }
+        if name.starts_with(prefix) {
+            return Ok(Some((name, &rest[..size])));
+        }
+        // members are aligned to 2 bytes
+        data = &rest[(size + size % 2).min(rest.len())..];
+    }
+    Ok(None)
+}
+
+/// Read the sha256 of all regular files in the `data.tar` of a .deb
+pub fn deb_hashes(deb: &[u8]) -> Result<Vec<(String, String)>> {
+    let Some((name, data)) = ar_member(deb, "data.tar")? else {
+        bail!("Failed to find data.tar in package");
+    };
+    let reader: Box<dyn Read> = match name.rsplit_once('.') {
+        Some((_, "tar")) => Box::new(data),
+        Some((_, "gz")) => Box::new(flate2::read::GzDecoder::new(data)),
+        Some((_, "xz")) => Box::new(liblzma::read::XzDecoder::new(data)),
+        Some((_, "zst")) => Box::new(zstd::stream::read::Decoder::new(data)?),
+        _ => bail!("Unsupported compression of {name:?}"),
+    };
+
+    let mut hashes = Vec::new();
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries()? {
+        let mut entry = entry.context("Failed to read entry from package")?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let path = entry.path()?.to_string_lossy().into_owned();
+        let path = path.trim_start_matches("./").to_string();
+        let mut hasher = Sha256::new();
+        io::copy(&mut entry, &mut hasher)
+            .with_context(|| anyhow!("Failed to read {path:?} from package"))?;
+        hashes.push((path, hex::encode(hasher.finalize())));
+    }
+    Ok(hashes)
+}
+
+/// The trusted hashes of an installed package, as path relative to the root and hash
+async fn trusted_files(
+    dbpath: &Path,
+    installed: &Installed,
+    snapshot: Option<&(reqwest::Client, String)>,
+) -> Result<Vec<(String, String)>> {
+    let pkg = &installed.pkg;
+    let mut files = read_md5sums(dbpath, pkg)
+        .await?
+        .into_iter()
+        .map(|(path, md5)| (path, md5.to_string()))
+        .collect::<Vec<_>>();
+    files.extend(
+        installed
+            .conffiles
+            .iter()
+            .filter_map(|(path, md5)| {
+                Some((path.clone(), Checksum::new(Algorithm::Md5, md5).ok()?))
+            })
+            .map(|(path, md5)| (path.trim_start_matches('/').to_string(), md5.to_string())),
+    );
+
+    let Some((client, url)) = snapshot else {
+        return Ok(files);
+    };
+    // md5 is only good for detecting accidental changes, prefer the sha256 of the original package
+    let sha256 = match fetch_snapshot(client, url, pkg).await {
+        Ok(deb) => tokio::task::spawn_blocking(move || deb_hashes(&deb)).await?,
+        Err(err) => Err(err),
+    };
+    match sha256 {
+        Ok(sha256) => {
+            let sha256 = sha256.into_iter().collect::<std::collections::HashMap<_, _>>();
+            for (path, hash) in &mut files {
+                if let Some(sha256) = sha256.get(path.as_str()) {
+                    *hash = sha256.clone();
+                }
+            }
+        }
+        Err(err) => warn!(
+            "Failed to fetch {:?} {:?} from snapshot archive, using md5 from dpkg database: {err:#}",
+            pkg.name, pkg.version
+        ),
+    }
+    Ok(files)
+}
+
+/// Read the installed packages from the dpkg database and send the trusted hashes of their files
+pub fn spawn_list_installed(
+    event_tx: mpsc::UnboundedSender<Event>,
+    root: PathBuf,
+    snapshot: Option<(reqwest::Client, String)>,
+    shutdown: CancellationToken,
+) {
+    tokio::spawn(async move {
+        let dbpath = root.join(DBPATH);
+        let path = dbpath.join("status");
+        let installed = match fs::read_to_string(&path).await {
+            Ok(status) => parse_status(&status),
+            Err(err) => {
+                warn!("Failed to read dpkg database {path:?}: {err:#}");
+                Vec::new()
+            }
+        };
+        for installed in &installed {
+            debug!(
+                "Found installed package: {:?} {:?}",
+                installed.pkg.name, installed.pkg.version
+            );
+            if event_tx.send(Event::PkgQueued).is_err() {
+                return;
+            }
+        }
+
+        let mut results = futures::stream::iter(installed)
+            .map(|installed| {
+                let dbpath = dbpath.clone();
+                let snapshot = snapshot.clone();
+                async move {
+                    let files = trusted_files(&dbpath, &installed, snapshot.as_ref()).await;
+                    (installed, files)
+                }
+            })
+            .buffer_unordered(NUM_HTTP_WORKERS);
+        loop {
+            let next = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                next = results.next() => next,
+            };
+            let Some((installed, files)) = next else {
+                break;
+            };
+            match files {
+                Ok(files) => {
+                    let pkg = Arc::new(installed.pkg);
+                    for (path, hash) in files {
+                        let path = resolve_merged_usr(&root, &path);
+                        let event = Event::TrustedFile(path, hash, Some(pkg.clone()));
+                        if event_tx.send(event).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(err) => warn!(
+                    "Failed to read files of {:?} {:?}: {err:#}",
+                    installed.pkg.name, installed.pkg.version
+                ),
+            }
+            if event_tx.send(Event::PkgCompleted).is_err() {
+                return;
+            }
+        }
+
+        event_tx.send(Event::CompletedListInstalled).ok();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_dpkg_status() {
+        let status = "Package: base-files
+Status: install ok installed
+Priority: required
+Architecture: amd64
+Version: 12.4+deb12u5
+Conffiles:
+ /etc/debian_version 5b4b5d8a1d7c8d9a6e7ac4b8b4aa4f7c
+ /etc/host.conf 4eb63731c9f5e30903ac4fc07a7fe3d6 obsolete
+ /etc/issue 3ebcb7e3d6e2f1c5b1c8b1e3bb3e1d7e
+Description: Debian base system miscellaneous files
+
+Package: libfoo1
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 1.0-1
+";
+        let installed = parse_status(status);
+        assert_eq!(installed.len(), 1);
+        assert_eq!(installed[0].pkg.name, "base-files");
+        assert_eq!(installed[0].pkg.version, "12.4+deb12u5");
+        assert_eq!(installed[0].pkg.arch, "amd64");
+        assert_eq!(
+            installed[0].conffiles,
+            vec![
+                (
+                    "/etc/debian_version".to_string(),
+                    "5b4b5d8a1d7c8d9a6e7ac4b8b4aa4f7c".to_string()
+                ),
+                (
+                    "/etc/issue".to_string(),
+                    "3ebcb7e3d6e2f1c5b1c8b1e3bb3e1d7e".to_string()
+                ),
+            ]
+        );
+
+        let md5sums = parse_md5sums("d41d8cd98f00b204e9800998ecf8427e  usr/bin/foo\n");
+        assert_eq!(md5sums[0].0, "usr/bin/foo");
+        assert_eq!(
+            md5sums[0].1.to_string(),
+            "md5:d41d8cd98f00b204e9800998ecf8427e"
+        );
+    }
+}
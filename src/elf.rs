@@ -0,0 +1,169 @@
+use crate::errors::*;
+use crate::fetch;
+use crate::pkg;
+use object::{Object, ObjectSection, SectionFlags};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+const SHF_ALLOC: u64 = 0x2;
+
+pub fn is_elf(data: &[u8]) -> bool {
+    data.starts_with(b"\x7fELF")
+}
+
+/// Section-by-section differences between the packaged and the installed binary
+#[derive(Debug, Default, PartialEq)]
+pub struct ElfDiff {
+    /// Sections that are loaded into memory and differ (or were added/removed)
+    pub code: Vec<String>,
+    /// Sections that only hold metadata, like notes, comments or debug info
+    pub metadata: Vec<String>,
+    /// Metadata sections that are missing in the installed binary
+    pub stripped: Vec<String>,
+}
+
+impl fmt::Display for ElfDiff {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        if !self.code.is_empty() {
+            write!(w, "elf: code differs in {}", self.code.join(", "))
+        } else if !self.metadata.is_empty() {
+            write!(
+                w,
+                "elf: only metadata differs in {}",
+                self.metadata.join(", ")
+            )
+        } else if !self.stripped.is_empty() {
+            write!(w, "elf: stripped {}", self.stripped.join(", "))
+        } else {
+            write!(w, "elf: sections are identical, only headers differ")
+        }
+    }
+}
+
+fn sections(file: &object::File) -> Result<BTreeMap<String, (bool, Vec<u8>)>> {
+    let mut sections = BTreeMap::new();
+    for section in file.sections() {
+        let name = section.name()?.to_string();
+        if name.is_empty() {
+            continue;
+        }
+        let alloc = match section.flags() {
+            SectionFlags::Elf { sh_flags } => sh_flags & SHF_ALLOC != 0,
+            _ => true,
+        };
+        // notes like the build-id are loaded, but don't contain code
+        let loaded = alloc && !name.starts_with(".note");
+        sections.insert(name, (loaded, section.data()?.to_vec()));
+    }
+    Ok(sections)
+}
+
+/// Compare two ELF binaries section by section
+pub fn diff(original: &[u8], installed: &[u8]) -> Result<ElfDiff> {
+    let original = object::File::parse(original).context("Failed to parse original elf")?;
+    let installed = object::File::parse(installed).context("Failed to parse installed elf")?;
+    let original = sections(&original)?;
+    let installed = sections(&installed)?;
+
+    let mut diff = ElfDiff::default();
+    for (name, (loaded, data)) in &original {
+        match installed.get(name) {
+            Some((_, other)) if other == data => (),
+            Some(_) if *loaded => diff.code.push(name.clone()),
+            Some(_) => diff.metadata.push(name.clone()),
+            None if *loaded => diff.code.push(name.clone()),
+            None => diff.stripped.push(name.clone()),
+        }
+    }
+    for (name, (loaded, _)) in &installed {
+        if !original.contains_key(name) {
+            if *loaded {
+                diff.code.push(name.clone());
+            } else {
+                diff.metadata.push(name.clone());
+            }
+        }
+    }
+
+    Ok(diff)
+}
+
+async fn diff_file(
+    client: &reqwest::Client,
+    archive: &[String],
+    owners: &HashMap<PathBuf, pkg::Package>,
+    root: &Path,
+    path: &Path,
+) -> Result<Option<ElfDiff>> {
+    let installed = fs::read(path)
+        .await
+        .with_context(|| anyhow!("Failed to read file: {path:?}"))?;
+    if !is_elf(&installed) {
+        return Ok(None);
+    }
+
+    let rel = path.strip_prefix(root).unwrap_or(path);
+    let Some(pkg) = owners.get(rel) else {
+        debug!("No package owns {path:?}, skipping elf analysis");
+        return Ok(None);
+    };
+    let Some(original) = fetch::fetch_package_file(client, archive, pkg, rel).await? else {
+        bail!("Failed to find {rel:?} in package {:?}", pkg.name);
+    };
+
+    Ok(Some(diff(&original, &installed)?))
+}
+
+/// Compare flagged ELF binaries against the pristine files from their packages
+pub async fn diff_flagged(
+    client: &reqwest::Client,
+    root: &Path,
+    dbpath: &Path,
+    archive: &[String],
+    flagged: &BTreeMap<PathBuf, String>,
+) -> Result<HashMap<PathBuf, ElfDiff>> {
+    let owners = pkg::list_file_owners(dbpath).await?;
+
+    let mut diffs = HashMap::new();
+    for path in flagged.keys() {
+        match diff_file(client, archive, &owners, root, path).await {
+            Ok(Some(diff)) => {
+                info!("Analyzed elf binary {path:?}: {diff}");
+                diffs.insert(path.clone(), diff);
+            }
+            Ok(None) => (),
+            Err(err) => warn!("Failed to compare elf binary {path:?}: {err:#}"),
+        }
+    }
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section_offset(data: &[u8], name: &str) -> usize {
+        let file = object::File::parse(data).unwrap();
+        let section = file.section_by_name(name).unwrap();
+        section.file_range().unwrap().0 as usize
+    }
+
+    #[test]
+    fn classify_modified_sections() {
+        let original = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        assert_eq!(diff(&original, &original).unwrap(), ElfDiff::default());
+
+        let mut modified = original.clone();
+        modified[section_offset(&original, ".comment")] ^= 0xff;
+        let result = diff(&original, &modified).unwrap();
+        assert!(result.code.is_empty());
+        assert_eq!(result.metadata, vec![".comment".to_string()]);
+
+        let mut modified = original.clone();
+        modified[section_offset(&original, ".text")] ^= 0xff;
+        let result = diff(&original, &modified).unwrap();
+        assert_eq!(result.code, vec![".text".to_string()]);
+    }
+}
@@ -0,0 +1,578 @@
+use crate::disk::ScanError;
+use crate::errors::*;
+use crate::pkg::{self, Package};
+use crate::Event;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+
+const SECTOR_SIZE: u64 = 512;
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const MAGIC: u16 = 0xef53;
+const EXTENT_MAGIC: u16 = 0xf30a;
+const ROOT_INODE: u32 = 2;
+/// Size of the reads while hashing a file
+const CHUNK_SIZE: u64 = 1 << 20;
+
+const INCOMPAT_COMPRESSION: u32 = 0x1;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_ENCRYPT: u32 = 0x10000;
+
+const FLAG_EXTENTS: u32 = 0x80000;
+const FLAG_INLINE_DATA: u32 = 0x1000_0000;
+
+fn le16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn le32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn le64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Find the start of a partition (numbered from 1) in the MBR or GPT partition table of a disk image
+pub fn partition_offset(file: &File, partition: u32) -> Result<u64> {
+    let mut mbr = [0u8; SECTOR_SIZE as usize];
+    file.read_exact_at(&mut mbr, 0)
+        .context("Failed to read partition table")?;
+    if mbr[510..] != [0x55, 0xaa] {
+        bail!("No partition table found");
+    }
+
+    let entries = &mbr[446..510];
+    // a protective MBR with a single partition of type 0xee
+    if entries[4] == 0xee {
+        let mut header = [0u8; 92];
+        file.read_exact_at(&mut header, SECTOR_SIZE)
+            .context("Failed to read GPT header")?;
+        if &header[..8] != b"EFI PART" {
+            bail!("Invalid GPT header");
+        }
+        let table = le64(&header, 72);
+        let count = le32(&header, 80);
+        let entry_size = le32(&header, 84) as u64;
+        if partition == 0 || partition > count || entry_size < 128 {
+            bail!("Partition {partition} doesn't exist in GPT with {count} entries");
+        }
+        let mut entry = vec![0u8; entry_size as usize];
+        file.read_exact_at(
+            &mut entry,
+            table * SECTOR_SIZE + (partition as u64 - 1) * entry_size,
+        )
+        .context("Failed to read GPT entry")?;
+        if entry[..16].iter().all(|b| *b == 0) {
+            bail!("Partition {partition} is unused");
+        }
+        Ok(le64(&entry, 32) * SECTOR_SIZE)
+    } else {
+        if !(1..=4).contains(&partition) {
+            bail!("Only the primary partitions 1-4 of a MBR partition table are supported");
+        }
+        let entry = &entries[(partition as usize - 1) * 16..][..16];
+        if entry[4] == 0 {
+            bail!("Partition {partition} is unused");
+        }
+        Ok(le32(entry, 8) as u64 * SECTOR_SIZE)
+    }
+}
+
+/// An inode, only what's needed to walk directories and read files
+#[derive(Debug)]
+struct Inode {
+    mode: u16,
+    size: u64,
+    flags: u32,
+    /// Extent tree, block map, symlink target or inline data
+    block: [u8; 60],
+}
+
+impl Inode {
+    fn is_dir(&self) -> bool {
+        self.mode & 0xf000 == 0x4000
+    }
+
+    fn is_file(&self) -> bool {
+        self.mode & 0xf000 == 0x8000
+    }
+
+    /// Data that is stored in the inode itself, larger inline data continues in an extended attribute
+    fn inline_data(&self) -> Result<&[u8]> {
+        let size = usize::try_from(self.size).unwrap_or(usize::MAX);
+        self.block
+            .get(..size)
+            .context("Inline data in extended attributes isn't supported")
+    }
+}
+
+/// A directory entry
+#[derive(Debug, PartialEq)]
+pub struct DirEntry {
+    pub inode: u32,
+    pub name: Vec<u8>,
+}
+
+/// Parse the entries of a directory block, `.` and `..` are skipped
+pub fn parse_dir(data: &[u8]) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+    let mut pos = 0;
+    while let Some(header) = data.get(pos..pos + 8) {
+        let inode = le32(header, 0);
+        let rec_len = le16(header, 4) as usize;
+        let name_len = header[6] as usize;
+        if rec_len < 8 {
+            warn!("Invalid directory entry at offset {pos}");
+            break;
+        }
+        // unused entries, checksum tails and htree nodes have no inode
+        if inode != 0 {
+            if let Some(name) = data.get(pos + 8..pos + 8 + name_len) {
+                if name != b"." && name != b".." {
+                    entries.push(DirEntry {
+                        inode,
+                        name: name.to_vec(),
+                    });
+                }
+            }
+        }
+        pos += rec_len;
+    }
+    entries
+}
+
+/// An ext2/3/4 filesystem that is read in userspace, so the evidence is never mounted
+#[derive(Debug)]
+pub struct Filesystem {
+    file: File,
+    /// Start of the filesystem, for partitions in a disk image
+    offset: u64,
+    block_size: u64,
+    inodes_per_group: u32,
+    inode_size: u64,
+    /// Location of the inode table of each block group
+    inode_tables: Vec<u64>,
+}
+
+impl Filesystem {
+    pub fn open(path: &Path, partition: Option<u32>) -> Result<Self> {
+        let file = File::open(path).with_context(|| anyhow!("Failed to open image: {path:?}"))?;
+        let offset = match partition {
+            Some(partition) => partition_offset(&file, partition)
+                .with_context(|| anyhow!("Failed to find partition {partition} in {path:?}"))?,
+            None => 0,
+        };
+
+        let mut sb = [0u8; 1024];
+        file.read_exact_at(&mut sb, offset + SUPERBLOCK_OFFSET)
+            .with_context(|| anyhow!("Failed to read superblock: {path:?}"))?;
+        if le16(&sb, 56) != MAGIC {
+            bail!("No ext2/3/4 filesystem found in {path:?}");
+        }
+        let log_block_size = le32(&sb, 24);
+        if log_block_size > 6 {
+            bail!("Invalid block size in superblock: {path:?}");
+        }
+        let block_size = 1024 << log_block_size;
+
+        let incompat = le32(&sb, 96);
+        for (flag, name) in [
+            (INCOMPAT_COMPRESSION, "compression"),
+            (INCOMPAT_META_BG, "meta_bg"),
+            (INCOMPAT_ENCRYPT, "encryption"),
+        ] {
+            if incompat & flag != 0 {
+                bail!("Filesystem features aren't supported: {name}");
+            }
+        }
+        if incompat & INCOMPAT_RECOVER != 0 {
+            warn!("The journal of {path:?} wasn't replayed, the latest changes are missing from the scan");
+        }
+
+        let inodes_per_group = le32(&sb, 40);
+        let blocks_per_group = le32(&sb, 32) as u64;
+        let inode_size = if le32(&sb, 76) == 0 {
+            128
+        } else {
+            le16(&sb, 88) as u64
+        };
+        if inodes_per_group == 0 || blocks_per_group == 0 || inode_size < 128 {
+            bail!("Invalid superblock: {path:?}");
+        }
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        let mut blocks_count = le32(&sb, 4) as u64;
+        let mut desc_size = 32;
+        if is_64bit {
+            blocks_count |= (le32(&sb, 0x150) as u64) << 32;
+            desc_size = (le16(&sb, 0xfe) as usize).max(64);
+        }
+        let first_data_block = le32(&sb, 20) as u64;
+        let groups = blocks_count
+            .saturating_sub(first_data_block)
+            .div_ceil(blocks_per_group);
+
+        let mut descs = vec![0u8; groups as usize * desc_size];
+        file.read_exact_at(&mut descs, offset + (first_data_block + 1) * block_size)
+            .with_context(|| anyhow!("Failed to read block group descriptors: {path:?}"))?;
+        let inode_tables = descs
+            .chunks_exact(desc_size)
+            .map(|desc| {
+                let mut table = le32(desc, 8) as u64;
+                if is_64bit {
+                    table |= (le32(desc, 0x28) as u64) << 32;
+                }
+                table
+            })
+            .collect();
+
+        Ok(Filesystem {
+            file,
+            offset,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+        })
+    }
+
+    fn read_at(&self, buf: &mut [u8], pos: u64) -> Result<()> {
+        self.file
+            .read_exact_at(buf, self.offset + pos)
+            .with_context(|| anyhow!("Failed to read from image at offset {pos}"))
+    }
+
+    fn inode(&self, ino: u32) -> Result<Inode> {
+        let idx = ino.checked_sub(1).context("Invalid inode 0")?;
+        let table = self
+            .inode_tables
+            .get((idx / self.inodes_per_group) as usize)
+            .with_context(|| anyhow!("Inode {ino} is out of range"))?;
+        let pos = table * self.block_size + (idx % self.inodes_per_group) as u64 * self.inode_size;
+        let mut buf = [0u8; 128];
+        self.read_at(&mut buf, pos)?;
+        Ok(Inode {
+            mode: le16(&buf, 0),
+            size: le32(&buf, 4) as u64 | (le32(&buf, 0x6c) as u64) << 32,
+            flags: le32(&buf, 0x20),
+            block: buf[0x28..0x28 + 60].try_into().unwrap(),
+        })
+    }
+
+    /// The data of a file as (logical block, physical block, number of blocks), unwritten extents are left out
+    fn extents(&self, inode: &Inode) -> Result<Vec<(u64, u64, u64)>> {
+        let mut extents = Vec::new();
+        if inode.flags & FLAG_EXTENTS != 0 {
+            self.extent_tree(&inode.block, 0, &mut extents)?;
+        } else {
+            let blocks = inode.size.div_ceil(self.block_size);
+            let mut logical = 0;
+            for i in 0..12 {
+                self.block_map(
+                    le32(&inode.block, i * 4),
+                    0,
+                    &mut logical,
+                    blocks,
+                    &mut extents,
+                )?;
+            }
+            for (i, depth) in [(12, 1), (13, 2), (14, 3)] {
+                let ptr = le32(&inode.block, i * 4);
+                self.block_map(ptr, depth, &mut logical, blocks, &mut extents)?;
+            }
+        }
+        extents.sort();
+        Ok(extents)
+    }
+
+    fn extent_tree(
+        &self,
+        node: &[u8],
+        level: usize,
+        extents: &mut Vec<(u64, u64, u64)>,
+    ) -> Result<()> {
+        if node.len() < 12 || le16(node, 0) != EXTENT_MAGIC {
+            bail!("Invalid extent header");
+        }
+        if level > 5 {
+            bail!("Extent tree is too deep");
+        }
+        let entries = le16(node, 2) as usize;
+        let depth = le16(node, 6);
+        for i in 1..=entries {
+            let entry = node
+                .get(i * 12..(i + 1) * 12)
+                .context("Truncated extent node")?;
+            if depth == 0 {
+                let len = le16(entry, 4) as u64;
+                // unwritten extents are read as zeros
+                if len > 32768 {
+                    continue;
+                }
+                let start = (le16(entry, 6) as u64) << 32 | le32(entry, 8) as u64;
+                extents.push((le32(entry, 0) as u64, start, len));
+            } else {
+                let leaf = le32(entry, 4) as u64 | (le16(entry, 8) as u64) << 32;
+                let mut block = vec![0u8; self.block_size as usize];
+                self.read_at(&mut block, leaf * self.block_size)?;
+                self.extent_tree(&block, level + 1, extents)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Follow a (direct or indirect) pointer of an ext2/3 block map
+    fn block_map(
+        &self,
+        ptr: u32,
+        depth: u32,
+        logical: &mut u64,
+        blocks: u64,
+        extents: &mut Vec<(u64, u64, u64)>,
+    ) -> Result<()> {
+        let span = (self.block_size / 4).pow(depth);
+        if *logical >= blocks {
+            return Ok(());
+        }
+        if ptr == 0 {
+            *logical += span;
+            return Ok(());
+        }
+        if depth == 0 {
+            match extents.last_mut() {
+                Some((start, physical, len))
+                    if *start + *len == *logical && *physical + *len == ptr as u64 =>
+                {
+                    *len += 1;
+                }
+                _ => extents.push((*logical, ptr as u64, 1)),
+            }
+            *logical += 1;
+            return Ok(());
+        }
+        let mut block = vec![0u8; self.block_size as usize];
+        self.read_at(&mut block, ptr as u64 * self.block_size)?;
+        for chunk in block.chunks_exact(4) {
+            self.block_map(le32(chunk, 0), depth - 1, logical, blocks, extents)?;
+        }
+        Ok(())
+    }
+
+    /// Read the content of a file in chunks, holes and unwritten extents are read as zeros
+    fn read_file(&self, inode: &Inode, mut f: impl FnMut(&[u8])) -> Result<()> {
+        if inode.flags & FLAG_INLINE_DATA != 0 {
+            f(inode.inline_data()?);
+            return Ok(());
+        }
+        let zeros = vec![0u8; CHUNK_SIZE as usize];
+        let mut buf = vec![0u8; CHUNK_SIZE as usize];
+        let mut pos = 0;
+        let fill = |pos: &mut u64, end: u64, f: &mut dyn FnMut(&[u8])| {
+            while *pos < end {
+                let len = (end - *pos).min(CHUNK_SIZE);
+                f(&zeros[..len as usize]);
+                *pos += len;
+            }
+        };
+        for (logical, physical, len) in self.extents(inode)? {
+            let start = logical * self.block_size;
+            if start >= inode.size {
+                break;
+            }
+            fill(&mut pos, start, &mut f);
+            let end = (start + len * self.block_size).min(inode.size);
+            while pos < end {
+                let len = (end - pos).min(CHUNK_SIZE) as usize;
+                self.read_at(&mut buf[..len], physical * self.block_size + pos - start)?;
+                f(&buf[..len]);
+                pos += len as u64;
+            }
+        }
+        fill(&mut pos, inode.size, &mut f);
+        Ok(())
+    }
+
+    fn read_dir(&self, inode: &Inode) -> Result<Vec<DirEntry>> {
+        if inode.flags & FLAG_INLINE_DATA != 0 {
+            // the first 4 bytes are the inode of the parent directory
+            let data = inode.inline_data()?;
+            return Ok(parse_dir(data.get(4..).unwrap_or_default()));
+        }
+        let mut data = Vec::new();
+        self.read_file(inode, |chunk| data.extend_from_slice(chunk))?;
+        Ok(parse_dir(&data))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn scan(
+    event_tx: &mpsc::UnboundedSender<Event>,
+    image: &Path,
+    partition: Option<u32>,
+    root: &Path,
+    dbpath: &Path,
+    excluded: &HashSet<PathBuf>,
+    pkg_tx: Option<&mpsc::UnboundedSender<Package>>,
+    shutdown: &CancellationToken,
+) -> Result<()> {
+    let fs = Filesystem::open(image, partition)?;
+
+    let mut visited = HashSet::new();
+    let mut dirs = vec![(ROOT_INODE, PathBuf::new())];
+    while let Some((ino, rel)) = dirs.pop() {
+        if shutdown.is_cancelled() {
+            break;
+        }
+        let path = root.join(&rel);
+        event_tx.send(Event::DiskPwd(path.clone()))?;
+        let entries = match fs.inode(ino).and_then(|inode| fs.read_dir(&inode)) {
+            Ok(entries) => entries,
+            Err(err) => {
+                event_tx.send(Event::DiskError(ScanError::Other(Some(path), err)))?;
+                continue;
+            }
+        };
+
+        for entry in entries {
+            let rel = rel.join(OsStr::from_bytes(&entry.name));
+            let path = root.join(&rel);
+            if excluded.iter().any(|excluded| path.starts_with(excluded)) {
+                continue;
+            }
+            let inode = match fs.inode(entry.inode) {
+                Ok(inode) => inode,
+                Err(err) => {
+                    event_tx.send(Event::DiskError(ScanError::Other(Some(path), err)))?;
+                    continue;
+                }
+            };
+            if inode.is_dir() {
+                if visited.insert(entry.inode) {
+                    dirs.push((entry.inode, rel));
+                }
+                continue;
+            }
+            // ignore symlinks and special files for now
+            if !inode.is_file() {
+                continue;
+            }
+
+            let event = if let Some(pkg_tx) = pkg_tx.filter(|_| pkg::is_local_desc(dbpath, &rel)) {
+                let mut desc = Vec::new();
+                fs.read_file(&inode, |chunk| desc.extend_from_slice(chunk))
+                    .with_context(|| anyhow!("Failed to read pacman database: {rel:?}"))?;
+                if let Some(pkg) = pkg::parse_desc(&String::from_utf8_lossy(&desc)) {
+                    debug!("Found installed package: {:?} {:?}", pkg.name, pkg.version);
+                    event_tx.send(Event::PkgQueued)?;
+                    pkg_tx.send(pkg)?;
+                }
+                Event::DiskFileHashed(path, crate::disk::sha256(&desc))
+            } else {
+                let mut hasher = Sha256::new();
+                match fs.read_file(&inode, |chunk| hasher.update(chunk)) {
+                    Ok(()) => Event::DiskFileHashed(path, hex::encode(hasher.finalize())),
+                    Err(err) => Event::DiskError(ScanError::Other(Some(path), err)),
+                }
+            };
+            event_tx.send(event)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Read files from an ext2/3/4 image (or a partition of a disk image) without mounting it,
+/// the pacman database is read from the image too unless `pkg_tx` is `None`
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_scan(
+    event_tx: mpsc::UnboundedSender<Event>,
+    image: PathBuf,
+    partition: Option<u32>,
+    root: PathBuf,
+    dbpath: PathBuf,
+    excluded: HashSet<PathBuf>,
+    pkg_tx: Option<mpsc::UnboundedSender<Package>>,
+    shutdown: CancellationToken,
+) {
+    task::spawn_blocking(move || {
+        if let Err(err) = scan(
+            &event_tx,
+            &image,
+            partition,
+            &root,
+            &dbpath,
+            &excluded,
+            pkg_tx.as_ref(),
+            &shutdown,
+        ) {
+            event_tx.send(Event::DiskError(err.into())).ok();
+        }
+
+        if pkg_tx.is_some() {
+            event_tx.send(Event::CompletedListInstalled).ok();
+        }
+        event_tx.send(Event::CompletedDiskScan).ok();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_partitions_and_dirs() {
+        let path = std::env::temp_dir().join(format!(
+            "archlinux-userland-fs-cmp-ext4-{}.img",
+            std::process::id()
+        ));
+        let mut mbr = vec![0u8; 1024];
+        mbr[446 + 16 + 4] = 0x83;
+        mbr[446 + 16 + 8..446 + 16 + 12].copy_from_slice(&2048u32.to_le_bytes());
+        mbr[510] = 0x55;
+        mbr[511] = 0xaa;
+        std::fs::write(&path, &mbr).unwrap();
+        let file = File::open(&path).unwrap();
+        assert_eq!(partition_offset(&file, 2).unwrap(), 2048 * 512);
+        assert!(partition_offset(&file, 1).is_err());
+        assert!(partition_offset(&file, 5).is_err());
+        std::fs::remove_file(&path).ok();
+
+        let mut block = Vec::new();
+        for (inode, name, rec_len) in [
+            (2u32, &b"."[..], 12u16),
+            (2, b"..", 12),
+            (12, b"bin", 12),
+            (0, b"deleted", 16),
+            (13, b"pacman.conf", 20),
+        ] {
+            block.extend(inode.to_le_bytes());
+            block.extend(rec_len.to_le_bytes());
+            block.push(name.len() as u8);
+            block.push(0);
+            block.extend(name);
+            block.resize(block.len() + rec_len as usize - 8 - name.len(), 0);
+        }
+        assert_eq!(
+            parse_dir(&block),
+            vec![
+                DirEntry {
+                    inode: 12,
+                    name: b"bin".to_vec()
+                },
+                DirEntry {
+                    inode: 13,
+                    name: b"pacman.conf".to_vec()
+                },
+            ]
+        );
+    }
+}
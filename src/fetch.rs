@@ -1,76 +1,289 @@
 use crate::errors::*;
 use crate::mtree;
 use crate::pkg::Package;
+use crate::throttle::{Pause, RateLimit};
+use crate::trust;
 use crate::Event;
 use async_compression::tokio::bufread::{GzipDecoder, XzDecoder, ZstdDecoder};
 use async_stream::stream;
+use bytes::Bytes;
 use futures_core::stream::Stream;
-use futures_util::{pin_mut, StreamExt, TryStreamExt};
+use futures_util::{StreamExt, TryStreamExt};
+use reqwest::header::RANGE;
 use reqwest::StatusCode;
-use std::path::Path;
+use serde::Serialize;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::Poll;
-use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, AsyncRead, BufReader, ReadBuf};
+use std::time::Duration;
+use tokio::fs;
+use tokio::io::{self, AsyncBufRead, AsyncRead, AsyncReadExt, BufReader, ReadBuf};
 use tokio::sync::mpsc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Semaphore};
+use tokio::task;
+use tokio::time;
 use tokio_tar as tar;
 use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tokio_util::sync::CancellationToken;
 
-const NUM_HTTP_WORKERS: usize = 4;
-pub const PKG_COMPRESSION_EXTS: &[&str] = &["zst", "xz"];
+/// Default number of packages that are looked up concurrently
+pub const NUM_HTTP_WORKERS: usize = 4;
+/// Package extensions after `.pkg.tar`, old archive snapshots still have gzip and
+/// uncompressed packages (the empty extension is a plain `.pkg.tar`)
+pub const PKG_COMPRESSION_EXTS: &[&str] = &["zst", "xz", "gz", ""];
+/// Read buffer size of the decompression workers
+pub const DECOMPRESS_BUFFER_SIZE: usize = 64 * 1024;
+/// Downloaded chunks that are queued for a decompression worker before the download waits
+const DOWNLOAD_QUEUE_LEN: usize = 64;
+/// Upper limit for the delay between retries
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
 
-fn remote_tar_read_mtree<R: AsyncRead + Unpin>(
-    reader: R,
-) -> impl Stream<Item = Result<mtree::Entry>> {
-    stream! {
-        let mut tar = tar::Archive::new(reader);
-        let mut entries = tar.entries()?;
+/// Retries of http requests that failed with a transient error (connection errors, 5xx and 429),
+/// the delay is doubled after each attempt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    /// Attempts after the first one
+    pub retries: u32,
+    /// Delay before the first retry
+    pub backoff: Duration,
+}
 
-        while let Some(entry) = entries.next().await {
-            let entry = entry?;
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            retries: 3,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
 
-            if entry.header().entry_type() != tar::EntryType::Regular {
-                continue;
+impl Retry {
+    /// The delay before retry number `attempt` (starting at 0)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.backoff
+            .saturating_mul(1 << attempt.min(16))
+            .min(MAX_BACKOFF)
+    }
+}
+
+fn is_transient(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Send a GET request (from `offset` if it's a resumed download), transient errors are retried
+async fn send_with_retry(
+    client: &reqwest::Client,
+    url: &str,
+    offset: u64,
+    retry: &Retry,
+) -> Result<reqwest::Response> {
+    let mut attempt = 0;
+    loop {
+        let mut req = client.get(url);
+        if offset > 0 {
+            req = req.header(RANGE, format!("bytes={offset}-"));
+        }
+        let err = match req.send().await {
+            Ok(res) if is_transient(res.status()) => {
+                anyhow!(
+                    "HTTP request failed with status {:?}: {url:?}",
+                    res.status()
+                )
             }
+            Ok(res) => return Ok(res),
+            Err(err) => Error::from(err).context(anyhow!("Failed to send http request ({url:?})")),
+        };
+        if attempt >= retry.retries {
+            return Err(err);
+        }
+        let delay = retry.delay(attempt);
+        warn!("{err:#}, retrying in {delay:?}");
+        time::sleep(delay).await;
+        attempt += 1;
+    }
+}
 
-            let path = entry
-                .header()
-                .path()
-                .context("Filename was not valid utf-8")?;
+/// Counts the bytes that were actually transferred by downloads, all clones share the count
+#[derive(Debug, Clone, Default)]
+pub struct Transferred(Arc<AtomicU64>);
 
-            let Some(name) = path.file_name() else {
-                continue;
-            };
+impl Transferred {
+    pub fn add(&self, bytes: u64) {
+        self.0.fetch_add(bytes, Ordering::Relaxed);
+    }
 
-            debug!("Found path in remote tar file: {path:?} => {name:?}");
-            if name == ".MTREE" {
-                let f = BufReader::new(entry);
-                let f = GzipDecoder::new(f);
-                let f = BufReader::new(f);
-                let mut f = f.lines();
-
-                while let Some(line) = f
-                    .next_line()
-                    .await
-                    .context("Failed to read line from .MTREE")?
-                {
-                    if let Some(entry) = mtree::parse(&line) {
-                        yield Ok(entry);
-                    }
-                }
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
 
-                return;
+/// Reads the chunks of a download, this blocks and is only used by the decompression workers
+struct DownloadReader {
+    rx: mpsc::Receiver<io::Result<Bytes>>,
+    chunk: Bytes,
+}
+
+impl std::io::Read for DownloadReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+/// Read the file entries of the `.MTREE` from a package, this blocks
+fn read_package_mtree<R: std::io::Read>(
+    reader: R,
+    compression: &str,
+    buffer_size: usize,
+) -> Result<trust::Trusted> {
+    let reader = std::io::BufReader::with_capacity(buffer_size, reader);
+    let reader: Box<dyn std::io::Read> = match compression {
+        "zst" => Box::new(zstd::stream::read::Decoder::with_buffer(reader)?),
+        "xz" => Box::new(liblzma::bufread::XzDecoder::new(reader)),
+        "gz" => Box::new(flate2::bufread::GzDecoder::new(reader)),
+        "" => Box::new(reader),
+        _ => bail!("Unsupported compression format: {compression:?}"),
+    };
+
+    let mut tar = ::tar::Archive::new(reader);
+    for entry in tar.entries()? {
+        let entry = entry.context("Failed to read entry from package")?;
+        if entry.header().entry_type() != ::tar::EntryType::Regular {
+            continue;
+        }
+
+        let path = entry.path().context("Failed to read path from package")?;
+        debug!("Found path in package: {path:?}");
+        if path.file_name().is_none_or(|name| name != ".MTREE") {
+            continue;
+        }
+
+        let mtree = flate2::bufread::GzDecoder::new(std::io::BufReader::new(entry));
+        let mtree = std::io::BufReader::with_capacity(buffer_size, mtree);
+        let mut parser = mtree::Parser::default();
+        let mut trusted = trust::Trusted::default();
+        for line in mtree.lines() {
+            let line = line.context("Failed to read line from .MTREE")?;
+            if let Some(entry) = parser.parse(&line) {
+                trusted.push_mtree(entry);
             }
         }
+        return Ok(trusted);
+    }
+
+    bail!("Failed to find .MTREE in package")
+}
+
+/// Runs the decompression and `.MTREE` parsing of packages on dedicated threads, so
+/// slow decompression of large packages doesn't stall the download tasks
+#[derive(Debug, Clone)]
+pub struct Decompressors {
+    permits: Arc<Semaphore>,
+    buffer_size: usize,
+}
+
+impl Default for Decompressors {
+    fn default() -> Self {
+        Decompressors::new(num_cpus::get(), DECOMPRESS_BUFFER_SIZE)
+    }
+}
+
+impl Decompressors {
+    pub fn new(workers: usize, buffer_size: usize) -> Self {
+        Decompressors {
+            permits: Arc::new(Semaphore::new(workers.max(1))),
+            buffer_size,
+        }
+    }
 
-        yield Err(anyhow!("Failed to find .MTREE in package"));
+    /// Read the file entries of the `.MTREE` from a package on a decompression worker
+    pub async fn read_mtree<R: std::io::Read + Send + 'static>(
+        &self,
+        reader: R,
+        compression: &str,
+    ) -> Result<trust::Trusted> {
+        let permit = self.permits.clone().acquire_owned().await?;
+        let compression = compression.to_string();
+        let buffer_size = self.buffer_size;
+        task::spawn_blocking(move || {
+            let _permit = permit;
+            read_package_mtree(reader, &compression, buffer_size)
+        })
+        .await?
+    }
+
+    /// Read the `.MTREE` from a package while it's downloaded. `.MTREE` is at the start of the
+    /// package, so the download is dropped (closing the connection) as soon as it's been read
+    pub async fn read_mtree_download<S: Stream<Item = reqwest::Result<Bytes>>>(
+        &self,
+        body: S,
+        compression: &str,
+        transferred: &Transferred,
+    ) -> Result<trust::Trusted> {
+        let (tx, rx) = mpsc::channel(DOWNLOAD_QUEUE_LEN);
+        let mut received = 0;
+        let download = async {
+            let mut body = std::pin::pin!(body);
+            while let Some(chunk) = body.next().await {
+                if let Ok(chunk) = &chunk {
+                    received += chunk.len() as u64;
+                    transferred.add(chunk.len() as u64);
+                }
+                // the receiver is gone once the decompression worker is done
+                if tx.send(chunk.map_err(io::Error::other)).await.is_err() {
+                    break;
+                }
+            }
+        };
+        let reader = DownloadReader {
+            rx,
+            chunk: Bytes::new(),
+        };
+        let hashes = self.read_mtree(reader, compression);
+        let hashes = {
+            tokio::pin!(download, hashes);
+            tokio::select! {
+                hashes = &mut hashes => {
+                    debug!("Found .MTREE, aborting download");
+                    hashes
+                }
+                () = &mut download => hashes.await,
+            }
+        };
+        debug!("Received {received} bytes of the package");
+        hashes
     }
 }
 
 pub enum Decompress<R> {
     Zst(ZstdDecoder<R>),
     Xz(XzDecoder<R>),
+    Gz(GzipDecoder<R>),
+    /// An uncompressed `.pkg.tar`
+    Tar(R),
+}
+
+impl<R: AsyncBufRead> Decompress<R> {
+    /// Decompress a package by the extension of its file name, like `zst`
+    pub fn new(reader: R, compression: &str) -> Result<Self> {
+        Ok(match compression {
+            "zst" => Decompress::Zst(ZstdDecoder::new(reader)),
+            "xz" => Decompress::Xz(XzDecoder::new(reader)),
+            "gz" => Decompress::Gz(GzipDecoder::new(reader)),
+            "" => Decompress::Tar(reader),
+            _ => bail!("Unsupported compression format: {compression:?}"),
+        })
+    }
 }
 
 impl<R: AsyncBufRead + Unpin> AsyncRead for Decompress<R> {
@@ -82,6 +295,8 @@ impl<R: AsyncBufRead + Unpin> AsyncRead for Decompress<R> {
         match &mut *self {
             Decompress::Zst(inner) => Pin::new(inner).poll_read(cx, buf),
             Decompress::Xz(inner) => Pin::new(inner).poll_read(cx, buf),
+            Decompress::Gz(inner) => Pin::new(inner).poll_read(cx, buf),
+            Decompress::Tar(inner) => Pin::new(inner).poll_read(cx, buf),
         }
     }
 }
@@ -102,29 +317,90 @@ pub async fn head(client: &reqwest::Client, url: &str) -> Result<StatusCode> {
     }
 }
 
-async fn fetch_remote_mtree(
+/// An installed package and where it can be downloaded, for `--list-pkgs`
+#[derive(Debug, PartialEq, Serialize)]
+pub struct PackageUrl {
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+    /// The url that was found, or the first one that was tried
+    pub url: Option<String>,
+    pub exists: bool,
+}
+
+/// Find the url of a package with `HEAD` requests, the mirrors are tried in order. A mirror
+/// that fails is skipped, the error is only returned if no other mirror has the package
+pub async fn locate_package(
+    client: &reqwest::Client,
+    mirrors: &[String],
+    pkg: &Package,
+) -> Result<PackageUrl> {
+    let mut located = PackageUrl {
+        name: pkg.name.clone(),
+        version: pkg.version.clone(),
+        arch: pkg.arch.clone(),
+        url: None,
+        exists: false,
+    };
+    let mut error = None;
+    'mirrors: for archive in mirrors {
+        for ext in PKG_COMPRESSION_EXTS {
+            let url = pkg.to_url(archive, ext)?;
+            match head(client, &url).await {
+                Ok(status) if status.is_success() => {
+                    located.url = Some(url);
+                    located.exists = true;
+                    return Ok(located);
+                }
+                Ok(_) => {
+                    located.url.get_or_insert(url);
+                }
+                Err(err) => {
+                    debug!("Skipping mirror {archive:?}: {err:#}");
+                    error = Some(err);
+                    continue 'mirrors;
+                }
+            }
+        }
+    }
+    match error {
+        Some(err) => Err(err),
+        None => Ok(located),
+    }
+}
+
+/// Request a package from the archive, `None` if it doesn't exist. Interrupted
+/// transfers are resumed with a `Range` request, as long as the server supports it
+pub async fn download_package(
     client: &reqwest::Client,
     url: &str,
-    compression: &str,
-) -> Result<Option<impl Stream<Item = Result<mtree::Entry>>>> {
+    retry: &Retry,
+) -> Result<Option<impl Stream<Item = reqwest::Result<Bytes>> + Unpin>> {
+    let body = download_package_at(client, url, 0, retry).await?;
+    Ok(body.map(|(_, body)| body))
+}
+
+/// Request a package from `offset` on, like the rest of a partial download of a previous run.
+/// The returned offset is 0 if the server doesn't support ranges and sent the whole file instead
+pub async fn download_package_at(
+    client: &reqwest::Client,
+    url: &str,
+    offset: u64,
+    retry: &Retry,
+) -> Result<Option<(u64, impl Stream<Item = reqwest::Result<Bytes>> + Unpin)>> {
     info!("Fetching url {url:?}");
-    let res = client
-        .get(url)
-        .send()
-        .await
-        .with_context(|| anyhow!("Failed to send http request ({url:?})"))?;
+    let mut res = send_with_retry(client, url, offset, retry).await?;
+    if offset > 0 && res.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        debug!("Range of partial download isn't satisfiable, starting over: {url:?}");
+        res.bytes().await.ok();
+        res = send_with_retry(client, url, 0, retry).await?;
+    }
 
     let status = res.status();
     debug!("Received {status:?}, processing response...");
-    let bytes = res.bytes_stream();
-    let mut bytes = bytes
-        .map_err(futures::io::Error::other)
-        .into_async_read()
-        .compat();
-
     if !status.is_success() {
         // read the response to reuse connection (but discard the data)
-        io::copy(&mut bytes, &mut io::sink()).await.ok();
+        res.bytes().await.ok();
 
         if status == StatusCode::NOT_FOUND {
             Ok(None)
@@ -132,93 +408,283 @@ async fn fetch_remote_mtree(
             bail!("HTTP request failed with status {status:?}: {url:?}");
         }
     } else {
-        let bytes = BufReader::new(bytes);
-
-        let reader = match compression {
-            "zst" => Decompress::Zst(ZstdDecoder::new(bytes)),
-            "xz" => Decompress::Xz(XzDecoder::new(bytes)),
-            _ => bail!("Unsupported compression format: {compression:?}"),
+        let offset = match status {
+            StatusCode::PARTIAL_CONTENT => offset,
+            _ => 0,
         };
-
-        Ok(Some(remote_tar_read_mtree(reader)))
+        let body = resume_download(client.clone(), url.to_string(), res, offset, *retry);
+        Ok(Some((offset, body)))
     }
 }
 
-async fn fetch_trusted_hashes<'a>(
-    client: &'a reqwest::Client,
-    pkg: &'a Package,
-) -> impl Stream<Item = (String, String)> + 'a {
-    stream! {
+/// The body of a download that starts at `offset`, continued with a `Range` request if the
+/// connection drops
+fn resume_download(
+    client: reqwest::Client,
+    url: String,
+    mut res: reqwest::Response,
+    mut offset: u64,
+    retry: Retry,
+) -> impl Stream<Item = reqwest::Result<Bytes>> + Unpin {
+    Box::pin(stream! {
+        let mut attempt = 0;
+        loop {
+            let mut err = match res.chunk().await {
+                Ok(Some(chunk)) => {
+                    offset += chunk.len() as u64;
+                    yield Ok(chunk);
+                    continue;
+                }
+                Ok(None) => break,
+                Err(err) => err,
+            };
+            let resumed = loop {
+                if attempt >= retry.retries {
+                    break None;
+                }
+                let delay = retry.delay(attempt);
+                attempt += 1;
+                warn!("Download of {url:?} was interrupted after {offset} bytes ({err:#}), resuming in {delay:?}");
+                time::sleep(delay).await;
+                match client.get(&url).header(RANGE, format!("bytes={offset}-")).send().await {
+                    Ok(res) if res.status() == StatusCode::PARTIAL_CONTENT => break Some(res),
+                    Ok(res) => {
+                        warn!("Can't resume download, server responded with {:?}: {url:?}", res.status());
+                        break None;
+                    }
+                    Err(next) => err = next,
+                }
+            };
+            match resumed {
+                Some(next) => res = next,
+                None => {
+                    yield Err(err);
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Throttle a download to a rate limit that's shared by all downloads
+pub fn limit_rate<S: Stream<Item = reqwest::Result<Bytes>> + Unpin>(
+    mut body: S,
+    limit: RateLimit,
+) -> impl Stream<Item = reqwest::Result<Bytes>> + Unpin {
+    Box::pin(stream! {
+        while let Some(chunk) = body.next().await {
+            if let Ok(chunk) = &chunk {
+                limit.acquire(chunk.len() as u64).await;
+            }
+            yield chunk;
+        }
+    })
+}
+
+pub async fn open_remote_package(
+    client: &reqwest::Client,
+    url: &str,
+    compression: &str,
+) -> Result<Option<impl AsyncRead + Unpin>> {
+    let Some(bytes) = download_package(client, url, &Retry::default()).await? else {
+        return Ok(None);
+    };
+    let bytes = bytes
+        .map_err(futures::io::Error::other)
+        .into_async_read()
+        .compat();
+    let bytes = BufReader::new(bytes);
+    Ok(Some(Decompress::new(bytes, compression)?))
+}
+
+/// A decompressed package, the name of its file or url for errors
+pub type PackageReader = (String, Box<dyn AsyncRead + Unpin + Send>);
+
+/// Open a package from the first directory (like `--bundle` or `--pkg-cache`) or mirror that
+/// has it, `None` if none of them do
+pub async fn open_package(
+    client: &reqwest::Client,
+    dirs: &[PathBuf],
+    mirrors: &[String],
+    pkg: &Package,
+) -> Result<Option<PackageReader>> {
+    for dir in dirs {
         for ext in PKG_COMPRESSION_EXTS {
-            let Ok(url) = pkg.to_url(ext) else {
+            let path = dir.join(pkg.file_name(ext));
+            let Ok(file) = fs::File::open(&path).await else {
                 continue;
             };
+            let reader = Decompress::new(BufReader::new(file), ext)?;
+            return Ok(Some((format!("{path:?}"), Box::new(reader))));
+        }
+    }
+    for archive in mirrors {
+        for ext in PKG_COMPRESSION_EXTS {
+            let url = pkg.to_url(archive, ext)?;
+            let Some(reader) = open_remote_package(client, &url, ext).await? else {
+                continue;
+            };
+            return Ok(Some((format!("{url:?}"), Box::new(reader))));
+        }
+    }
+    Ok(None)
+}
 
-            match fetch_remote_mtree(client, &url, ext).await {
-                Ok(Some(mtree)) => {
-                    pin_mut!(mtree);
-
-                    while let Some(entry) = mtree.next().await {
-                        if let Ok(entry) = entry {
-                            let path = entry.path;
-                            if let mtree::EntryType::File(file) = entry.content {
-                                yield (path.clone(), file.sha256digest);
-                            }
-                        }
-                    }
+/// Download a package and extract a single file from it, the path is relative to the root.
+/// The mirrors are tried in order.
+pub async fn fetch_package_file(
+    client: &reqwest::Client,
+    mirrors: &[String],
+    pkg: &Package,
+    path: &Path,
+) -> Result<Option<Vec<u8>>> {
+    for archive in mirrors {
+        for ext in PKG_COMPRESSION_EXTS {
+            let url = pkg.to_url(archive, ext)?;
+            let Some(reader) = open_remote_package(client, &url, ext).await? else {
+                continue;
+            };
 
-                    break;
-                }
-                Ok(None) => (),
-                Err(err) => {
-                    warn!("Failed to fetch remote mtree: {err:#}");
+            let mut tar = tar::Archive::new(reader);
+            let mut entries = tar.entries()?;
+            while let Some(entry) = entries.next().await {
+                let mut entry = entry.context("Failed to read entry from package")?;
+                if entry.path()? == path {
+                    let mut buf = Vec::new();
+                    entry
+                        .read_to_end(&mut buf)
+                        .await
+                        .with_context(|| anyhow!("Failed to read {path:?} from package"))?;
+                    return Ok(Some(buf));
                 }
             }
+            return Ok(None);
         }
     }
+    Ok(None)
+}
+
+pub async fn write_cache(path: &Path, content: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| anyhow!("Failed to create directory: {parent:?}"))?;
+    }
+    // write to a temporary file first so concurrent runs never see partial data
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)
+        .await
+        .with_context(|| anyhow!("Failed to write file: {tmp:?}"))?;
+    fs::rename(&tmp, path)
+        .await
+        .with_context(|| anyhow!("Failed to rename {tmp:?} to {path:?}"))?;
+    Ok(())
+}
+
+/// Resolve a `.MTREE` path (like `./usr/bin/foo`) in the root, the metadata files of the package are skipped
+fn resolve_mtree_path(root: &Path, path: &str) -> Option<PathBuf> {
+    if matches!(
+        path,
+        "./.BUILDINFO" | "./.PKGINFO" | "./.INSTALL" | "./.CHANGELOG"
+    ) {
+        return None;
+    }
+    let Some(path) = path.strip_prefix("./") else {
+        warn!("Found malformed path in .MTREE: {path:?}");
+        return None;
+    };
+    if path.starts_with('/') {
+        warn!("Found double-slash path in .MTREE: {path:?}");
+        return None;
+    }
+    Some(root.join(path))
+}
+
+/// Create the http client for downloads, without timeouts unless configured. Without a
+/// proxy the `ALL_PROXY`, `HTTPS_PROXY` and `HTTP_PROXY` environment variables are used
+pub fn http_client(
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    proxy: Option<&str>,
+) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(proxy) = proxy {
+        let proxy =
+            reqwest::Proxy::all(proxy).with_context(|| anyhow!("Invalid proxy url: {proxy:?}"))?;
+        builder = builder.proxy(proxy);
+    }
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    builder.build().context("Failed to setup http client")
 }
 
+/// Lookup the trusted hashes of queued packages with the configured trust sources,
+/// `num_workers` packages are looked up concurrently
 pub fn spawn_workers(
     event_tx: mpsc::UnboundedSender<Event>,
     rx: mpsc::UnboundedReceiver<Package>,
     root: &Path,
+    sources: Arc<trust::Chain>,
+    num_workers: usize,
+    pause: Pause,
+    shutdown: CancellationToken,
 ) {
     let rx = Arc::new(Mutex::new(rx));
-    for _ in 0..NUM_HTTP_WORKERS {
+    for _ in 0..num_workers.max(1) {
         let root = root.to_owned();
+        let sources = sources.clone();
+        let pause = pause.clone();
+        let shutdown = shutdown.clone();
         let rx = rx.clone();
         let event_tx = event_tx.clone();
         tokio::spawn(async move {
-            let client = reqwest::Client::new();
-
             loop {
-                let pkg = {
-                    let mut lock = rx.lock().await;
-                    lock.recv().await
+                let pkg = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    pkg = async {
+                        pause.wait().await;
+                        rx.lock().await.recv().await
+                    } => pkg,
                 };
                 let Some(pkg) = pkg else { break };
 
-                let stream = fetch_trusted_hashes(&client, &pkg).await;
-                pin_mut!(stream);
-                while let Some((path, sha256)) = stream.next().await {
-                    match path.as_str() {
-                        "./.BUILDINFO" => continue,
-                        "./.PKGINFO" => continue,
-                        "./.INSTALL" => continue,
-                        "./.CHANGELOG" => continue,
-                        _ => (),
+                let trusted = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    trusted = sources.hashes(&pkg) => trusted,
+                };
+                let trusted = match trusted {
+                    Some(trusted) => trusted,
+                    None => {
+                        if event_tx.send(Event::NoTrustedSource(pkg.clone())).is_err() {
+                            break;
+                        }
+                        Default::default()
                     }
-                    debug!("Found path in package: {path:?} (sha256={sha256:?}");
-                    let Some(path) = path.strip_prefix("./") else {
-                        warn!("Found malformed path in .MTREE: {path:?}");
+                };
+                // the size is known before the file is handed to a hash worker
+                for (path, metadata) in trusted.metadata {
+                    let Some(path) = resolve_mtree_path(&root, &path) else {
                         continue;
                     };
-                    if path.starts_with('/') {
-                        warn!("Found double-slash path in .MTREE: {path:?}");
-                        continue;
+                    if event_tx
+                        .send(Event::TrustedMetadata(path, metadata))
+                        .is_err()
+                    {
+                        return;
                     }
-                    let path = root.join(path);
-                    if event_tx.send(Event::TrustedFile(path, sha256)).is_err() {
+                }
+                let pkg = Arc::new(pkg);
+                for (path, sha256) in trusted.hashes {
+                    debug!("Found path in package: {path:?} (sha256={sha256:?}");
+                    let Some(path) = resolve_mtree_path(&root, &path) else {
+                        continue;
+                    };
+                    let event = Event::TrustedFile(path, sha256, Some(pkg.clone()));
+                    if event_tx.send(event).is_err() {
                         // shutdown worker
                         return;
                     }
@@ -231,3 +697,194 @@ pub fn spawn_workers(
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// An uncompressed package with a `.MTREE` for `/usr/bin/foo`
+    fn package_tar() -> Vec<u8> {
+        let mut mtree = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        mtree
+            .write_all(b"#mtree\n/set type=file uid=0 gid=0 mode=644\n./usr/bin/foo time=1.0 size=3 sha256digest=98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4\n")
+            .unwrap();
+        let mtree = mtree.finish().unwrap();
+
+        let mut tar = ::tar::Builder::new(Vec::new());
+        for (path, data) in [(".PKGINFO", &b"pkgname = foo\n"[..]), (".MTREE", &mtree)] {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, data).unwrap();
+        }
+        tar.into_inner().unwrap()
+    }
+
+    /// A zstd compressed package with a `.MTREE` for `/usr/bin/foo`
+    fn package() -> Vec<u8> {
+        zstd::encode_all(&package_tar()[..], 0).unwrap()
+    }
+
+    #[test]
+    fn read_mtree_from_package() {
+        let pkg = package();
+        let trusted = read_package_mtree(&pkg[..], "zst", 4096).unwrap();
+        assert_eq!(
+            trusted.hashes,
+            vec![(
+                "./usr/bin/foo".to_string(),
+                "98ea6e4f216f2fb4b69fff9b3a44842c38686ca685f3f55dc48c5d3fb1107be4".to_string()
+            )]
+        );
+        assert!(read_package_mtree(&pkg[..], "gz", 4096).is_err());
+
+        // packages from old archive snapshots
+        let tar = package_tar();
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        gz.write_all(&tar).unwrap();
+        let gz = gz.finish().unwrap();
+        assert_eq!(read_package_mtree(&gz[..], "gz", 4096).unwrap(), trusted);
+        assert_eq!(read_package_mtree(&tar[..], "", 4096).unwrap(), trusted);
+    }
+
+    #[tokio::test]
+    async fn abort_download_after_mtree() {
+        let pkg = Bytes::from(package());
+        let len = pkg.len() as u64;
+        // the rest of the package never arrives
+        let body = futures::stream::iter([Ok(pkg)]).chain(futures::stream::pending());
+        let transferred = Transferred::default();
+        let trusted = time::timeout(
+            Duration::from_secs(10),
+            Decompressors::default().read_mtree_download(body, "zst", &transferred),
+        )
+        .await
+        .expect("Download wasn't aborted")
+        .unwrap();
+        assert_eq!(trusted.hashes.len(), 1);
+        assert_eq!(transferred.get(), len);
+    }
+
+    /// Answer each connection with the next scripted response, the received requests are returned
+    fn scripted_server(
+        responses: Vec<&'static [u8]>,
+    ) -> (String, std::thread::JoinHandle<Vec<String>>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/foo.pkg.tar.zst", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    request.push_str(&line.to_lowercase());
+                }
+                requests.push(request);
+                stream.write_all(response).unwrap();
+            }
+            requests
+        });
+        (url, server)
+    }
+
+    #[tokio::test]
+    async fn locate_package_on_mirrors() {
+        let not_found: &[u8] =
+            b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+        let (url, server) = scripted_server(vec![
+            not_found,
+            b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        ]);
+        let host = url.trim_end_matches("/foo.pkg.tar.zst");
+        let mirrors = [
+            // nothing listens here, the mirror is skipped
+            "http://127.0.0.1:1".to_string(),
+            format!("{host}/{{name}}-{{version}}-{{arch}}.pkg.tar.{{ext}}"),
+        ];
+        let pkg = Package {
+            name: "foo".to_string(),
+            version: "1.0-1".to_string(),
+            arch: "x86_64".to_string(),
+        };
+        let client = reqwest::Client::new();
+        let located = locate_package(&client, &mirrors, &pkg).await.unwrap();
+        assert_eq!(
+            located,
+            PackageUrl {
+                name: "foo".to_string(),
+                version: "1.0-1".to_string(),
+                arch: "x86_64".to_string(),
+                url: Some(format!("{host}/foo-1.0-1-x86_64.pkg.tar.xz")),
+                exists: true,
+            }
+        );
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("head /foo-1.0-1-x86_64.pkg.tar.zst "));
+
+        // only the unreachable mirror is left
+        let err = locate_package(&client, &mirrors[..1], &pkg).await;
+        assert!(err.is_err());
+    }
+
+    #[tokio::test]
+    async fn retry_and_resume_download() {
+        let (url, server) = scripted_server(vec![
+            b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            // the connection drops after half of the body
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\nhello",
+            b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/10\r\nContent-Length: 5\r\nConnection: close\r\n\r\nworld",
+        ]);
+        let retry = Retry {
+            retries: 2,
+            backoff: Duration::from_millis(1),
+        };
+        let client = reqwest::Client::new();
+        let body = download_package(&client, &url, &retry)
+            .await
+            .unwrap()
+            .unwrap();
+        let body = body.try_collect::<Vec<_>>().await.unwrap().concat();
+        assert_eq!(body, b"helloworld");
+
+        let requests = server.join().unwrap();
+        assert!(!requests[1].contains("range:"));
+        assert!(requests[2].contains("range: bytes=5-"));
+
+        // a partial download of a previous run is continued, unless the server sends everything
+        let (url, server) = scripted_server(vec![
+            b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 5-9/10\r\nContent-Length: 5\r\nConnection: close\r\n\r\nworld",
+            b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\nhelloworld",
+        ]);
+        let (offset, body) = download_package_at(&client, &url, 5, &retry)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(offset, 5);
+        assert_eq!(
+            body.try_collect::<Vec<_>>().await.unwrap().concat(),
+            b"world"
+        );
+        let (offset, body) = download_package_at(&client, &url, 5, &retry)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(offset, 0);
+        assert_eq!(
+            body.try_collect::<Vec<_>>().await.unwrap().concat(),
+            b"helloworld"
+        );
+        let requests = server.join().unwrap();
+        assert!(requests[0].contains("range: bytes=5-"));
+
+        assert_eq!(retry.delay(0), Duration::from_millis(1));
+        assert_eq!(retry.delay(3), Duration::from_millis(8));
+        assert_eq!(Retry::default().delay(10), MAX_BACKOFF);
+    }
+}
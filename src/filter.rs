@@ -0,0 +1,228 @@
+use crate::errors::*;
+use crate::mounts::Mounts;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// A shell-style pattern for paths of the investigated system, like `/usr/bin/*` or `/home/**`.
+/// `*`, `?` and `[a-z]` match within a single path component, `**` matches any number of them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glob {
+    components: Vec<String>,
+}
+
+impl Glob {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let components = pattern
+            .split('/')
+            .filter(|c| !c.is_empty())
+            .map(String::from)
+            .collect::<Vec<_>>();
+        for component in &components {
+            if component.contains("**") && component != "**" {
+                bail!("`**` must be a path component of its own: {pattern:?}");
+            }
+            if component.matches('[').count() != component.matches(']').count() {
+                bail!("Unterminated character class in pattern: {pattern:?}");
+            }
+        }
+        Ok(Glob { components })
+    }
+
+    /// Check if a path (relative to the root) matches the pattern
+    pub fn matches(&self, path: &Path) -> bool {
+        let path = components(path);
+        match_components(&self.components, &path)
+    }
+
+    /// Check if anything below a directory (relative to the root) may match the pattern
+    fn may_match_below(&self, dir: &Path) -> bool {
+        let dir = components(dir);
+        for (pattern, component) in self.components.iter().zip(&dir) {
+            if pattern == "**" {
+                return true;
+            }
+            if !match_component(pattern.as_bytes(), component.as_bytes()) {
+                return false;
+            }
+        }
+        self.components.len() > dir.len()
+    }
+
+    /// Check if everything below a directory (relative to the root) matches, like `/home/**` for `/home`
+    fn matches_all_below(&self, dir: &Path) -> bool {
+        match self.components.split_last() {
+            Some((last, parent)) if last == "**" => match_components(parent, &components(dir)),
+            _ => false,
+        }
+    }
+}
+
+fn components(path: &Path) -> Vec<String> {
+    path.iter()
+        .map(|c| c.to_string_lossy().into_owned())
+        .filter(|c| c != "/")
+        .collect()
+}
+
+fn match_components(pattern: &[String], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| match_components(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((component, path)) => {
+                match_component(first.as_bytes(), component.as_bytes())
+                    && match_components(rest, path)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Match a whole path like `fnmatch(3)` without `FNM_PATHNAME`, so `*` also matches `/`
+pub(crate) fn fnmatch(pattern: &[u8], path: &[u8]) -> bool {
+    match_component(pattern, path)
+}
+
+/// Match a single path component with `*`, `?` and character classes like `[a-z]` or `[!0-9]`
+fn match_component(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|skip| match_component(rest, &name[skip..])),
+        Some((b'?', rest)) => !name.is_empty() && match_component(rest, &name[1..]),
+        Some((b'[', rest)) => {
+            let Some((c, name)) = name.split_first() else {
+                return false;
+            };
+            let Some(end) = rest.iter().skip(1).position(|b| *b == b']') else {
+                return false;
+            };
+            let (class, rest) = (&rest[..end + 1], &rest[end + 2..]);
+            let (negated, class) = match class.split_first() {
+                Some((b'!' | b'^', class)) => (true, class),
+                _ => (false, class),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    found |= (class[i]..=class[i + 2]).contains(c);
+                    i += 3;
+                } else {
+                    found |= class[i] == *c;
+                    i += 1;
+                }
+            }
+            found != negated && match_component(rest, name)
+        }
+        Some((p, rest)) => name.first() == Some(p) && match_component(rest, &name[1..]),
+    }
+}
+
+/// Restricts the disk scan with `--include-glob` and `--exclude-glob`
+#[derive(Debug, Default, Clone)]
+pub struct PathFilter {
+    root: PathBuf,
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    /// Files in separately mounted directories are matched with their path in the investigated system
+    mounts: Mounts,
+    /// Only these files (relative to the root) are part of the scan, like with `--paths-from`
+    only: Option<HashSet<PathBuf>>,
+}
+
+impl PathFilter {
+    pub fn new(root: PathBuf, include: Vec<Glob>, exclude: Vec<Glob>) -> Self {
+        PathFilter {
+            root,
+            include,
+            exclude,
+            mounts: Mounts::default(),
+            only: None,
+        }
+    }
+
+    pub fn with_only(mut self, paths: HashSet<PathBuf>) -> Self {
+        self.only = Some(paths);
+        self
+    }
+
+    pub fn with_mounts(mut self, mounts: Mounts) -> Self {
+        self.mounts = mounts;
+        self
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        match self.mounts.unresolve(path) {
+            Cow::Borrowed(path) => path.strip_prefix(&self.root).unwrap_or(path).into(),
+            Cow::Owned(path) => path
+                .strip_prefix(&self.root)
+                .map(Path::to_path_buf)
+                .unwrap_or(path)
+                .into(),
+        }
+    }
+
+    /// Check if a file (with the root) is part of the scan
+    pub fn is_included(&self, path: &Path) -> bool {
+        let path = self.relative(path);
+        !self.exclude.iter().any(|glob| glob.matches(&path))
+            && (self.include.is_empty() || self.include.iter().any(|glob| glob.matches(&path)))
+            && self.only.as_ref().is_none_or(|only| only.contains(&*path))
+    }
+
+    /// Check if a directory (with the root) needs to be walked
+    pub fn is_walked(&self, dir: &Path) -> bool {
+        let dir = self.relative(dir);
+        !self.exclude.iter().any(|glob| glob.matches_all_below(&dir))
+            && (self.include.is_empty()
+                || self.include.iter().any(|glob| glob.may_match_below(&dir)))
+            && self
+                .only
+                .as_ref()
+                .is_none_or(|only| only.iter().any(|path| path.starts_with(&dir)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_filter() {
+        let glob = |pattern| Glob::new(pattern).unwrap();
+        assert!(glob("/usr/bin/*").matches(Path::new("usr/bin/sudo")));
+        assert!(!glob("/usr/bin/*").matches(Path::new("usr/bin/x/sudo")));
+        assert!(glob("/etc/**/*.conf").matches(Path::new("etc/pacman.conf")));
+        assert!(glob("/etc/**/*.conf").matches(Path::new("etc/a/b/c.conf")));
+        assert!(glob("/usr/lib/libnss_[a-f]*.so.?").matches(Path::new("usr/lib/libnss_files.so.2")));
+        assert!(!glob("/usr/lib/libnss_[!a-f]*").matches(Path::new("usr/lib/libnss_files.so.2")));
+        assert!(Glob::new("/home/a**").is_err());
+        assert!(Glob::new("/usr/lib/[a-f").is_err());
+
+        let filter = PathFilter::new(
+            PathBuf::from("/mnt"),
+            vec![glob("/usr/bin/*"), glob("/etc/**")],
+            vec![glob("/etc/ssl/**"), glob("/usr/bin/*.pyc")],
+        );
+        assert!(filter.is_walked(Path::new("/mnt")));
+        assert!(filter.is_walked(Path::new("/mnt/usr")));
+        assert!(filter.is_walked(Path::new("/mnt/usr/bin")));
+        assert!(!filter.is_walked(Path::new("/mnt/usr/lib")));
+        assert!(filter.is_walked(Path::new("/mnt/etc/pam.d")));
+        assert!(!filter.is_walked(Path::new("/mnt/etc/ssl")));
+        assert!(filter.is_included(Path::new("/mnt/usr/bin/sudo")));
+        assert!(!filter.is_included(Path::new("/mnt/usr/bin/foo.pyc")));
+        assert!(!filter.is_included(Path::new("/mnt/usr/lib/libc.so.6")));
+        assert!(filter.is_included(Path::new("/mnt/etc/pam.d/sudo")));
+        assert!(!filter.is_included(Path::new("/mnt/etc/ssl/cert.pem")));
+
+        let filter = filter.with_only(HashSet::from([PathBuf::from("usr/bin/sudo")]));
+        assert!(filter.is_walked(Path::new("/mnt/usr/bin")));
+        assert!(!filter.is_walked(Path::new("/mnt/etc/pam.d")));
+        assert!(filter.is_included(Path::new("/mnt/usr/bin/sudo")));
+        assert!(!filter.is_included(Path::new("/mnt/usr/bin/su")));
+    }
+}
@@ -0,0 +1,187 @@
+use crate::errors::*;
+use crate::filter::Glob;
+use std::path::Path;
+use tokio::fs;
+
+/// Indexes of kernel modules that are written by depmod, other `modules.*` files are part of the package
+const DEPMOD_FILES: &[&str] = &[
+    "modules.alias",
+    "modules.alias.bin",
+    "modules.builtin.alias.bin",
+    "modules.builtin.bin",
+    "modules.dep",
+    "modules.dep.bin",
+    "modules.devname",
+    "modules.softdep",
+    "modules.symbols",
+    "modules.symbols.bin",
+    "modules.weakdep",
+];
+
+/// Recognize files that are legitimately generated or updated after install,
+/// the path is relative to the root and the category is returned
+pub fn classify(path: &Path) -> Option<&'static str> {
+    let name = path.file_name()?.to_str()?;
+
+    if path.components().any(|c| c.as_os_str() == "__pycache__")
+        || name.ends_with(".pyc")
+        || name.ends_with(".pyo")
+    {
+        return Some("python bytecode");
+    }
+    if path.starts_with("var/cache/fontconfig") {
+        return Some("fontconfig cache");
+    }
+    if path.starts_with("usr/share/mime") && !path.starts_with("usr/share/mime/packages") {
+        return Some("mime cache");
+    }
+    if path.starts_with("usr/share/icons") && name == "icon-theme.cache" {
+        return Some("icon cache");
+    }
+    if path.starts_with("usr/lib/gdk-pixbuf-2.0") && name == "loaders.cache" {
+        return Some("gdk-pixbuf cache");
+    }
+    if (path.starts_with("usr/lib/gtk-2.0") || path.starts_with("usr/lib/gtk-3.0"))
+        && name == "immodules.cache"
+    {
+        return Some("gtk module cache");
+    }
+    if path == Path::new("usr/lib/gio/modules/giomodule.cache") {
+        return Some("gio module cache");
+    }
+    if path == Path::new("usr/share/glib-2.0/schemas/gschemas.compiled") {
+        return Some("gsettings schema cache");
+    }
+    if path == Path::new("etc/ld.so.cache") {
+        return Some("linker cache");
+    }
+    if path == Path::new("usr/share/info/dir") {
+        return Some("info index");
+    }
+    if path.starts_with("boot") && name.starts_with("initramfs-") && name.ends_with(".img") {
+        return Some("initramfs image");
+    }
+    if path.parent().and_then(Path::parent) == Some(Path::new("usr/lib/modules"))
+        && DEPMOD_FILES.contains(&name)
+    {
+        return Some("kernel module index");
+    }
+    if path == Path::new("var/cache/ldconfig/aux-cache") {
+        return Some("linker cache");
+    }
+    if path.starts_with("etc/gconf/gconf.xml.defaults") {
+        return Some("gconf defaults");
+    }
+    if path == Path::new("usr/share/applications/mimeinfo.cache") {
+        return Some("desktop database");
+    }
+    if path == Path::new("usr/lib/udev/hwdb.bin") || path == Path::new("etc/udev/hwdb.bin") {
+        return Some("udev hwdb");
+    }
+    if path == Path::new("usr/lib/locale/locale-archive") {
+        return Some("locale archive");
+    }
+    if path.starts_with("usr/share/fonts") && (name == "fonts.dir" || name == "fonts.scale") {
+        return Some("font index");
+    }
+
+    None
+}
+
+/// Files that are expected to change after install, the built-in rules of [`classify`]
+/// (unless disabled with `--no-default-ignores`) and the globs of `--ignore-file`
+#[derive(Debug, Default)]
+pub struct Ignores {
+    defaults: bool,
+    globs: Vec<Glob>,
+}
+
+impl Ignores {
+    pub fn new(defaults: bool) -> Self {
+        Ignores {
+            defaults,
+            globs: Vec::new(),
+        }
+    }
+
+    /// Add the globs of an ignore file, one per line, `#` starts a comment
+    pub fn parse(&mut self, content: &str) -> Result<()> {
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if !line.is_empty() {
+                self.globs.push(Glob::new(line)?);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn load(&mut self, path: &Path) -> Result<()> {
+        let content = fs::read_to_string(path)
+            .await
+            .with_context(|| anyhow!("Failed to read ignore file: {path:?}"))?;
+        self.parse(&content)
+            .with_context(|| anyhow!("Failed to parse ignore file: {path:?}"))
+    }
+
+    /// The category of a file (relative to the root) that is expected to change
+    pub fn classify(&self, path: &Path) -> Option<&'static str> {
+        if let Some(category) = classify(path).filter(|_| self.defaults) {
+            Some(category)
+        } else if self.globs.iter().any(|glob| glob.matches(path)) {
+            Some("ignore file")
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_generated() {
+        assert_eq!(
+            classify(Path::new(
+                "usr/lib/python3.11/__pycache__/os.cpython-311.pyc"
+            )),
+            Some("python bytecode")
+        );
+        assert_eq!(
+            classify(Path::new("usr/share/mime/mime.cache")),
+            Some("mime cache")
+        );
+        assert_eq!(
+            classify(Path::new("usr/share/mime/packages/freedesktop.org.xml")),
+            None
+        );
+        assert_eq!(
+            classify(Path::new("var/cache/fontconfig/abc-le64.cache-9")),
+            Some("fontconfig cache")
+        );
+        assert_eq!(classify(Path::new("usr/bin/python")), None);
+        assert_eq!(
+            classify(Path::new("usr/lib/modules/6.9.7-arch1-1/modules.dep.bin")),
+            Some("kernel module index")
+        );
+        assert_eq!(
+            classify(Path::new("usr/lib/modules/6.9.7-arch1-1/modules.order")),
+            None
+        );
+
+        let mut ignores = Ignores::new(false);
+        ignores
+            .parse("# local tweaks\n/opt/app/cache/**\n\n/etc/motd # generated\n")
+            .unwrap();
+        assert_eq!(
+            ignores.classify(Path::new("usr/share/mime/mime.cache")),
+            None
+        );
+        assert_eq!(
+            ignores.classify(Path::new("opt/app/cache/a/b")),
+            Some("ignore file")
+        );
+        assert_eq!(ignores.classify(Path::new("etc/motd")), Some("ignore file"));
+        assert!(ignores.parse("/opt/a**").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+/// A trusted digest, sha256 (by far the most common) is kept as raw bytes
+#[derive(Debug, Clone, PartialEq)]
+enum Digest {
+    Sha256([u8; 32]),
+    /// Tagged digests of other algorithms, like `md5:...`
+    Other(Box<str>),
+}
+
+impl Digest {
+    fn new(hash: &str) -> Self {
+        let mut sha256 = [0u8; 32];
+        // only lowercase hex round-trips through the bytes
+        if !hash.bytes().any(|b| b.is_ascii_uppercase())
+            && hex::decode_to_slice(hash, &mut sha256).is_ok()
+        {
+            Digest::Sha256(sha256)
+        } else {
+            Digest::Other(hash.into())
+        }
+    }
+
+    fn to_hex(&self) -> String {
+        match self {
+            Digest::Sha256(sha256) => hex::encode(sha256),
+            Digest::Other(hash) => hash.to_string(),
+        }
+    }
+
+    fn heap_size(&self) -> usize {
+        match self {
+            Digest::Sha256(_) => 0,
+            Digest::Other(hash) => hash.len(),
+        }
+    }
+}
+
+/// The directory and file name a path is stored under
+fn split(path: &Path) -> (&Path, &OsStr) {
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name),
+        _ => (path, OsStr::new("")),
+    }
+}
+
+/// The trusted hashes of all files by path, like a `HashMap<PathBuf, String>` but with
+/// every directory stored once and sha256 digests as bytes. On installs with millions of
+/// files this is a fraction of the memory
+#[derive(Debug, Default, Clone)]
+pub struct TrustedHashes {
+    dirs: HashMap<PathBuf, HashMap<Box<OsStr>, Digest>>,
+    len: usize,
+}
+
+impl TrustedHashes {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn digest(&self, path: &Path) -> Option<&Digest> {
+        let (dir, name) = split(path);
+        self.dirs.get(dir)?.get(name)
+    }
+
+    /// The trusted hash of a file, formatted like it was inserted
+    pub fn get(&self, path: &Path) -> Option<String> {
+        self.digest(path).map(Digest::to_hex)
+    }
+
+    pub fn contains_key(&self, path: &Path) -> bool {
+        self.digest(path).is_some()
+    }
+
+    /// Insert or replace the trusted hash of a file
+    pub fn insert(&mut self, path: &Path, hash: &str) {
+        let (dir, name) = split(path);
+        let files = match self.dirs.get_mut(dir) {
+            Some(files) => files,
+            None => self.dirs.entry(dir.to_owned()).or_default(),
+        };
+        if files.insert(name.into(), Digest::new(hash)).is_none() {
+            self.len += 1;
+        }
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.dirs.iter().flat_map(|(dir, files)| {
+            files.keys().map(move |name| match name.is_empty() {
+                true => dir.clone(),
+                false => dir.join(&**name),
+            })
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (PathBuf, String)> + '_ {
+        self.keys().map(|path| {
+            let hash = self.get(&path).unwrap_or_default();
+            (path, hash)
+        })
+    }
+
+    /// Estimated size on the heap, for `--max-memory`
+    pub fn memory_size(&self) -> u64 {
+        const OVERHEAD: usize = mem::size_of::<(Box<OsStr>, Digest)>() + 8;
+        self.dirs
+            .iter()
+            .map(|(dir, files)| {
+                dir.capacity()
+                    + files
+                        .iter()
+                        .map(|(name, digest)| name.len() + digest.heap_size() + OVERHEAD)
+                        .sum::<usize>()
+            })
+            .sum::<usize>() as u64
+    }
+}
+
+impl<P: AsRef<Path>, S: AsRef<str>> FromIterator<(P, S)> for TrustedHashes {
+    fn from_iter<I: IntoIterator<Item = (P, S)>>(iter: I) -> Self {
+        let mut hashes = TrustedHashes::default();
+        for (path, hash) in iter {
+            hashes.insert(path.as_ref(), hash.as_ref());
+        }
+        hashes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_trusted_hashes() {
+        let sha256 = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+        let md5 = "md5:b1946ac92492d2347c6235b4d2611184";
+        let mut hashes = TrustedHashes::default();
+        hashes.insert(Path::new("/mnt/usr/bin/ls"), sha256);
+        hashes.insert(Path::new("/mnt/usr/bin/cat"), md5);
+        hashes.insert(Path::new("/mnt/usr/bin/ls"), sha256);
+        hashes.insert(Path::new("/"), "ABCD");
+        assert_eq!(hashes.len(), 3);
+        assert_eq!(hashes.dirs.len(), 2);
+
+        assert_eq!(hashes.get(Path::new("/mnt/usr/bin/ls")).unwrap(), sha256);
+        assert_eq!(hashes.get(Path::new("/mnt/usr/bin/cat")).unwrap(), md5);
+        assert_eq!(hashes.get(Path::new("/")).unwrap(), "ABCD");
+        assert!(!hashes.contains_key(Path::new("/mnt/usr/bin")));
+        assert!(matches!(
+            hashes.digest(Path::new("/mnt/usr/bin/ls")),
+            Some(Digest::Sha256(_))
+        ));
+
+        let mut paths = hashes.keys().collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            ["/", "/mnt/usr/bin/cat", "/mnt/usr/bin/ls"].map(PathBuf::from)
+        );
+        let map = hashes.iter().collect::<HashMap<_, _>>();
+        assert_eq!(map.into_iter().collect::<TrustedHashes>().len(), 3);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::errors::*;
+use crate::report::{Entry, Report};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS runs (
+    id INTEGER PRIMARY KEY,
+    root BLOB NOT NULL,
+    timestamp INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS packages (
+    run INTEGER NOT NULL REFERENCES runs(id),
+    name TEXT NOT NULL,
+    version TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS findings (
+    run INTEGER NOT NULL REFERENCES runs(id),
+    kind TEXT NOT NULL,
+    path BLOB,
+    sha256 TEXT,
+    expected TEXT,
+    package TEXT,
+    package_version TEXT,
+    details TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS runs_root ON runs(root);
+CREATE INDEX IF NOT EXISTS findings_run ON findings(run);
+";
+
+/// A previous run that was stored in the database
+#[derive(Debug, PartialEq)]
+pub struct Run {
+    pub id: i64,
+    /// Seconds since the unix epoch
+    pub timestamp: i64,
+    pub entries: Vec<Entry>,
+}
+
+/// The results of previous runs in a sqlite database, given with `--db`
+pub struct History {
+    conn: Connection,
+}
+
+impl History {
+    pub fn open(path: &Path) -> Result<Self> {
+        let conn = Connection::open(path)
+            .with_context(|| anyhow!("Failed to open results database: {path:?}"))?;
+        conn.execute_batch(SCHEMA)
+            .with_context(|| anyhow!("Failed to create tables in results database: {path:?}"))?;
+        Ok(History { conn })
+    }
+
+    /// The most recent run of the same root, if any
+    pub fn last_run(&self, root: &Path) -> Result<Option<Run>> {
+        let run = self
+            .conn
+            .query_row(
+                "SELECT id, timestamp FROM runs WHERE root = ?1 ORDER BY id DESC LIMIT 1",
+                params![root.as_os_str().as_bytes()],
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+            )
+            .optional()
+            .context("Failed to query previous runs")?;
+        let Some((id, timestamp)) = run else {
+            return Ok(None);
+        };
+
+        let mut stmt = self.conn.prepare(
+            "SELECT kind, path, sha256, expected, package, package_version, details
+             FROM findings WHERE run = ?1 ORDER BY rowid",
+        )?;
+        let rows = stmt.query_map(params![id], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<Vec<u8>>>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, Option<String>>(5)?,
+                row.get::<_, String>(6)?,
+            ))
+        })?;
+        let mut entries = Vec::new();
+        for row in rows {
+            let (kind, path, sha256, expected, package, package_version, details) =
+                row.context("Failed to read finding of previous run")?;
+            let path = path.map(|path| PathBuf::from(OsStr::from_bytes(&path)));
+            let mut entry = Entry::new(kind, path);
+            entry.sha256 = sha256;
+            entry.expected = expected;
+            entry.package = package;
+            entry.package_version = package_version;
+            entry.details = serde_json::from_str(&details)
+                .context("Failed to parse details of previous finding")?;
+            entries.push(entry);
+        }
+        Ok(Some(Run {
+            id,
+            timestamp,
+            entries,
+        }))
+    }
+
+    /// Store the findings of a run together with the set of installed packages
+    pub fn store(&mut self, report: &Report, packages: &BTreeSet<(String, String)>) -> Result<i64> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO runs (root, timestamp) VALUES (?1, ?2)",
+            params![report.root.as_os_str().as_bytes(), timestamp],
+        )
+        .context("Failed to store run")?;
+        let id = tx.last_insert_rowid();
+        {
+            let mut stmt =
+                tx.prepare("INSERT INTO packages (run, name, version) VALUES (?1, ?2, ?3)")?;
+            for (name, version) in packages {
+                stmt.execute(params![id, name, version])
+                    .context("Failed to store package of run")?;
+            }
+            let mut stmt = tx.prepare(
+                "INSERT INTO findings (run, kind, path, sha256, expected, package, package_version, details)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            for entry in &report.entries {
+                stmt.execute(params![
+                    id,
+                    entry.kind,
+                    entry.path.as_ref().map(|path| path.as_os_str().as_bytes()),
+                    entry.sha256,
+                    entry.expected,
+                    entry.package,
+                    entry.package_version,
+                    serde_json::to_string(&entry.details)?,
+                ])
+                .context("Failed to store finding of run")?;
+            }
+        }
+        tx.commit().context("Failed to store run")?;
+        Ok(id)
+    }
+}
+
+/// Identifies a finding across runs, findings without a path (like packages without a
+/// trusted source) are told apart by their details
+fn key(entry: &Entry) -> (&str, Option<&Path>, Option<&str>, Option<&[String]>) {
+    (
+        &entry.kind,
+        entry.path.as_deref(),
+        entry.sha256.as_deref(),
+        entry.path.is_none().then_some(&entry.details[..]),
+    )
+}
+
+/// The findings that weren't reported by the previous run, a file that was modified
+/// again (with a different hash) is reported as new
+pub fn new_findings(previous: &[Entry], entries: Vec<Entry>) -> Vec<Entry> {
+    let previous = previous.iter().map(key).collect::<HashSet<_>>();
+    entries
+        .into_iter()
+        .filter(|entry| !previous.contains(&key(entry)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_against_last_run() {
+        let path = std::env::temp_dir().join(format!("history-test-{}.sqlite", std::process::id()));
+        let mut history = History::open(&path).unwrap();
+        let root = PathBuf::from("/mnt");
+        assert_eq!(history.last_run(&root).unwrap(), None);
+
+        let mut wrong = Entry::path("WRONG SHA256", "/mnt/usr/bin/sudo");
+        wrong.sha256 = Some("aa".to_string());
+        let untrusted = Entry::new("NO TRUSTED SOURCE", None).detail("foo-1.0-1-x86_64");
+        let report = Report {
+            root: root.clone(),
+            entries: vec![wrong.clone(), untrusted.clone()],
+            ..Default::default()
+        };
+        let packages = BTreeSet::from([("sudo".to_string(), "1.9.15-1".to_string())]);
+        let id = history.store(&report, &packages).unwrap();
+
+        let other = Report {
+            root: PathBuf::from("/mnt2"),
+            ..Default::default()
+        };
+        history.store(&other, &packages).unwrap();
+
+        let last = history.last_run(&root).unwrap().unwrap();
+        assert_eq!(last.id, id);
+        assert_eq!(last.entries, report.entries);
+
+        let mut modified_again = wrong.clone();
+        modified_again.sha256 = Some("bb".to_string());
+        let missing = Entry::path("MISSING FILE", "/mnt/usr/bin/ls");
+        let new = new_findings(
+            &last.entries,
+            vec![wrong, untrusted, modified_again.clone(), missing.clone()],
+        );
+        assert_eq!(new, vec![modified_again, missing]);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
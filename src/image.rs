@@ -0,0 +1,186 @@
+use crate::errors::*;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
+
+/// Split `disk.img[:partition]` into the image path and partition number
+pub fn parse_arg(arg: &str) -> (PathBuf, Option<u32>) {
+    if let Some((path, partition)) = arg.rsplit_once(':') {
+        if let Ok(partition) = partition.parse() {
+            return (PathBuf::from(path), Some(partition));
+        }
+    }
+    (PathBuf::from(arg), None)
+}
+
+/// Run a command and return its trimmed stdout, failing on a non-zero exit status
+pub fn command<I: AsRef<OsStr>>(program: &str, args: &[I]) -> Result<String> {
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| anyhow!("Failed to execute {program}"))?;
+    if !output.status.success() {
+        bail!(
+            "{program} exited with error ({}): {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn is_qcow2(path: &Path) -> Result<bool> {
+    let mut magic = [0u8; 4];
+    let mut file =
+        fs::File::open(path).with_context(|| anyhow!("Failed to open image: {path:?}"))?;
+    Ok(file.read_exact(&mut magic).is_ok() && magic == QCOW2_MAGIC)
+}
+
+/// Find an nbd device that isn't connected yet
+fn free_nbd_device() -> Result<PathBuf> {
+    for idx in 0.. {
+        let size = Path::new("/sys/block").join(format!("nbd{idx}/size"));
+        let Ok(size) = fs::read_to_string(&size) else {
+            bail!("No free nbd device found (is the nbd kernel module loaded?)");
+        };
+        if size.trim() == "0" {
+            return Ok(PathBuf::from(format!("/dev/nbd{idx}")));
+        }
+    }
+    unreachable!()
+}
+
+#[derive(Debug)]
+enum Device {
+    Loop(PathBuf),
+    Nbd(PathBuf),
+}
+
+impl Device {
+    fn path(&self) -> &Path {
+        match self {
+            Device::Loop(path) => path,
+            Device::Nbd(path) => path,
+        }
+    }
+
+    fn detach(&self) -> Result<()> {
+        match self {
+            Device::Loop(path) => command("losetup", &[OsStr::new("-d"), path.as_os_str()])?,
+            Device::Nbd(path) => {
+                command("qemu-nbd", &[OsStr::new("--disconnect"), path.as_os_str()])?
+            }
+        };
+        Ok(())
+    }
+}
+
+/// A disk image attached as read-only block device and mounted into a temporary
+/// directory, everything is torn down again when dropped
+#[derive(Debug)]
+pub struct Image {
+    pub mountpoint: PathBuf,
+    device: Device,
+    mounted: bool,
+}
+
+impl Image {
+    pub fn mount(path: &Path, partition: Option<u32>) -> Result<Self> {
+        let device = if is_qcow2(path)? {
+            let device = free_nbd_device()?;
+            info!("Connecting qcow2 image {path:?} to {device:?}");
+            command(
+                "qemu-nbd",
+                &[
+                    OsStr::new("--read-only"),
+                    OsStr::new("--connect"),
+                    device.as_os_str(),
+                    path.as_os_str(),
+                ],
+            )?;
+            Device::Nbd(device)
+        } else {
+            info!("Attaching disk image {path:?} to loop device");
+            let device = command(
+                "losetup",
+                &[
+                    OsStr::new("--find"),
+                    OsStr::new("--show"),
+                    OsStr::new("--read-only"),
+                    OsStr::new("--partscan"),
+                    path.as_os_str(),
+                ],
+            )?;
+            Device::Loop(PathBuf::from(device))
+        };
+
+        let mut image = Image {
+            mountpoint: std::env::temp_dir()
+                .join(format!("archlinux-userland-fs-cmp-{}", std::process::id())),
+            device,
+            mounted: false,
+        };
+
+        let mut source = image.device.path().as_os_str().to_owned();
+        if let Some(partition) = partition {
+            source.push(format!("p{partition}"));
+        }
+
+        fs::create_dir(&image.mountpoint)
+            .with_context(|| anyhow!("Failed to create mountpoint: {:?}", image.mountpoint))?;
+        info!("Mounting {source:?} read-only at {:?}", image.mountpoint);
+        command(
+            "mount",
+            &[
+                OsStr::new("-o"),
+                OsStr::new("ro"),
+                &source,
+                image.mountpoint.as_os_str(),
+            ],
+        )
+        .with_context(|| anyhow!("Failed to mount filesystem from {source:?}"))?;
+        image.mounted = true;
+
+        Ok(image)
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        if self.mounted {
+            info!("Unmounting {:?}", self.mountpoint);
+            if let Err(err) = command("umount", &[&self.mountpoint]) {
+                warn!("Failed to unmount {:?}: {err:#}", self.mountpoint);
+                return;
+            }
+        }
+        if let Err(err) = fs::remove_dir(&self.mountpoint) {
+            debug!("Failed to remove mountpoint {:?}: {err:#}", self.mountpoint);
+        }
+        if let Err(err) = self.device.detach() {
+            warn!("Failed to detach {:?}: {err:#}", self.device.path());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_image_arg() {
+        assert_eq!(parse_arg("disk.img"), (PathBuf::from("disk.img"), None));
+        assert_eq!(
+            parse_arg("disk.img:2"),
+            (PathBuf::from("disk.img"), Some(2))
+        );
+        assert_eq!(
+            parse_arg("/tmp/a:b/disk.qcow2"),
+            (PathBuf::from("/tmp/a:b/disk.qcow2"), None)
+        );
+    }
+}
@@ -0,0 +1,389 @@
+use crate::errors::*;
+use clap::ValueEnum;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::time::{self, Duration};
+
+const VIRUSTOTAL_URL: &str = "https://www.virustotal.com/api/v3/files/";
+const MALWAREBAZAAR_URL: &str = "https://mb-api.abuse.ch/api/v1/";
+/// Hashes that weren't found are looked up again after a week
+const NEGATIVE_CACHE_TTL: i64 = 7 * 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Provider {
+    Virustotal,
+    Malwarebazaar,
+}
+
+impl Provider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Virustotal => "virustotal",
+            Provider::Malwarebazaar => "malwarebazaar",
+        }
+    }
+
+    /// The api endpoint, for the list of hosts the fetcher process may connect to
+    pub fn url(&self) -> &'static str {
+        match self {
+            Provider::Virustotal => VIRUSTOTAL_URL,
+            Provider::Malwarebazaar => MALWAREBAZAAR_URL,
+        }
+    }
+
+    pub fn api_key_env(&self) -> &'static str {
+        match self {
+            Provider::Virustotal => "VT_API_KEY",
+            Provider::Malwarebazaar => "MALWAREBAZAAR_API_KEY",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    NotFound,
+    Detections { malicious: u64, total: u64 },
+    Known { signature: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Annotation {
+    pub provider: Provider,
+    pub verdict: Verdict,
+}
+
+impl fmt::Display for Annotation {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        let provider = self.provider.name();
+        match &self.verdict {
+            Verdict::NotFound => write!(w, "{provider}: not found"),
+            Verdict::Detections { malicious, total } => {
+                write!(w, "{provider}: {malicious}/{total} detections")
+            }
+            Verdict::Known {
+                signature: Some(signature),
+            } => write!(w, "{provider}: known malware ({signature})"),
+            Verdict::Known { signature: None } => write!(w, "{provider}: known malware"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct VtResponse {
+    data: VtData,
+}
+
+#[derive(Debug, Deserialize)]
+struct VtData {
+    attributes: VtAttributes,
+}
+
+#[derive(Debug, Deserialize)]
+struct VtAttributes {
+    last_analysis_stats: HashMap<String, u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MbResponse {
+    query_status: String,
+    #[serde(default)]
+    data: Vec<MbEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MbEntry {
+    signature: Option<String>,
+}
+
+pub struct Lookup {
+    client: reqwest::Client,
+    provider: Provider,
+    api_key: String,
+    interval: time::Interval,
+    cache: HashMap<String, Verdict>,
+    negative_cache_path: Option<PathBuf>,
+    /// Unix timestamps of when a hash wasn't found
+    negative_cache: HashMap<String, i64>,
+}
+
+/// The detections of a file in a virustotal response
+fn parse_virustotal(body: &[u8]) -> Result<Verdict> {
+    let res = serde_json::from_slice::<VtResponse>(body)
+        .context("Failed to parse virustotal response")?;
+    let stats = res.data.attributes.last_analysis_stats;
+
+    let malicious = stats.get("malicious").copied().unwrap_or(0);
+    let total = stats.values().sum();
+    Ok(Verdict::Detections { malicious, total })
+}
+
+/// The verdict of a malwarebazaar `get_info` response
+fn parse_malwarebazaar(body: &[u8]) -> Result<Verdict> {
+    let res = serde_json::from_slice::<MbResponse>(body)
+        .context("Failed to parse malwarebazaar response")?;
+
+    match res.query_status.as_str() {
+        "ok" => Ok(Verdict::Known {
+            signature: res.data.into_iter().find_map(|entry| entry.signature),
+        }),
+        "hash_not_found" => Ok(Verdict::NotFound),
+        status => bail!("Unexpected malwarebazaar query status: {status:?}"),
+    }
+}
+
+/// Executable by mode or an ELF binary, untracked executables are looked up before other files
+fn is_executable(path: &Path) -> bool {
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return false;
+    };
+    if metadata.permissions().mode() & 0o111 != 0 {
+        return true;
+    }
+    let mut magic = [0; 4];
+    std::fs::File::open(path)
+        .and_then(|mut file| std::io::Read::read_exact(&mut file, &mut magic))
+        .is_ok_and(|_| crate::elf::is_elf(&magic))
+}
+
+/// Pick up to `limit` files to look up, every lookup waits for the rate limit. Flagged files
+/// come first, then untracked executables and then the rest. The number of skipped files is
+/// returned too
+pub fn select(
+    flagged: &BTreeMap<PathBuf, String>,
+    untracked: Vec<(PathBuf, String)>,
+    limit: usize,
+) -> (BTreeMap<PathBuf, String>, usize) {
+    let (executables, others) = untracked
+        .into_iter()
+        .filter(|(path, _)| !flagged.contains_key(path))
+        .partition::<Vec<_>, _>(|(path, _)| is_executable(path));
+    let files = flagged
+        .iter()
+        .map(|(path, sha256)| (path.clone(), sha256.clone()))
+        .chain(executables)
+        .chain(others)
+        .collect::<Vec<_>>();
+    let skipped = files.len().saturating_sub(limit);
+    (files.into_iter().take(limit).collect(), skipped)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+impl Lookup {
+    pub fn new(
+        client: reqwest::Client,
+        provider: Provider,
+        api_key: String,
+        requests_per_minute: u32,
+        negative_cache_path: Option<PathBuf>,
+    ) -> Result<Self> {
+        if requests_per_minute == 0 {
+            bail!("Lookup rate limit needs to be at least one request per minute");
+        }
+        let interval = time::interval(Duration::from_secs(60) / requests_per_minute);
+
+        let mut negative_cache = HashMap::new();
+        if let Some(path) = &negative_cache_path {
+            if let Ok(buf) = std::fs::read(path) {
+                negative_cache = serde_json::from_slice::<HashMap<String, i64>>(&buf)
+                    .with_context(|| anyhow!("Failed to parse lookup cache: {path:?}"))?;
+                negative_cache.retain(|_, time| *time + NEGATIVE_CACHE_TTL > now());
+            }
+        }
+
+        Ok(Self {
+            client,
+            provider,
+            api_key,
+            interval,
+            cache: HashMap::new(),
+            negative_cache_path,
+            negative_cache,
+        })
+    }
+
+    async fn save_negative_cache(&self) -> Result<()> {
+        let Some(path) = &self.negative_cache_path else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| anyhow!("Failed to create directory: {parent:?}"))?;
+        }
+        let buf = serde_json::to_vec(&self.negative_cache)?;
+        fs::write(path, buf)
+            .await
+            .with_context(|| anyhow!("Failed to write lookup cache: {path:?}"))?;
+        Ok(())
+    }
+
+    async fn query_virustotal(&self, sha256: &str) -> Result<Verdict> {
+        let url = format!("{VIRUSTOTAL_URL}{sha256}");
+        let res = self
+            .client
+            .get(&url)
+            .header("x-apikey", &self.api_key)
+            .send()
+            .await
+            .with_context(|| anyhow!("Failed to send http request ({url:?})"))?;
+
+        let status = res.status();
+        if status == StatusCode::NOT_FOUND {
+            return Ok(Verdict::NotFound);
+        } else if !status.is_success() {
+            bail!("Unexpected http status code ({url:?}): {status:?}");
+        }
+
+        let body = res.bytes().await.context("Failed to read http response")?;
+        parse_virustotal(&body)
+    }
+
+    async fn query_malwarebazaar(&self, sha256: &str) -> Result<Verdict> {
+        let res = self
+            .client
+            .post(MALWAREBAZAAR_URL)
+            .header("Auth-Key", &self.api_key)
+            .form(&[("query", "get_info"), ("hash", sha256)])
+            .send()
+            .await
+            .with_context(|| anyhow!("Failed to send http request ({MALWAREBAZAAR_URL:?})"))?
+            .error_for_status()?;
+
+        let body = res.bytes().await.context("Failed to read http response")?;
+        parse_malwarebazaar(&body)
+    }
+
+    pub async fn query(&mut self, sha256: &str) -> Result<Annotation> {
+        let verdict = if let Some(verdict) = self.cache.get(sha256) {
+            verdict.clone()
+        } else if self.negative_cache.contains_key(sha256) {
+            debug!("Hash is in negative lookup cache: {sha256:?}");
+            Verdict::NotFound
+        } else {
+            self.interval.tick().await;
+            debug!("Looking up sha256 with {:?}: {sha256:?}", self.provider);
+            let verdict = match self.provider {
+                Provider::Virustotal => self.query_virustotal(sha256).await?,
+                Provider::Malwarebazaar => self.query_malwarebazaar(sha256).await?,
+            };
+            if verdict == Verdict::NotFound {
+                self.negative_cache.insert(sha256.to_string(), now());
+            }
+            self.cache.insert(sha256.to_string(), verdict.clone());
+            verdict
+        };
+
+        Ok(Annotation {
+            provider: self.provider,
+            verdict,
+        })
+    }
+
+    /// Query all given files, lookup failures are logged but do not abort
+    pub async fn annotate(
+        &mut self,
+        files: &BTreeMap<PathBuf, String>,
+    ) -> HashMap<PathBuf, Annotation> {
+        let mut annotations = HashMap::new();
+        for (path, sha256) in files {
+            match self.query(sha256).await {
+                Ok(annotation) => {
+                    info!("Lookup result for {path:?}: {annotation}");
+                    annotations.insert(path.clone(), annotation);
+                }
+                Err(err) => warn!("Failed to lookup hash for {path:?}: {err:#}"),
+            }
+        }
+        if let Err(err) = self.save_negative_cache().await {
+            warn!("Failed to save lookup cache: {err:#}");
+        }
+        annotations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_executables_first() {
+        let dir = std::env::temp_dir().join(format!("intel-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("backdoor.sh");
+        std::fs::write(&script, b"#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let binary = dir.join("libevil.so");
+        std::fs::write(&binary, b"\x7fELF\x02\x01").unwrap();
+        let notes = dir.join("notes.txt");
+        std::fs::write(&notes, b"todo\n").unwrap();
+
+        let flagged = BTreeMap::from([(PathBuf::from("/usr/bin/sudo"), "a".to_string())]);
+        let untracked = vec![
+            (notes, "b".to_string()),
+            (script.clone(), "c".to_string()),
+            (binary.clone(), "d".to_string()),
+        ];
+        let (files, skipped) = select(&flagged, untracked.clone(), 3);
+        assert_eq!(skipped, 1);
+        assert_eq!(
+            files.into_keys().collect::<Vec<_>>(),
+            vec![script, binary, PathBuf::from("/usr/bin/sudo")]
+        );
+        let (files, skipped) = select(&flagged, untracked, 100);
+        assert_eq!((files.len(), skipped), (4, 0));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn parse_lookup_responses() {
+        let verdict = parse_virustotal(
+            br#"{"data":{"id":"abc","type":"file","attributes":{"meaningful_name":"sshd","last_analysis_stats":{"malicious":3,"suspicious":1,"undetected":60,"harmless":0}}}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            verdict,
+            Verdict::Detections {
+                malicious: 3,
+                total: 64
+            }
+        );
+        assert!(parse_virustotal(br#"{"error":{"code":"QuotaExceededError"}}"#).is_err());
+
+        let verdict = parse_malwarebazaar(
+            br#"{"query_status":"ok","data":[{"sha256_hash":"abc","signature":null},{"sha256_hash":"abc","signature":"XorDDoS"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            verdict,
+            Verdict::Known {
+                signature: Some("XorDDoS".to_string())
+            }
+        );
+        let annotation = Annotation {
+            provider: Provider::Malwarebazaar,
+            verdict,
+        };
+        assert_eq!(
+            annotation.to_string(),
+            "malwarebazaar: known malware (XorDDoS)"
+        );
+        assert_eq!(
+            parse_malwarebazaar(br#"{"query_status":"hash_not_found"}"#).unwrap(),
+            Verdict::NotFound
+        );
+        assert!(parse_malwarebazaar(br#"{"query_status":"illegal_hash"}"#).is_err());
+    }
+}
@@ -0,0 +1,156 @@
+use crate::errors::*;
+use crate::hashes::TrustedHashes;
+use crate::report::Entry;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Index files written by depmod after install, these aren't owned by any package
+const DEPMOD_FILES: &[&str] = &[
+    "modules.alias",
+    "modules.alias.bin",
+    "modules.builtin.alias.bin",
+    "modules.builtin.bin",
+    "modules.dep",
+    "modules.dep.bin",
+    "modules.devname",
+    "modules.softdep",
+    "modules.symbols",
+    "modules.symbols.bin",
+    "modules.weakdep",
+];
+
+#[derive(Debug)]
+pub enum Finding {
+    /// A loadable kernel module that isn't owned by any package
+    Untracked(PathBuf),
+    /// A file in a module tree that isn't owned by any package
+    UntrackedFile(PathBuf),
+    /// A kernel module built by dkms, not shipped by a package but expected
+    Dkms(PathBuf),
+    /// A module tree for a kernel that is no longer installed
+    StaleTree(PathBuf),
+    Error(PathBuf, Error),
+}
+
+impl From<Finding> for Entry {
+    fn from(finding: Finding) -> Self {
+        match finding {
+            Finding::Untracked(path) => Entry::path("MODULE NO SHA256", path),
+            Finding::UntrackedFile(path) => Entry::path("MODULE TREE NO SHA256", path),
+            Finding::Dkms(path) => Entry::path("MODULE DKMS", path),
+            Finding::StaleTree(path) => Entry::path("MODULE STALE TREE", path),
+            Finding::Error(path, err) => {
+                Entry::path("MODULE ERROR", path).detail(format!("{err:#}"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Files in the module trees that have been covered by this pass
+    pub covered: Vec<PathBuf>,
+    pub findings: Vec<Finding>,
+}
+
+pub fn is_module(name: &str) -> bool {
+    name.ends_with(".ko") || name.contains(".ko.")
+}
+
+/// Classify an untracked file, the path is relative to the module tree
+fn classify(rel: &Path) -> Option<fn(PathBuf) -> Finding> {
+    let name = rel.file_name()?.to_str()?;
+    if rel.parent() == Some(Path::new("")) && DEPMOD_FILES.contains(&name) {
+        None
+    } else if !is_module(name) {
+        Some(Finding::UntrackedFile)
+    } else if rel.starts_with("updates/dkms") {
+        Some(Finding::Dkms)
+    } else {
+        Some(Finding::Untracked)
+    }
+}
+
+/// A module tree belongs to an installed kernel if the package of its kernel image is installed
+fn is_installed(tree: &Path, trusted: &TrustedHashes) -> bool {
+    trusted.contains_key(&tree.join("vmlinuz")) || trusted.contains_key(&tree.join("pkgbase"))
+}
+
+fn check_tree(tree: &Path, trusted: &TrustedHashes, report: &mut Report) -> Result<()> {
+    let mut untracked = Vec::new();
+    for entry in WalkDir::new(tree) {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+        let path = entry.into_path();
+        if trusted.contains_key(&path) {
+            continue;
+        }
+        report.covered.push(path.clone());
+        let rel = path.strip_prefix(tree)?;
+        if let Some(finding) = classify(rel) {
+            untracked.push(finding(path));
+        }
+    }
+    // untracked kernel modules are reported first
+    untracked.sort_by_key(|finding| !matches!(finding, Finding::Untracked(_)));
+    report.findings.extend(untracked);
+    Ok(())
+}
+
+/// Verify the module trees in /usr/lib/modules are owned by installed packages
+pub fn check(root: &Path, trusted: &TrustedHashes) -> Result<Report> {
+    let mut report = Report::default();
+
+    let modules = root.join("usr/lib/modules");
+    let mut trees = fs::read_dir(&modules)
+        .with_context(|| anyhow!("Failed to read directory: {modules:?}"))?
+        .map(|entry| Ok(entry?.path()))
+        .collect::<Result<Vec<_>>>()?;
+    trees.sort();
+
+    for tree in trees {
+        if !tree.is_dir() {
+            continue;
+        }
+        if !is_installed(&tree, trusted) {
+            for entry in WalkDir::new(&tree).into_iter().flatten() {
+                if !entry.file_type().is_dir() {
+                    report.covered.push(entry.into_path());
+                }
+            }
+            report.findings.push(Finding::StaleTree(tree));
+            continue;
+        }
+        if let Err(err) = check_tree(&tree, trusted, &mut report) {
+            report.findings.push(Finding::Error(tree, err));
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_untracked() {
+        let kind = |path| classify(Path::new(path)).map(|f| Entry::from(f(PathBuf::new())).kind);
+        assert_eq!(kind("modules.dep"), None);
+        assert_eq!(
+            kind("kernel/drivers/foo.ko.zst").as_deref(),
+            Some("MODULE NO SHA256")
+        );
+        assert_eq!(
+            kind("updates/dkms/nvidia.ko.zst").as_deref(),
+            Some("MODULE DKMS")
+        );
+        assert_eq!(
+            kind("kernel/modules.dep").as_deref(),
+            Some("MODULE TREE NO SHA256")
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use crate::errors::*;
+use std::collections::HashSet;
+use std::path::Path;
+use tokio::fs;
+
+fn is_sha256(s: &str) -> bool {
+    s.len() == 64 && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn csv_fields(line: &str) -> impl Iterator<Item = &str> {
+    line.split(',').map(|field| field.trim().trim_matches('"'))
+}
+
+/// Parse a list of known-good sha256 hashes
+///
+/// Supported are plain lists with one hash per line, `sha256sum` output and
+/// csv files with a header containing a sha256 column (like NSRL RDS exports).
+pub fn parse(content: &str) -> HashSet<String> {
+    let mut hashes = HashSet::new();
+    let mut lines = content.lines().peekable();
+
+    let csv_column = lines.peek().and_then(|header| {
+        csv_fields(header).position(|field| {
+            let field = field.to_ascii_lowercase();
+            field == "sha256" || field == "sha-256"
+        })
+    });
+    if csv_column.is_some() {
+        lines.next();
+    }
+
+    for line in lines {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let hash = if let Some(column) = csv_column {
+            csv_fields(line).nth(column)
+        } else {
+            line.split_whitespace().next()
+        };
+
+        match hash {
+            Some(hash) if is_sha256(hash) => {
+                hashes.insert(hash.to_ascii_lowercase());
+            }
+            _ => trace!("Ignoring line in known-good hash set: {line:?}"),
+        }
+    }
+
+    hashes
+}
+
+pub async fn load(paths: &[impl AsRef<Path>]) -> Result<HashSet<String>> {
+    let mut hashes = HashSet::new();
+    for path in paths {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .await
+            .with_context(|| anyhow!("Failed to read known-good hash set: {path:?}"))?;
+        let parsed = parse(&content);
+        info!("Loaded {} known-good hashes from {path:?}", parsed.len());
+        hashes.extend(parsed);
+    }
+    Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sha256sum() {
+        let hashes = parse("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  usr/share/empty\n# comment\n\nnot-a-hash foo\n");
+        assert_eq!(
+            hashes,
+            HashSet::from([
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn parse_nsrl_csv() {
+        let hashes = parse("\"sha256\",\"sha1\",\"md5\",\"file_name\"\n\"E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855\",\"DA39A3EE5E6B4B0D3255BFEF95601890AFD80709\",\"D41D8CD98F00B204E9800998ECF8427E\",\"empty\"\n");
+        assert_eq!(
+            hashes,
+            HashSet::from([
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string()
+            ])
+        );
+    }
+}
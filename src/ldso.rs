@@ -0,0 +1,246 @@
+use crate::errors::*;
+use crate::hashes::TrustedHashes;
+use crate::report::Entry;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CACHE_MAGIC: &[u8] = b"glibc-ld.so.cache1.1";
+const CACHE_HEADER_LEN: usize = 48;
+const CACHE_ENTRY_LEN: usize = 24;
+/// Directories the dynamic linker searches by default
+const DEFAULT_DIRS: &[&str] = &["/usr/lib", "/usr/lib32"];
+
+#[derive(Debug)]
+pub enum Finding {
+    /// A library that is loaded into every dynamically linked process
+    Preload(PathBuf, String),
+    /// A library in a directory of the linker search path that isn't owned by any package
+    UntrackedLibrary(PathBuf),
+    /// The linker cache references a library that isn't owned by any package
+    CacheUntracked(PathBuf),
+    Error(PathBuf, Error),
+}
+
+impl From<Finding> for Entry {
+    fn from(finding: Finding) -> Self {
+        match finding {
+            Finding::Preload(path, entry) => Entry::path("LD PRELOAD", path).detail(entry),
+            Finding::UntrackedLibrary(path) => Entry::path("LD UNTRACKED LIBRARY", path),
+            Finding::CacheUntracked(path) => Entry::path("LD CACHE UNTRACKED", path),
+            Finding::Error(path, err) => Entry::path("LD ERROR", path).detail(format!("{err:#}")),
+        }
+    }
+}
+
+/// Parse `ld.so.conf`, returns the listed directories and the include patterns
+pub fn parse_conf(content: &str) -> (Vec<String>, Vec<String>) {
+    let mut dirs = Vec::new();
+    let mut includes = Vec::new();
+    for line in content.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(pattern) = line.strip_prefix("include") {
+            if pattern.starts_with(char::is_whitespace) {
+                includes.extend(pattern.split_whitespace().map(String::from));
+                continue;
+            }
+        }
+        if line.starts_with("hwcap") {
+            continue;
+        }
+        dirs.extend(
+            line.split([' ', '\t', ',', ':'])
+                .filter(|dir| !dir.is_empty())
+                .map(String::from),
+        );
+    }
+    (dirs, includes)
+}
+
+/// Expand an include pattern, only a wildcard in the filename is supported
+fn expand_include(root: &Path, pattern: &str) -> Vec<PathBuf> {
+    let pattern = Path::new("/etc").join(pattern);
+    let path = crate::resolve_target_path(root, &pattern);
+    let (Some(dir), Some(name)) = (path.parent(), path.file_name().and_then(|n| n.to_str())) else {
+        return Vec::new();
+    };
+    let Some((prefix, suffix)) = name.split_once('*') else {
+        return vec![path];
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut files = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with(prefix) && n.ends_with(suffix))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// The directories of the linker search path (as paths of the investigated system)
+fn search_dirs(root: &Path) -> BTreeSet<String> {
+    let mut dirs = DEFAULT_DIRS
+        .iter()
+        .map(|dir| dir.to_string())
+        .collect::<BTreeSet<_>>();
+
+    let mut queue = vec![crate::resolve_target_path(
+        root,
+        Path::new("/etc/ld.so.conf"),
+    )];
+    let mut seen = BTreeSet::new();
+    while let Some(path) = queue.pop() {
+        if !seen.insert(path.clone()) {
+            continue;
+        }
+        let Ok(content) = fs::read_to_string(&path) else {
+            continue;
+        };
+        let (found, includes) = parse_conf(&content);
+        dirs.extend(found);
+        for pattern in includes {
+            queue.extend(expand_include(root, &pattern));
+        }
+    }
+    dirs
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .context("Unexpected end of ld.so.cache")?;
+    Ok(u32::from_ne_bytes(bytes.try_into()?))
+}
+
+fn read_str(data: &[u8], offset: usize) -> Result<String> {
+    let data = data
+        .get(offset..)
+        .context("Invalid string offset in ld.so.cache")?;
+    let end = data
+        .iter()
+        .position(|b| *b == 0)
+        .context("Unterminated string in ld.so.cache")?;
+    Ok(String::from_utf8_lossy(&data[..end]).into_owned())
+}
+
+/// Parse the library paths from a (new format) `ld.so.cache`
+pub fn parse_cache(data: &[u8]) -> Result<Vec<String>> {
+    if !data.starts_with(CACHE_MAGIC) {
+        bail!("Unsupported ld.so.cache format");
+    }
+    let nlibs = read_u32(data, CACHE_MAGIC.len())? as usize;
+
+    let mut libs = Vec::new();
+    for idx in 0..nlibs {
+        let entry = CACHE_HEADER_LEN + idx * CACHE_ENTRY_LEN;
+        let value = read_u32(data, entry + 8)? as usize;
+        libs.push(read_str(data, value)?);
+    }
+    Ok(libs)
+}
+
+fn is_library(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(".so") || name.contains(".so."))
+}
+
+/// Check if the library, or the file it links to, is owned by a package
+fn is_tracked(root: &Path, path: &Path, trusted: &TrustedHashes) -> bool {
+    if trusted.contains_key(path) {
+        return true;
+    }
+    let (Ok(real_root), Ok(real)) = (fs::canonicalize(root), fs::canonicalize(path)) else {
+        return false;
+    };
+    real.strip_prefix(&real_root)
+        .is_ok_and(|rel| trusted.contains_key(&root.join(rel)))
+}
+
+/// Look for dynamic linker configuration that loads untracked code
+pub fn check(root: &Path, trusted: &TrustedHashes) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    let preload = crate::resolve_target_path(root, Path::new("/etc/ld.so.preload"));
+    match fs::read_to_string(&preload) {
+        Ok(content) => {
+            let (entries, _) = parse_conf(&content);
+            for entry in entries {
+                findings.push(Finding::Preload(preload.clone(), entry));
+            }
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => findings.push(Finding::Error(preload.clone(), err.into())),
+    }
+
+    for dir in search_dirs(root) {
+        let dir = crate::resolve_target_path(root, Path::new(&dir));
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        let mut untracked = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+            .map(|entry| entry.path())
+            .filter(|path| is_library(path) && !trusted.contains_key(path))
+            .collect::<Vec<_>>();
+        untracked.sort();
+        findings.extend(untracked.into_iter().map(Finding::UntrackedLibrary));
+    }
+
+    let cache = crate::resolve_target_path(root, Path::new("/etc/ld.so.cache"));
+    match fs::read(&cache) {
+        Ok(data) => match parse_cache(&data) {
+            Ok(libs) => {
+                let libs = libs
+                    .iter()
+                    .map(|lib| crate::resolve_target_path(root, Path::new(lib)))
+                    .filter(|lib| !is_tracked(root, lib, trusted))
+                    .collect::<BTreeSet<_>>();
+                findings.extend(libs.into_iter().map(Finding::CacheUntracked));
+            }
+            Err(err) => findings.push(Finding::Error(cache, err)),
+        },
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Err(err) => findings.push(Finding::Error(cache, err.into())),
+    }
+
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ld_so_conf() {
+        let (dirs, includes) =
+            parse_conf("# comment\ninclude ld.so.conf.d/*.conf\n/opt/lib, /usr/local/lib\n\n");
+        assert_eq!(dirs, vec!["/opt/lib", "/usr/local/lib"]);
+        assert_eq!(includes, vec!["ld.so.conf.d/*.conf"]);
+    }
+
+    #[test]
+    fn parse_ld_so_cache() {
+        let strings = b"libfoo.so.1\0/usr/lib/libfoo.so.1\0";
+        let strings_offset = CACHE_HEADER_LEN + CACHE_ENTRY_LEN;
+
+        let mut data = CACHE_MAGIC.to_vec();
+        data.extend(1u32.to_ne_bytes());
+        data.extend((strings.len() as u32).to_ne_bytes());
+        data.resize(CACHE_HEADER_LEN, 0);
+        data.extend(0i32.to_ne_bytes());
+        data.extend((strings_offset as u32).to_ne_bytes());
+        data.extend((strings_offset as u32 + 12).to_ne_bytes());
+        data.resize(strings_offset, 0);
+        data.extend(strings);
+
+        assert_eq!(parse_cache(&data).unwrap(), vec!["/usr/lib/libfoo.so.1"]);
+    }
+}
@@ -0,0 +1,233 @@
+//! Verification engine of archlinux-userland-fs-cmp, compares a mounted (or archived)
+//! Arch Linux filesystem against a trusted source like the `.MTREE` of the installed packages.
+//!
+//! The scan is driven by background tasks that report their progress as [`Event`]s
+//! over a channel, the caller keeps track of the state:
+//!
+//! - [`pkg::spawn_list_installed`] reads the pacman database and queues the installed packages
+//! - [`fetch::spawn_workers`] looks up the trusted hashes of each package with a [`trust::Chain`]
+//! - [`dpkg::spawn_list_installed`], [`apk::spawn_list_installed`] and [`rpm::spawn_list_installed`] replace both
+//!   for Debian, Alpine and Fedora systems, the hashes are read from the database of their package manager
+//! - [`disk::spawn_scan`] walks the filesystem and emits the files that were found
+//! - [`disk::spawn_hashers`] hashes files on request and verifies them against a trusted hash
+//!
+//! The results can be written as a [`report::Report`], as text or json. [`scanner::Scan`] keeps
+//! track of the events and classifies the findings, [`scanner::Scanner`] runs a scan of a pacman
+//! system with sensible defaults. The command line tool adds its sandbox, exports and extra
+//! checks on top.
+//!
+//! ```no_run
+//! use archlinux_userland_fs_cmp::throttle::Pause;
+//! use archlinux_userland_fs_cmp::{disk, fetch, pkg, trust, Event};
+//! use std::path::Path;
+//! use std::sync::Arc;
+//! use tokio::sync::mpsc;
+//! use tokio_util::sync::CancellationToken;
+//!
+//! # async fn example() {
+//! let root = Path::new("/mnt");
+//! // cancelling the token stops all workers
+//! let shutdown = CancellationToken::new();
+//! let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+//! let (pkg_tx, pkg_rx) = mpsc::unbounded_channel();
+//! pkg::spawn_list_installed(event_tx.clone(), pkg_tx, root.join("var/lib/pacman"), Default::default(), shutdown.clone());
+//! let sources = trust::Chain::new(vec![Box::new(trust::Archive {
+//!     client: reqwest::Client::new(),
+//!     urls: vec![pkg::ARCHIVE_URL.to_string()],
+//!     decompress: fetch::Decompressors::default(),
+//!     retry: fetch::Retry::default(),
+//!     limit_rate: None,
+//!     transferred: Default::default(),
+//!     snapshot: None,
+//! })]);
+//! let pause = Pause::default();
+//! fetch::spawn_workers(event_tx.clone(), pkg_rx, root, Arc::new(sources), 4, pause.clone(), shutdown.clone());
+//! disk::spawn_scan(event_tx, vec![root.to_owned()], Default::default(), Default::default(), vec![], 4, 4, None, pause, shutdown);
+//!
+//! while let Some(event) = event_rx.recv().await {
+//!     match event {
+//!         Event::TrustedFile(path, sha256, _) => println!("trusted {path:?}: {sha256}"),
+//!         Event::DiskFile(path, _) => println!("found {path:?}"),
+//!         _ => (),
+//!     }
+//! }
+//! # }
+//! ```
+
+/// Vulnerability groups of the Arch Linux security tracker
+pub mod advisory;
+/// Export trusted hashes as an AIDE database
+pub mod aide;
+/// Accepted local modifications
+pub mod allowlist;
+/// The apk database of Alpine systems
+pub mod apk;
+/// Command line arguments
+pub mod args;
+/// Scaling the number of hash workers by their throughput
+pub mod autoscale;
+/// Package managers of the investigated system
+pub mod backend;
+/// Signed baselines of files that aren't owned by any package
+pub mod baseline;
+/// Kernel images, initramfs, microcode and EFI binaries
+pub mod boot;
+/// Use a known-good directory tree as trusted source
+pub mod compare;
+/// Parser for newc cpio archives
+pub mod cpio;
+/// Quarantine and chain-of-custody log
+pub mod custody;
+/// Compare files with the content of their packages instead of only the `.MTREE`
+pub mod deep;
+/// Hash algorithms of trusted hashes
+pub mod digest;
+/// Walking and hashing the filesystem
+pub mod disk;
+/// Downloading the installed packages, for `--download-pkgs`
+pub mod download;
+/// The dpkg database of Debian systems
+pub mod dpkg;
+/// Section-by-section comparison of ELF binaries
+pub mod elf;
+pub mod errors;
+/// Read ext2/3/4 images in userspace, without mounting them
+pub mod ext4;
+/// Download trusted hashes and files from the package archive
+pub mod fetch;
+/// Glob filters for the paths of a scan
+pub mod filter;
+/// Files that are legitimately generated after install
+pub mod generated;
+/// Compact in-memory storage of trusted hashes
+pub mod hashes;
+/// Results of previous runs in a sqlite database, to report only new findings
+pub mod history;
+/// Attach and mount disk images
+pub mod image;
+/// Threat intel lookups of unknown hashes
+pub mod intel;
+/// Kernel module tree audit
+pub mod kmod;
+/// Known-good hash sets like NSRL
+pub mod knowngood;
+/// Dynamic linker hijack detection
+pub mod ldso;
+/// Temporary snapshots of logical volumes
+pub mod lvm;
+/// `sha256sum` compatible hash manifests
+pub mod manifest;
+/// Directories of the investigated system that are mounted separately
+pub mod mounts;
+/// Parser for the `.MTREE` of packages
+pub mod mtree;
+/// Parser for pacman.conf of the investigated system
+pub mod pacman_conf;
+/// Log of the files that passed verification, for `--log-passed`
+pub mod passlog;
+/// The local pacman database
+pub mod pkg;
+/// setuid/setgid files and file capabilities
+pub mod privileges;
+/// The fetcher process that does all network access, separated from the scanner
+pub mod privsep;
+/// Audit profiles
+pub mod profile;
+/// Progress bars of the terminal UI
+pub mod progress;
+/// Repository databases of Arch Linux Archive date snapshots
+pub mod repodb;
+/// Structured results of a scan
+pub mod report;
+/// Replacing flagged files with the files of their packages
+pub mod restore;
+/// The sqlite rpmdb of Fedora and RHEL systems
+pub mod rpm;
+/// Dropping capabilities and mount namespaces
+pub mod sandbox;
+/// SARIF 2.1.0 output for code scanning tools
+pub mod sarif;
+/// The state of a scan, and a builder to run one from other tools
+pub mod scanner;
+/// Read-only btrfs snapshots
+pub mod snapshot;
+/// Scan squashfs images without mounting them
+pub mod squashfs;
+/// Caches and state between runs
+pub mod state;
+/// systemd unit and drop-in tampering
+pub mod systemd;
+/// Scan (compressed) tarballs without extracting them
+pub mod tarball;
+/// Diffs of modified text files against their packages
+pub mod textdiff;
+/// Memory cap for the state of a scan
+pub mod throttle;
+/// Trace changes through multiple snapshots
+pub mod timeline;
+/// Pluggable sources of trusted hashes
+pub mod trust;
+/// Package version comparison
+pub mod vercmp;
+
+use crate::disk::{HashVerify, ScanError};
+use crate::pkg::Package;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+/// Progress of the background tasks of a scan
+#[derive(Debug)]
+pub enum Event {
+    PkgQueued,
+    PkgCompleted,
+    /// None of the trust sources has the package, its files can't be verified
+    NoTrustedSource(Package),
+    /// A trusted hash and the package it was read from, if any
+    TrustedFile(PathBuf, String, Option<Arc<Package>>),
+    /// Permissions, ownership or symlink target of a file from the `.MTREE`
+    TrustedMetadata(PathBuf, mtree::Metadata),
+    WrongMetadata(PathBuf, String),
+    /// A packaged file, directory or symlink was replaced with another type, like a symlink
+    TypeChanged(PathBuf, String),
+    /// A file that was found on disk and its size
+    DiskFile(PathBuf, u64),
+    /// A file that has already been hashed while reading it, like from a tarball
+    DiskFileHashed(PathBuf, String),
+    DiskPwd(PathBuf),
+    DiskError(ScanError),
+    CompletedListInstalled,
+    CompletedDiskScan,
+    AvailableHasher(oneshot::Sender<disk::HashBatch>),
+    /// A verified (or hashed) file and its size
+    CompletedHashing(HashVerify, u64),
+}
+
+/// Top-level directories that are symlinks into /usr on merged-/usr systems
+const MERGED_USR_DIRS: &[&str] = &["bin", "sbin", "lib", "lib32", "lib64", "libx32"];
+
+/// Resolve a path of the investigated system relative to the scan root
+pub fn resolve_target_path(root: &Path, mut path: &Path) -> PathBuf {
+    while let Ok(v) = path.strip_prefix("/") {
+        path = v;
+    }
+    root.join(path)
+}
+
+/// Resolve paths like `bin/ls` to `usr/bin/ls` if `bin` is a symlink on a merged-/usr system,
+/// symlinks are not followed by the scan so the file would show up as untracked otherwise
+pub fn resolve_merged_usr(root: &Path, path: &str) -> PathBuf {
+    let path = path.trim_start_matches('/');
+    if let Some((dir, rest)) = path.split_once('/') {
+        if MERGED_USR_DIRS.contains(&dir) {
+            if let Ok(target) = std::fs::read_link(root.join(dir)) {
+                let target = target.to_string_lossy();
+                let target = target.trim_start_matches('/');
+                if target == format!("usr/{dir}") {
+                    return root.join(target).join(rest);
+                }
+            }
+        }
+    }
+    root.join(path)
+}
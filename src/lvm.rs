@@ -0,0 +1,94 @@
+use crate::errors::*;
+use crate::image::command;
+use std::fs;
+use std::path::PathBuf;
+
+/// A temporary, read-only mounted snapshot of a logical volume, it's unmounted
+/// and removed again when dropped
+#[derive(Debug)]
+pub struct LvmSnapshot {
+    pub mountpoint: PathBuf,
+    lv: String,
+    mounted: bool,
+}
+
+impl LvmSnapshot {
+    /// Snapshot `VG/LV`, this is expected to run inside of a private mount namespace
+    pub fn create(origin: &str) -> Result<Self> {
+        let Some((vg, _)) = origin.split_once('/') else {
+            bail!("Logical volume needs to be given as VG/LV: {origin:?}");
+        };
+        let name = format!("archlinux-userland-fs-cmp-{}", std::process::id());
+
+        // thin volumes don't need space reserved for the snapshot
+        let pool = command("lvs", &["--noheadings", "-o", "pool_lv", origin])
+            .with_context(|| anyhow!("Failed to find logical volume: {origin:?}"))?;
+        info!("Creating snapshot {vg}/{name} of logical volume {origin}");
+        if pool.is_empty() {
+            command(
+                "lvcreate",
+                &["--snapshot", "-l", "10%ORIGIN", "-n", &name, origin],
+            )
+        } else {
+            command(
+                "lvcreate",
+                &[
+                    "--snapshot",
+                    "--setactivationskip",
+                    "n",
+                    "-n",
+                    &name,
+                    origin,
+                ],
+            )
+        }
+        .with_context(|| anyhow!("Failed to create snapshot of {origin:?}"))?;
+
+        let mut snapshot = LvmSnapshot {
+            mountpoint: std::env::temp_dir().join(&name),
+            lv: format!("{vg}/{name}"),
+            mounted: false,
+        };
+
+        let device = format!("/dev/{}", snapshot.lv);
+        // xfs refuses to mount a filesystem with the same uuid twice
+        let fstype = command("blkid", &["-o", "value", "-s", "TYPE", &device]).unwrap_or_default();
+        let options = if fstype == "xfs" { "ro,nouuid" } else { "ro" };
+
+        fs::create_dir(&snapshot.mountpoint)
+            .with_context(|| anyhow!("Failed to create mountpoint: {:?}", snapshot.mountpoint))?;
+        info!("Mounting {device:?} read-only at {:?}", snapshot.mountpoint);
+        command(
+            "mount",
+            &[
+                "-o",
+                options,
+                &device,
+                &snapshot.mountpoint.to_string_lossy(),
+            ],
+        )
+        .with_context(|| anyhow!("Failed to mount snapshot {device:?}"))?;
+        snapshot.mounted = true;
+
+        Ok(snapshot)
+    }
+}
+
+impl Drop for LvmSnapshot {
+    fn drop(&mut self) {
+        if self.mounted {
+            info!("Unmounting {:?}", self.mountpoint);
+            if let Err(err) = command("umount", &[&self.mountpoint]) {
+                warn!("Failed to unmount {:?}: {err:#}", self.mountpoint);
+                return;
+            }
+        }
+        if let Err(err) = fs::remove_dir(&self.mountpoint) {
+            debug!("Failed to remove mountpoint {:?}: {err:#}", self.mountpoint);
+        }
+        info!("Removing snapshot {}", self.lv);
+        if let Err(err) = command("lvremove", &["-y", &self.lv]) {
+            warn!("Failed to remove snapshot {}: {err:#}", self.lv);
+        }
+    }
+}
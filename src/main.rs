@@ -1,290 +1,652 @@
-pub mod args;
-pub mod disk;
-pub mod errors;
-pub mod fetch;
-pub mod mtree;
-pub mod pkg;
-pub mod sandbox;
-
-use crate::args::Args;
-use crate::disk::HashVerify;
-use crate::errors::*;
-use clap::Parser;
-use colored::{Color, Colorize};
-use env_logger::Env;
+use archlinux_userland_fs_cmp::args::{Args, CacheAction, CompareReports, SubCommand};
+use archlinux_userland_fs_cmp::autoscale::Autoscale;
+use archlinux_userland_fs_cmp::backend::Backend;
+use archlinux_userland_fs_cmp::errors::*;
+use archlinux_userland_fs_cmp::history::{self, History};
+use archlinux_userland_fs_cmp::progress::{self, Progress};
+use archlinux_userland_fs_cmp::report::{Entry, Format, Report};
+use archlinux_userland_fs_cmp::scanner::{self, Scan};
+use archlinux_userland_fs_cmp::throttle::{MemoryCap, RateLimit};
+use archlinux_userland_fs_cmp::{
+    advisory, aide, allowlist, apk, baseline, boot, compare, custody, deep, digest, disk, download,
+    dpkg, elf, ext4, fetch, filter, generated, image, intel, kmod, knowngood, ldso, lvm, manifest,
+    mounts, pacman_conf, passlog, pkg, privileges, privsep, repodb, report, resolve_merged_usr,
+    resolve_target_path, restore, rpm, sandbox, snapshot, squashfs, state, systemd, tarball,
+    textdiff, timeline, trust, Event,
+};
+use clap::{Parser, ValueEnum};
+use env_logger::{Env, WriteStyle};
+use futures_util::StreamExt;
 use num_format::{Locale, ToFormattedString};
-use std::collections::{BTreeSet, HashMap, VecDeque};
-use std::io::Write;
-use std::path::PathBuf;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::IsTerminal;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
 use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio::signal;
 use tokio::sync::mpsc;
-use tokio::sync::oneshot;
+use tokio::task;
 use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
 
-const PATH_TRUNCATE: usize = 85;
-
-#[derive(Debug)]
-pub enum Event {
-    PkgQueued,
-    PkgCompleted,
-    TrustedFile(PathBuf, String),
-    DiskFile(PathBuf),
-    DiskPwd(PathBuf),
-    DiskError(Error),
-    CompletedListInstalled,
-    CompletedDiskScan,
-    AvailableHasher(oneshot::Sender<(PathBuf, String)>),
-    CompletedHashing(HashVerify),
+/// How often the progress is written to the `--state-file`
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60);
+/// Exit code if files were flagged, including untracked and missing files and findings of the checks
+const EXIT_FLAGGED: u8 = 1;
+/// Exit code if nothing was flagged, but some files couldn't be read
+const EXIT_DISK_ERRORS: u8 = 2;
+/// Exit code if nothing else was found, but the hashes of some packages couldn't be obtained
+const EXIT_UNTRUSTED_PKGS: u8 = 3;
+/// Exit code if the scan failed or was aborted
+const EXIT_ERROR: u8 = 4;
+
+/// Draw the progress bars, or log the status line if logging is verbose
+fn print_status(app: &Scan, progress: Option<&Progress>, json: bool) {
+    if json {
+        match serde_json::to_string(&progress::Summary::from(app)) {
+            Ok(summary) => eprintln!("{summary}"),
+            Err(err) => warn!("Failed to serialize progress: {err:#}"),
+        }
+    } else if let Some(progress) = progress {
+        progress.update(app);
+    } else {
+        info!("{}", app.status());
+    }
 }
 
-#[derive(Default)]
-pub struct App {
-    num_hash_worker: usize,
-    retired_hashers: usize,
+/// Options whose values are redacted from the command line in the report summary
+const SECRET_ARGS: &[&str] = &["--lookup-api-key"];
 
-    completed_pkgs: u64,
-    total_pkgs: u64,
-    trusted_hashes: HashMap<PathBuf, String>,
+/// The command line for the report summary, without secrets
+fn command_line() -> Vec<String> {
+    let mut redact = false;
+    std::env::args_os()
+        .map(|arg| {
+            let arg = arg.to_string_lossy().into_owned();
+            if mem::take(&mut redact) {
+                return "<redacted>".to_string();
+            }
+            if let Some((name, _)) = arg.split_once('=') {
+                if SECRET_ARGS.contains(&name) {
+                    return format!("{name}=<redacted>");
+                }
+            }
+            redact = SECRET_ARGS.contains(&arg.as_str());
+            arg
+        })
+        .collect()
+}
 
-    running_list_installed: bool,
-    running_disk_scan: bool,
+/// The statistics of the scan, `written` are the findings that were already written with `--stream`
+fn summary(app: &Scan, written: &[Entry], entries: &[Entry]) -> report::Summary {
+    let mut summary = report::Summary {
+        tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        command_line: command_line(),
+        packages_total: app.total_pkgs,
+        packages_untrusted: app.untrusted_pkgs.len(),
+        files_verified: app.trusted_found.len() as u64,
+        files_hashed: app.files_hashed,
+        bytes_hashed: app.bytes_hashed,
+        bytes_downloaded: app.transferred.get(),
+        phases: app.phases.clone(),
+        duration_secs: app
+            .started
+            .map(|started| started.elapsed().as_secs_f64())
+            .unwrap_or_default(),
+        ..Default::default()
+    };
+    // flagged files are repeated if the analysis added details to them
+    let repeated = written
+        .iter()
+        .map(|entry| (&entry.kind, &entry.path))
+        .collect::<HashSet<_>>();
+    summary.count_findings(written);
+    summary.count_findings(
+        entries
+            .iter()
+            .filter(|entry| !repeated.contains(&(&entry.kind, &entry.path))),
+    );
+    summary
+}
 
-    waiting_for_data: BTreeSet<PathBuf>,
-    waiting_for_hasher: VecDeque<(PathBuf, String)>,
-    available_hashers: VecDeque<oneshot::Sender<(PathBuf, String)>>,
+/// Open the file the report is written to, or stdout
+async fn open_output(path: Option<&Path>) -> Result<Box<dyn AsyncWrite + Unpin>> {
+    if let Some(path) = path {
+        let file = File::create(path)
+            .await
+            .with_context(|| anyhow!("Failed to open file: {path:?}"))?;
+        Ok(Box::new(file))
+    } else {
+        Ok(Box::new(io::stdout()))
+    }
+}
 
-    files_passed: u64,
-    files_flagged: BTreeSet<PathBuf>,
+#[tokio::main]
+async fn run(mut args: Args) -> Result<ExitCode> {
+    let started_at = Instant::now();
+    let mut root = args.root().to_owned();
 
-    disk_errors: Vec<Error>,
-    disk_pwd: Option<PathBuf>,
-}
+    // a fast first pass, everything the local database doesn't know is fetched as usual
+    if args.trust_local_db && !args.trust_source.contains(&trust::Kind::LocalDb) {
+        args.trust_source.insert(0, trust::Kind::LocalDb);
+    }
+    let mut selected = selected_pkgs(&args)?;
 
-impl App {
-    fn new(num_hash_worker: usize) -> Self {
-        Self {
-            num_hash_worker,
-            running_list_installed: true,
-            running_disk_scan: true,
-            ..Default::default()
+    // the snapshot is deleted again once it goes out of scope
+    let _snapshot = match &args.snapshot {
+        Some(path) if path.as_os_str() == "auto" => {
+            if snapshot::is_btrfs(&root)? {
+                let snapshot = snapshot::Snapshot::create(&root)?;
+                root = snapshot.path.clone();
+                Some(snapshot)
+            } else {
+                warn!("Scan root {root:?} is not on btrfs, scanning the live filesystem");
+                None
+            }
+        }
+        Some(path) => {
+            root = path.clone();
+            None
+        }
+        None => None,
+    };
+    // same for the mounted disk image
+    let _image = if let Some(arg) = &args.image {
+        let (path, partition) = image::parse_arg(arg);
+        let image = image::Image::mount(&path, partition)?;
+        root = image.mountpoint.clone();
+        Some(image)
+    } else {
+        None
+    };
+    let _lvm_snapshot = if let Some(origin) = &args.lvm_snapshot {
+        let snapshot = lvm::LvmSnapshot::create(origin)?;
+        root = snapshot.mountpoint.clone();
+        Some(snapshot)
+    } else {
+        None
+    };
+    let mounts = mounts::Mounts::new(root.clone(), args.map.clone());
+    for dir in mounts.host_dirs() {
+        if !dir.is_dir() {
+            bail!("Mapped directory does not exist: {dir:?}");
         }
     }
+    // tarballs and images can't be read before the scan
+    let pacman_conf = if args.backend == Backend::Pacman && !args.reads_archive() {
+        pacman_conf::load(&root, &args.pacman_conf).await?
+    } else {
+        Default::default()
+    };
+    if args.dbpath.is_none() {
+        args.dbpath = pacman_conf.dbpath.clone();
+    }
+    let target_dbpath = resolve_target_path(Path::new(""), args.dbpath());
+    let dbpath = mounts.resolve(root.join(&target_dbpath));
 
-    fn update(&mut self, event: Event) -> bool {
-        match event {
-            Event::PkgQueued => self.total_pkgs += 1,
-            Event::PkgCompleted => {
-                self.completed_pkgs += 1;
-                return true;
-            }
-            Event::TrustedFile(path, sha256) => {
-                if let Some(old) = self.trusted_hashes.get(&path) {
-                    warn!("Unexpected duplicate for {path:?} ({sha256:?} vs {old:?})");
-                } else {
-                    if self.waiting_for_data.remove(&path) {
-                        self.waiting_for_hasher
-                            .push_back((path.clone(), sha256.clone()));
-                    }
-                    self.trusted_hashes.insert(path, sha256);
-                }
-            }
-            Event::DiskFile(path) => {
-                if let Some(sha256) = self.trusted_hashes.get(&path) {
-                    self.waiting_for_hasher.push_back((path, sha256.clone()));
-                } else {
-                    self.waiting_for_data.insert(path);
+    // spot checks only fetch the packages that own the listed files
+    let mut paths_from = None;
+    if let Some(list) = &args.paths_from {
+        if args.backend != Backend::Pacman {
+            bail!("--paths-from is only supported with the pacman backend");
+        }
+        let paths = read_paths(list, &root)?;
+        let owners = pkg::list_file_owners(&dbpath).await?;
+        let selected = selected.get_or_insert_default();
+        let mut untracked = Vec::new();
+        for path in &paths {
+            match owners.get(path) {
+                Some(pkg) => {
+                    selected.insert(pkg.name.clone());
                 }
+                None => untracked.push(mounts.resolve(root.join(path))),
             }
-            Event::DiskPwd(path) => {
-                self.disk_pwd = Some(path);
-            }
-            Event::DiskError(err) => {
-                self.disk_errors.push(err);
-            }
-            Event::CompletedListInstalled => {
-                self.running_list_installed = false;
-                return true;
-            }
-            Event::CompletedDiskScan => {
-                self.running_disk_scan = false;
-                self.disk_pwd = None;
-                return true;
-            }
-            Event::AvailableHasher(hasher) => {
-                self.available_hashers.push_back(hasher);
-            }
-            Event::CompletedHashing(hashed) => match hashed {
-                HashVerify::Passed(_) => self.files_passed += 1,
-                HashVerify::Flagged(path) => {
-                    self.files_flagged.insert(path);
-                }
-            },
         }
-
-        false
+        info!(
+            "Verifying {} files owned by {} packages",
+            paths.len(),
+            selected.len()
+        );
+        paths_from = Some((paths, untracked));
     }
 
-    fn redraw(&self, logs_enabled: bool) {
-        let mut status = "packages: ".bold().to_string();
-        status.push_str(
-            &format!(
-                "{:>7}",
-                self.completed_pkgs.to_formatted_string(&Locale::en)
-            )
-            .color(if self.completed_pkgs < self.total_pkgs {
-                Color::Yellow
-            } else {
-                Color::Green
-            })
-            .to_string(),
-        );
-        status.push_str(&"/".bold().to_string());
-        status.push_str(
-            &self
-                .total_pkgs
-                .to_formatted_string(&Locale::en)
-                .bold()
-                .to_string(),
+    if args.stream && matches!(args.format, Format::Json | Format::Sarif) {
+        bail!(
+            "--stream can't be used with --format {}, use --format jsonl instead",
+            args.format.to_possible_value().unwrap().get_name()
         );
-        if self.running_list_installed {
-            status.push_str("...");
-        }
-
-        if !self.trusted_hashes.is_empty() {
-            status.push_str(
-                &format!(
-                    " (files: {:>7})",
-                    self.trusted_hashes.len().to_formatted_string(&Locale::en)
-                )
-                .bright_black()
-                .to_string(),
-            );
-        }
+    }
 
-        status.push_str(&" | scanned disk: ".bold().to_string());
-        status.push_str(
-            &format!(
-                "{:>8}",
-                self.waiting_for_data.len().to_formatted_string(&Locale::en)
-            )
-            .yellow()
-            .to_string(),
-        );
-        if self.running_disk_scan {
-            status.push_str("...");
-        }
-        status.push('/');
-        status.push_str(&format!(
-            "{:>7}",
-            self.waiting_for_hasher
-                .len()
-                .to_formatted_string(&Locale::en)
-        ));
-
-        status.push_str(&" | hashing ".bold().to_string());
-        {
-            let running_hash_workers =
-                self.num_hash_worker - self.retired_hashers - self.available_hashers.len();
-            let s = format!("{running_hash_workers}/{}", self.num_hash_worker);
-            let s = if running_hash_workers == self.num_hash_worker {
-                s.cyan()
-            } else if running_hash_workers == 0 {
-                s.bright_black()
-            } else {
-                s.normal()
-            }
-            .to_string();
-            status.push('[');
-            status.push_str(&s);
-            status.push(']');
-        }
-
-        status.push_str(&" | passed".bold().to_string());
-        status.push('=');
-        status.push_str(
-            &self
-                .files_passed
-                .to_formatted_string(&Locale::en)
-                .green()
-                .to_string(),
-        );
-        status.push_str(&" failed".bold().to_string());
-        status.push('=');
-        status.push_str(
-            &self
-                .files_flagged
-                .len()
-                .to_formatted_string(&Locale::en)
-                .red()
-                .to_string(),
-        );
+    // ensure we can correctly open the file for reporting
+    let mut writer = open_output(args.output.as_deref()).await?;
 
-        if let Some(path) = &self.disk_pwd {
-            let path = format!("{:?}", path.display());
+    let state = state::StateDir::new(args.state_dir.clone())?;
 
-            let mut offset = 0;
-            let mut width = 0;
-            let mut truncated = false;
-            for c in path.chars() {
-                width += unicode_width::UnicodeWidthChar::width(c).unwrap_or(1);
-                if width < PATH_TRUNCATE {
-                    offset += c.len_utf8();
-                } else {
-                    truncated = true;
-                    break;
-                }
-            }
+    let mut lookup = if let Some(provider) = args.lookup_hashes {
+        let api_key = if let Some(key) = args.lookup_api_key.take() {
+            key
+        } else {
+            let env = provider.api_key_env();
+            std::env::var(env).with_context(|| {
+                anyhow!("Missing api key for {provider:?} (use --lookup-api-key or ${env})")
+            })?
+        };
+        let cache = state
+            .dir(state::Kind::Lookups)
+            .join(format!("{}.json", provider.name()));
+        Some(intel::Lookup::new(
+            args.http_client()?,
+            provider,
+            api_key,
+            args.lookup_rate,
+            Some(cache),
+        )?)
+    } else {
+        None
+    };
 
-            let path = &path[..offset];
-            let truncated = if truncated { "..." } else { "" };
-            status.push_str(&format!(" ({path}{truncated})").bright_black().to_string());
-        }
+    let known_good = knowngood::load(&args.known_good).await?;
 
-        if logs_enabled {
-            info!("{status}");
-        } else {
-            println!("{status}");
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default();
+    let incremental_path = if args.incremental {
+        Some(state::Incremental::path_for(&state, &root))
+    } else {
+        None
+    };
+    let mut resumed = HashMap::new();
+    let mut incremental = if let Some(path) = args.state_file.as_ref().filter(|_| args.resume) {
+        let checkpoint = state::Checkpoint::load(path).await?;
+        if checkpoint.root != root {
+            bail!(
+                "State file {path:?} is from a scan of {:?}, not {root:?}",
+                checkpoint.root
+            );
+        }
+        info!(
+            "Resuming scan, {} files have already been verified",
+            checkpoint.verified.len()
+        );
+        // keep them in the next checkpoint, even if the scan is interrupted before they're seen again
+        resumed = checkpoint.verified.clone();
+        Some(checkpoint.into_incremental())
+    } else if let Some(path) = &incremental_path {
+        let mut previous = state::Incremental::load(path).await?;
+        previous.sample = args.incremental_sample;
+        Some(previous)
+    } else if args.state_file.is_some() {
+        // file timestamps are only taken with a previous state
+        Some(state::Incremental::default())
+    } else {
+        None
+    };
+    let since = match &args.since {
+        _ if args.paranoid => None,
+        Some(state::Since::Timestamp(since)) => Some(*since),
+        Some(state::Since::LastRun) => {
+            let path = state::last_run_path(&state, &root);
+            let last_run = state::load_last_run(&path).await?;
+            if last_run.is_none() {
+                info!("No previous run of {root:?} found, verifying all files");
+            }
+            last_run
+        }
+        None => None,
+    };
+    if let Some(since) = since {
+        warn!("Files with the size and modification time of their package are trusted if they didn't change before {since}, modification times can be forged");
+        incremental.get_or_insert_default().since = Some(since);
+    }
+    if args.paranoid {
+        if let Some(incremental) = &mut incremental {
+            incremental.sample = 1.0;
         }
     }
-}
-
-#[tokio::main]
-async fn run(args: Args) -> Result<()> {
-    let dbpath = args.path.join(&args.dbpath);
 
-    // ensure we can correctly open the file for reporting
-    let mut writer = if let Some(path) = args.output {
-        Box::new(
-            File::create(&path)
-                .await
-                .with_context(|| anyhow!("Failed to open file: {path:?}"))?,
-        ) as Box<dyn AsyncWrite + Unpin>
+    let allowlist = if let Some(path) = &args.allowlist {
+        allowlist::load(&root, path).await?
+    } else if state.allowlist().exists() {
+        allowlist::load(&root, &state.allowlist()).await?
     } else {
-        Box::new(io::stdout()) as Box<dyn AsyncWrite + Unpin>
-    };
+        HashMap::new()
+    }
+    .into_iter()
+    .map(|(path, sha256)| (mounts.resolve(path), sha256))
+    .collect();
 
     // setup scan
     let (event_tx, mut event_rx) = mpsc::unbounded_channel();
-    let (http_tx, http_rx) = mpsc::unbounded_channel();
 
-    fetch::spawn_workers(event_tx.clone(), http_rx, &args.path);
-    pkg::spawn_list_installed(event_tx.clone(), http_tx, dbpath);
+    // all workers are stopped once the scan returns, including on errors
+    let shutdown = CancellationToken::new();
+    let _shutdown = shutdown.clone().drop_guard();
+    {
+        let shutdown = shutdown.clone();
+        tokio::spawn(async move {
+            if signal::ctrl_c().await.is_ok() {
+                warn!("Received Ctrl-C, shutting down");
+                shutdown.cancel();
+            }
+        });
+    }
+
+    let num_hash_worker = args.concurrency.unwrap_or_else(num_cpus::get);
+    let memory_cap = args.max_memory.map(MemoryCap::new);
+    let (walker_pause, fetch_pause) = memory_cap
+        .as_ref()
+        .map(|cap| (cap.walker.clone(), cap.fetch.clone()))
+        .unwrap_or_default();
+    let transferred = fetch::Transferred::default();
+    let mut pkg_tx = None;
+    if let Some(SubCommand::Compare(compare)) = &args.subcommand {
+        let excluded = args
+            .exclude
+            .iter()
+            .map(|p| resolve_target_path(Path::new(""), p))
+            .collect();
+        compare::spawn_trusted_tree(
+            event_tx.clone(),
+            compare.trusted.clone(),
+            root.clone(),
+            excluded,
+            num_hash_worker,
+            shutdown.clone(),
+        );
+    } else if let Some(path) = args
+        .hashes_from
+        .as_ref()
+        .filter(|_| !args.trust_source.contains(&trust::Kind::Manifest))
+    {
+        for (path, sha256, pkg) in manifest::load(path, &root, args.hashes_algorithm).await? {
+            event_tx.send(Event::TrustedFile(path, sha256, pkg))?;
+        }
+        event_tx.send(Event::CompletedListInstalled)?;
+    } else if args.backend == Backend::Dpkg {
+        let snapshot = if args.debian_snapshot {
+            Some((args.http_client()?, args.debian_snapshot_url.clone()))
+        } else {
+            None
+        };
+        dpkg::spawn_list_installed(event_tx.clone(), root.clone(), snapshot, shutdown.clone());
+    } else if args.backend == Backend::Apk {
+        apk::spawn_list_installed(event_tx.clone(), root.clone(), shutdown.clone());
+    } else if args.backend == Backend::Rpm {
+        rpm::spawn_list_installed(event_tx.clone(), root.clone(), shutdown.clone());
+    } else {
+        let (http_tx, http_rx) = mpsc::unbounded_channel();
+        let mut sources = trust::Chain::default();
+        let decompress = fetch::Decompressors::new(
+            args.decompress_workers.unwrap_or_else(num_cpus::get),
+            args.decompress_buffer_size as usize,
+        );
+        let limit_rate = args.limit_rate.map(RateLimit::new);
+        // the pacman cache is checked right before the archive (or last), so only missing packages are downloaded
+        let pkg_cache = |dir: &PathBuf| -> Box<dyn trust::TrustSource> {
+            Box::new(trust::Bundle {
+                dir: dir.clone(),
+                decompress: decompress.clone(),
+            })
+        };
+        let mut pkg_caches = args.pkg_cache.clone();
+        if args.trust_cache_dir {
+            warn!("Using the package cache of the investigated system, it's only as trustworthy as the investigated system");
+            pkg_caches.extend(
+                pacman_conf
+                    .cache_dirs
+                    .iter()
+                    .map(|dir| mounts.resolve(resolve_target_path(&root, dir))),
+            );
+        }
+        for kind in &args.trust_source {
+            if *kind == trust::Kind::Archive {
+                for dir in &pkg_caches {
+                    sources.push(pkg_cache(dir));
+                }
+            }
+            let source: Box<dyn trust::TrustSource> = match kind {
+                trust::Kind::Cache => Box::new(trust::Cache {
+                    dir: args
+                        .mtree_cache
+                        .clone()
+                        .unwrap_or_else(|| state.dir(state::Kind::Mtree)),
+                }),
+                trust::Kind::Archive => Box::new(trust::Archive {
+                    client: args.http_client()?,
+                    urls: args.archive_url.clone(),
+                    decompress: decompress.clone(),
+                    retry: args.http_retry(),
+                    limit_rate: limit_rate.clone(),
+                    transferred: transferred.clone(),
+                    snapshot: match &args.archive_date {
+                        Some(date) => Some(load_snapshot(&args, &pacman_conf, date).await?),
+                        None => None,
+                    },
+                }),
+                trust::Kind::Bundle => {
+                    let Some(dir) = &args.bundle else {
+                        bail!("The bundle trust source requires --bundle");
+                    };
+                    Box::new(trust::Bundle {
+                        dir: dir.clone(),
+                        decompress: decompress.clone(),
+                    })
+                }
+                trust::Kind::LocalDb => {
+                    warn!("Using the .MTREE of the local pacman database, it's only as trustworthy as the investigated system");
+                    Box::new(trust::LocalDb {
+                        dbpath: dbpath.clone(),
+                    })
+                }
+                trust::Kind::Manifest => {
+                    let Some(path) = &args.hashes_from else {
+                        bail!("The manifest trust source requires --hashes-from");
+                    };
+                    Box::new(
+                        trust::Manifest::load(path, dbpath.clone(), args.hashes_algorithm).await?,
+                    )
+                }
+            };
+            sources.push(source);
+        }
+        if !args.trust_source.contains(&trust::Kind::Archive) {
+            for dir in &pkg_caches {
+                sources.push(pkg_cache(dir));
+            }
+        }
+        fetch::spawn_workers(
+            event_tx.clone(),
+            http_rx,
+            &root,
+            Arc::new(sources),
+            args.http_concurrency,
+            fetch_pause,
+            shutdown.clone(),
+        );
+        if args.reads_archive() {
+            // the pacman database is read from the tarball or image
+            pkg_tx = Some(http_tx);
+        } else {
+            pkg::spawn_list_installed(
+                event_tx.clone(),
+                http_tx,
+                dbpath.clone(),
+                selected.clone(),
+                shutdown.clone(),
+            );
+        }
+    }
     let excluded = args
         .exclude
         .iter()
-        .map(PathBuf::as_path)
-        .map(|mut p| {
-            while let Ok(v) = p.strip_prefix("/") {
-                p = v;
+        .map(|p| mounts.resolve(resolve_target_path(&root, p)))
+        .collect::<HashSet<_>>();
+    let excluded_dirs = excluded.clone();
+    let mut filter = filter::PathFilter::new(
+        root.clone(),
+        args.include_glob.clone(),
+        args.exclude_glob.clone(),
+    )
+    .with_mounts(mounts.clone());
+    let mut untracked_targets = Vec::new();
+    if let Some((paths, untracked)) = paths_from {
+        filter = filter.with_only(paths.into_iter().collect());
+        untracked_targets = untracked;
+    }
+    let previous = if args.reads_archive() {
+        None
+    } else {
+        incremental.map(Arc::new)
+    };
+    // hash workers are added while scanning if the concurrency isn't fixed
+    let mut autoscale = args
+        .concurrency
+        .is_none()
+        .then(|| Autoscale::new(1, num_cpus::get() * disk::MAX_HASH_WORKERS_PER_CPU));
+    let spawn_hashers = {
+        // a weak sender, so the channel still closes once all workers are done
+        let event_tx = event_tx.downgrade();
+        let previous = previous.clone();
+        let shutdown = shutdown.clone();
+        move |n| {
+            if let Some(event_tx) = event_tx.upgrade() {
+                disk::spawn_hashers(&event_tx, n, previous.clone(), &shutdown);
             }
-            args.path.join(p)
-        })
-        .collect();
-    let num_hash_worker = args.concurrency.unwrap_or_else(num_cpus::get);
-    disk::spawn_scan(event_tx, args.path, excluded, num_hash_worker);
+        }
+    };
+    let mut targets = None;
+    if let Some(tarball) = &args.input_tar {
+        disk::spawn_hashers(&event_tx, num_hash_worker, None, &shutdown);
+        tarball::spawn_scan(
+            event_tx,
+            tarball.clone(),
+            root.clone(),
+            target_dbpath.clone(),
+            excluded,
+            pkg_tx,
+            shutdown.clone(),
+        );
+    } else if let Some(image) = &args.squashfs {
+        disk::spawn_hashers(&event_tx, num_hash_worker, None, &shutdown);
+        squashfs::spawn_scan(
+            event_tx,
+            image.clone(),
+            root.clone(),
+            target_dbpath.clone(),
+            excluded,
+            pkg_tx,
+            shutdown.clone(),
+        );
+    } else if let Some(image) = &args.ext4 {
+        disk::spawn_hashers(&event_tx, num_hash_worker, None, &shutdown);
+        let (image, partition) = image::parse_arg(image);
+        ext4::spawn_scan(
+            event_tx,
+            image,
+            partition,
+            root.clone(),
+            target_dbpath.clone(),
+            excluded,
+            pkg_tx,
+            shutdown.clone(),
+        );
+    } else if selected.is_some() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        // files without a package are hashed anyway, they're reported as untracked
+        for path in untracked_targets {
+            tx.send(path).ok();
+        }
+        disk::spawn_targeted_scan(
+            event_tx,
+            rx,
+            excluded,
+            filter.clone(),
+            num_hash_worker,
+            previous,
+            shutdown.clone(),
+        );
+        targets = Some(tx);
+    } else {
+        let priority = args
+            .profile
+            .map(|profile| profile.priority_dirs(&root))
+            .unwrap_or_default()
+            .into_iter()
+            .map(|dir| mounts.resolve(dir))
+            .collect();
+        // the mapped directories are walked instead of their mountpoints
+        let mut paths = vec![root.clone()];
+        paths.extend(mounts.host_dirs().map(PathBuf::from));
+        let mut excluded = excluded;
+        excluded.extend(mounts.mountpoints());
+        disk::spawn_scan(
+            event_tx,
+            paths,
+            excluded,
+            filter.clone(),
+            priority,
+            num_hash_worker,
+            args.walk_threads.unwrap_or_else(num_cpus::get),
+            previous,
+            walker_pause,
+            shutdown.clone(),
+        );
+    }
 
-    let mut app = App::new(num_hash_worker);
+    let mut ignores = generated::Ignores::new(!args.no_default_ignores);
+    for path in &args.ignore_file {
+        ignores.load(path).await?;
+    }
+
+    // pacman keeps modified config files on upgrades, they're expected to differ
+    let backup_files = if args.backend == Backend::Pacman && !args.strict && !args.reads_archive() {
+        pkg::list_backup_files(&dbpath)
+            .await
+            .unwrap_or_else(|err| {
+                warn!("Failed to read backup files from pacman database: {err:#}");
+                HashSet::new()
+            })
+            .into_iter()
+            .map(|path| mounts.resolve(root.join(path)))
+            .collect()
+    } else {
+        HashSet::new()
+    };
+
+    let mut app = Scan::new(
+        num_hash_worker,
+        lookup.is_some() || !known_good.is_empty(),
+        allowlist,
+    );
+    app.targets = targets;
+    app.streamed = args.stream.then(Vec::new);
+    app.passed = args.print_passed.then(BTreeMap::new);
+    app.passed_log = args
+        .log_passed
+        .as_deref()
+        .map(passlog::PassLog::create)
+        .transpose()?;
+    app.stamps = resumed;
+    app.report_md5_only = args.backend == Backend::Pacman;
+    app.backup_files = backup_files;
+    app.ignores = ignores;
+    // pacman's NoUpgrade and NoExtract are ignored for a strict scan
+    if !args.strict {
+        app.pacman_conf = pacman_conf.clone();
+    }
+    app.profile = args.profile;
+    app.mounts = mounts;
+    app.show_all_untracked = args.show_all_untracked;
+    app.transferred = transferred;
+    app.started = Some(started_at);
+    // findings that were already written with --stream
+    let mut written = Vec::new();
 
     let mut interval = time::interval(if args.verbose == 0 {
         Duration::from_millis(500)
@@ -292,12 +654,23 @@ async fn run(args: Args) -> Result<()> {
         Duration::from_secs(3)
     });
 
+    let mut checkpoint = time::interval(CHECKPOINT_INTERVAL);
+    checkpoint.reset();
+
+    // the bars would be torn apart by verbose logs, and only clutter redirected output
+    let progress = (args.verbose == 0
+        && !args.progress_json
+        && !args.quiet
+        && std::io::stderr().is_terminal())
+    .then(|| Progress::new(args.use_color()));
     let mut redraw = true;
+    let mut aborted = false;
     loop {
         tokio::select! {
             event = event_rx.recv() => {
                 if let Some(event) = event {
-                    if app.update(event) {
+                    // json progress is only written at the interval
+                    if app.update(event) && !args.progress_json {
                         redraw = true;
                     }
                 } else {
@@ -306,105 +679,1136 @@ async fn run(args: Args) -> Result<()> {
                 }
             }
             _ = interval.tick() => {
+                if let Some(cap) = &memory_cap {
+                    let (pending, trusted) = app.memory_usage();
+                    cap.update(pending, trusted, app.running_disk_scan, !app.trust_complete());
+                }
+                if let Some(autoscale) = &mut autoscale {
+                    let target = autoscale.update(
+                        Instant::now(),
+                        app.files_hashed,
+                        app.active_hashers(),
+                        app.waiting_for_hasher.len(),
+                    );
+                    if let Some(target) = target {
+                        app.scale_hashers(target, &spawn_hashers);
+                    }
+                }
                 redraw = true;
             }
+            _ = checkpoint.tick(), if args.state_file.is_some() => {
+                if let Some(path) = &args.state_file {
+                    app.save_checkpoint(path, &root, started).await?;
+                }
+            }
+            _ = shutdown.cancelled() => {
+                if let Some(path) = &args.state_file {
+                    app.save_checkpoint(path, &root, started).await?;
+                    warn!("Saved progress to {path:?}, continue the scan with --resume");
+                }
+                bail!("Scan was interrupted");
+            }
         }
-
-        while !app.waiting_for_hasher.is_empty() && !app.available_hashers.is_empty() {
-            let hasher = app.available_hashers.pop_front().unwrap();
-            let task = app.waiting_for_hasher.pop_front().unwrap();
-            if hasher.send(task).is_err() {
-                warn!("All hashers have crashed");
-                return Ok(());
+        // write new findings right away, so they survive an interrupted scan
+        let streamed = app.streamed.as_mut().map(mem::take).unwrap_or_default();
+        if !streamed.is_empty() {
+            let mut report = Report {
+                root: root.clone(),
+                ..Default::default()
+            };
+            for mut entry in streamed {
+                let Some(path) = &entry.path else {
+                    report.entries.push(entry);
+                    continue;
+                };
+                if entry.kind == "WRONG SHA256" || entry.kind == "WRONG SIZE" {
+                    match app.classify(&root, path) {
+                        // config files and expected mutations are reported at the end
+                        scanner::Class::Config | scanner::Class::Generated(_) => continue,
+                        scanner::Class::Sensitive => {
+                            entry.kind = format!("SENSITIVE {}", entry.kind);
+                        }
+                        scanner::Class::Modified => (),
+                    }
+                }
+                report.entries.push(entry);
             }
+            report.write(&mut writer, args.format).await?;
+            written.append(&mut report.entries);
         }
 
-        while !app.available_hashers.is_empty()
-            && app.waiting_for_hasher.is_empty()
-            && !app.running_disk_scan
-            && app.completed_pkgs == app.total_pkgs
-        {
-            app.available_hashers.pop_front();
-            app.retired_hashers += 1;
+        // all trusted files have been sent to the targeted scan
+        if app.trust_complete() {
+            app.targets = None;
         }
 
-        if redraw {
-            app.redraw(args.verbose > 0);
+        if args.fail_fast && app.has_flagged(&root) {
+            warn!("Found a modified file, stopping the scan");
+            shutdown.cancel();
+            aborted = true;
+            break;
+        }
+
+        app.dispatch_hashers();
+
+        if redraw && !args.quiet {
+            print_status(&app, progress.as_ref(), args.progress_json);
             redraw = false;
         }
     }
 
+    app.complete_phase("hashing");
+
     // redraw one final time
-    app.redraw(args.verbose > 0);
+    if !args.quiet {
+        print_status(&app, progress.as_ref(), args.progress_json);
+    }
+    if let Some(progress) = &progress {
+        progress.finish();
+    }
 
-    // write report
-    let mut buf = Vec::new();
-    for path in app.waiting_for_data {
-        writeln!(buf, "[NO SHA256] {path:?}")?;
-        writer
-            .write_all(&buf)
-            .await
-            .context("Failed to write report")?;
-        buf.clear();
+    // the state file is only needed to continue an incomplete scan
+    if let Some(path) = &args.state_file {
+        if aborted {
+            app.save_checkpoint(path, &root, started).await?;
+        } else if let Err(err) = fs::remove_file(path) {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to remove state file {path:?}: {err:#}");
+            }
+        }
     }
-    for err in app.disk_errors {
-        writeln!(buf, "[DISK ERROR] {err:#}")?;
-        writer
-            .write_all(&buf)
+
+    // trusted hashes that weren't read from a package (like a manifest) are looked up
+    // in the pacman database for `pacman -Qkk` lines and the remediation list
+    let needs_owners = (args.format == Format::PacmanQkk || args.remediation_out.is_some())
+        && app.trusted_owners.is_empty();
+    let owners = if needs_owners && args.backend == Backend::Pacman {
+        pkg::list_file_owners(&dbpath).await.unwrap_or_else(|err| {
+            warn!("Failed to read file owners from pacman database: {err:#}");
+            HashMap::new()
+        })
+    } else {
+        if needs_owners {
+            warn!("Package owners are only known with the pacman backend");
+        }
+        HashMap::new()
+    };
+    let trusted_owners = mem::take(&mut app.trusted_owners);
+    let owner = |path: &Path| {
+        trusted_owners
+            .get(path)
+            .map(|pkg| &**pkg)
+            .or_else(|| owners.get(path.strip_prefix(&root).ok()?))
+    };
+
+    if aborted {
+        // the scan is incomplete, only report what has been flagged so far
+        app.apply_allowlist();
+        app.classify_findings(&root);
+        let mut report = Report {
+            root: root.clone(),
+            ..Default::default()
+        };
+        // with --stream the flagged files have already been written
+        let files_flagged = app.files_flagged.iter().filter(|_| app.streamed.is_none());
+        for (path, sha256) in files_flagged {
+            let mut entry = Entry::path("WRONG SHA256", path);
+            entry.sha256 = Some(sha256.clone());
+            entry.expected = app.trusted_hashes.get(path);
+            entry.set_package(owner(path));
+            report.entries.push(entry);
+        }
+        let files_wrong_size = app
+            .files_wrong_size
+            .iter()
+            .filter(|_| app.streamed.is_none());
+        for (path, (expected, actual)) in files_wrong_size {
+            let mut entry = scanner::wrong_size(path, *expected, *actual);
+            entry.expected = app.trusted_hashes.get(path);
+            entry.set_package(owner(path));
+            report.entries.push(entry);
+        }
+        if let Some(path) = &args.remediation_out {
+            let flagged = app.files_flagged.keys().chain(app.files_wrong_size.keys());
+            pkg::write_remediation(path, flagged.filter_map(|p| owner(p))).await?;
+        }
+        if !args.no_summary {
+            report.summary = Some(summary(&app, &written, &report.entries));
+        }
+        report.write(&mut writer, args.format).await?;
+        let status = if app.files_flagged.is_empty() && app.files_wrong_size.is_empty() {
+            EXIT_ERROR
+        } else {
+            EXIT_FLAGGED
+        };
+        return Ok(ExitCode::from(status));
+    }
+
+    // the setuid/setgid bits that packages call for, before the metadata is verified
+    let mut expected_privileges = privileges::Expected::default();
+    if args.check_privileges {
+        expected_privileges.modes = app
+            .trusted_metadata
+            .iter()
+            .filter_map(|(path, metadata)| {
+                let mode = metadata.mode? & privileges::PRIVILEGED_MODE;
+                (mode != 0).then(|| (path.clone(), mode))
+            })
+            .collect();
+    }
+
+    // permissions, ownership and symlink targets are only known for a mounted filesystem
+    if !args.reads_archive() && !app.trusted_metadata.is_empty() {
+        info!("Verifying metadata of {} files", app.trusted_metadata.len());
+        let mut trusted = mem::take(&mut app.trusted_metadata);
+        trusted.retain(|path, _| filter.is_included(path));
+        let excluded = excluded_dirs.clone();
+        let events =
+            task::spawn_blocking(move || disk::verify_metadata(trusted, &excluded)).await?;
+        for event in events {
+            app.update(event);
+        }
+    }
+
+    let files_missing = app.files_missing(&excluded_dirs, &filter);
+    // pacman doesn't extract files matching NoExtract, like trimmed locales and docs
+    let (files_no_extract, files_missing) = files_missing
+        .into_iter()
+        .partition::<Vec<_>, _>(|path| app.is_no_extract(&root, path));
+
+    if args.export_hashes.is_some() || args.export_aide.is_some() {
+        // exports are sha256 only, files that were verified already had theirs computed
+        let exported = app
+            .trusted_hashes
+            .iter()
+            .filter(|(path, hash)| {
+                let is_sha256 = digest::Checksum::parse(hash)
+                    .is_ok_and(|checksum| checksum.algorithm == digest::Algorithm::Sha256);
+                if !is_sha256 {
+                    debug!("Not exporting {path:?}, there's no sha256 for it");
+                }
+                is_sha256
+            })
+            .collect::<HashMap<_, _>>();
+
+        if let Some(path) = &args.export_hashes {
+            info!("Exporting trusted hashes to {path:?}");
+            manifest::export(path, &root, &exported, &trusted_owners).await?;
+        }
+        if let Some(path) = &args.export_aide {
+            info!("Exporting aide database to {path:?}");
+            let modified = app
+                .files_flagged
+                .keys()
+                .chain(app.files_wrong_size.keys())
+                .chain(app.files_wrong_metadata.keys())
+                .chain(app.files_type_changed.keys())
+                .map(PathBuf::as_path)
+                .collect::<BTreeSet<_>>();
+            aide::export(path, &root, &exported, &modified).await?;
+        }
+    }
+
+    if let Some(path) = &incremental_path {
+        info!("Saving incremental state to {path:?}");
+        let state = state::Incremental {
+            started,
+            files: mem::take(&mut app.stamps),
+            ..Default::default()
+        };
+        state.save(path).await?;
+    }
+    if let Some(log) = app.passed_log.take() {
+        log.finish()?;
+    }
+    if args.since.is_some() {
+        state::save_last_run(&state::last_run_path(&state, &root), started).await?;
+    }
+
+    let mut boot_findings = Vec::new();
+    if args.verify_boot {
+        let boot = args.boot_dir.clone().unwrap_or_else(|| root.join("boot"));
+        info!("Verifying boot files in {boot:?}");
+        let report = task::block_in_place(|| boot::verify(&root, &boot, &app.trusted_hashes))?;
+        for path in &report.covered {
+            app.waiting_for_data.remove(path);
+        }
+        boot_findings.extend(report.findings);
+    }
+    if args.verify_efi {
+        let esps = if args.esp.is_empty() {
+            ["efi", "boot"]
+                .into_iter()
+                .map(|p| root.join(p))
+                .filter(|p| p.is_dir())
+                .collect()
+        } else {
+            args.esp.clone()
+        };
+        info!("Verifying EFI binaries in {esps:?}");
+        let report = task::block_in_place(|| boot::verify_efi(&esps, &app.trusted_hashes));
+        for path in &report.covered {
+            app.waiting_for_data.remove(path);
+        }
+        boot_findings.extend(report.findings);
+    }
+
+    let mut module_findings = Vec::new();
+    if args.check_modules {
+        info!("Verifying kernel module trees");
+        let report = task::block_in_place(|| kmod::check(&root, &app.trusted_hashes))?;
+        for path in &report.covered {
+            app.waiting_for_data.remove(path);
+        }
+        module_findings.extend(report.findings);
+    }
+
+    // files with a different size weren't read during the scan, but the features looking
+    // into modified files need them too
+    let mut wrong_size_hashes = BTreeMap::new();
+    if args.check_systemd || args.elf_diff || args.cve || lookup.is_some() {
+        for path in app.files_wrong_size.keys() {
+            match disk::hash_file(path).await {
+                Ok(sha256) => {
+                    wrong_size_hashes.insert(path.clone(), hex::encode(sha256));
+                }
+                Err(err) => warn!("Failed to hash file with a different size {path:?}: {err:#}"),
+            }
+        }
+    }
+    let modified = |app: &Scan| {
+        let wrong_size = wrong_size_hashes
+            .iter()
+            .filter(|(path, _)| app.files_wrong_size.contains_key(*path));
+        app.files_flagged
+            .iter()
+            .chain(wrong_size)
+            .map(|(path, sha256)| (path.clone(), sha256.clone()))
+            .collect::<BTreeMap<_, _>>()
+    };
+
+    let mut systemd_findings = Vec::new();
+    if args.check_systemd {
+        info!("Verifying systemd units and drop-ins");
+        let modified = modified(&app);
+        let report = task::block_in_place(|| systemd::check(&root, &app.trusted_hashes, &modified));
+        for path in &report.covered {
+            app.waiting_for_data.remove(path);
+            app.files_flagged.remove(path);
+            app.files_wrong_size.remove(path);
+        }
+        systemd_findings.extend(report.findings);
+    }
+
+    let ld_findings = if args.check_ld {
+        info!("Checking dynamic linker configuration");
+        task::block_in_place(|| ldso::check(&root, &app.trusted_hashes))
+    } else {
+        Vec::new()
+    };
+
+    let privilege_findings = if args.check_privileges {
+        info!("Checking setuid/setgid files and file capabilities");
+        expected_privileges.capabilities = privileges::read_install_scripts(&root, &dbpath)
+            .into_iter()
+            .map(|path| app.mounts.resolve(path))
+            .collect();
+        let mut paths = vec![root.clone()];
+        paths.extend(app.mounts.host_dirs().map(PathBuf::from));
+        let mut excluded = excluded_dirs.clone();
+        excluded.extend(app.mounts.mountpoints());
+        task::block_in_place(|| {
+            privileges::check(
+                &paths,
+                &excluded,
+                &filter,
+                &app.trusted_hashes,
+                &expected_privileges,
+            )
+        })
+    } else {
+        Vec::new()
+    };
+
+    app.apply_allowlist();
+
+    // downgrade untracked files that are in a known-good hash set
+    let files_known_good = app
+        .waiting_for_data
+        .iter()
+        .filter(|path| {
+            app.untracked_hashes
+                .get(*path)
+                .is_some_and(|sha256| known_good.contains(sha256))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    for path in &files_known_good {
+        app.waiting_for_data.remove(path);
+    }
+
+    // move files that are expected to change after install into a low-severity bucket, and
+    // files that pacman doesn't overwrite (NoUpgrade) to the config files
+    app.classify_findings(&root);
+    if args.hide_generated {
+        app.files_generated.clear();
+    }
+
+    // untracked files outside of the package-managed directories (like /home) are only counted
+    let mut untracked_elsewhere = 0;
+    let files_untracked = app
+        .waiting_for_data
+        .iter()
+        .filter(|path| {
+            let shown = args.show_all_untracked
+                || app.is_package_managed(&root, path)
+                || app.is_sensitive(&root, path);
+            if !shown {
+                untracked_elsewhere += 1;
+            }
+            shown
+        })
+        .collect::<Vec<_>>();
+
+    // compare modified binaries with the originals from their packages
+    let elf_diffs = if args.elf_diff {
+        elf::diff_flagged(
+            &args.http_client()?,
+            &root,
+            &dbpath,
+            &args.archive_url,
+            &modified(&app),
+        )
+        .await?
+    } else {
+        HashMap::new()
+    };
+
+    // compare the files with the content of their packages, instead of only their .MTREE
+    let deep = if args.deep_compare {
+        let mut pkgs = BTreeMap::new();
+        for pkg in trusted_owners.values() {
+            pkgs.entry((&pkg.name, &pkg.version))
+                .or_insert_with(|| (**pkg).clone());
+        }
+        let pkgs = pkgs.into_values().collect::<Vec<_>>();
+        let dirs = args
+            .bundle
+            .iter()
+            .chain(&args.pkg_cache)
+            .cloned()
+            .collect::<Vec<_>>();
+        info!("Comparing the content of {} packages", pkgs.len());
+        deep::compare(
+            &args.http_client()?,
+            &dirs,
+            &args.archive_url,
+            &root,
+            &pkgs,
+            &app.trusted_hashes,
+            args.http_concurrency,
+        )
+        .await
+    } else {
+        deep::Comparison::default()
+    };
+
+    // show what changed in modified text files, like unit files and scripts
+    let text_diffs = if args.text_diff || args.diff_dir.is_some() {
+        let dirs = args
+            .bundle
+            .iter()
+            .chain(&args.pkg_cache)
+            .cloned()
+            .collect::<Vec<_>>();
+        let text_diffs = textdiff::diff_flagged(
+            &args.http_client()?,
+            &dirs,
+            &args.archive_url,
+            &root,
+            app.files_flagged
+                .keys()
+                .chain(app.files_wrong_size.keys())
+                .map(PathBuf::as_path),
+            owner,
+            args.text_diff_max_size,
+        )
+        .await;
+        if let Some(dir) = &args.diff_dir {
+            fs::create_dir_all(dir)
+                .with_context(|| anyhow!("Failed to create directory: {dir:?}"))?;
+            for (path, diff) in &text_diffs {
+                let rel = path.strip_prefix(&root).unwrap_or(path);
+                let file = dir.join(textdiff::diff_file_name(rel));
+                fs::write(&file, diff)
+                    .with_context(|| anyhow!("Failed to write diff: {file:?}"))?;
+            }
+        }
+        text_diffs
+    } else {
+        HashMap::new()
+    };
+
+    // open vulnerabilities of the packages owning flagged files
+    let cves = if args.cve {
+        advisory::annotate(&args.http_client()?, &root, &dbpath, &modified(&app))
             .await
-            .context("Failed to write report")?;
-        buf.clear();
+            .unwrap_or_else(|err| {
+                warn!("Failed to correlate CVEs: {err:#}");
+                HashMap::new()
+            })
+    } else {
+        HashMap::new()
+    };
+
+    // query threat intel for files we couldn't verify
+    let annotations = if let Some(lookup) = &mut lookup {
+        let untracked = files_untracked
+            .iter()
+            .filter_map(|path| {
+                let sha256 = app.untracked_hashes.get(*path)?;
+                Some(((*path).clone(), sha256.clone()))
+            })
+            .collect::<Vec<_>>();
+        let (files, skipped) =
+            task::block_in_place(|| intel::select(&modified(&app), untracked, args.lookup_limit));
+        if skipped > 0 {
+            warn!(
+                "Not looking up {skipped} more files, the limit is {} (--lookup-limit)",
+                args.lookup_limit
+            );
+        }
+        info!(
+            "Looking up {} hashes, this takes up to {} minutes at {} lookups per minute",
+            files.len(),
+            files.len().div_ceil(args.lookup_rate.max(1) as usize),
+            args.lookup_rate
+        );
+        lookup.annotate(&files).await
+    } else {
+        HashMap::new()
+    };
+
+    // findings in high-value locations are reported first, with their own tag
+    let mut files_untracked = files_untracked;
+    files_untracked.sort_by_key(|path| !app.is_sensitive(&root, path));
+    let mut files_flagged = app.files_flagged.keys().collect::<Vec<_>>();
+    files_flagged.sort_by_key(|path| !app.is_sensitive(&root, path));
+    let mut files_wrong_size = app.files_wrong_size.keys().collect::<Vec<_>>();
+    files_wrong_size.sort_by_key(|path| !app.is_sensitive(&root, path));
+
+    // find the snapshot interval the files first changed in
+    let timeline = if args.roots.len() > 1 {
+        info!(
+            "Tracing flagged files through {} snapshots",
+            args.roots.len()
+        );
+        let paths = files_untracked
+            .iter()
+            .copied()
+            .chain(files_flagged.iter().copied())
+            .chain(files_wrong_size.iter().copied())
+            .collect::<Vec<_>>();
+        timeline::trace(&root, &args.roots, &app.trusted_hashes, &paths).await
+    } else {
+        HashMap::new()
+    };
+
+    if let Some(dir) = &args.quarantine {
+        info!("Copying flagged files into quarantine: {dir:?}");
+        let operator = args.operator.clone().unwrap_or_else(custody::operator);
+        let paths = files_untracked
+            .iter()
+            .copied()
+            .chain(files_flagged.iter().copied())
+            .chain(files_wrong_size.iter().copied())
+            .collect::<Vec<_>>();
+        task::block_in_place(|| custody::quarantine(dir, operator, &paths))?;
+    }
+
+    // replace modified and missing files with the files of their packages, after they were
+    // quarantined
+    let restored = if args.restore {
+        let dirs = args
+            .bundle
+            .iter()
+            .chain(&args.pkg_cache)
+            .cloned()
+            .collect::<Vec<_>>();
+        let paths = files_flagged
+            .iter()
+            .chain(&files_wrong_size)
+            .copied()
+            .chain(&files_missing)
+            .map(PathBuf::as_path);
+        restore::restore(
+            &args.http_client()?,
+            &dirs,
+            &args.archive_url,
+            &root,
+            paths,
+            owner,
+            &app.trusted_hashes,
+            args.dry_run,
+            |path, pkg| args.yes || task::block_in_place(|| restore::prompt(path, pkg)),
+        )
+        .await
+    } else {
+        Vec::new()
+    };
+
+    let fatal_errors = app
+        .disk_errors
+        .iter()
+        .filter(|err| args.fail_on_errors.contains(&err.class()))
+        .count();
+    let status = if fatal_errors > 0 {
+        error!("Failed to read {fatal_errors} files with errors selected by --fail-on-errors");
+        EXIT_ERROR
+    } else if !files_flagged.is_empty()
+        || !files_wrong_size.is_empty()
+        || !files_untracked.is_empty()
+        || !files_missing.is_empty()
+        || !app.files_wrong_metadata.is_empty()
+        || !app.files_type_changed.is_empty()
+        || !boot_findings.is_empty()
+        || !module_findings.is_empty()
+        || !systemd_findings.is_empty()
+        || !ld_findings.is_empty()
+        || !privilege_findings.is_empty()
+        || !deep.findings.is_empty()
+    {
+        EXIT_FLAGGED
+    } else if !app.disk_errors.is_empty() {
+        EXIT_DISK_ERRORS
+    } else if !app.untrusted_pkgs.is_empty() {
+        EXIT_UNTRUSTED_PKGS
+    } else {
+        0
+    };
+
+    // write report
+    let mut report = Report {
+        root: root.clone(),
+        ..Default::default()
+    };
+    app.untrusted_pkgs
+        .sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+    for pkg in &app.untrusted_pkgs {
+        let mut entry = Entry::new("NO TRUSTED SOURCE", None)
+            .detail(format!("{}-{}-{}", pkg.name, pkg.version, pkg.arch));
+        entry.set_package(Some(pkg));
+        report.entries.push(entry);
+    }
+    for path in files_known_good {
+        report.entries.push(Entry::path("KNOWN GOOD", path));
+    }
+    for (path, sha256) in &app.files_modified_config {
+        let mut entry = Entry::path("MODIFIED CONFIG", path);
+        entry.sha256 = Some(sha256.clone());
+        entry.expected = app.trusted_hashes.get(path);
+        entry.set_package(owner(path));
+        report.entries.push(entry);
+    }
+    for (path, (expected, actual)) in &app.files_no_upgrade_size {
+        let mut entry =
+            Entry::path("MODIFIED CONFIG", path).detail(format!("size: {expected} -> {actual}"));
+        entry.expected = app.trusted_hashes.get(path);
+        entry.set_package(owner(path));
+        report.entries.push(entry);
+    }
+    for path in &files_no_extract {
+        let mut entry = Entry::path("NO EXTRACT", path);
+        entry.set_package(owner(path));
+        report.entries.push(entry);
+    }
+    for path in &app.files_md5_only {
+        let mut entry = Entry::path("MD5 ONLY", path);
+        entry.set_package(owner(path));
+        report.entries.push(entry);
+    }
+    for (path, category) in &app.files_generated {
+        report
+            .entries
+            .push(Entry::path("EXPECTED MUTATION", path).detail(*category));
+    }
+    for path in files_untracked {
+        let kind = if app.is_package_managed(&root, path) {
+            "UNTRACKED"
+        } else {
+            "NO SHA256"
+        };
+        let mut entry = Entry::path(app.tag(&root, path, kind), path);
+        entry.sha256 = app.untracked_hashes.get(path).cloned();
+        entry.details.extend(timeline.get(path).cloned());
+        entry.details.extend(
+            annotations
+                .get(path)
+                .map(|annotation| annotation.to_string()),
+        );
+        report.entries.push(entry);
+    }
+    if untracked_elsewhere > 0 {
+        report
+            .entries
+            .push(scanner::untracked_elsewhere(untracked_elsewhere));
+    }
+    // group errors by their class
+    app.disk_errors
+        .sort_by(|a, b| (a.class(), a.path()).cmp(&(b.class(), b.path())));
+    let mut error_counts = BTreeMap::<_, usize>::new();
+    for err in &app.disk_errors {
+        *error_counts.entry(err.class()).or_default() += 1;
+        let mut entry = Entry::new(err.kind(), err.path().map(Path::to_owned));
+        entry.set_package(err.path().and_then(owner));
+        entry.details.extend(err.detail());
+        // with --stream the errors have already been written
+        if app.streamed.is_none() {
+            report.entries.push(entry);
+        }
+    }
+    for (class, count) in error_counts {
+        if class.is_expected() {
+            info!("Encountered {count} errors of class {class:?}");
+        } else {
+            warn!("Encountered {count} errors of class {class:?}");
+        }
+    }
+    // with --diff-dir the finding refers to the file the diff was written to
+    let attach_diff = |entry: &mut Entry, path: &Path| match (&args.diff_dir, text_diffs.get(path))
+    {
+        (Some(dir), Some(_)) => {
+            let rel = path.strip_prefix(&root).unwrap_or(path);
+            let file = dir.join(textdiff::diff_file_name(rel));
+            entry.details.push(format!("diff: {}", file.display()));
+        }
+        (None, diff) => entry.diff = diff.cloned(),
+        (Some(_), None) => (),
+    };
+    for path in files_flagged {
+        let mut entry = Entry::path(app.tag(&root, path, "WRONG SHA256"), path);
+        entry.sha256 = app.files_flagged.get(path).cloned();
+        entry.expected = app.trusted_hashes.get(path);
+        entry.set_package(owner(path));
+        entry.details.extend(timeline.get(path).cloned());
+        entry
+            .details
+            .extend(elf_diffs.get(path).map(|diff| diff.to_string()));
+        entry
+            .details
+            .extend(deep.diffs.get(path).into_iter().flatten().cloned());
+        attach_diff(&mut entry, path);
+        entry
+            .details
+            .extend(cves.get(path).map(|cves| format!("cve: {cves}")));
+        entry.details.extend(
+            annotations
+                .get(path)
+                .map(|annotation| annotation.to_string()),
+        );
+        // with --stream the file is only repeated if the analysis added details
+        if app.streamed.is_some() && entry.details.is_empty() && entry.diff.is_none() {
+            continue;
+        }
+        report.entries.push(entry);
     }
-    for path in app.files_flagged {
-        writeln!(buf, "[WRONG SHA256] {path:?}")?;
+    // with --stream these have already been written
+    for path in files_wrong_size.iter().filter(|_| app.streamed.is_none()) {
+        let (expected, actual) = app.files_wrong_size[*path];
+        let mut entry = scanner::wrong_size(path, expected, actual);
+        entry.kind = app.tag(&root, path, &entry.kind);
+        entry.expected = app.trusted_hashes.get(path);
+        entry.set_package(owner(path));
+        entry
+            .details
+            .extend(deep.diffs.get(*path).into_iter().flatten().cloned());
+        attach_diff(&mut entry, path);
+        report.entries.push(entry);
+    }
+    for path in &files_missing {
+        let mut entry = Entry::path(app.tag(&root, path, "MISSING FILE"), path);
+        entry.set_package(owner(path));
+        report.entries.push(entry);
+    }
+    for (path, diff) in &app.files_type_changed {
+        let mut entry = Entry::path(app.tag(&root, path, "TYPE CHANGED"), path).detail(diff);
+        entry.set_package(owner(path));
+        report.entries.push(entry);
+    }
+    for (path, diff) in &app.files_wrong_metadata {
+        let mut entry = Entry::path("WRONG METADATA", path).detail(diff);
+        entry.set_package(owner(path));
+        report.entries.push(entry);
+    }
+    report
+        .entries
+        .extend(boot_findings.into_iter().map(Entry::from));
+    report
+        .entries
+        .extend(module_findings.into_iter().map(Entry::from));
+    report
+        .entries
+        .extend(systemd_findings.into_iter().map(Entry::from));
+    report
+        .entries
+        .extend(ld_findings.into_iter().map(Entry::from));
+    report
+        .entries
+        .extend(privilege_findings.into_iter().map(Entry::from));
+    for finding in deep.findings {
+        let mut entry = Entry::from(finding);
+        if entry.package.is_none() {
+            entry.set_package(entry.path.as_deref().and_then(owner));
+        }
+        report.entries.push(entry);
+    }
+    for (path, method) in app.passed.iter().flatten() {
+        let mut entry = Entry::path("PASSED", path);
+        entry.expected = app.trusted_hashes.get(path);
+        if *method == passlog::Method::Unchanged {
+            entry = entry.detail("not read, unchanged since a previous run or --since");
+        } else {
+            entry.sha256 = entry.expected.clone().filter(|hash| !hash.contains(':'));
+        }
+        entry.set_package(owner(path));
+        report.entries.push(entry);
+    }
+    report.entries.extend(restored.into_iter().map(Entry::from));
+    if let Some(path) = &args.remediation_out {
+        let flagged = app.files_flagged.keys().chain(app.files_wrong_size.keys());
+        pkg::write_remediation(path, flagged.filter_map(|p| owner(p))).await?;
+    }
+    app.complete_phase("checks");
+    // the summary covers all findings of the scan, even with --diff-last
+    if !args.no_summary {
+        report.summary = Some(summary(&app, &written, &report.entries));
+    }
+    if let Some(path) = &args.db {
+        let packages = trusted_owners
+            .values()
+            .map(|pkg| &**pkg)
+            .chain(&app.untrusted_pkgs)
+            .map(|pkg| (pkg.name.clone(), pkg.version.clone()))
+            .collect::<BTreeSet<_>>();
+        task::block_in_place(|| -> Result<()> {
+            let mut history = History::open(path)?;
+            let previous = history.last_run(&root)?;
+            history.store(&report, &packages)?;
+            if !args.diff_last {
+                return Ok(());
+            }
+            if let Some(previous) = previous {
+                info!(
+                    "Comparing to previous run #{} with {} findings",
+                    previous.id,
+                    previous.entries.len()
+                );
+                report.entries =
+                    history::new_findings(&previous.entries, mem::take(&mut report.entries));
+            } else {
+                info!("No previous run of {root:?} in the database, reporting all findings");
+            }
+            Ok(())
+        })?;
+    }
+    report.write(&mut writer, args.format).await?;
+
+    Ok(ExitCode::from(status))
+}
+
+/// Load the repository databases of `--archive-date`, for the repositories in pacman.conf
+async fn load_snapshot(
+    args: &Args,
+    pacman_conf: &pacman_conf::PacmanConf,
+    date: &str,
+) -> Result<repodb::Index> {
+    if args.archive_url.iter().any(|url| url.contains('{')) {
+        bail!("--archive-date needs the base url of an archive, not a url template");
+    }
+    let repos = if pacman_conf.repos.is_empty() {
+        repodb::DEFAULT_REPOS
+            .iter()
+            .map(|s| s.to_string())
+            .collect()
+    } else {
+        pacman_conf
+            .repos
+            .iter()
+            .map(|repo| repo.name.clone())
+            .collect::<Vec<_>>()
+    };
+    let arch = pacman_conf
+        .architecture
+        .iter()
+        .find(|arch| *arch != "auto")
+        .map(String::as_str)
+        .unwrap_or(repodb::DEFAULT_ARCH);
+    repodb::load(
+        &args.http_client()?,
+        &args.archive_url,
+        date,
+        &repos,
+        arch,
+        &args.http_retry(),
+    )
+    .await
+}
+
+/// The files listed for `--paths-from` (relative to the root), read from stdin for `-`
+fn read_paths(list: &Path, root: &Path) -> Result<Vec<PathBuf>> {
+    let list = if list.as_os_str() == "-" {
+        std::io::read_to_string(std::io::stdin()).context("Failed to read paths from stdin")?
+    } else {
+        fs::read_to_string(list).with_context(|| anyhow!("Failed to read file: {list:?}"))?
+    };
+    let paths = list
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let path = resolve_merged_usr(root, line);
+            path.strip_prefix(root)
+                .map(Path::to_path_buf)
+                .unwrap_or(path)
+        })
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        bail!("No paths listed in --paths-from");
+    }
+    Ok(paths)
+}
+
+/// The packages selected with `--pkg` and `--pkg-file`, `None` if all packages are verified
+fn selected_pkgs(args: &Args) -> Result<Option<HashSet<String>>> {
+    let mut selected = args.pkgs.iter().cloned().collect::<HashSet<_>>();
+    if let Some(path) = &args.pkg_file {
+        let list =
+            fs::read_to_string(path).with_context(|| anyhow!("Failed to read file: {path:?}"))?;
+        selected.extend(pkg::parse_names(&list));
+    }
+    if selected.is_empty() && args.pkg_file.is_some() {
+        bail!("No packages selected in --pkg-file");
+    }
+    if selected.is_empty() {
+        return Ok(None);
+    }
+    if args.backend != Backend::Pacman {
+        bail!("Selecting packages is only supported with the pacman backend");
+    }
+    Ok(Some(selected))
+}
+
+/// Queue the installed packages (or those selected with `--pkg`), the progress events
+/// are discarded
+async fn spawn_installed(args: &mut Args) -> Result<mpsc::UnboundedReceiver<pkg::Package>> {
+    if args.dbpath.is_none() {
+        args.dbpath = pacman_conf::load(args.root(), &args.pacman_conf)
+            .await?
+            .dbpath;
+    }
+    let dbpath = resolve_target_path(args.root(), args.dbpath());
+
+    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+    let (http_tx, http_rx) = mpsc::unbounded_channel();
+    // the listing stops if nobody receives its events
+    tokio::spawn(async move { while event_rx.recv().await.is_some() {} });
+
+    pkg::spawn_list_installed(
+        event_tx,
+        http_tx,
+        dbpath,
+        selected_pkgs(args)?,
+        CancellationToken::new(),
+    );
+    Ok(http_rx)
+}
+
+#[tokio::main]
+async fn list_pkgs(mut args: Args) -> Result<ExitCode> {
+    if !matches!(args.format, Format::Text | Format::Json | Format::Jsonl) {
+        bail!(
+            "--list-pkgs can't be used with --format {}",
+            args.format.to_possible_value().unwrap().get_name()
+        );
+    }
+    let mut http_rx = spawn_installed(&mut args).await?;
+
+    let client = args.http_client()?;
+    let mut writer = open_output(args.output.as_deref()).await?;
+    // the packages are checked concurrently, but listed in the order of the database
+    let mut located = futures_util::stream::poll_fn(|cx| http_rx.poll_recv(cx))
+        .map(|pkg: pkg::Package| {
+            let client = &client;
+            let mirrors = &args.archive_url;
+            async move {
+                let result = fetch::locate_package(client, mirrors, &pkg).await;
+                (pkg, result)
+            }
+        })
+        .buffered(args.http_concurrency.max(1));
+
+    let mut listed = Vec::new();
+    let mut missing = 0;
+    while let Some((pkg, result)) = located.next().await {
+        let located = match result {
+            Ok(located) => located,
+            Err(err) => {
+                warn!(
+                    "Failed to determine url for {}-{}: {err:#}",
+                    pkg.name, pkg.version
+                );
+                fetch::PackageUrl {
+                    name: pkg.name,
+                    version: pkg.version,
+                    arch: pkg.arch,
+                    url: None,
+                    exists: false,
+                }
+            }
+        };
+        if !located.exists {
+            warn!(
+                "Failed to find {}-{} on any mirror",
+                located.name, located.version
+            );
+            missing += 1;
+        }
+        let line = match args.format {
+            Format::Text => located.url.clone().filter(|_| located.exists),
+            Format::Jsonl => Some(serde_json::to_string(&located)?),
+            _ => {
+                listed.push(located);
+                None
+            }
+        };
+        if let Some(mut line) = line {
+            line.push('\n');
+            writer
+                .write_all(line.as_bytes())
+                .await
+                .context("Failed to write package list")?;
+        }
+    }
+    if args.format == Format::Json {
+        let mut buf = serde_json::to_vec_pretty(&listed)?;
+        buf.push(b'\n');
         writer
             .write_all(&buf)
             .await
-            .context("Failed to write report")?;
-        buf.clear();
+            .context("Failed to write package list")?;
     }
+    writer.flush().await?;
 
-    Ok(())
+    if missing > 0 {
+        error!("Failed to find {missing} packages");
+        Ok(ExitCode::from(EXIT_UNTRUSTED_PKGS))
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
 }
 
 #[tokio::main]
-async fn list_pkgs(args: Args) -> Result<()> {
-    let dbpath = args.path.join(&args.dbpath);
+async fn download_pkgs(mut args: Args, dir: PathBuf) -> Result<ExitCode> {
+    fs::create_dir_all(&dir).with_context(|| anyhow!("Failed to create directory: {dir:?}"))?;
+    let mut http_rx = spawn_installed(&mut args).await?;
 
-    let (event_tx, mut event_rx) = mpsc::unbounded_channel();
-    let (http_tx, mut http_rx) = mpsc::unbounded_channel();
+    let client = args.http_client()?;
+    let retry = args.http_retry();
+    let keyring = args.verify_sigs.then_some(args.sig_keyring.as_path());
+    let mut results = futures_util::stream::poll_fn(|cx| http_rx.poll_recv(cx))
+        .map(|pkg: pkg::Package| {
+            let (client, mirrors, dir, retry) = (&client, &args.archive_url, &dir, &retry);
+            async move {
+                let result = download::download(client, mirrors, dir, &pkg, retry, keyring).await;
+                (pkg, result)
+            }
+        })
+        .buffer_unordered(args.http_concurrency.max(1));
 
-    pkg::spawn_list_installed(event_tx, http_tx, dbpath);
+    let mut downloaded = Vec::new();
+    let mut missing = 0;
+    let mut failed = 0;
+    while let Some((pkg, result)) = results.next().await {
+        match result {
+            Ok(Some(pkg)) => {
+                info!("Downloaded {:?}", pkg.file);
+                downloaded.push(pkg);
+            }
+            Ok(None) => {
+                warn!("Failed to find {}-{} on any mirror", pkg.name, pkg.version);
+                missing += 1;
+            }
+            Err(err) => {
+                error!("Failed to download {}-{}: {err:#}", pkg.name, pkg.version);
+                failed += 1;
+            }
+        }
+    }
+    download::write_manifest(&dir, &mut downloaded).await?;
+    println!(
+        "Downloaded {} packages to {dir:?}, {missing} not found, {failed} failed",
+        downloaded.len()
+    );
 
-    let client = reqwest::Client::new();
-    loop {
-        tokio::select! {
-            Some(_msg) = event_rx.recv() => (),
-            Some(pkg) = http_rx.recv() => {
-                let mut found = false;
-
-                for ext in fetch::PKG_COMPRESSION_EXTS {
-                    let Ok(url) = pkg.to_url(ext) else { continue };
-                    if fetch::head(&client, &url).await?.is_success() {
-                        println!("{url}");
-                        found = true;
-                        break;
-                    }
-                }
+    if failed > 0 {
+        Ok(ExitCode::from(EXIT_ERROR))
+    } else if missing > 0 {
+        Ok(ExitCode::from(EXIT_UNTRUSTED_PKGS))
+    } else {
+        Ok(ExitCode::SUCCESS)
+    }
+}
 
-                if !found {
-                    bail!("Failed to determine url for pkg: {pkg:?}");
-                }
+#[tokio::main]
+async fn cache_cmd(action: CacheAction, state: state::StateDir) -> Result<()> {
+    match action {
+        CacheAction::Stats => {
+            println!("{}", state.path.display());
+            for (kind, files, size) in task::block_in_place(|| state.stats())? {
+                println!(
+                    "  {:<12} {:>8} files {:>12} bytes",
+                    kind.name(),
+                    files.to_formatted_string(&Locale::en),
+                    size.to_formatted_string(&Locale::en)
+                );
             }
-            else => break,
         }
+        CacheAction::Clear { kinds } => state.clear(&kinds).await?,
     }
-
     Ok(())
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+#[tokio::main]
+async fn compare_reports(
+    args: CompareReports,
+    output: Option<&Path>,
+    format: Format,
+) -> Result<()> {
+    let a = Report::load(&args.a).await?;
+    let b = Report::load(&args.b).await?;
+    let mut writer = open_output(output).await?;
+
+    let name = |path: &Path| path.to_string_lossy().into_owned();
+    let report = Report {
+        root: PathBuf::from("/"),
+        entries: report::divergence((&name(&args.a), &a), (&name(&args.b), &b)),
+        ..Default::default()
+    };
+    report.write(&mut writer, format).await
+}
+
+fn main() -> ExitCode {
+    match start() {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+fn start() -> Result<ExitCode> {
+    let mut args = Args::parse();
 
     let log_level = match args.verbose {
         0 => "warn",
@@ -412,15 +1816,195 @@ fn main() -> Result<()> {
         2 => "debug",
         _ => "trace",
     };
-    env_logger::init_from_env(Env::default().default_filter_or(log_level));
+    let color = args.use_color();
+    env_logger::Builder::from_env(Env::default().default_filter_or(log_level))
+        .write_style(if color {
+            WriteStyle::Always
+        } else {
+            WriteStyle::Never
+        })
+        .init();
+    colored::control::set_override(color);
+
+    // Keep the mounted snapshot private to this process
+    if args.lvm_snapshot.is_some() {
+        sandbox::private_mount_namespace()?;
+    }
 
     // Remove all capabilities we don't need before accessing the filesystem
-    sandbox::init()?;
+    sandbox::init(args.keep_read_cap)?;
+    disk::set_read_options(disk::ReadOptions {
+        buffer_size: args.hash_buffer_size as usize,
+        mmap_threshold: args.mmap_threshold,
+    })?;
 
     // Start into tokio and regular program
-    if args.list_pkgs {
-        list_pkgs(args)
+    if args.print_schema {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report::schema(args.format))?
+        );
+    } else if args.list_pkgs {
+        return list_pkgs(args);
+    } else if let Some(dir) = args.download_pkgs.clone() {
+        return download_pkgs(args, dir);
+    } else if let Some(SubCommand::Baseline(baseline)) = args.subcommand {
+        let num_hash_worker = args.concurrency.unwrap_or_else(num_cpus::get);
+        let state = state::StateDir::new(args.state_dir)?;
+        baseline::run(baseline.action, args.output, num_hash_worker, state)?;
+    } else if let Some(SubCommand::CompareReports(compare)) = args.subcommand {
+        compare_reports(compare, args.output.as_deref(), args.format)?;
+    } else if let Some(SubCommand::VerifyCustody(verify)) = args.subcommand {
+        let content = fs::read_to_string(&verify.log)
+            .with_context(|| anyhow!("Failed to read custody log: {:?}", verify.log))?;
+        let (records, last) = custody::verify(&content)?;
+        println!("Verified {records} records, last hash: {last}");
+    } else if let Some(SubCommand::Cache(cache)) = args.subcommand {
+        let state = state::StateDir::new(args.state_dir)?;
+        cache_cmd(cache.action, state)?;
     } else {
-        run(args)
+        // snapshots and images are cleaned up with external commands once the scan is done
+        let needs_cleanup = args.image.is_some()
+            || args.lvm_snapshot.is_some()
+            || args.snapshot.as_deref() == Some(Path::new("auto"));
+        if args.keep_read_cap && needs_cleanup {
+            bail!("--keep-read-cap can't be used with --snapshot auto, it's cleaned up with external commands");
+        } else if args.no_sandbox || needs_cleanup {
+            debug!("Not hardening the sandbox");
+        } else {
+            let mut policy = sandbox_policy(&args)?;
+            policy.require_landlock = args.keep_read_cap;
+            if args.proxy.is_some() || has_env_proxy() {
+                info!(
+                    "Not separating network access into a fetcher process, a proxy is configured"
+                );
+            } else {
+                let port = privsep::spawn(fetcher_hosts(&args))?;
+                args.proxy = Some(format!("http://127.0.0.1:{port}"));
+                policy.connect_port = Some(port);
+            }
+            sandbox::harden(&policy).with_context(|| match args.keep_read_cap {
+                true => anyhow!("Failed to confine --keep-read-cap"),
+                false => anyhow!("Failed to harden the sandbox"),
+            })?;
+        }
+        return run(args);
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Proxies from the environment are used by the http client if no proxy is set explicitly
+fn has_env_proxy() -> bool {
+    [
+        "ALL_PROXY",
+        "HTTPS_PROXY",
+        "HTTP_PROXY",
+        "all_proxy",
+        "https_proxy",
+        "http_proxy",
+    ]
+    .iter()
+    .any(|var| std::env::var_os(var).is_some_and(|value| !value.is_empty()))
+}
+
+/// The hosts the fetcher process may connect to, the configured mirrors and apis
+fn fetcher_hosts(args: &Args) -> privsep::Allowed {
+    let mut allowed = privsep::Allowed::default();
+    for url in &args.archive_url {
+        allowed.add_url(url);
+    }
+    if args
+        .archive_url
+        .iter()
+        .any(|url| url.trim_end_matches('/') == pkg::ARCHIVE_URL)
+    {
+        allowed.add_host(privsep::ARCHIVE_REDIRECT_HOST);
+    }
+    if args.backend == Backend::Dpkg && args.debian_snapshot {
+        allowed.add_url(&args.debian_snapshot_url);
+    }
+    if args.cve {
+        allowed.add_url(advisory::TRACKER_URL);
+    }
+    if let Some(provider) = args.lookup_hashes {
+        allowed.add_url(provider.url());
+    }
+    allowed
+}
+
+/// The paths a scan reads and writes, everything else is hidden by the hardened sandbox
+fn sandbox_policy(args: &Args) -> Result<sandbox::Policy> {
+    let mut policy = sandbox::Policy::default();
+    // tarballs and images are scanned as if they were mounted at /
+    if args.restore && !args.dry_run {
+        policy.write.push(args.root().to_owned());
+    } else if !args.reads_archive() {
+        policy.read.push(args.root().to_owned());
+    }
+    if let Some(SubCommand::Compare(compare)) = &args.subcommand {
+        policy.read.push(compare.trusted.clone());
+    }
+    if let Some(ext4) = &args.ext4 {
+        policy.read.push(image::parse_arg(ext4).0);
+    }
+    policy.read.extend(
+        [
+            &args.snapshot,
+            &args.input_tar,
+            &args.squashfs,
+            &args.pkg_file,
+            &args.paths_from,
+            &args.hashes_from,
+            &args.bundle,
+            &args.allowlist,
+            &args.boot_dir,
+        ]
+        .into_iter()
+        .flatten()
+        .cloned(),
+    );
+    policy.read.extend(
+        args.roots
+            .iter()
+            .chain(&args.pkg_cache)
+            .chain(&args.known_good)
+            .chain(&args.esp)
+            .chain(&args.ignore_file)
+            .cloned(),
+    );
+    policy
+        .read
+        .extend(args.map.iter().map(|map| map.host.clone()));
+
+    // rules can only be added for directories that exist
+    let state = state::StateDir::new(args.state_dir.clone())?;
+    for dir in [Some(&state.path), args.mtree_cache.as_ref()]
+        .into_iter()
+        .flatten()
+    {
+        fs::create_dir_all(dir).with_context(|| anyhow!("Failed to create directory: {dir:?}"))?;
+        policy.write.push(dir.clone());
+    }
+    // files that are created later, sqlite also writes a journal next to the database
+    for path in [
+        &args.output,
+        &args.remediation_out,
+        &args.log_passed,
+        &args.export_hashes,
+        &args.export_aide,
+        &args.state_file,
+        &args.db,
+        &args.quarantine,
+        &args.diff_dir,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        policy.write.push(dir);
     }
+    Ok(policy)
 }
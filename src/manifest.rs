@@ -0,0 +1,349 @@
+use crate::digest::{Algorithm, Checksum};
+use crate::errors::*;
+use crate::pkg::Package;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncWriteExt, BufWriter};
+
+fn unescape(path: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(path.len());
+    let mut bytes = path.iter();
+    while let Some(&b) = bytes.next() {
+        if b == b'\\' {
+            match bytes.next() {
+                Some(b'n') => out.push(b'\n'),
+                Some(b'r') => out.push(b'\r'),
+                Some(&b) => out.push(b),
+                None => out.push(b'\\'),
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn split_once<'a>(line: &'a [u8], delimiter: &[u8]) -> Option<(&'a [u8], &'a [u8])> {
+    let pos = line
+        .windows(delimiter.len())
+        .position(|window| window == delimiter)?;
+    Some((&line[..pos], &line[pos + delimiter.len()..]))
+}
+
+fn rsplit_once<'a>(line: &'a [u8], delimiter: &[u8]) -> Option<(&'a [u8], &'a [u8])> {
+    let pos = line
+        .windows(delimiter.len())
+        .rposition(|window| window == delimiter)?;
+    Some((&line[..pos], &line[pos + delimiter.len()..]))
+}
+
+/// Parse a line of `sha256sum` output (or the BSD-style `SHA256 (path) = hash`)
+pub fn parse_line(line: &str) -> Option<(String, PathBuf)> {
+    let (checksum, path) = parse_checksum_line(line.as_bytes(), Algorithm::Sha256)?;
+    if checksum.algorithm != Algorithm::Sha256 {
+        return None;
+    }
+    Some((checksum.hex, path))
+}
+
+/// Parse a line of `sha256sum`, `sha512sum` or `b3sum` output (or the BSD-style `BLAKE3 (path) = hash`),
+/// untagged lines use the default algorithm unless the length is only valid for sha512, sha1 or md5
+pub fn parse_checksum_line(line: &[u8], default: Algorithm) -> Option<(Checksum, PathBuf)> {
+    let (escaped, line) = match line.strip_prefix(b"\\") {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+
+    let tagged = split_once(line, b" (").and_then(|(tag, tagged)| {
+        let tag = std::str::from_utf8(tag).ok()?;
+        Some((Algorithm::from_tag(tag)?, tagged))
+    });
+    let (algorithm, hash, path) = if let Some((algorithm, tagged)) = tagged {
+        let (path, hash) = rsplit_once(tagged, b") = ")?;
+        (algorithm, hash, path)
+    } else {
+        let (hash, path) = split_once(line, b" ")?;
+        // text mode uses a second space, binary mode is marked with `*`
+        let path = path
+            .strip_prefix(b" ")
+            .or_else(|| path.strip_prefix(b"*"))?;
+        let algorithm = [Algorithm::Sha512, Algorithm::Sha1, Algorithm::Md5]
+            .into_iter()
+            .find(|algorithm| algorithm.hex_len() == hash.len())
+            .unwrap_or(default);
+        (algorithm, hash, path)
+    };
+
+    let checksum = Checksum::new(algorithm, std::str::from_utf8(hash).ok()?).ok()?;
+
+    let path = if escaped {
+        unescape(path)
+    } else {
+        path.to_vec()
+    };
+    Some((checksum, PathBuf::from(OsString::from_vec(path))))
+}
+
+/// Format the comment that attributes the following lines to a package, `sha256sum -c` skips it
+pub fn format_package(pkg: &Package) -> String {
+    format!("# package: {} {} {}\n", pkg.name, pkg.version, pkg.arch)
+}
+
+/// Parse a comment written by [`format_package`]
+pub fn parse_package(line: &str) -> Option<Package> {
+    let mut parts = line.strip_prefix("# package: ")?.split(' ');
+    let (Some(name), Some(version), Some(arch), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    Some(Package {
+        name: name.to_string(),
+        version: version.to_string(),
+        arch: arch.to_string(),
+    })
+}
+
+/// Read a manifest, paths are resolved relative to the scan root. Entries after a
+/// `# package:` comment are attributed to that package
+pub async fn load(
+    path: &Path,
+    root: &Path,
+    default: Algorithm,
+) -> Result<Vec<(PathBuf, String, Option<Arc<Package>>)>> {
+    // paths are written as they are, they aren't necessarily utf-8
+    let content = fs::read(path)
+        .await
+        .with_context(|| anyhow!("Failed to read hash manifest: {path:?}"))?;
+
+    let mut hashes = Vec::new();
+    let mut pkg = None;
+    for (num, line) in content.split(|&b| b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b"#") {
+            if let Some(package) = std::str::from_utf8(line).ok().and_then(parse_package) {
+                pkg = Some(Arc::new(package));
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let Some((checksum, path)) = parse_checksum_line(line, default) else {
+            bail!(
+                "Invalid hash manifest entry in line {}: {:?}",
+                num + 1,
+                String::from_utf8_lossy(line)
+            );
+        };
+        hashes.push((
+            crate::resolve_target_path(root, &path),
+            checksum.to_string(),
+            pkg.clone(),
+        ));
+    }
+    info!("Loaded {} trusted hashes from {path:?}", hashes.len());
+
+    Ok(hashes)
+}
+
+/// Format a line compatible with `sha256sum -c`, including its escaping rules. Like
+/// coreutils, paths that aren't utf-8 are written as they are
+pub fn format_line(sha256: &str, path: &Path) -> Vec<u8> {
+    let path = path.as_os_str().as_bytes();
+    let mut line = Vec::with_capacity(sha256.len() + path.len() + 4);
+    if path.iter().any(|b| matches!(b, b'\\' | b'\n' | b'\r')) {
+        line.push(b'\\');
+        line.extend_from_slice(sha256.as_bytes());
+        line.extend_from_slice(b"  ");
+        for &b in path {
+            match b {
+                b'\\' => line.extend_from_slice(b"\\\\"),
+                b'\n' => line.extend_from_slice(b"\\n"),
+                b'\r' => line.extend_from_slice(b"\\r"),
+                b => line.push(b),
+            }
+        }
+    } else {
+        line.extend_from_slice(sha256.as_bytes());
+        line.extend_from_slice(b"  ");
+        line.extend_from_slice(path);
+    }
+    line.push(b'\n');
+    line
+}
+
+/// Write all trusted hashes with paths relative to the scan root, grouped by the package owning them
+pub async fn export(
+    path: &Path,
+    root: &Path,
+    hashes: &HashMap<PathBuf, String>,
+    owners: &HashMap<PathBuf, Arc<Package>>,
+) -> Result<()> {
+    let file = File::create(path)
+        .await
+        .with_context(|| anyhow!("Failed to open file: {path:?}"))?;
+    let mut writer = BufWriter::new(file);
+
+    // files without a package come first, before any `# package:` comment
+    let mut sorted = BTreeMap::<_, (_, BTreeMap<_, _>)>::new();
+    for (path, sha256) in hashes {
+        let pkg = owners.get(path);
+        let key = pkg.map(|pkg| (&pkg.name, &pkg.version, &pkg.arch));
+        sorted
+            .entry(key)
+            .or_insert_with(|| (pkg, BTreeMap::new()))
+            .1
+            .insert(path.strip_prefix(root).unwrap_or(path), sha256);
+    }
+
+    for (pkg, files) in sorted.into_values() {
+        if let Some(pkg) = pkg {
+            writer
+                .write_all(format_package(pkg).as_bytes())
+                .await
+                .context("Failed to write hash manifest")?;
+        }
+        for (path, sha256) in files {
+            writer
+                .write_all(&format_line(sha256, path))
+                .await
+                .context("Failed to write hash manifest")?;
+        }
+    }
+    writer
+        .flush()
+        .await
+        .context("Failed to write hash manifest")?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+
+    #[test]
+    fn format_regular_line() {
+        let line = format_line(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            Path::new("usr/share/empty"),
+        );
+        assert_eq!(
+            line,
+            b"e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  usr/share/empty\n"
+        );
+    }
+
+    #[test]
+    fn format_escaped_line() {
+        let line = format_line(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            Path::new("usr/share/a\\b\nc"),
+        );
+        assert_eq!(
+            line,
+            b"\\e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  usr/share/a\\\\b\\nc\n"
+        );
+    }
+
+    #[test]
+    fn parse_lines() {
+        let sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let expected = Some((sha256.to_string(), PathBuf::from("usr/share/empty")));
+        assert_eq!(parse_line(&format!("{sha256}  usr/share/empty")), expected);
+        assert_eq!(parse_line(&format!("{sha256} *usr/share/empty")), expected);
+        assert_eq!(
+            parse_line(&format!("SHA256 (usr/share/empty) = {sha256}")),
+            expected
+        );
+        assert_eq!(parse_line("abc  usr/share/empty"), None);
+    }
+
+    #[test]
+    fn parse_other_algorithms() {
+        let blake3 = "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262";
+        let (checksum, path) = parse_checksum_line(
+            format!("{blake3}  usr/share/empty").as_bytes(),
+            Algorithm::Blake3,
+        )
+        .unwrap();
+        assert_eq!(checksum.to_string(), format!("blake3:{blake3}"));
+        assert_eq!(path, Path::new("usr/share/empty"));
+
+        let (checksum, _) = parse_checksum_line(
+            format!("BLAKE3 (usr/share/empty) = {blake3}").as_bytes(),
+            Algorithm::Sha256,
+        )
+        .unwrap();
+        assert_eq!(checksum.algorithm, Algorithm::Blake3);
+        assert_eq!(
+            parse_line(&format!("BLAKE3 (usr/share/empty) = {blake3}")),
+            None
+        );
+
+        let sha512 = "cf83e1357eefb8bdf1542850d66d8007d620e4050b5715dc83f4a921d36ce9ce47d0d13c5d85f2b0ff8318d2877eec2f63b931bd47417a81a538327af927da3e";
+        let (checksum, _) = parse_checksum_line(
+            format!("{sha512}  usr/share/empty").as_bytes(),
+            Algorithm::Sha256,
+        )
+        .unwrap();
+        assert_eq!(checksum.algorithm, Algorithm::Sha512);
+    }
+
+    #[test]
+    fn package_comment_roundtrip() {
+        let pkg = Package {
+            name: "openssh".to_string(),
+            version: "9.8p1-1".to_string(),
+            arch: "x86_64".to_string(),
+        };
+        let line = format_package(&pkg);
+        assert_eq!(line, "# package: openssh 9.8p1-1 x86_64\n");
+        let parsed = parse_package(line.trim_end_matches('\n')).unwrap();
+        assert_eq!(
+            (parsed.name, parsed.version, parsed.arch),
+            (pkg.name, pkg.version, pkg.arch)
+        );
+        assert!(parse_package("# just a comment").is_none());
+    }
+
+    #[test]
+    fn parse_escaped_roundtrip() {
+        let sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let path = Path::new("usr/share/a\\b\nc");
+        let line = format_line(sha256, path);
+        let line = std::str::from_utf8(&line).unwrap();
+        assert_eq!(
+            parse_line(line.trim_end_matches('\n')),
+            Some((sha256.to_string(), path.to_owned()))
+        );
+    }
+
+    #[tokio::test]
+    async fn non_utf8_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("manifest-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let path = Path::new(OsStr::from_bytes(b"usr/share/caf\xe9"));
+
+        // written as is, like `sha256sum` does
+        let line = format_line(sha256, path);
+        assert_eq!(&line[66..], b"usr/share/caf\xe9\n");
+        let manifest = dir.join("SHA256SUMS");
+        std::fs::write(&manifest, line).unwrap();
+        let hashes = load(&manifest, Path::new("/mnt"), Algorithm::Sha256)
+            .await
+            .unwrap();
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(hashes[0].0, Path::new("/mnt").join(path));
+        assert_eq!(hashes[0].1, sha256);
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,140 @@
+use crate::errors::*;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
+
+/// A directory of the investigated system that is mounted somewhere else on the analysis host,
+/// given as `--map /usr=/mnt/usr_part`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Map {
+    /// Path in the investigated system, relative to the root
+    pub target: PathBuf,
+    /// Where the directory is mounted on the analysis host
+    pub host: PathBuf,
+}
+
+impl Map {
+    pub fn parse(s: &str) -> Result<Self> {
+        let Some((target, host)) = s.split_once('=') else {
+            bail!("Expected a mapping like `/usr=/mnt/usr_part`: {s:?}");
+        };
+        let target = target.trim_matches('/');
+        if target.is_empty() {
+            bail!("The root can't be mapped, use the scan path instead: {s:?}");
+        }
+        if host.is_empty() {
+            bail!("Missing host directory in mapping: {s:?}");
+        }
+        Ok(Map {
+            target: PathBuf::from(target),
+            host: PathBuf::from(host),
+        })
+    }
+}
+
+/// The scan root together with the directories that are mounted separately, so paths of
+/// the investigated system can be resolved across all of them
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Mounts {
+    root: PathBuf,
+    /// The longest (most specific) target comes first
+    maps: Vec<Map>,
+}
+
+impl Mounts {
+    pub fn new(root: PathBuf, mut maps: Vec<Map>) -> Self {
+        maps.sort_by_key(|map| std::cmp::Reverse(map.target.components().count()));
+        Mounts { root, maps }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.maps.is_empty()
+    }
+
+    /// Translate a path with the root to the path on the analysis host
+    pub fn resolve(&self, path: PathBuf) -> PathBuf {
+        self.resolve_without(path, None)
+    }
+
+    fn resolve_without(&self, path: PathBuf, skip: Option<usize>) -> PathBuf {
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return path;
+        };
+        for (i, map) in self.maps.iter().enumerate() {
+            if Some(i) == skip {
+                continue;
+            }
+            if let Ok(rest) = relative.strip_prefix(&map.target) {
+                return map.host.join(rest);
+            }
+        }
+        path
+    }
+
+    /// Translate a path on the analysis host back to the path with the root, for globs of the investigated system
+    pub fn unresolve<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        for map in &self.maps {
+            if let Ok(rest) = path.strip_prefix(&map.host) {
+                return self.root.join(&map.target).join(rest).into();
+            }
+        }
+        path.into()
+    }
+
+    /// The (usually empty) mountpoints on the analysis host, their mapped directories are walked instead
+    pub fn mountpoints(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.maps
+            .iter()
+            .enumerate()
+            .map(|(i, map)| self.resolve_without(self.root.join(&map.target), Some(i)))
+    }
+
+    /// The mapped directories on the analysis host
+    pub fn host_dirs(&self) -> impl Iterator<Item = &Path> {
+        self.maps.iter().map(|map| map.host.as_path())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_mapped_paths() {
+        let map = |s: &str| Map::parse(s).unwrap();
+        assert!(Map::parse("/usr").is_err());
+        assert!(Map::parse("/=/mnt").is_err());
+        assert!(Map::parse("/usr=").is_err());
+
+        let mounts = Mounts::new(
+            PathBuf::from("/mnt/root"),
+            vec![map("/usr=/mnt/usr_part"), map("/usr/local/=/mnt/local")],
+        );
+        assert_eq!(
+            mounts.resolve(PathBuf::from("/mnt/root/usr/bin/sudo")),
+            Path::new("/mnt/usr_part/bin/sudo")
+        );
+        assert_eq!(
+            mounts.resolve(PathBuf::from("/mnt/root/usr/local/bin/foo")),
+            Path::new("/mnt/local/bin/foo")
+        );
+        assert_eq!(
+            mounts.resolve(PathBuf::from("/mnt/root/usrx/foo")),
+            Path::new("/mnt/root/usrx/foo")
+        );
+        assert_eq!(
+            mounts.resolve(PathBuf::from("/mnt/root/etc/passwd")),
+            Path::new("/mnt/root/etc/passwd")
+        );
+        assert_eq!(
+            mounts.unresolve(Path::new("/mnt/usr_part/bin/sudo")),
+            Path::new("/mnt/root/usr/bin/sudo")
+        );
+        assert_eq!(
+            mounts.mountpoints().collect::<Vec<_>>(),
+            vec![
+                PathBuf::from("/mnt/usr_part/local"),
+                PathBuf::from("/mnt/root/usr")
+            ]
+        );
+    }
+}
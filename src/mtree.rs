@@ -1,10 +1,154 @@
 use crate::errors::*;
+use std::collections::HashMap;
 
 #[derive(Debug, PartialEq)]
 pub struct Entry {
     pub path: String,
     pub time: String,
     pub content: EntryType,
+    pub metadata: Metadata,
+}
+
+/// The type of an entry, `type=` in the `.MTREE`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+    Dir,
+    Link,
+}
+
+impl Kind {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "file" => Some(Kind::File),
+            "dir" => Some(Kind::Dir),
+            "link" => Some(Kind::Link),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::File => "file",
+            Kind::Dir => "dir",
+            Kind::Link => "link",
+        }
+    }
+}
+
+/// Type, permissions, ownership, symlink target, size and modification time of an entry, `None`
+/// if the `.MTREE` doesn't say
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: Option<Kind>,
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub link: Option<String>,
+    /// Size of regular files, to flag files with a different size without hashing them
+    pub size: Option<u64>,
+    /// Modification time of regular files in seconds, the files of a package are extracted with it
+    pub mtime: Option<i64>,
+}
+
+impl Metadata {
+    /// Read the metadata keywords of an entry, values that fail to parse are ignored
+    pub fn from_keywords<'a>(keywords: impl IntoIterator<Item = (&'a str, &'a str)>) -> Self {
+        let mut metadata = Metadata::default();
+        for (key, value) in keywords {
+            match key {
+                "mode" => metadata.mode = u32::from_str_radix(value, 8).ok(),
+                "uid" => metadata.uid = value.parse().ok(),
+                "gid" => metadata.gid = value.parse().ok(),
+                "link" => metadata.link = Some(unescape(value)),
+                "size" => metadata.size = value.parse().ok(),
+                "time" => {
+                    let seconds = value.split_once('.').map_or(value, |(seconds, _)| seconds);
+                    metadata.mtime = seconds.parse().ok();
+                }
+                "type" => metadata.kind = Kind::parse(value),
+                _ => (),
+            }
+        }
+        if metadata.kind.is_some_and(|kind| kind != Kind::File) {
+            metadata.mtime = None;
+        }
+        metadata
+    }
+
+    /// The type of the entry, entries without a type are files unless they have a symlink target
+    pub fn kind(&self) -> Kind {
+        match (self.kind, &self.link) {
+            (Some(kind), _) => kind,
+            (None, Some(_)) => Kind::Link,
+            (None, None) => Kind::File,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Metadata::default()
+    }
+
+    /// Format as `.MTREE` keywords, the inverse of [`Metadata::from_keywords`]
+    pub fn to_keywords(&self) -> String {
+        let mut keywords = Vec::new();
+        if let Some(kind) = self.kind {
+            keywords.push(format!("type={}", kind.name()));
+        }
+        if let Some(mode) = self.mode {
+            keywords.push(format!("mode={mode:o}"));
+        }
+        if let Some(uid) = self.uid {
+            keywords.push(format!("uid={uid}"));
+        }
+        if let Some(gid) = self.gid {
+            keywords.push(format!("gid={gid}"));
+        }
+        if let Some(link) = &self.link {
+            keywords.push(format!("link={}", escape(link)));
+        }
+        if let Some(size) = self.size {
+            keywords.push(format!("size={size}"));
+        }
+        if let Some(mtime) = self.mtime {
+            keywords.push(format!("time={mtime}"));
+        }
+        keywords.join(" ")
+    }
+}
+
+/// Decode the `\ooo` octal escapes that mtree uses for whitespace and special characters
+fn unescape(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .filter(|_| bytes[i] == b'\\')
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        if let Some(byte) = octal {
+            out.push(byte);
+            i += 4;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_graphic() && byte != b'\\' {
+            out.push(byte as char);
+        } else {
+            out.push_str(&format!("\\{byte:03o}"));
+        }
+    }
+    out
 }
 
 #[derive(Debug, PartialEq)]
@@ -19,7 +163,19 @@ pub struct File {
     pub size: u64,
     // do not consider mtree without md5 invalid
     pub md5digest: Option<String>,
-    pub sha256digest: String,
+    /// Missing in packages that are older than pacman 4.1, they only have md5
+    pub sha256digest: Option<String>,
+}
+
+impl File {
+    /// The strongest digest of the file, md5 is prefixed like `md5:`
+    pub fn digest(&self) -> Option<String> {
+        match (&self.sha256digest, &self.md5digest) {
+            (Some(sha256), _) => Some(sha256.clone()),
+            (None, Some(md5)) => Some(format!("md5:{md5}")),
+            (None, None) => None,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -31,7 +187,36 @@ pub struct Link {
     pub link: String,
 }
 
+/// Parses the lines of a `.MTREE`, the `/set` defaults apply to all entries that follow
+#[derive(Debug, Default)]
+pub struct Parser {
+    defaults: HashMap<String, String>,
+}
+
+impl Parser {
+    pub fn parse(&mut self, line: &str) -> Option<Entry> {
+        if let Some(keywords) = line.strip_prefix("/set ") {
+            for (key, value) in keywords.split(' ').filter_map(|kw| kw.split_once('=')) {
+                self.defaults.insert(key.to_string(), value.to_string());
+            }
+            None
+        } else if let Some(keys) = line.strip_prefix("/unset ") {
+            for key in keys.split(' ') {
+                self.defaults.remove(key);
+            }
+            None
+        } else {
+            parse_with_defaults(line, &self.defaults)
+        }
+    }
+}
+
+/// Parse a single line, without the defaults of previous `/set` lines
 pub fn parse(line: &str) -> Option<Entry> {
+    parse_with_defaults(line, &HashMap::new())
+}
+
+fn parse_with_defaults(line: &str, defaults: &HashMap<String, String>) -> Option<Entry> {
     if !line.starts_with('.') {
         return None;
     }
@@ -45,30 +230,38 @@ pub fn parse(line: &str) -> Option<Entry> {
     let mut link = None;
 
     let (path, metadata) = line.split_once(' ')?;
-    for md in metadata.split(' ') {
-        if let Some((key, value)) = md.split_once('=') {
-            match key {
-                "time" => time = Some(value.to_string()),
-                "size" => {
-                    let value = value.parse().ok()?;
-                    size = Some(value);
-                }
-                "md5digest" => md5digest = Some(value.to_string()),
-                "sha256digest" => sha256digest = Some(value.to_string()),
-                "mode" => mode = Some(value.to_string()),
-                "type" => t = Some(value.to_string()),
-                "link" => link = Some(value.to_string()),
-                _ => (),
+    let keywords = defaults
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .chain(metadata.split(' ').filter_map(|md| md.split_once('=')))
+        .collect::<Vec<_>>();
+    for &(key, value) in &keywords {
+        match key {
+            "time" => time = Some(value.to_string()),
+            "size" => {
+                let value = value.parse().ok()?;
+                size = Some(value);
             }
+            "md5digest" => md5digest = Some(value.to_string()),
+            "sha256digest" => sha256digest = Some(value.to_string()),
+            "mode" => mode = Some(value.to_string()),
+            "type" => t = Some(value.to_string()),
+            "link" => link = Some(value.to_string()),
+            _ => (),
         }
     }
 
     let content = match t.as_deref() {
-        None => EntryType::File(File {
-            size: size?,
-            md5digest,
-            sha256digest: sha256digest?,
-        }),
+        None | Some("file") => {
+            if md5digest.is_none() && sha256digest.is_none() {
+                return None;
+            }
+            EntryType::File(File {
+                size: size?,
+                md5digest,
+                sha256digest,
+            })
+        }
         Some("dir") => EntryType::Directory(Directory {}),
         Some("link") => EntryType::Link(Link {
             mode: mode?,
@@ -84,6 +277,7 @@ pub fn parse(line: &str) -> Option<Entry> {
         path: path.to_string(),
         time: time?,
         content,
+        metadata: Metadata::from_keywords(keywords),
     })
 }
 
@@ -103,12 +297,29 @@ mod tests {
                 content: EntryType::File(File {
                     size: 171753536,
                     md5digest: Some("a301a912dd0206dbfb43241d0a95bc4a".to_string()),
-                    sha256digest:
+                    sha256digest: Some(
                         "e25add8820bcc151001e8720722a582b22586f4ac11a1a24a42606f7dc8511e6"
-                            .to_string(),
+                            .to_string()
+                    ),
                 }),
+                metadata: Metadata {
+                    size: Some(171753536),
+                    mtime: Some(1704931316),
+                    ..Default::default()
+                },
             })
         );
+
+        // packages built before pacman 4.1 only have md5
+        let line = "./usr/bin/foo time=1.0 size=3 md5digest=a301a912dd0206dbfb43241d0a95bc4a";
+        let Some(EntryType::File(file)) = parse(line).map(|entry| entry.content) else {
+            panic!("Expected a file");
+        };
+        assert_eq!(
+            file.digest().as_deref(),
+            Some("md5:a301a912dd0206dbfb43241d0a95bc4a")
+        );
+        assert_eq!(parse("./usr/bin/foo time=1.0 size=3"), None);
     }
 
     #[test]
@@ -121,6 +332,10 @@ mod tests {
                 path: "./usr/lib/signal-desktop".to_string(),
                 time: "1704931316.0".to_string(),
                 content: EntryType::Directory(Directory {}),
+                metadata: Metadata {
+                    kind: Some(Kind::Dir),
+                    ..Default::default()
+                },
             })
         );
     }
@@ -138,7 +353,54 @@ mod tests {
                     mode: "777".to_string(),
                     link: "/usr/lib/signal-desktop/signal-desktop".to_string(),
                 }),
+                metadata: Metadata {
+                    kind: Some(Kind::Link),
+                    mode: Some(0o777),
+                    link: Some("/usr/lib/signal-desktop/signal-desktop".to_string()),
+                    ..Default::default()
+                },
             })
         );
     }
+
+    #[test]
+    fn parse_set_defaults() {
+        let mut parser = Parser::default();
+        assert_eq!(parser.parse("/set type=file uid=0 gid=0 mode=644"), None);
+        let entry = parser
+            .parse("./usr/bin/sudo time=1.0 mode=4755 size=1 sha256digest=abcd")
+            .unwrap();
+        assert!(matches!(entry.content, EntryType::File(_)));
+        let expected = Metadata {
+            kind: Some(Kind::File),
+            mode: Some(0o4755),
+            uid: Some(0),
+            gid: Some(0),
+            link: None,
+            size: Some(1),
+            mtime: Some(1),
+        };
+        assert_eq!(entry.metadata, expected);
+        assert_eq!(
+            entry.metadata.to_keywords(),
+            "type=file mode=4755 uid=0 gid=0 size=1 time=1"
+        );
+
+        let entry = parser
+            .parse("./usr/bin/a\\040b time=1.0 mode=777 type=link link=x\\040y")
+            .unwrap();
+        assert_eq!(entry.metadata.link.as_deref(), Some("x y"));
+        assert_eq!(
+            entry.metadata.to_keywords(),
+            "type=link mode=777 uid=0 gid=0 link=x\\040y"
+        );
+        assert_eq!(entry.metadata.kind(), Kind::Link);
+        let keywords = entry.metadata.to_keywords();
+        let keywords = keywords.split(' ').filter_map(|kw| kw.split_once('='));
+        assert_eq!(Metadata::from_keywords(keywords), entry.metadata);
+
+        assert_eq!(parser.parse("/unset uid gid"), None);
+        let entry = parser.parse("./etc/foo time=1.0 size=1 sha256digest=abcd");
+        assert_eq!(entry.unwrap().metadata.uid, None);
+    }
 }
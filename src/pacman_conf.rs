@@ -0,0 +1,218 @@
+use crate::errors::*;
+use crate::filter;
+use crate::resolve_target_path;
+use std::io;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Location of pacman.conf, relative to the root
+pub const PATH: &str = "etc/pacman.conf";
+
+/// A repository section of pacman.conf
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Repo {
+    pub name: String,
+    /// Server urls, with `$repo` and `$arch` still in them
+    pub servers: Vec<String>,
+    /// Files with more `Server` lines, like `/etc/pacman.d/mirrorlist`
+    pub includes: Vec<PathBuf>,
+}
+
+/// The settings of pacman.conf that tell where the database and caches are
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PacmanConf {
+    /// Absolute path of the pacman database in the investigated system
+    pub dbpath: Option<PathBuf>,
+    /// Absolute paths of the package caches in the investigated system
+    pub cache_dirs: Vec<PathBuf>,
+    pub architecture: Vec<String>,
+    pub repos: Vec<Repo>,
+    /// Patterns of files that pacman doesn't extract, like `usr/share/locale/*`
+    pub no_extract: Vec<String>,
+    /// Patterns of files that pacman doesn't overwrite on upgrades, like `etc/hosts`
+    pub no_upgrade: Vec<String>,
+}
+
+impl PacmanConf {
+    /// Check if pacman skips extracting a file (relative to the root)
+    pub fn is_no_extract(&self, path: &Path) -> bool {
+        matches_patterns(&self.no_extract, path)
+    }
+
+    /// Check if pacman keeps a file (relative to the root) on upgrades, like a config file
+    pub fn is_no_upgrade(&self, path: &Path) -> bool {
+        matches_patterns(&self.no_upgrade, path)
+    }
+}
+
+/// Match a path like pacman matches `NoExtract` and `NoUpgrade`: `*` also matches `/`, and the
+/// last matching pattern decides, so `!pattern` can make an exception for an earlier one
+fn matches_patterns(patterns: &[String], path: &Path) -> bool {
+    let path = path.to_string_lossy();
+    let path = path.trim_start_matches('/');
+    patterns
+        .iter()
+        .rev()
+        .find_map(|pattern| {
+            let (negated, pattern) = match pattern.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern.as_str()),
+            };
+            filter::fnmatch(pattern.as_bytes(), path.as_bytes()).then_some(!negated)
+        })
+        .unwrap_or(false)
+}
+
+/// Parse pacman.conf, `Include` files are listed but not read
+pub fn parse(conf: &str) -> PacmanConf {
+    let mut parsed = PacmanConf::default();
+    let mut section = None;
+    for line in conf.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = Some(name.to_string());
+            if name != "options" {
+                parsed.repos.push(Repo {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let (key, value) = (key.trim(), value.trim());
+        match (section.as_deref(), key) {
+            (Some("options"), "DBPath") => parsed.dbpath = Some(value.into()),
+            (Some("options"), "CacheDir") => {
+                parsed
+                    .cache_dirs
+                    .extend(value.split_whitespace().map(PathBuf::from));
+            }
+            (Some("options"), "Architecture") => {
+                parsed
+                    .architecture
+                    .extend(value.split_whitespace().map(String::from));
+            }
+            (Some("options"), "NoExtract") => {
+                parsed
+                    .no_extract
+                    .extend(value.split_whitespace().map(String::from));
+            }
+            (Some("options"), "NoUpgrade") => {
+                parsed
+                    .no_upgrade
+                    .extend(value.split_whitespace().map(String::from));
+            }
+            (Some("options"), _) | (None, _) => (),
+            (Some(_), key) => {
+                let Some(repo) = parsed.repos.last_mut() else {
+                    continue;
+                };
+                match key {
+                    "Server" => repo.servers.push(value.to_string()),
+                    "Include" => repo.includes.push(value.into()),
+                    _ => (),
+                }
+            }
+        }
+    }
+    parsed
+}
+
+/// Read pacman.conf of the investigated system and the `Include` files of its repositories,
+/// a missing pacman.conf is the same as an empty one
+pub async fn load(root: &Path, path: &Path) -> Result<PacmanConf> {
+    let path = resolve_target_path(root, path);
+    let conf = match fs::read_to_string(&path).await {
+        Ok(conf) => conf,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            debug!("No pacman.conf found at {path:?}");
+            return Ok(PacmanConf::default());
+        }
+        Err(err) => return Err(err).with_context(|| anyhow!("Failed to read file: {path:?}")),
+    };
+    let mut conf = parse(&conf);
+    for repo in &mut conf.repos {
+        for include in &repo.includes {
+            let include = resolve_target_path(root, include);
+            match fs::read_to_string(&include).await {
+                Ok(mirrorlist) => repo.servers.extend(
+                    parse(&format!("[{}]\n{mirrorlist}", repo.name))
+                        .repos
+                        .into_iter()
+                        .flat_map(|repo| repo.servers),
+                ),
+                Err(err) => warn!(
+                    "Failed to read {include:?} of repository {:?}: {err:#}",
+                    repo.name
+                ),
+            }
+        }
+    }
+    Ok(conf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pacman_conf() {
+        let conf = parse(
+            "#
+# /etc/pacman.conf
+#
+[options]
+#RootDir     = /
+DBPath      = /srv/pacman/db/
+CacheDir    = /srv/pacman/pkg/ /var/cache/pacman/pkg/
+Architecture = x86_64 x86_64_v3
+NoUpgrade   = etc/hosts
+NoExtract   = usr/share/help/* !usr/share/help/en*
+NoExtract   = usr/share/locale/* !usr/share/locale/en_US/*
+CheckSpace
+SigLevel    = Required DatabaseOptional
+
+[core]
+Include = /etc/pacman.d/mirrorlist
+
+[custom]
+SigLevel = Optional TrustAll
+Server = file:///home/custompkgs # local repo
+",
+        );
+        assert_eq!(conf.dbpath, Some(PathBuf::from("/srv/pacman/db/")));
+        assert_eq!(
+            conf.cache_dirs,
+            vec![
+                PathBuf::from("/srv/pacman/pkg/"),
+                PathBuf::from("/var/cache/pacman/pkg/")
+            ]
+        );
+        assert_eq!(conf.architecture, vec!["x86_64", "x86_64_v3"]);
+        assert!(conf.is_no_upgrade(Path::new("etc/hosts")));
+        assert!(!conf.is_no_upgrade(Path::new("etc/hostname")));
+        assert!(conf.is_no_extract(Path::new("usr/share/locale/de/LC_MESSAGES/sed.mo")));
+        assert!(!conf.is_no_extract(Path::new("usr/share/locale/en_US/LC_MESSAGES/sed.mo")));
+        assert!(conf.is_no_extract(Path::new("usr/share/help/de/gedit/index.page")));
+        assert!(!conf.is_no_extract(Path::new("usr/share/help/en_GB/gedit/index.page")));
+        assert!(!conf.is_no_extract(Path::new("usr/share/doc/sed/README")));
+        assert_eq!(
+            conf.repos,
+            vec![
+                Repo {
+                    name: "core".to_string(),
+                    servers: vec![],
+                    includes: vec![PathBuf::from("/etc/pacman.d/mirrorlist")],
+                },
+                Repo {
+                    name: "custom".to_string(),
+                    servers: vec!["file:///home/custompkgs".to_string()],
+                    includes: vec![],
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,139 @@
+use crate::errors::*;
+use crate::pkg::Package;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How a file passed verification
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Method {
+    /// The file was read and its hash matched
+    Hashed,
+    /// The file didn't change since a previous run (or `--since`) and wasn't read
+    Unchanged,
+}
+
+/// A line of the log, the evidence that a file was verified
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Record<'a> {
+    pub path: &'a Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package_version: Option<&'a str>,
+    /// The hash of the file as found on disk, unknown for unchanged files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<&'a str>,
+    /// The trusted hash the file was compared against
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<&'a str>,
+    pub method: Method,
+    /// Unix timestamp of the verification
+    pub verified_at: u64,
+}
+
+/// An ndjson log with a [`Record`] for every file that passed verification
+pub struct PassLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl PassLog {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| anyhow!("Failed to create pass log: {path:?}"))?;
+        Ok(PassLog {
+            path: path.to_owned(),
+            writer: BufWriter::new(file),
+        })
+    }
+
+    pub fn write(
+        &mut self,
+        path: &Path,
+        pkg: Option<&Package>,
+        sha256: Option<&str>,
+        expected: Option<&str>,
+        method: Method,
+    ) -> Result<()> {
+        let verified_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let record = Record {
+            path,
+            package: pkg.map(|pkg| pkg.name.as_str()),
+            package_version: pkg.map(|pkg| pkg.version.as_str()),
+            sha256,
+            expected,
+            method,
+            verified_at,
+        };
+        serde_json::to_writer(&mut self.writer, &record)?;
+        self.writer
+            .write_all(b"\n")
+            .with_context(|| anyhow!("Failed to write pass log: {:?}", self.path))
+    }
+
+    /// Flush the log, records that are still buffered are lost otherwise
+    pub fn finish(mut self) -> Result<()> {
+        self.writer
+            .flush()
+            .with_context(|| anyhow!("Failed to write pass log: {:?}", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_pass_log() {
+        let path = std::env::temp_dir().join(format!("passlog-test-{}.jsonl", std::process::id()));
+        let pkg = Package {
+            name: "coreutils".to_string(),
+            version: "9.5-1".to_string(),
+            arch: "x86_64".to_string(),
+        };
+        let sha256 = "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+        let mut log = PassLog::create(&path).unwrap();
+        log.write(
+            Path::new("/usr/bin/ls"),
+            Some(&pkg),
+            Some(sha256),
+            Some(sha256),
+            Method::Hashed,
+        )
+        .unwrap();
+        log.write(
+            Path::new("/usr/bin/cat"),
+            None,
+            None,
+            Some(sha256),
+            Method::Unchanged,
+        )
+        .unwrap();
+        log.finish().unwrap();
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let lines = log
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["path"], "/usr/bin/ls");
+        assert_eq!(lines[0]["package"], "coreutils");
+        assert_eq!(lines[0]["package_version"], "9.5-1");
+        assert_eq!(lines[0]["sha256"], sha256);
+        assert_eq!(lines[0]["method"], "hashed");
+        assert!(lines[0]["verified_at"].as_u64().unwrap() > 0);
+        assert_eq!(lines[1]["method"], "unchanged");
+        assert!(lines[1].get("sha256").is_none());
+        assert!(lines[1].get("package").is_none());
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -4,10 +4,19 @@ use async_stream::stream;
 use async_walkdir::WalkDir;
 use futures_core::stream::Stream;
 use futures_util::{pin_mut, StreamExt};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
 use tokio::fs;
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Location of the pacman database, relative to the root
+pub const DBPATH: &str = "var/lib/pacman";
+/// The Arch Linux Archive, it keeps every package that was ever published
+pub const ARCHIVE_URL: &str = "https://archive.archlinux.org";
+/// Layout of the Arch Linux Archive, used for urls that aren't a template
+pub const ARCHIVE_TEMPLATE: &str = "packages/{first}/{name}/{name}-{version}-{arch}.pkg.tar.{ext}";
 
 #[derive(Debug, Clone)]
 pub struct Package {
@@ -17,18 +26,179 @@ pub struct Package {
 }
 
 impl Package {
-    pub fn to_url(&self, ext: &str) -> Result<String> {
+    /// The file name of the package, like in a package cache
+    pub fn file_name(&self, ext: &str) -> String {
+        let name = format!("{}-{}-{}.pkg.tar", self.name, self.version, self.arch);
+        if ext.is_empty() {
+            name
+        } else {
+            format!("{name}.{ext}")
+        }
+    }
+
+    /// The url of the package, `archive` is either the base url of an archive with the
+    /// layout of the Arch Linux Archive or a template like `https://mirror/{arch}/{name}-{version}-{arch}.pkg.tar.{ext}`
+    pub fn to_url(&self, archive: &str, ext: &str) -> Result<String> {
         let Some(first) = self.name.chars().next() else {
             bail!("Package name can't be empty")
         };
-        let pkgname = &self.name;
-        let pkgver = &self.version;
-        let arch = &self.arch;
-        let url = format!("https://archive.archlinux.org/packages/{first}/{pkgname}/{pkgname}-{pkgver}-{arch}.pkg.tar.{ext}");
+        let template = if archive.contains('{') {
+            archive.to_string()
+        } else {
+            format!("{}/{ARCHIVE_TEMPLATE}", archive.trim_end_matches('/'))
+        };
+
+        let mut url = String::new();
+        let mut rest = template.as_str();
+        while let Some((before, after)) = rest.split_once('{') {
+            let Some((key, after)) = after.split_once('}') else {
+                bail!("Unterminated placeholder in url template: {archive:?}");
+            };
+            url.push_str(before);
+            match key {
+                "first" => url.push(first),
+                "name" => url.push_str(&self.name),
+                "version" => url.push_str(&self.version),
+                "arch" => url.push_str(&self.arch),
+                // an uncompressed package is a plain `.pkg.tar`
+                "ext" if ext.is_empty() => {
+                    if url.ends_with('.') {
+                        url.pop();
+                    }
+                }
+                "ext" => url.push_str(ext),
+                _ => bail!("Unknown placeholder {key:?} in url template: {archive:?}"),
+            }
+            rest = after;
+        }
+        url.push_str(rest);
         Ok(url)
     }
 }
 
+/// Parse a `desc` file from the local pacman database
+pub fn parse_desc(desc: &str) -> Option<Package> {
+    let mut name = None;
+    let mut version = None;
+    let mut arch = None;
+
+    for section in desc.split("\n\n") {
+        let section = section.split('\n').collect::<Vec<_>>();
+
+        match (section.first(), section.len()) {
+            (Some(&"%NAME%"), 2) => name = Some(section[1]),
+            (Some(&"%VERSION%"), 2) => version = Some(section[1]),
+            (Some(&"%ARCH%"), 2) => arch = Some(section[1]),
+            _ => (),
+        }
+    }
+
+    Some(Package {
+        name: name?.to_string(),
+        version: version?.to_string(),
+        arch: arch?.to_string(),
+    })
+}
+
+/// Check if a path (relative to the root) is a `desc` file in the local pacman database
+pub fn is_local_desc(dbpath: &Path, path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == "desc")
+        && path
+            .parent()
+            .and_then(Path::parent)
+            .is_some_and(|dir| dir == dbpath.join("local"))
+}
+
+/// Parse the `%FILES%` section of a `files` file from the local pacman database
+pub fn parse_files(files: &str) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for section in files.split("\n\n") {
+        let mut lines = section.lines();
+        if lines.next() == Some("%FILES%") {
+            paths.extend(lines.map(PathBuf::from));
+        }
+    }
+    paths
+}
+
+/// Parse the `%BACKUP%` section of a `files` file, the config files that are expected to be modified
+pub fn parse_backup(files: &str) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for section in files.split("\n\n") {
+        let mut lines = section.lines();
+        if lines.next() == Some("%BACKUP%") {
+            // each line is the path and the md5 of the file as it was installed
+            paths.extend(lines.filter_map(|line| Some(PathBuf::from(line.split_once('\t')?.0))));
+        }
+    }
+    paths
+}
+
+/// Write the packages (deduplicated) as `name=version` lines, ready for `pacman -S --overwrite '*' $(cat FILE)`
+pub async fn write_remediation<'a>(
+    path: &Path,
+    pkgs: impl IntoIterator<Item = &'a Package>,
+) -> Result<()> {
+    let pkgs = pkgs
+        .into_iter()
+        .map(|pkg| format!("{}={}\n", pkg.name, pkg.version))
+        .collect::<BTreeSet<_>>();
+    info!(
+        "Writing {} packages for remediation to {path:?}",
+        pkgs.len()
+    );
+    let content = pkgs.into_iter().collect::<String>();
+    fs::write(path, content)
+        .await
+        .with_context(|| anyhow!("Failed to write remediation list: {path:?}"))
+}
+
+/// All paths owned by installed packages (relative to the root) and their package
+pub async fn list_file_owners(path: &Path) -> Result<HashMap<PathBuf, Package>> {
+    let mut owners = HashMap::new();
+    let mut entries = WalkDir::new(path.join("local"));
+    while let Some(entry) = entries.next().await {
+        let entry = entry.context("Failed to read from pacman database")?;
+        if entry.file_name().to_str() != Some("files") {
+            continue;
+        }
+        let path = entry.path();
+        let files = fs::read_to_string(&path)
+            .await
+            .with_context(|| anyhow!("Failed to read file: {path:?}"))?;
+        let desc = path.with_file_name("desc");
+        let desc = fs::read_to_string(&desc)
+            .await
+            .with_context(|| anyhow!("Failed to read file: {desc:?}"))?;
+        let Some(pkg) = parse_desc(&desc) else {
+            warn!("Failed to parse package description: {path:?}");
+            continue;
+        };
+        for file in parse_files(&files) {
+            owners.insert(file, pkg.clone());
+        }
+    }
+    Ok(owners)
+}
+
+/// The backup files of all installed packages (relative to the root)
+pub async fn list_backup_files(path: &Path) -> Result<HashSet<PathBuf>> {
+    let mut backup = HashSet::new();
+    let mut entries = WalkDir::new(path.join("local"));
+    while let Some(entry) = entries.next().await {
+        let entry = entry.context("Failed to read from pacman database")?;
+        if entry.file_name().to_str() != Some("files") {
+            continue;
+        }
+        let path = entry.path();
+        let files = fs::read_to_string(&path)
+            .await
+            .with_context(|| anyhow!("Failed to read file: {path:?}"))?;
+        backup.extend(parse_backup(&files));
+    }
+    Ok(backup)
+}
+
 pub fn list_installed(path: &Path) -> impl Stream<Item = Result<Package>> {
     let path = path.join("local");
 
@@ -54,44 +224,47 @@ pub fn list_installed(path: &Path) -> impl Stream<Item = Result<Package>> {
             let desc = fs::read_to_string(&path).await
                 .with_context(|| anyhow!("Failed to read file: {path:?}"))?;
 
-            let mut name = None;
-            let mut version = None;
-            let mut arch = None;
-
-            for section in desc.split("\n\n") {
-                let section = section.split('\n').collect::<Vec<_>>();
-
-                match (section.first(), section.len()) {
-                    (Some(&"%NAME%"), 2) => name = Some(section[1]),
-                    (Some(&"%VERSION%"), 2) => version = Some(section[1]),
-                    (Some(&"%ARCH%"), 2) => arch = Some(section[1]),
-                    _ => (),
-                }
-            }
-
-            if let (Some(name), Some(version), Some(arch)) = (name, version, arch) {
-                yield Ok(Package {
-                    name: name.to_string(),
-                    version: version.to_string(),
-                    arch: arch.to_string(),
-                })
+            if let Some(pkg) = parse_desc(&desc) {
+                yield Ok(pkg);
             }
         }
     }
 }
 
+/// Parse a list of package names, one per line, empty lines and `#` comments are ignored
+pub fn parse_names(list: &str) -> HashSet<String> {
+    list.lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Read the installed packages and queue them, only the `selected` packages if it's set
 pub fn spawn_list_installed(
     event_tx: mpsc::UnboundedSender<Event>,
     tx: mpsc::UnboundedSender<Package>,
     dbpath: PathBuf,
+    selected: Option<HashSet<String>>,
+    shutdown: CancellationToken,
 ) {
     tokio::spawn(async move {
         let s = list_installed(&dbpath);
         pin_mut!(s);
+        let all = selected.is_none();
+        let mut selected = selected.unwrap_or_default();
 
-        while let Some(pkg) = s.next().await {
+        loop {
+            let pkg = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                pkg = s.next() => pkg,
+            };
+            let Some(pkg) = pkg else { break };
             match pkg {
                 Ok(pkg) => {
+                    if !all && !selected.remove(&pkg.name) {
+                        continue;
+                    }
                     debug!("Found installed package: {:?} {:?}", pkg.name, pkg.version);
                     if event_tx.send(Event::PkgQueued).is_err() {
                         break;
@@ -104,7 +277,65 @@ pub fn spawn_list_installed(
                 Err(err) => warn!("Failed to read installed packages: {err:#?}"),
             }
         }
+        for name in selected {
+            warn!("Selected package {name:?} is not installed");
+        }
 
         event_tx.send(Event::CompletedListInstalled).ok();
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn package_url() {
+        let pkg = Package {
+            name: "openssh".to_string(),
+            version: "9.8p1-1".to_string(),
+            arch: "x86_64".to_string(),
+        };
+        assert_eq!(
+            pkg.to_url("https://archive.archlinux.org/", "zst").unwrap(),
+            "https://archive.archlinux.org/packages/o/openssh/openssh-9.8p1-1-x86_64.pkg.tar.zst"
+        );
+        assert_eq!(
+            pkg.to_url(
+                "http://mirror.archlinuxarm.org/{arch}/core/{name}-{version}-{arch}.pkg.tar.{ext}",
+                "xz"
+            )
+            .unwrap(),
+            "http://mirror.archlinuxarm.org/x86_64/core/openssh-9.8p1-1-x86_64.pkg.tar.xz"
+        );
+        assert_eq!(
+            pkg.to_url("https://archive.archlinux.org/", "").unwrap(),
+            "https://archive.archlinux.org/packages/o/openssh/openssh-9.8p1-1-x86_64.pkg.tar"
+        );
+        assert_eq!(pkg.file_name("gz"), "openssh-9.8p1-1-x86_64.pkg.tar.gz");
+        assert!(pkg.to_url("https://mirror/{repo}/{name}", "zst").is_err());
+        assert!(pkg.to_url("https://mirror/{name", "zst").is_err());
+    }
+
+    #[test]
+    fn parse_backup_files() {
+        let files = "%FILES%\netc/\netc/pacman.conf\nusr/bin/pacman\n\n%BACKUP%\netc/pacman.conf\t2a4d7e4ae6f3bc8d6c6bd5c2a8a3d4e1\netc/makepkg.conf\t8c1bd5ad9a2a5b7ac0ae7ab8fb6b0a39\n\n";
+        assert_eq!(parse_files(files).len(), 3);
+        assert_eq!(
+            parse_backup(files),
+            vec![
+                PathBuf::from("etc/pacman.conf"),
+                PathBuf::from("etc/makepkg.conf")
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_package_names() {
+        let names = parse_names("# suspected\nopenssh\n\n  systemd  # init\n");
+        assert_eq!(
+            names,
+            HashSet::from(["openssh".to_string(), "systemd".to_string()])
+        );
+    }
+}
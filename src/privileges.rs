@@ -0,0 +1,348 @@
+use crate::errors::*;
+use crate::filter::PathFilter;
+use crate::hashes::TrustedHashes;
+use crate::report::Entry;
+use std::collections::{HashMap, HashSet};
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// The setuid and setgid bits of a file mode
+pub const PRIVILEGED_MODE: u32 = 0o6000;
+/// Names of the capabilities by their bit, like `capability.h`
+const CAPABILITIES: &[&str] = &[
+    "cap_chown",
+    "cap_dac_override",
+    "cap_dac_read_search",
+    "cap_fowner",
+    "cap_fsetid",
+    "cap_kill",
+    "cap_setgid",
+    "cap_setuid",
+    "cap_setpcap",
+    "cap_linux_immutable",
+    "cap_net_bind_service",
+    "cap_net_broadcast",
+    "cap_net_admin",
+    "cap_net_raw",
+    "cap_ipc_lock",
+    "cap_ipc_owner",
+    "cap_sys_module",
+    "cap_sys_rawio",
+    "cap_sys_chroot",
+    "cap_sys_ptrace",
+    "cap_sys_pacct",
+    "cap_sys_admin",
+    "cap_sys_boot",
+    "cap_sys_nice",
+    "cap_sys_resource",
+    "cap_sys_time",
+    "cap_sys_tty_config",
+    "cap_mknod",
+    "cap_lease",
+    "cap_audit_write",
+    "cap_audit_control",
+    "cap_setfcap",
+    "cap_mac_override",
+    "cap_mac_admin",
+    "cap_syslog",
+    "cap_wake_alarm",
+    "cap_block_suspend",
+    "cap_audit_read",
+    "cap_perfmon",
+    "cap_bpf",
+    "cap_checkpoint_restore",
+];
+
+#[derive(Debug)]
+pub enum Finding {
+    /// A file with setuid/setgid bits or capabilities that isn't owned by any package
+    Untracked(PathBuf, Vec<String>),
+    /// A file of a package with privileges that its package doesn't call for
+    Unexpected(PathBuf, Vec<String>),
+    Error(PathBuf, Error),
+}
+
+impl From<Finding> for Entry {
+    fn from(finding: Finding) -> Self {
+        match finding {
+            Finding::Untracked(path, details) => {
+                let mut entry = Entry::path("PRIVILEGED UNTRACKED", path);
+                entry.details = details;
+                entry
+            }
+            Finding::Unexpected(path, details) => {
+                let mut entry = Entry::path("PRIVILEGED UNEXPECTED", path);
+                entry.details = details;
+                entry
+            }
+            Finding::Error(path, err) => {
+                Entry::path("PRIVILEGES ERROR", path).detail(format!("{err:#}"))
+            }
+        }
+    }
+}
+
+/// What the packages call for, the files that are expected to be privileged
+#[derive(Debug, Default)]
+pub struct Expected {
+    /// The setuid/setgid bits of packaged files, from their trusted `.MTREE`
+    pub modes: HashMap<PathBuf, u32>,
+    /// Files that an install script grants capabilities with `setcap`. The install scripts are
+    /// read from the local pacman database and are only as trustworthy as the investigated system
+    pub capabilities: HashSet<PathBuf>,
+}
+
+/// Format the `security.capability` xattr like `getcap`, e.g. `cap_net_raw=ep`
+pub fn decode_capability(data: &[u8]) -> Option<String> {
+    let word = |offset: usize| -> Option<u64> {
+        let bytes = data.get(offset..offset + 4)?;
+        Some(u32::from_le_bytes(bytes.try_into().ok()?) as u64)
+    };
+    let magic = word(0)?;
+    let (mut permitted, mut inheritable) = (word(4)?, word(8)?);
+    // version 2 and 3 have the upper 32 capabilities in a second pair
+    if magic >> 24 >= 2 {
+        permitted |= word(12)? << 32;
+        inheritable |= word(16)? << 32;
+    }
+    let names = (0..64)
+        .filter(|bit| (permitted | inheritable) & (1 << bit) != 0)
+        .map(|bit| match CAPABILITIES.get(bit) {
+            Some(name) => name.to_string(),
+            None => format!("cap_{bit}"),
+        })
+        .collect::<Vec<_>>();
+    let mut flags = String::new();
+    if magic & 1 != 0 {
+        flags.push('e');
+    }
+    if inheritable != 0 {
+        flags.push('i');
+    }
+    if permitted != 0 {
+        flags.push('p');
+    }
+    Some(format!("{}={flags}", names.join(",")))
+}
+
+/// Read the `security.capability` xattr of a file, `None` if it has no capabilities
+fn read_capability(path: &Path) -> io::Result<Option<Vec<u8>>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = [0u8; 32];
+    let ret = unsafe {
+        libc::lgetxattr(
+            c_path.as_ptr(),
+            c"security.capability".as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+        )
+    };
+    if ret < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENODATA) | Some(libc::ENOTSUP) => Ok(None),
+            _ => Err(err),
+        };
+    }
+    Ok(Some(buf[..ret as usize].to_vec()))
+}
+
+/// The paths an install script grants capabilities to, relative to the root
+pub fn parse_setcap(script: &str) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for line in script.lines() {
+        let mut words = line
+            .split_whitespace()
+            .skip_while(|word| !word.ends_with("setcap"));
+        if words.next().is_none() {
+            continue;
+        }
+        for word in words {
+            // redirections and the next command
+            if word.starts_with(['>', '<', '|', '&', ';']) || word.starts_with("2>") {
+                break;
+            }
+            let word = word.trim_matches(|c| c == '\'' || c == '"' || c == ';');
+            // options and the capabilities like `cap_net_raw+ep`
+            if word.starts_with('-') || word.contains(['=', '+']) || !word.contains('/') {
+                continue;
+            }
+            paths.push(PathBuf::from(word.trim_start_matches('/')));
+        }
+    }
+    paths
+}
+
+/// Collect the files that install scripts in the local pacman database grant capabilities to
+pub fn read_install_scripts(root: &Path, dbpath: &Path) -> HashSet<PathBuf> {
+    let mut capabilities = HashSet::new();
+    let Ok(dir) = fs::read_dir(dbpath.join("local")) else {
+        return capabilities;
+    };
+    for entry in dir.flatten() {
+        let Ok(script) = fs::read_to_string(entry.path().join("install")) else {
+            continue;
+        };
+        capabilities.extend(
+            parse_setcap(&script)
+                .into_iter()
+                .map(|path| root.join(path)),
+        );
+    }
+    capabilities
+}
+
+/// Walk the filesystem for files with setuid/setgid bits or capabilities, and report the
+/// ones that are untracked or more privileged than their package calls for. This blocks
+pub fn check(
+    paths: &[PathBuf],
+    excluded: &HashSet<PathBuf>,
+    filter: &PathFilter,
+    trusted: &TrustedHashes,
+    expected: &Expected,
+) -> Vec<Finding> {
+    let mut findings = Vec::new();
+    for path in paths {
+        let walk = WalkDir::new(path).into_iter().filter_entry(|entry| {
+            !excluded.contains(entry.path())
+                && (!entry.file_type().is_dir() || filter.is_walked(entry.path()))
+        });
+        for entry in walk {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    // these have been reported by the disk scan already
+                    debug!("Failed to read directory entry: {err:#}");
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() || !filter.is_included(entry.path()) {
+                continue;
+            }
+            let path = entry.path();
+            let mode = match entry.metadata() {
+                Ok(metadata) => metadata.permissions().mode(),
+                Err(err) => {
+                    debug!("Failed to read metadata of {path:?}: {err:#}");
+                    continue;
+                }
+            };
+            let capability = match read_capability(path) {
+                Ok(capability) => capability,
+                Err(err) => {
+                    findings.push(Finding::Error(
+                        path.to_owned(),
+                        Error::from(err).context("Failed to read capabilities"),
+                    ));
+                    continue;
+                }
+            };
+            if mode & PRIVILEGED_MODE == 0 && capability.is_none() {
+                continue;
+            }
+
+            let mut privileges = Vec::new();
+            let mut unexpected = Vec::new();
+            if mode & PRIVILEGED_MODE != 0 {
+                let detail = format!("mode: {:o}", mode & 0o7777);
+                let allowed = expected.modes.get(path).copied().unwrap_or_default();
+                if mode & PRIVILEGED_MODE & !allowed != 0 {
+                    unexpected.push(format!("{detail}, not set by the package"));
+                }
+                privileges.push(detail);
+            }
+            if let Some(capability) = &capability {
+                let detail = decode_capability(capability)
+                    .unwrap_or_else(|| "invalid security.capability".to_string());
+                if !expected.capabilities.contains(path) {
+                    unexpected.push(detail.clone());
+                }
+                privileges.push(detail);
+            }
+
+            if !trusted.contains_key(path) {
+                findings.push(Finding::Untracked(path.to_owned(), privileges));
+            } else if !unexpected.is_empty() {
+                findings.push(Finding::Unexpected(path.to_owned(), unexpected));
+            }
+        }
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn privileged_files() {
+        // cap_net_raw=ep, version 2
+        let mut xattr = vec![0x01, 0x00, 0x00, 0x02];
+        xattr.extend((1u32 << 13).to_le_bytes());
+        xattr.extend([0; 12]);
+        assert_eq!(decode_capability(&xattr).unwrap(), "cap_net_raw=ep");
+        assert_eq!(decode_capability(&xattr[..6]), None);
+
+        assert_eq!(
+            parse_setcap("post_install() {\n  setcap 'cap_net_raw+ep' usr/bin/mtr-packet 2> /dev/null\n  /usr/bin/setcap cap_ipc_lock=ep /usr/bin/gnome-keyring-daemon\n}\n"),
+            vec![
+                PathBuf::from("usr/bin/mtr-packet"),
+                PathBuf::from("usr/bin/gnome-keyring-daemon"),
+            ]
+        );
+
+        let dir = std::env::temp_dir().join(format!("privileges-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("usr/bin")).unwrap();
+        for (name, mode) in [("sudo", 0o4755), ("backdoor", 0o4755), ("ls", 0o755)] {
+            let path = dir.join("usr/bin").join(name);
+            fs::write(&path, b"").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        let trusted = ["sudo", "ls"]
+            .iter()
+            .map(|name| (dir.join("usr/bin").join(name), String::new()))
+            .collect::<TrustedHashes>();
+        let mut expected = Expected::default();
+        expected.modes.insert(dir.join("usr/bin/sudo"), 0o4000);
+        let filter = PathFilter::new(dir.clone(), Vec::new(), Vec::new());
+        let findings = check(
+            std::slice::from_ref(&dir),
+            &HashSet::new(),
+            &filter,
+            &trusted,
+            &expected,
+        );
+        let entries = findings.into_iter().map(Entry::from).collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            vec![
+                Entry::path("PRIVILEGED UNTRACKED", dir.join("usr/bin/backdoor"))
+                    .detail("mode: 4755")
+            ]
+        );
+
+        // the package doesn't call for setuid anymore
+        expected.modes.clear();
+        let findings = check(
+            std::slice::from_ref(&dir),
+            &HashSet::new(),
+            &filter,
+            &trusted,
+            &expected,
+        );
+        let mut entries = findings.into_iter().map(Entry::from).collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.kind.cmp(&b.kind));
+        assert_eq!(
+            entries[0],
+            Entry::path("PRIVILEGED UNEXPECTED", dir.join("usr/bin/sudo"))
+                .detail("mode: 4755, not set by the package")
+        );
+
+        fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,303 @@
+use crate::errors::*;
+use crate::sandbox;
+use caps::CapSet;
+use std::io;
+use std::net::TcpListener as StdTcpListener;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Largest request head that is accepted from the scanner process
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// The Arch Linux Archive redirects older packages to archive.org
+pub const ARCHIVE_REDIRECT_HOST: &str = "archive.org";
+
+/// The hosts the fetcher process connects to, including their subdomains
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Allowed {
+    hosts: Vec<String>,
+}
+
+impl Allowed {
+    pub fn add_host(&mut self, host: &str) {
+        let host = host.to_lowercase();
+        if !self.hosts.contains(&host) {
+            self.hosts.push(host);
+        }
+    }
+
+    /// Add the host of an `http(s)://` url, like a mirror or an api endpoint
+    pub fn add_url(&mut self, url: &str) {
+        let Some((_, rest)) = url.split_once("://") else {
+            return;
+        };
+        let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+        let authority = authority.rsplit('@').next().unwrap_or_default();
+        if let Some((host, _)) = split_host_port(authority) {
+            self.add_host(host);
+        } else if !authority.is_empty() {
+            self.add_host(authority.trim_start_matches('[').trim_end_matches(']'));
+        }
+    }
+
+    pub fn contains(&self, host: &str) -> bool {
+        let host = host.to_lowercase();
+        self.hosts.iter().any(|allowed| {
+            host == *allowed
+                || host
+                    .strip_suffix(allowed.as_str())
+                    .is_some_and(|sub| sub.ends_with('.'))
+        })
+    }
+}
+
+/// Split `host:port` (or `[::1]:port`), the brackets of ipv6 addresses are removed
+fn split_host_port(authority: &str) -> Option<(&str, u16)> {
+    let (host, port) = authority.rsplit_once(':')?;
+    let port = port.parse().ok()?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']')?,
+        None if host.contains(':') => return None,
+        None => host,
+    };
+    Some((host, port))
+}
+
+/// What the fetcher does with a request of the scanner process
+#[derive(Debug, PartialEq)]
+pub enum Forward {
+    /// Connect to `host:port` and pass the (TLS) connection through, for https
+    Tunnel { host: String, port: u16 },
+    /// Send a plain http request, rewritten for the origin server
+    Request {
+        host: String,
+        port: u16,
+        head: String,
+    },
+}
+
+impl Forward {
+    fn host(&self) -> &str {
+        match self {
+            Forward::Tunnel { host, .. } | Forward::Request { host, .. } => host,
+        }
+    }
+
+    fn port(&self) -> u16 {
+        match self {
+            Forward::Tunnel { port, .. } | Forward::Request { port, .. } => *port,
+        }
+    }
+}
+
+/// Parse the head of a proxy request, `CONNECT` for https and absolute urls for plain http.
+/// Plain http requests are sent with `Connection: close`, so a connection can't be reused
+/// for requests to other hosts
+pub fn parse_request(head: &str) -> Result<Forward> {
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let parts = request_line.split(' ').collect::<Vec<_>>();
+    let [method, target, version] = parts[..] else {
+        bail!("Invalid request line: {request_line:?}");
+    };
+
+    if method == "CONNECT" {
+        let Some((host, port)) = split_host_port(target) else {
+            bail!("Invalid CONNECT target: {target:?}");
+        };
+        return Ok(Forward::Tunnel {
+            host: host.to_string(),
+            port,
+        });
+    }
+
+    let Some(rest) = target.strip_prefix("http://") else {
+        bail!("Only http urls can be requested without CONNECT: {target:?}");
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => rest.split_at(idx),
+        None => (rest, "/"),
+    };
+    let (host, port) = match split_host_port(authority) {
+        Some((host, port)) => (host, port),
+        None => (authority, 80),
+    };
+    if host.is_empty() || authority.contains('@') {
+        bail!("Invalid url: {target:?}");
+    }
+
+    let mut rewritten = format!("{method} {path} {version}\r\n");
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or_default().to_lowercase();
+        if ["connection", "proxy-connection", "proxy-authorization"].contains(&name.as_str()) {
+            continue;
+        }
+        rewritten.push_str(line);
+        rewritten.push_str("\r\n");
+    }
+    rewritten.push_str("Connection: close\r\n\r\n");
+    Ok(Forward::Request {
+        host: host.to_string(),
+        port,
+        head: rewritten,
+    })
+}
+
+async fn handle(mut client: TcpStream, allowed: &Allowed) -> Result<()> {
+    let mut buf = Vec::new();
+    let head_len = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            bail!("Request head is too large");
+        }
+        let mut chunk = [0u8; 4096];
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let head = std::str::from_utf8(&buf[..head_len]).context("Request head is not utf-8")?;
+    let forward = parse_request(head)?;
+
+    if !allowed.contains(forward.host()) {
+        client.write_all(b"HTTP/1.1 403 Forbidden\r\n\r\n").await?;
+        bail!(
+            "Refused connection to {:?}, it's not in the list of allowed hosts",
+            forward.host()
+        );
+    }
+    let mut upstream = match TcpStream::connect((forward.host(), forward.port())).await {
+        Ok(upstream) => upstream,
+        Err(err) => {
+            client
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n")
+                .await?;
+            return Err(err).with_context(|| anyhow!("Failed to connect to {:?}", forward.host()));
+        }
+    };
+    match &forward {
+        Forward::Tunnel { .. } => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await?;
+        }
+        Forward::Request { head, .. } => upstream.write_all(head.as_bytes()).await?,
+    }
+    upstream.write_all(&buf[head_len..]).await?;
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await?;
+    Ok(())
+}
+
+async fn serve(listener: TcpListener, allowed: Allowed) -> Result<()> {
+    let allowed = Arc::new(allowed);
+    loop {
+        let (stream, _) = listener
+            .accept()
+            .await
+            .context("Failed to accept connection")?;
+        let allowed = allowed.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle(stream, &allowed).await {
+                warn!("Fetcher process: {err:#}");
+            }
+        });
+    }
+}
+
+fn run_fetcher(listener: StdTcpListener, allowed: Allowed, parent: libc::pid_t) -> Result<()> {
+    // exit together with the scanner process, even if it gets killed
+    if unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) } != 0 {
+        let err = io::Error::last_os_error();
+        return Err(err).context("Failed to set parent death signal");
+    }
+    if unsafe { libc::getppid() } != parent {
+        return Ok(());
+    }
+    for set in [CapSet::Effective, CapSet::Permitted] {
+        caps::clear(None, set)
+            .with_context(|| anyhow!("Failed to clear capability set ({set:?})"))?;
+    }
+    // nothing but what name resolution needs, the investigated filesystem isn't reachable
+    sandbox::harden(&sandbox::Policy::default())?;
+
+    listener.set_nonblocking(true)?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to start runtime of fetcher process")?;
+    runtime.block_on(async {
+        let listener = TcpListener::from_std(listener)?;
+        serve(listener, allowed).await
+    })
+}
+
+/// Fork the fetcher process that does all network access of the scan, as a proxy on localhost that
+/// only connects to the allowed hosts. It's sandboxed without access to the filesystem, so data read
+/// from the evidence disk can't be sent anywhere else. This needs to happen before any threads are
+/// started, returns the port of the proxy
+pub fn spawn(allowed: Allowed) -> Result<u16> {
+    let listener = StdTcpListener::bind("127.0.0.1:0").context("Failed to bind fetcher process")?;
+    let port = listener.local_addr()?.port();
+    let parent = unsafe { libc::getpid() };
+    match unsafe { libc::fork() } {
+        -1 => {
+            let err = io::Error::last_os_error();
+            Err(err).context("Failed to fork fetcher process")
+        }
+        0 => {
+            let code = match run_fetcher(listener, allowed, parent) {
+                Ok(()) => 0,
+                Err(err) => {
+                    error!("Fetcher process failed: {err:#}");
+                    1
+                }
+            };
+            std::process::exit(code);
+        }
+        pid => {
+            debug!("Started fetcher process (pid={pid}) on port {port}");
+            Ok(port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_requests() {
+        let mut allowed = Allowed::default();
+        allowed.add_url("https://archive.archlinux.org");
+        allowed.add_url("http://user@Mirror.example:8080/{arch}/{name}");
+        allowed.add_url("http://[::1]:8000/");
+        allowed.add_host(ARCHIVE_REDIRECT_HOST);
+        assert!(allowed.contains("archive.archlinux.org"));
+        assert!(allowed.contains("mirror.example"));
+        assert!(allowed.contains("::1"));
+        assert!(allowed.contains("ia801234.us.archive.org"));
+        assert!(!allowed.contains("evilarchive.org"));
+        assert!(!allowed.contains("archlinux.org"));
+
+        assert_eq!(
+            parse_request("CONNECT archive.archlinux.org:443 HTTP/1.1\r\nHost: archive.archlinux.org:443\r\n\r\n").unwrap(),
+            Forward::Tunnel {
+                host: "archive.archlinux.org".to_string(),
+                port: 443,
+            }
+        );
+        assert_eq!(
+            parse_request("GET http://mirror.example:8080/x86_64/foo.pkg.tar.xz HTTP/1.1\r\nHost: mirror.example:8080\r\nConnection: keep-alive\r\n\r\n").unwrap(),
+            Forward::Request {
+                host: "mirror.example".to_string(),
+                port: 8080,
+                head: "GET /x86_64/foo.pkg.tar.xz HTTP/1.1\r\nHost: mirror.example:8080\r\nConnection: close\r\n\r\n".to_string(),
+            }
+        );
+        assert!(parse_request("GET /relative HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_request("CONNECT archive.archlinux.org HTTP/1.1\r\n\r\n").is_err());
+    }
+}
@@ -0,0 +1,78 @@
+use clap::ValueEnum;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Profile {
+    /// Prioritize and separately report high-value locations, like PATH directories or PAM modules
+    Sensitive,
+}
+
+/// Directories that are commonly used for persistence, relative to the root
+const SENSITIVE_DIRS: &[&str] = &[
+    "usr/bin",
+    "usr/local/bin",
+    "usr/local/sbin",
+    "usr/lib/systemd",
+    "etc/systemd",
+    "usr/lib/security",
+    "etc/pam.d",
+    "usr/lib/modules",
+    "etc/profile.d",
+    "etc/bash",
+    "etc/zsh",
+];
+
+/// Single files that are commonly used for persistence, relative to the root
+const SENSITIVE_FILES: &[&str] = &[
+    "etc/profile",
+    "etc/bash.bashrc",
+    "etc/bash.bash_logout",
+    "etc/environment",
+    "etc/nsswitch.conf",
+    "etc/ld.so.preload",
+];
+
+impl Profile {
+    /// Directories that are scanned before the rest of the filesystem
+    pub fn priority_dirs(&self, root: &Path) -> Vec<PathBuf> {
+        match self {
+            Profile::Sensitive => SENSITIVE_DIRS
+                .iter()
+                .map(|dir| root.join(dir))
+                .filter(|dir| dir.is_dir())
+                .collect(),
+        }
+    }
+
+    /// Check if a path (relative to the root) is in a high-value location
+    pub fn matches(&self, path: &Path) -> bool {
+        match self {
+            Profile::Sensitive => {
+                SENSITIVE_DIRS.iter().any(|dir| path.starts_with(dir))
+                    || SENSITIVE_FILES.iter().any(|file| path == Path::new(file))
+                    // NSS modules are loaded into almost every process
+                    || (path.parent() == Some(Path::new("usr/lib"))
+                        && path
+                            .file_name()
+                            .and_then(|name| name.to_str())
+                            .is_some_and(|name| name.starts_with("libnss_")))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_sensitive_paths() {
+        let profile = Profile::Sensitive;
+        assert!(profile.matches(Path::new("usr/bin/sudo")));
+        assert!(profile.matches(Path::new("usr/lib/security/pam_unix.so")));
+        assert!(profile.matches(Path::new("usr/lib/libnss_files.so.2")));
+        assert!(profile.matches(Path::new("etc/profile")));
+        assert!(!profile.matches(Path::new("usr/lib/libc.so.6")));
+        assert!(!profile.matches(Path::new("usr/share/doc/README")));
+    }
+}
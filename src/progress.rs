@@ -0,0 +1,212 @@
+use crate::scanner::Scan;
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use num_format::{Locale, ToFormattedString};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+const PKGS_TEMPLATE: &str = "{prefix:.bold} [{bar:20.green/white}] {pos}/{len} {msg}";
+const HASHING_TEMPLATE: &str =
+    "{prefix:.bold} [{bar:20.cyan/white}] {binary_bytes}/{binary_total_bytes} ({binary_bytes_per_sec}, ETA {eta}) {msg}";
+const DISK_TEMPLATE: &str = "{prefix:.bold} {wide_msg}";
+
+/// Remove the styles of a template, like `{prefix:.bold}` to `{prefix}`
+fn plain(template: &str) -> String {
+    let mut plain = String::new();
+    let mut rest = template;
+    while let Some((before, after)) = rest.split_once('{') {
+        let (key, after) = after.split_once('}').unwrap_or((after, ""));
+        let key = match key.split_once(':') {
+            // keep the width, like `{bar:20}`
+            Some((name, style)) => match style.split_once('.') {
+                Some((width, _)) if !width.is_empty() => format!("{name}:{width}"),
+                _ => name.to_string(),
+            },
+            None => key.to_string(),
+        };
+        plain.push_str(before);
+        plain.push('{');
+        plain.push_str(&key);
+        plain.push('}');
+        rest = after;
+    }
+    plain.push_str(rest);
+    plain
+}
+
+fn bar(multi: &MultiProgress, prefix: &'static str, template: &str, color: bool) -> ProgressBar {
+    let template = match color {
+        true => template.to_string(),
+        false => plain(template),
+    };
+    let style = ProgressStyle::with_template(&template)
+        .expect("Invalid progress bar template")
+        .progress_chars("=> ");
+    multi.add(ProgressBar::new(0).with_style(style).with_prefix(prefix))
+}
+
+/// Progress bars of the packages and of the hashed bytes, drawn on stderr.
+/// The total size grows while the disk is scanned, so the ETA is only reliable once the scan completed
+pub struct Progress {
+    multi: MultiProgress,
+    pkgs: ProgressBar,
+    hashing: ProgressBar,
+    disk: ProgressBar,
+}
+
+impl Progress {
+    pub fn new(color: bool) -> Self {
+        let multi = MultiProgress::with_draw_target(ProgressDrawTarget::stderr());
+        let pkgs = bar(&multi, "packages", PKGS_TEMPLATE, color);
+        let hashing = bar(&multi, "hashing ", HASHING_TEMPLATE, color);
+        let disk = bar(&multi, "disk    ", DISK_TEMPLATE, color);
+        Progress {
+            multi,
+            pkgs,
+            hashing,
+            disk,
+        }
+    }
+
+    /// Redraw with the current state of the scan
+    pub fn update(&self, scan: &Scan) {
+        self.pkgs.set_length(scan.total_pkgs);
+        self.pkgs.set_position(scan.completed_pkgs);
+        let listing = if scan.running_list_installed {
+            "..."
+        } else {
+            ""
+        };
+        let mut msg = format!(
+            "{listing}(files: {}",
+            scan.trusted_hashes.len().to_formatted_string(&Locale::en)
+        );
+        let transferred = scan.transferred.get();
+        if transferred > 0 {
+            msg.push_str(&format!(", downloaded: {}", HumanBytes(transferred)));
+        }
+        msg.push(')');
+        self.pkgs.set_message(msg);
+
+        // files that were found while hashing was already in progress
+        self.hashing
+            .set_length(scan.bytes_found.max(scan.bytes_hashed));
+        self.hashing.set_position(scan.bytes_hashed);
+        self.hashing.set_message(format!(
+            "[{}/{}] passed={} failed={}",
+            scan.busy_hashers(),
+            scan.num_hash_worker,
+            scan.files_passed.to_formatted_string(&Locale::en),
+            scan.files_flagged.len().to_formatted_string(&Locale::en),
+        ));
+
+        let mut msg = format!(
+            "{} waiting for a trusted hash, {} for a hasher",
+            scan.waiting_for_data.len().to_formatted_string(&Locale::en),
+            scan.waiting_for_hasher
+                .len()
+                .to_formatted_string(&Locale::en),
+        );
+        if let Some(path) = &scan.disk_pwd {
+            msg.push_str(&format!(" ({:?})", path.display()));
+        } else if !scan.running_disk_scan {
+            msg.push_str(" (scan complete)");
+        }
+        self.disk.set_message(msg);
+    }
+
+    /// Leave the bars on screen after the scan
+    pub fn finish(&self) {
+        self.pkgs.finish();
+        self.hashing.finish();
+        self.disk.finish();
+        // the report starts on a new line
+        if !self.multi.is_hidden() {
+            eprintln!();
+        }
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
+
+/// A snapshot of the progress for `--progress-json`, fields are only ever added
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub packages_completed: u64,
+    pub packages_total: u64,
+    /// The list of installed packages is still being read
+    pub listing_packages: bool,
+    pub trusted_files: usize,
+    pub disk_scan_running: bool,
+    /// The directory the disk scan is currently in
+    pub current_dir: Option<PathBuf>,
+    pub waiting_for_trusted_hash: usize,
+    pub waiting_for_hasher: usize,
+    pub hash_workers_busy: usize,
+    pub hash_workers: usize,
+    pub files_hashed: u64,
+    pub files_passed: u64,
+    pub files_flagged: usize,
+    pub disk_errors: usize,
+    pub bytes_found: u64,
+    pub bytes_hashed: u64,
+    /// Bytes that were actually downloaded, packages are only read up to their `.MTREE`
+    pub bytes_downloaded: u64,
+}
+
+impl From<&Scan> for Summary {
+    fn from(scan: &Scan) -> Self {
+        Summary {
+            packages_completed: scan.completed_pkgs,
+            packages_total: scan.total_pkgs,
+            listing_packages: scan.running_list_installed,
+            trusted_files: scan.trusted_hashes.len(),
+            disk_scan_running: scan.running_disk_scan,
+            current_dir: scan.disk_pwd.clone(),
+            waiting_for_trusted_hash: scan.waiting_for_data.len(),
+            waiting_for_hasher: scan.waiting_for_hasher.len(),
+            hash_workers_busy: scan.busy_hashers(),
+            hash_workers: scan.num_hash_worker,
+            files_hashed: scan.files_hashed,
+            files_passed: scan.files_passed,
+            files_flagged: scan.files_flagged.len(),
+            disk_errors: scan.disk_errors.len(),
+            bytes_found: scan.bytes_found,
+            bytes_hashed: scan.bytes_hashed,
+            bytes_downloaded: scan.transferred.get(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn progress_summary_json() {
+        let mut scan = Scan::new(4, false, Default::default());
+        scan.total_pkgs = 3;
+        scan.completed_pkgs = 1;
+        scan.disk_pwd = Some(PathBuf::from("/mnt/usr/bin"));
+        let json = serde_json::to_value(Summary::from(&scan)).unwrap();
+        assert_eq!(json["packages_completed"], 1);
+        assert_eq!(json["packages_total"], 3);
+        assert_eq!(json["listing_packages"], true);
+        assert_eq!(json["current_dir"], "/mnt/usr/bin");
+        assert_eq!(json["hash_workers"], 4);
+        assert_eq!(json["hash_workers_busy"], 4);
+    }
+
+    #[test]
+    fn plain_templates() {
+        assert_eq!(
+            plain(PKGS_TEMPLATE),
+            "{prefix} [{bar:20}] {pos}/{len} {msg}"
+        );
+        assert_eq!(plain(DISK_TEMPLATE), "{prefix} {wide_msg}");
+        assert!(ProgressStyle::with_template(&plain(HASHING_TEMPLATE)).is_ok());
+    }
+}
@@ -0,0 +1,198 @@
+use crate::errors::*;
+use crate::fetch;
+use crate::pkg::{self, Package};
+use futures_util::TryStreamExt;
+use std::collections::HashMap;
+use std::io::Read;
+use tokio::task;
+
+/// Repositories that are loaded if pacman.conf doesn't list any, `community` only exists in snapshots before 2023
+pub const DEFAULT_REPOS: &[&str] = &["core", "extra", "community", "multilib"];
+/// The Arch Linux Archive only keeps repositories of this architecture
+pub const DEFAULT_ARCH: &str = "x86_64";
+
+/// Parse a date of the Arch Linux Archive like `2024/01/31`
+pub fn parse_date(s: &str) -> Result<String> {
+    let parts = s.split('/').collect::<Vec<_>>();
+    let [year, month, day] = parts[..] else {
+        bail!("Expected a date like `2024/01/31`: {s:?}");
+    };
+    for (part, len, max) in [(year, 4, 9999), (month, 2, 12), (day, 2, 31)] {
+        let valid = part.len() == len
+            && part
+                .parse::<u16>()
+                .is_ok_and(|value| (1..=max).contains(&value));
+        if !valid {
+            bail!("Expected a date like `2024/01/31`: {s:?}");
+        }
+    }
+    Ok(s.to_string())
+}
+
+/// The packages of the repository databases of a date snapshot, by name and version
+#[derive(Debug, Default, Clone)]
+pub struct Index {
+    /// Path of the package file relative to the archive, like `repos/2024/01/31/core/os/x86_64/...`
+    packages: HashMap<(String, String), String>,
+}
+
+impl Index {
+    pub fn len(&self) -> usize {
+        self.packages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packages.is_empty()
+    }
+
+    /// Add the packages of a repository database, `dir` is the directory of the
+    /// repository relative to the archive
+    pub fn add(&mut self, dir: &str, packages: Vec<(Package, String)>) {
+        for (pkg, filename) in packages {
+            self.packages
+                .insert((pkg.name, pkg.version), format!("{dir}/{filename}"));
+        }
+    }
+
+    /// The url of a package on an archive (or mirror of it) and its compression,
+    /// `None` if the package wasn't in the repositories at that date
+    pub fn url<'a>(&'a self, archive: &str, pkg: &Package) -> Option<(String, &'a str)> {
+        let path = self
+            .packages
+            .get(&(pkg.name.clone(), pkg.version.clone()))?;
+        let ext = match path.rsplit_once(".pkg.tar") {
+            Some((_, ext)) => ext.trim_start_matches('.'),
+            None => "",
+        };
+        Some((format!("{}/{path}", archive.trim_end_matches('/')), ext))
+    }
+}
+
+/// Read the `desc` files of a (gzip or zstd compressed) repository database,
+/// as the package and the file name of the package
+pub fn parse_db(db: &[u8]) -> Result<Vec<(Package, String)>> {
+    let reader: Box<dyn Read> = if db.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(zstd::stream::read::Decoder::new(db)?)
+    } else {
+        Box::new(flate2::read::GzDecoder::new(db))
+    };
+    let mut tar = tar::Archive::new(reader);
+    let mut packages = Vec::new();
+    for entry in tar.entries()? {
+        let mut entry = entry.context("Failed to read entry from repository database")?;
+        let path = entry.path()?;
+        if path.file_name().is_none_or(|name| name != "desc") {
+            continue;
+        }
+        let mut desc = String::new();
+        entry
+            .read_to_string(&mut desc)
+            .context("Failed to read desc from repository database")?;
+        let Some(pkg) = pkg::parse_desc(&desc) else {
+            continue;
+        };
+        let Some(filename) = parse_filename(&desc) else {
+            continue;
+        };
+        packages.push((pkg, filename.to_string()));
+    }
+    Ok(packages)
+}
+
+fn parse_filename(desc: &str) -> Option<&str> {
+    desc.split("\n\n").find_map(|section| {
+        let section = section.split('\n').collect::<Vec<_>>();
+        match section[..] {
+            ["%FILENAME%", filename] => Some(filename),
+            _ => None,
+        }
+    })
+}
+
+/// Download the repository databases of a date snapshot from the first archive that has them,
+/// repositories that don't exist at that date (like custom ones) are skipped
+pub async fn load(
+    client: &reqwest::Client,
+    archives: &[String],
+    date: &str,
+    repos: &[String],
+    arch: &str,
+    retry: &fetch::Retry,
+) -> Result<Index> {
+    let mut index = Index::default();
+    for repo in repos {
+        let dir = format!("repos/{date}/{repo}/os/{arch}");
+        let mut db = None;
+        for archive in archives {
+            let url = format!("{}/{dir}/{repo}.db", archive.trim_end_matches('/'));
+            match fetch::download_package(client, &url, retry).await {
+                Ok(Some(body)) => {
+                    db = Some(body.try_collect::<Vec<_>>().await?.concat());
+                    break;
+                }
+                Ok(None) => (),
+                Err(err) => warn!("Failed to download repository database {url:?}: {err:#}"),
+            }
+        }
+        let Some(db) = db else {
+            warn!("Repository {repo:?} doesn't exist in the snapshot of {date}");
+            continue;
+        };
+        let packages = task::spawn_blocking(move || parse_db(&db))
+            .await?
+            .with_context(|| anyhow!("Failed to read repository database of {repo:?}"))?;
+        info!(
+            "Loaded {} packages of {repo:?} from the snapshot of {date}",
+            packages.len()
+        );
+        index.add(&dir, packages);
+    }
+    if index.is_empty() {
+        bail!("Failed to load any repository of the snapshot of {date}");
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn resolve_through_repo_db() {
+        assert_eq!(parse_date("2024/01/31").unwrap(), "2024/01/31");
+        assert!(parse_date("2024-01-31").is_err());
+        assert!(parse_date("2024/13/01").is_err());
+        assert!(parse_date("2024/1/31").is_err());
+
+        let mut tar = tar::Builder::new(Vec::new());
+        let desc = b"%FILENAME%\nopenssh-9.8p1-1-x86_64.pkg.tar.zst\n\n%NAME%\nopenssh\n\n%VERSION%\n9.8p1-1\n\n%ARCH%\nx86_64\n\n";
+        let mut header = tar::Header::new_gnu();
+        header.set_size(desc.len() as u64);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, "openssh-9.8p1-1/desc", &desc[..])
+            .unwrap();
+        let mut db = flate2::write::GzEncoder::new(Vec::new(), Default::default());
+        db.write_all(&tar.into_inner().unwrap()).unwrap();
+        let db = db.finish().unwrap();
+
+        let packages = parse_db(&db).unwrap();
+        assert_eq!(packages.len(), 1);
+        let mut index = Index::default();
+        index.add("repos/2024/08/01/core/os/x86_64", packages);
+        let mut pkg = Package {
+            name: "openssh".to_string(),
+            version: "9.8p1-1".to_string(),
+            arch: "x86_64".to_string(),
+        };
+        assert_eq!(
+            index.url("https://archive.archlinux.org/", &pkg),
+            Some((
+                "https://archive.archlinux.org/repos/2024/08/01/core/os/x86_64/openssh-9.8p1-1-x86_64.pkg.tar.zst".to_string(),
+                "zst"
+            ))
+        );
+        pkg.version = "9.7p1-2".to_string();
+        assert_eq!(index.url("https://archive.archlinux.org/", &pkg), None);
+    }
+}
@@ -0,0 +1,656 @@
+use crate::errors::*;
+use crate::pkg::Package;
+use clap::ValueEnum;
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+
+/// Version of the json report, increased on incompatible changes
+pub const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+    /// One json object per finding and line, for streaming into other tools
+    Jsonl,
+    /// Lines in the style of `pacman -Qkk`, for scripts built around it
+    PacmanQkk,
+    /// A SARIF 2.1.0 log, for code scanning dashboards and other security tooling
+    Sarif,
+}
+
+/// A single line of the report
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    /// The hash of the file as found on disk
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The trusted hash the file was compared against, other algorithms than sha256 are prefixed like `md5:`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    /// Name of the package owning the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    /// Version of the package owning the file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub package_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<String>,
+    /// A unified diff against the file of the package, for modified text files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub diff: Option<String>,
+}
+
+impl Entry {
+    pub fn new(kind: impl Into<String>, path: Option<PathBuf>) -> Self {
+        Entry {
+            kind: kind.into(),
+            path,
+            sha256: None,
+            expected: None,
+            package: None,
+            package_version: None,
+            details: Vec::new(),
+            diff: None,
+        }
+    }
+
+    pub fn path(kind: impl Into<String>, path: impl Into<PathBuf>) -> Self {
+        Self::new(kind, Some(path.into()))
+    }
+
+    pub fn set_package(&mut self, pkg: Option<&Package>) {
+        self.package = pkg.map(|pkg| pkg.name.clone());
+        self.package_version = pkg.map(|pkg| pkg.version.clone());
+    }
+
+    pub fn detail(mut self, detail: impl ToString) -> Self {
+        self.details.push(detail.to_string());
+        self
+    }
+
+    /// Format as `pacman -Qkk` warnings, files without an owner are written without a package name
+    pub fn pacman_qkk(&self) -> Vec<String> {
+        let reasons = match self.kind.trim_start_matches("SENSITIVE ") {
+            "WRONG SHA256" => vec!["SHA256 checksum mismatch".to_string()],
+            "WRONG SIZE" => vec!["Size mismatch".to_string()],
+            "UNTRACKED" | "NO SHA256" => vec!["Not owned by any package".to_string()],
+            "MISSING FILE" => vec!["No such file or directory".to_string()],
+            "TYPE CHANGED" => vec!["File type mismatch".to_string()],
+            "WRONG METADATA" => self
+                .details
+                .iter()
+                .flat_map(|detail| detail.split(", "))
+                .map(|diff| match diff.split_once(':').map(|(field, _)| field) {
+                    Some("mode") => "Permissions mismatch".to_string(),
+                    Some("uid") => "UID mismatch".to_string(),
+                    Some("gid") => "GID mismatch".to_string(),
+                    Some("link") => "Symlink path mismatch".to_string(),
+                    _ => diff.to_string(),
+                })
+                .collect(),
+            kind => vec![kind.to_string()],
+        };
+        let path = match &self.path {
+            Some(path) => path.display().to_string(),
+            None => self.details.join(", "),
+        };
+        reasons
+            .into_iter()
+            .map(|reason| match &self.package {
+                Some(package) => format!("warning: {package}: {path} ({reason})"),
+                None => format!("warning: {path} ({reason})"),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "[{}]", self.kind)?;
+        if let Some(path) = &self.path {
+            write!(w, " {path:?}")?;
+            if let Some(package) = &self.package {
+                match &self.package_version {
+                    Some(version) => write!(w, " (package: {package}-{version})")?,
+                    None => write!(w, " (package: {package})")?,
+                }
+            }
+            for detail in &self.details {
+                write!(w, " ({detail})")?;
+            }
+        } else {
+            for detail in &self.details {
+                write!(w, " {detail}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A line of the jsonl report, every finding carries the version and root of the report
+#[derive(Debug, Serialize)]
+struct Line<'a> {
+    version: u32,
+    root: &'a Path,
+    #[serde(flatten)]
+    entry: &'a Entry,
+}
+
+fn format_version() -> u32 {
+    FORMAT_VERSION
+}
+
+/// A phase of the scan, the phases run concurrently so each is timed from the start of the scan
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Phase {
+    pub name: String,
+    /// Seconds since the start of the scan until the phase completed
+    pub seconds: f64,
+}
+
+/// Statistics of the scan and how it was invoked, so the report can be audited on its own
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Summary {
+    pub tool_version: String,
+    pub command_line: Vec<String>,
+    pub packages_total: u64,
+    pub packages_untrusted: usize,
+    /// Files that were compared against a trusted hash
+    pub files_verified: u64,
+    pub files_hashed: u64,
+    pub bytes_hashed: u64,
+    pub bytes_downloaded: u64,
+    pub phases: Vec<Phase>,
+    pub duration_secs: f64,
+    /// Number of findings by their kind
+    pub findings: BTreeMap<String, usize>,
+}
+
+impl Summary {
+    pub fn count_findings<'a>(&mut self, entries: impl IntoIterator<Item = &'a Entry>) {
+        for entry in entries {
+            *self.findings.entry(entry.kind.clone()).or_default() += 1;
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        let command_line = self
+            .command_line
+            .iter()
+            .map(|arg| {
+                if arg.is_empty()
+                    || arg.contains(|c: char| c.is_whitespace() || "'\"\\$".contains(c))
+                {
+                    format!("{arg:?}")
+                } else {
+                    arg.clone()
+                }
+            })
+            .collect::<Vec<_>>();
+        writeln!(w, "# version: {}", self.tool_version)?;
+        writeln!(w, "# command line: {}", command_line.join(" "))?;
+        writeln!(
+            w,
+            "# packages: {} ({} without trusted source)",
+            self.packages_total, self.packages_untrusted
+        )?;
+        writeln!(
+            w,
+            "# files: {} verified, {} hashed ({}), downloaded: {}",
+            self.files_verified,
+            self.files_hashed,
+            HumanBytes(self.bytes_hashed),
+            HumanBytes(self.bytes_downloaded)
+        )?;
+        let phases = self
+            .phases
+            .iter()
+            .map(|phase| format!("{} at {:.1}s", phase.name, phase.seconds))
+            .collect::<Vec<_>>();
+        writeln!(
+            w,
+            "# duration: {:.1}s ({})",
+            self.duration_secs,
+            phases.join(", ")
+        )?;
+        let findings = self
+            .findings
+            .iter()
+            .map(|(kind, count)| format!("{count} {kind}"))
+            .collect::<Vec<_>>();
+        if findings.is_empty() {
+            writeln!(w, "# findings: none")
+        } else {
+            writeln!(w, "# findings: {}", findings.join(", "))
+        }
+    }
+}
+
+/// The structured results of a scan
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Report {
+    /// The [`FORMAT_VERSION`] of the report, reports written before it was added are version 1
+    #[serde(default = "format_version")]
+    pub version: u32,
+    pub root: PathBuf,
+    pub entries: Vec<Entry>,
+    /// Statistics of the scan, only the full report of the command line tool has them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<Summary>,
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Report {
+            version: FORMAT_VERSION,
+            root: PathBuf::new(),
+            entries: Vec::new(),
+            summary: None,
+        }
+    }
+}
+
+impl Report {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let buf = fs::read(path)
+            .await
+            .with_context(|| anyhow!("Failed to read report: {path:?}"))?;
+        let report = serde_json::from_slice::<Report>(&buf)
+            .with_context(|| anyhow!("Failed to parse report: {path:?}"))?;
+        if report.version > FORMAT_VERSION {
+            bail!(
+                "Report {path:?} has format version {}, this version only supports up to {FORMAT_VERSION}",
+                report.version
+            );
+        }
+        Ok(report)
+    }
+
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W, format: Format) -> Result<()> {
+        match format {
+            Format::Text => {
+                for entry in &self.entries {
+                    writer
+                        .write_all(format!("{entry}\n").as_bytes())
+                        .await
+                        .context("Failed to write report")?;
+                    if let Some(diff) = &entry.diff {
+                        writer
+                            .write_all(indent(diff).as_bytes())
+                            .await
+                            .context("Failed to write report")?;
+                    }
+                }
+                if let Some(summary) = &self.summary {
+                    writer
+                        .write_all(summary.to_string().as_bytes())
+                        .await
+                        .context("Failed to write report")?;
+                }
+            }
+            Format::PacmanQkk => {
+                for line in self.entries.iter().flat_map(Entry::pacman_qkk) {
+                    writer
+                        .write_all(format!("{line}\n").as_bytes())
+                        .await
+                        .context("Failed to write report")?;
+                }
+            }
+            Format::Json => {
+                let mut buf = serde_json::to_vec_pretty(self)?;
+                buf.push(b'\n');
+                writer
+                    .write_all(&buf)
+                    .await
+                    .context("Failed to write report")?;
+            }
+            Format::Sarif => {
+                let mut buf = serde_json::to_vec_pretty(&crate::sarif::to_sarif(self))?;
+                buf.push(b'\n');
+                writer
+                    .write_all(&buf)
+                    .await
+                    .context("Failed to write report")?;
+            }
+            Format::Jsonl => {
+                for entry in &self.entries {
+                    let line = Line {
+                        version: self.version,
+                        root: &self.root,
+                        entry,
+                    };
+                    let mut buf = serde_json::to_vec(&line)?;
+                    buf.push(b'\n');
+                    writer
+                        .write_all(&buf)
+                        .await
+                        .context("Failed to write report")?;
+                }
+            }
+        }
+        writer.flush().await.context("Failed to write report")?;
+        Ok(())
+    }
+
+    /// Findings with their path relative to the scan root, to compare reports of different hosts
+    fn relative(&self) -> BTreeMap<(String, PathBuf), Option<&str>> {
+        self.entries
+            .iter()
+            .filter_map(|entry| {
+                let path = entry.path.as_ref()?;
+                let rel = path.strip_prefix(&self.root).unwrap_or(path);
+                Some((
+                    (entry.kind.clone(), Path::new("/").join(rel)),
+                    entry.sha256.as_deref(),
+                ))
+            })
+            .collect()
+    }
+}
+
+/// Indent the lines of a diff below its entry in the text report
+fn indent(diff: &str) -> String {
+    diff.lines().map(|line| format!("    {line}\n")).collect()
+}
+
+/// The properties of a finding in the report
+fn entry_properties() -> serde_json::Value {
+    serde_json::json!({
+        "kind": {
+            "description": "Kind of the finding, like `WRONG SHA256` or `UNTRACKED`",
+            "type": "string",
+        },
+        "path": { "type": "string" },
+        "sha256": {
+            "description": "The hash of the file as found on disk",
+            "type": "string",
+        },
+        "expected": {
+            "description": "The trusted hash the file was compared against, other algorithms than sha256 are prefixed like `md5:`",
+            "type": "string",
+        },
+        "package": {
+            "description": "Name of the package owning the file",
+            "type": "string",
+        },
+        "package_version": {
+            "description": "Version of the package owning the file",
+            "type": "string",
+        },
+        "details": {
+            "type": "array",
+            "items": { "type": "string" },
+        },
+        "diff": {
+            "description": "A unified diff against the file of the package, for modified text files",
+            "type": "string",
+        },
+    })
+}
+
+/// The JSON Schema of the report, for `jsonl` it describes a single line and `sarif` refers to the SARIF schema
+pub fn schema(format: Format) -> serde_json::Value {
+    if format == Format::Sarif {
+        return serde_json::json!({ "$ref": crate::sarif::SCHEMA });
+    }
+    let version = serde_json::json!({
+        "description": "Format version of the report, increased on incompatible changes",
+        "const": FORMAT_VERSION,
+    });
+    let root = serde_json::json!({
+        "description": "The root of the scanned filesystem",
+        "type": "string",
+    });
+    if format == Format::Jsonl {
+        let mut properties = entry_properties();
+        properties["version"] = version;
+        properties["root"] = root;
+        return serde_json::json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "title": "archlinux-userland-fs-cmp report line",
+            "type": "object",
+            "required": ["version", "root", "kind"],
+            "properties": properties,
+            "additionalProperties": false,
+        });
+    }
+    serde_json::json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "archlinux-userland-fs-cmp report",
+        "type": "object",
+        "required": ["version", "root", "entries"],
+        "properties": {
+            "version": version,
+            "root": root,
+            "entries": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["kind"],
+                    "properties": entry_properties(),
+                    "additionalProperties": false,
+                },
+            },
+            "summary": {
+                "description": "Statistics of the scan and how it was invoked",
+                "type": "object",
+                "properties": {
+                    "tool_version": { "type": "string" },
+                    "command_line": { "type": "array", "items": { "type": "string" } },
+                    "packages_total": { "type": "integer" },
+                    "packages_untrusted": { "type": "integer" },
+                    "files_verified": { "type": "integer" },
+                    "files_hashed": { "type": "integer" },
+                    "bytes_hashed": { "type": "integer" },
+                    "bytes_downloaded": { "type": "integer" },
+                    "phases": {
+                        "description": "Phases run concurrently, each is timed from the start of the scan until it completed",
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "required": ["name", "seconds"],
+                            "properties": {
+                                "name": { "type": "string" },
+                                "seconds": { "type": "number" },
+                            },
+                        },
+                    },
+                    "duration_secs": { "type": "number" },
+                    "findings": {
+                        "description": "Number of findings by their kind",
+                        "type": "object",
+                        "additionalProperties": { "type": "integer" },
+                    },
+                },
+            },
+        },
+    })
+}
+
+/// Report findings that are only present in one of the reports, or with different content
+pub fn divergence(a: (&str, &Report), b: (&str, &Report)) -> Vec<Entry> {
+    let (name_a, a) = a;
+    let (name_b, b) = b;
+    let a = a.relative();
+    let b = b.relative();
+
+    let mut entries = Vec::new();
+    for ((kind, path), sha256) in &a {
+        match b.get(&(kind.clone(), path.clone())) {
+            None => entries.push(Entry::path(format!("ONLY {name_a}"), path).detail(kind)),
+            Some(other) if other != sha256 => {
+                entries.push(Entry::path("DIFFERENT CONTENT", path).detail(kind))
+            }
+            Some(_) => (),
+        }
+    }
+    for (kind, path) in b.keys() {
+        if !a.contains_key(&(kind.clone(), path.clone())) {
+            entries.push(Entry::path(format!("ONLY {name_b}"), path).detail(kind));
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diverging_findings() {
+        let a = Report {
+            root: PathBuf::from("/mnt"),
+            entries: vec![
+                Entry::path("WRONG SHA256", "/mnt/usr/bin/foo"),
+                Entry::path("NO SHA256", "/mnt/usr/bin/bar"),
+            ],
+            ..Default::default()
+        };
+        let b = Report {
+            root: PathBuf::from("/"),
+            entries: vec![Entry::path("WRONG SHA256", "/usr/bin/foo")],
+            ..Default::default()
+        };
+        let entries = divergence(("a", &a), ("b", &b));
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| entry.to_string())
+                .collect::<Vec<_>>(),
+            vec!["[ONLY a] \"/usr/bin/bar\" (NO SHA256)"]
+        );
+    }
+
+    #[test]
+    fn pacman_qkk_lines() {
+        let mut flagged = Entry::path("SENSITIVE WRONG SHA256", "/usr/bin/sudo");
+        flagged.package = Some("sudo".to_string());
+        let metadata = Entry::path("WRONG METADATA", "/etc/shadow")
+            .detail("mode: 0600 -> 0644, uid: 0 -> 1000");
+        let untracked = Entry::path("NO SHA256", "/usr/bin/backdoor");
+        let mut missing = Entry::path("MISSING FILE", "/usr/lib/libfoo.so");
+        missing.package = Some("foo".to_string());
+        let lines = [flagged, metadata, untracked, missing]
+            .iter()
+            .flat_map(Entry::pacman_qkk)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            vec![
+                "warning: sudo: /usr/bin/sudo (SHA256 checksum mismatch)",
+                "warning: /etc/shadow (Permissions mismatch)",
+                "warning: /etc/shadow (UID mismatch)",
+                "warning: /usr/bin/backdoor (Not owned by any package)",
+                "warning: foo: /usr/lib/libfoo.so (No such file or directory)",
+            ]
+        );
+    }
+
+    #[test]
+    fn versioned_json() {
+        let mut entry = Entry::path("WRONG SHA256", "/usr/bin/foo").detail("modified");
+        entry.sha256 = Some("abcd".to_string());
+        entry.expected = Some("dcba".to_string());
+        entry.set_package(Some(&Package {
+            name: "foo".to_string(),
+            version: "1.0-1".to_string(),
+            arch: "x86_64".to_string(),
+        }));
+        assert_eq!(
+            entry.to_string(),
+            r#"[WRONG SHA256] "/usr/bin/foo" (package: foo-1.0-1) (modified)"#
+        );
+        let report = Report {
+            entries: vec![entry],
+            summary: Some(Summary::default()),
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["version"], FORMAT_VERSION);
+
+        // every field that is written is documented in the schema
+        let schema = schema(Format::Json);
+        let properties = |value: &serde_json::Value| {
+            value
+                .as_object()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        for key in properties(&json) {
+            assert!(schema["properties"].get(&key).is_some(), "{key}");
+        }
+        let items = &schema["properties"]["entries"]["items"]["properties"];
+        for key in properties(&json["entries"][0]) {
+            assert!(items.get(&key).is_some(), "{key}");
+        }
+        let summary = &schema["properties"]["summary"]["properties"];
+        for key in properties(&json["summary"]) {
+            assert!(summary.get(&key).is_some(), "{key}");
+        }
+
+        let line = serde_json::to_value(Line {
+            version: report.version,
+            root: &report.root,
+            entry: &report.entries[0],
+        })
+        .unwrap();
+        let line_schema = super::schema(Format::Jsonl);
+        for key in properties(&line) {
+            assert!(line_schema["properties"].get(&key).is_some(), "{key}");
+        }
+
+        let old = serde_json::from_str::<Report>(r#"{"root":"/","entries":[]}"#).unwrap();
+        assert_eq!(old.version, 1);
+    }
+
+    #[test]
+    fn summary_block() {
+        let mut summary = Summary {
+            tool_version: "0.1.0".to_string(),
+            command_line: vec![
+                "archlinux-userland-fs-cmp".to_string(),
+                "/mnt/my disk".to_string(),
+                "--stream".to_string(),
+            ],
+            packages_total: 812,
+            packages_untrusted: 2,
+            files_verified: 1000,
+            files_hashed: 900,
+            bytes_hashed: 2048,
+            phases: vec![Phase {
+                name: "disk scan".to_string(),
+                seconds: 1.5,
+            }],
+            duration_secs: 3.0,
+            ..Default::default()
+        };
+        summary.count_findings(&[
+            Entry::path("UNTRACKED", "/mnt/my disk/usr/bin/a"),
+            Entry::path("UNTRACKED", "/mnt/my disk/usr/bin/b"),
+            Entry::path("WRONG SHA256", "/mnt/my disk/usr/bin/c"),
+        ]);
+        assert_eq!(
+            summary.to_string(),
+            "# version: 0.1.0\n\
+             # command line: archlinux-userland-fs-cmp \"/mnt/my disk\" --stream\n\
+             # packages: 812 (2 without trusted source)\n\
+             # files: 1000 verified, 900 hashed (2.00 KiB), downloaded: 0 B\n\
+             # duration: 3.0s (disk scan at 1.5s)\n\
+             # findings: 2 UNTRACKED, 1 WRONG SHA256\n"
+        );
+    }
+}
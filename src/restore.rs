@@ -0,0 +1,441 @@
+use crate::digest::{Checksum, MultiHasher};
+use crate::errors::*;
+use crate::fetch;
+use crate::hashes::TrustedHashes;
+use crate::pkg::Package;
+use crate::report::Entry;
+use futures_util::StreamExt;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio_tar as tar;
+
+#[derive(Debug)]
+pub enum Action {
+    /// The file was replaced with the one of its package
+    Restored(PathBuf, Package),
+    /// The file of the package was verified, but not written because of `--dry-run`
+    WouldRestore(PathBuf, Package),
+    Error(PathBuf, Option<Package>, Error),
+}
+
+impl From<Action> for Entry {
+    fn from(action: Action) -> Self {
+        let (mut entry, pkg) = match action {
+            Action::Restored(path, pkg) => (Entry::path("RESTORED", path), Some(pkg)),
+            Action::WouldRestore(path, pkg) => (Entry::path("WOULD RESTORE", path), Some(pkg)),
+            Action::Error(path, pkg, err) => (
+                Entry::path("RESTORE ERROR", path).detail(format!("{err:#}")),
+                pkg,
+            ),
+        };
+        entry.set_package(pkg.as_ref());
+        entry
+    }
+}
+
+/// Ask on the terminal if a file should be restored, anything but `y` declines
+pub fn prompt(path: &Path, pkg: &Package) -> bool {
+    eprint!("Restore {path:?} from {}-{}? [y/N] ", pkg.name, pkg.version);
+    io::stderr().flush().ok();
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim(), "y" | "Y" | "yes"),
+        Err(_) => false,
+    }
+}
+
+/// The temporary file a member is extracted to, it's renamed over the file once it's verified
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.restore"))
+}
+
+/// Refuse to write below a symlink in the root, a compromised system could redirect a
+/// restore to any file with a symlinked directory
+fn check_parents(root: &Path, path: &Path) -> Result<()> {
+    let rel = path
+        .strip_prefix(root)
+        .with_context(|| anyhow!("Refusing to restore outside of the root: {path:?}"))?;
+    let mut dir = root.to_owned();
+    for component in rel.parent().into_iter().flat_map(Path::components) {
+        dir.push(component);
+        let metadata = std::fs::symlink_metadata(&dir)
+            .with_context(|| anyhow!("Failed to read directory: {dir:?}"))?;
+        if !metadata.is_dir() {
+            bail!("Refusing to restore below a symlink or file: {dir:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Extract a file of the package and verify it against the trusted hash, then replace the
+/// file on disk with it, including its mode and ownership
+async fn restore_member<R: AsyncRead + Unpin>(
+    entry: &mut tar::Entry<R>,
+    root: &Path,
+    path: &Path,
+    trusted: &TrustedHashes,
+    dry_run: bool,
+) -> Result<()> {
+    let expected = trusted
+        .get(path)
+        .context("File has no trusted hash to verify the package against")?;
+    let expected = Checksum::parse(&expected)?;
+    let header = entry.header();
+    let mode = header.mode()? & 0o7777;
+    let (uid, gid) = (header.uid()? as u32, header.gid()? as u32);
+
+    let tmp = temp_path(path);
+    let mut file = if dry_run {
+        None
+    } else {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| anyhow!("Failed to create directory: {parent:?}"))?;
+        }
+        check_parents(root, path)?;
+        // an existing temporary file (like a planted symlink) is left alone as evidence
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .mode(0o600)
+            .open(&tmp)
+            .await
+            .with_context(|| anyhow!("Failed to create file: {tmp:?}"))?;
+        Some(file)
+    };
+
+    let result = async {
+        let mut hasher = MultiHasher::new(&[expected.algorithm]);
+        let mut buf = vec![0; fetch::DECOMPRESS_BUFFER_SIZE];
+        loop {
+            let n = entry
+                .read(&mut buf)
+                .await
+                .context("Failed to read file from package")?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            if let Some(file) = &mut file {
+                file.write_all(&buf[..n])
+                    .await
+                    .with_context(|| anyhow!("Failed to write to file: {tmp:?}"))?;
+            }
+        }
+        if !hasher.finalize().iter().any(|c| c.matches(&expected)) {
+            bail!("File of the package doesn't match the trusted hash {expected}");
+        }
+        let Some(file) = file else {
+            return Ok(());
+        };
+        // chown clears the setuid bits, so the mode is set after it
+        let file = file.into_std().await;
+        std::os::unix::fs::fchown(&file, Some(uid), Some(gid))
+            .with_context(|| anyhow!("Failed to change ownership of {tmp:?} to {uid}:{gid}"))?;
+        file.set_permissions(std::fs::Permissions::from_mode(mode))
+            .with_context(|| anyhow!("Failed to change mode of {tmp:?} to {mode:o}"))?;
+        file.sync_all()
+            .with_context(|| anyhow!("Failed to sync file: {tmp:?}"))?;
+        fs::rename(&tmp, path)
+            .await
+            .with_context(|| anyhow!("Failed to replace {path:?}"))
+    }
+    .await;
+    if result.is_err() && !dry_run {
+        fs::remove_file(&tmp).await.ok();
+    }
+    result
+}
+
+/// Restore the given files (relative to the root) from a package
+async fn restore_members<R: AsyncRead + Unpin>(
+    reader: R,
+    root: &Path,
+    pkg: &Package,
+    mut paths: HashMap<PathBuf, &Path>,
+    trusted: &TrustedHashes,
+    dry_run: bool,
+) -> Result<Vec<Action>> {
+    let mut actions = Vec::new();
+    let mut tar = tar::Archive::new(reader);
+    let mut entries = tar.entries()?;
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.context("Failed to read entry from package")?;
+        let Some(path) = paths.remove(entry.path()?.as_ref()) else {
+            continue;
+        };
+        let action = if !entry.header().entry_type().is_file() {
+            Action::Error(
+                path.to_owned(),
+                Some(pkg.clone()),
+                anyhow!("Only regular files can be restored"),
+            )
+        } else {
+            match restore_member(&mut entry, root, path, trusted, dry_run).await {
+                Ok(()) if dry_run => Action::WouldRestore(path.to_owned(), pkg.clone()),
+                Ok(()) => {
+                    info!("Restored {path:?} from {}-{}", pkg.name, pkg.version);
+                    Action::Restored(path.to_owned(), pkg.clone())
+                }
+                Err(err) => Action::Error(path.to_owned(), Some(pkg.clone()), err),
+            }
+        };
+        actions.push(action);
+        if paths.is_empty() {
+            break;
+        }
+    }
+    for path in paths.into_values() {
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        actions.push(Action::Error(
+            path.to_owned(),
+            Some(pkg.clone()),
+            anyhow!("Package doesn't contain {rel:?}"),
+        ));
+    }
+    Ok(actions)
+}
+
+/// Replace flagged and missing files with the files of their packages, every package is only
+/// read once. Files that `confirm` declines are skipped, with `dry_run` nothing is written and
+/// nobody is asked
+#[allow(clippy::too_many_arguments)]
+pub async fn restore<'a>(
+    client: &reqwest::Client,
+    dirs: &[PathBuf],
+    mirrors: &[String],
+    root: &Path,
+    paths: impl Iterator<Item = &'a Path>,
+    owner: impl Fn(&Path) -> Option<&'a Package>,
+    trusted: &TrustedHashes,
+    dry_run: bool,
+    mut confirm: impl FnMut(&Path, &Package) -> bool,
+) -> Vec<Action> {
+    let mut actions = Vec::new();
+    let mut by_pkg = BTreeMap::<_, (&Package, HashMap<PathBuf, &Path>)>::new();
+    for path in paths {
+        let Some(pkg) = owner(path) else {
+            let err = anyhow!("No package owns this file");
+            actions.push(Action::Error(path.to_owned(), None, err));
+            continue;
+        };
+        let Ok(rel) = path.strip_prefix(root) else {
+            continue;
+        };
+        if !dry_run && !confirm(path, pkg) {
+            info!("Not restoring {path:?}");
+            continue;
+        }
+        by_pkg
+            .entry((&pkg.name, &pkg.version))
+            .or_insert_with(|| (pkg, HashMap::new()))
+            .1
+            .insert(rel.to_owned(), path);
+    }
+
+    for (pkg, paths) in by_pkg.into_values() {
+        let result = match fetch::open_package(client, dirs, mirrors, pkg).await {
+            Ok(Some((source, reader))) => {
+                restore_members(reader, root, pkg, paths.clone(), trusted, dry_run)
+                    .await
+                    .with_context(|| anyhow!("Failed to read package: {source}"))
+            }
+            Ok(None) => Err(anyhow!(
+                "Failed to find package {}-{}",
+                pkg.name,
+                pkg.version
+            )),
+            Err(err) => Err(err),
+        };
+        match result {
+            Ok(result) => actions.extend(result),
+            Err(err) => {
+                warn!("Failed to restore files of {}: {err:#}", pkg.name);
+                for path in paths.into_values() {
+                    let err = anyhow!("{err:#}");
+                    actions.push(Action::Error(path.to_owned(), Some(pkg.clone()), err));
+                }
+            }
+        }
+    }
+    actions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disk;
+    use std::os::unix::fs::MetadataExt;
+
+    #[tokio::test]
+    async fn restore_from_package() {
+        let dir = std::env::temp_dir().join(format!("restore-test-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("usr/bin")).unwrap();
+        let sudo = root.join("usr/bin/sudo");
+        std::fs::write(&sudo, b"backdoor").unwrap();
+        let su = root.join("usr/bin/su");
+        std::fs::write(&su, b"backdoor").unwrap();
+        // missing from disk
+        let visudo = root.join("usr/bin/visudo");
+        // the package doesn't match the trusted hash
+        let sudoedit = root.join("usr/bin/sudoedit");
+        std::fs::write(&sudoedit, b"backdoor").unwrap();
+
+        let uid = std::fs::metadata(&dir).unwrap().uid();
+        let gid = std::fs::metadata(&dir).unwrap().gid();
+        let mut tar = ::tar::Builder::new(Vec::new());
+        for (path, data) in [
+            ("usr/bin/sudo", &b"sudo"[..]),
+            ("usr/bin/su", b"su"),
+            ("usr/bin/visudo", b"visudo"),
+            ("usr/bin/sudoedit", b"evil"),
+        ] {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o4755);
+            header.set_uid(uid.into());
+            header.set_gid(gid.into());
+            tar.append_data(&mut header, path, data).unwrap();
+        }
+        let pkg = Package {
+            name: "sudo".to_string(),
+            version: "1.9.15.p5-1".to_string(),
+            arch: "x86_64".to_string(),
+        };
+        std::fs::write(dir.join(pkg.file_name("")), tar.into_inner().unwrap()).unwrap();
+        let trusted = [
+            (&sudo, disk::sha256(b"sudo")),
+            (&su, disk::sha256(b"su")),
+            (&visudo, disk::sha256(b"visudo")),
+            (&sudoedit, disk::sha256(b"sudoedit")),
+        ]
+        .into_iter()
+        .collect::<TrustedHashes>();
+
+        let paths = [&sudo, &su, &visudo, &sudoedit];
+        let client = reqwest::Client::new();
+        let run = |dry_run| {
+            restore(
+                &client,
+                std::slice::from_ref(&dir),
+                &[],
+                &root,
+                paths.iter().map(|path| path.as_path()),
+                |_| Some(&pkg),
+                &trusted,
+                dry_run,
+                // su is declined
+                |path: &Path, _: &Package| path != su,
+            )
+        };
+        let kinds = |actions: Vec<Action>| {
+            let mut kinds = actions
+                .into_iter()
+                .map(Entry::from)
+                .map(|entry| (entry.kind, entry.path.unwrap()))
+                .collect::<Vec<_>>();
+            kinds.sort();
+            kinds
+        };
+
+        assert_eq!(
+            kinds(run(true).await),
+            vec![
+                ("RESTORE ERROR".to_string(), sudoedit.clone()),
+                ("WOULD RESTORE".to_string(), su.clone()),
+                ("WOULD RESTORE".to_string(), sudo.clone()),
+                ("WOULD RESTORE".to_string(), visudo.clone()),
+            ]
+        );
+        assert_eq!(std::fs::read(&sudo).unwrap(), b"backdoor");
+        assert!(!visudo.exists());
+
+        assert_eq!(
+            kinds(run(false).await),
+            vec![
+                ("RESTORE ERROR".to_string(), sudoedit.clone()),
+                ("RESTORED".to_string(), sudo.clone()),
+                ("RESTORED".to_string(), visudo.clone()),
+            ]
+        );
+        assert_eq!(std::fs::read(&sudo).unwrap(), b"sudo");
+        assert_eq!(std::fs::metadata(&sudo).unwrap().mode() & 0o7777, 0o4755);
+        assert_eq!(std::fs::read(&visudo).unwrap(), b"visudo");
+        assert_eq!(std::fs::read(&su).unwrap(), b"backdoor");
+        assert_eq!(std::fs::read(&sudoedit).unwrap(), b"backdoor");
+        assert!(!temp_path(&sudoedit).exists());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn refuse_planted_symlinks() {
+        let dir = std::env::temp_dir().join(format!("restore-links-test-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("usr/bin")).unwrap();
+        std::fs::create_dir_all(dir.join("elsewhere")).unwrap();
+        let shadow = dir.join("shadow");
+        std::fs::write(&shadow, b"root:x:").unwrap();
+        std::fs::set_permissions(&shadow, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let sudo = root.join("usr/bin/sudo");
+        std::fs::write(&sudo, b"backdoor").unwrap();
+        // the temporary file of the restore points somewhere else
+        std::os::unix::fs::symlink(&shadow, temp_path(&sudo)).unwrap();
+        // and so does a directory
+        std::os::unix::fs::symlink(dir.join("elsewhere"), root.join("usr/lib")).unwrap();
+        let lib = root.join("usr/lib/libsudo_util.so");
+
+        let mut tar = ::tar::Builder::new(Vec::new());
+        for (path, data) in [
+            ("usr/bin/sudo", &b"sudo"[..]),
+            ("usr/lib/libsudo_util.so", b"lib"),
+        ] {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o4755);
+            tar.append_data(&mut header, path, data).unwrap();
+        }
+        let pkg = Package {
+            name: "sudo".to_string(),
+            version: "1.9.15.p5-1".to_string(),
+            arch: "x86_64".to_string(),
+        };
+        std::fs::write(dir.join(pkg.file_name("")), tar.into_inner().unwrap()).unwrap();
+        let trusted = [(&sudo, disk::sha256(b"sudo")), (&lib, disk::sha256(b"lib"))]
+            .into_iter()
+            .collect::<TrustedHashes>();
+
+        let actions = restore(
+            &reqwest::Client::new(),
+            std::slice::from_ref(&dir),
+            &[],
+            &root,
+            [sudo.as_path(), lib.as_path()].into_iter(),
+            |_| Some(&pkg),
+            &trusted,
+            false,
+            |_: &Path, _: &Package| true,
+        )
+        .await;
+        assert_eq!(actions.len(), 2);
+        assert!(actions
+            .iter()
+            .all(|action| matches!(action, Action::Error(..))));
+        assert_eq!(std::fs::read(&shadow).unwrap(), b"root:x:");
+        assert_eq!(std::fs::metadata(&shadow).unwrap().mode() & 0o7777, 0o600);
+        assert!(temp_path(&sudo).is_symlink());
+        assert_eq!(std::fs::read(&sudo).unwrap(), b"backdoor");
+        assert!(!dir.join("elsewhere/libsudo_util.so").exists());
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
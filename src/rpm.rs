@@ -0,0 +1,328 @@
+use crate::digest::{Algorithm, Checksum};
+use crate::errors::*;
+use crate::pkg::Package;
+use crate::{resolve_merged_usr, Event};
+use rusqlite::{Connection, OpenFlags};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+/// Locations of the sqlite rpmdb relative to the root, newer Fedora releases moved it into /usr
+pub const DB_PATHS: &[&str] = &[
+    "usr/lib/sysimage/rpm/rpmdb.sqlite",
+    "var/lib/rpm/rpmdb.sqlite",
+];
+
+const TAG_NAME: u32 = 1000;
+const TAG_VERSION: u32 = 1001;
+const TAG_RELEASE: u32 = 1002;
+const TAG_EPOCH: u32 = 1003;
+const TAG_ARCH: u32 = 1022;
+const TAG_FILEDIGESTS: u32 = 1035;
+const TAG_DIRINDEXES: u32 = 1116;
+const TAG_BASENAMES: u32 = 1117;
+const TAG_DIRNAMES: u32 = 1118;
+const TAG_FILEDIGESTALGO: u32 = 5011;
+
+const TYPE_INT32: u32 = 4;
+const TYPE_STRING: u32 = 6;
+const TYPE_STRING_ARRAY: u32 = 8;
+const TYPE_I18NSTRING: u32 = 9;
+
+/// An installed package from the rpmdb
+#[derive(Debug, Clone)]
+pub struct Installed {
+    pub pkg: Package,
+    /// Files relative to the root and their checksum
+    pub files: Vec<(String, Checksum)>,
+}
+
+/// A header blob of the rpmdb, a list of tags that point into a data store
+pub struct Header<'a> {
+    entries: Vec<(u32, u32, usize, usize)>,
+    data: &'a [u8],
+}
+
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+impl<'a> Header<'a> {
+    pub fn parse(blob: &'a [u8]) -> Result<Self> {
+        let il = be32(blob, 0).context("Truncated rpm header")? as usize;
+        let dl = be32(blob, 4).context("Truncated rpm header")? as usize;
+        let index_len = il.checked_mul(16).context("Invalid rpm header size")?;
+        let data = blob
+            .get(8 + index_len..)
+            .and_then(|data| data.get(..dl))
+            .context("Truncated rpm header")?;
+
+        let mut entries = Vec::with_capacity(il);
+        for i in 0..il {
+            let entry = 8 + i * 16;
+            let tag = be32(blob, entry).context("Truncated rpm header")?;
+            let kind = be32(blob, entry + 4).context("Truncated rpm header")?;
+            let offset = be32(blob, entry + 8).context("Truncated rpm header")?;
+            let count = be32(blob, entry + 12).context("Truncated rpm header")?;
+            entries.push((tag, kind, offset as usize, count as usize));
+        }
+        Ok(Header { entries, data })
+    }
+
+    fn entry(&self, tag: u32) -> Option<(u32, &'a [u8], usize)> {
+        let (_, kind, offset, count) = self.entries.iter().find(|entry| entry.0 == tag)?;
+        Some((*kind, self.data.get(*offset..)?, *count))
+    }
+
+    pub fn string(&self, tag: u32) -> Option<&'a str> {
+        self.string_array(tag)?.into_iter().next()
+    }
+
+    pub fn string_array(&self, tag: u32) -> Option<Vec<&'a str>> {
+        let (kind, data, count) = self.entry(tag)?;
+        if ![TYPE_STRING, TYPE_STRING_ARRAY, TYPE_I18NSTRING].contains(&kind) {
+            return None;
+        }
+        let count = if kind == TYPE_STRING { 1 } else { count };
+        let mut strings = data.split(|b| *b == 0);
+        let mut out = Vec::new();
+        for _ in 0..count {
+            out.push(std::str::from_utf8(strings.next()?).ok()?);
+        }
+        Some(out)
+    }
+
+    pub fn int32_array(&self, tag: u32) -> Option<Vec<u32>> {
+        let (kind, data, count) = self.entry(tag)?;
+        if kind != TYPE_INT32 {
+            return None;
+        }
+        (0..count).map(|i| be32(data, i.checked_mul(4)?)).collect()
+    }
+}
+
+/// Hash algorithms of `FILEDIGESTALGO`, numbered like in OpenPGP
+fn digest_algorithm(id: u32) -> Option<Algorithm> {
+    match id {
+        1 => Some(Algorithm::Md5),
+        2 => Some(Algorithm::Sha1),
+        8 => Some(Algorithm::Sha256),
+        10 => Some(Algorithm::Sha512),
+        _ => None,
+    }
+}
+
+/// Read the package and the digests of its files from a header blob
+pub fn parse_header(blob: &[u8]) -> Result<Installed> {
+    let header = Header::parse(blob)?;
+    let name = header
+        .string(TAG_NAME)
+        .context("Missing name in rpm header")?;
+    let version = header
+        .string(TAG_VERSION)
+        .context("Missing version in rpm header")?;
+    let release = header
+        .string(TAG_RELEASE)
+        .context("Missing release in rpm header")?;
+    let version = match header
+        .int32_array(TAG_EPOCH)
+        .and_then(|e| e.first().copied())
+    {
+        Some(epoch) => format!("{epoch}:{version}-{release}"),
+        None => format!("{version}-{release}"),
+    };
+    // the gpg-pubkey pseudo packages don't have an architecture
+    let arch = header.string(TAG_ARCH).unwrap_or("noarch");
+
+    let mut files = Vec::new();
+    if let Some(digests) = header.string_array(TAG_FILEDIGESTS) {
+        // packages without the tag are from before rpm supported anything but md5
+        let id = header
+            .int32_array(TAG_FILEDIGESTALGO)
+            .and_then(|algo| algo.first().copied())
+            .unwrap_or(1);
+        let algorithm = digest_algorithm(id)
+            .with_context(|| anyhow!("Unsupported file digest algorithm in {name:?}: {id}"))?;
+
+        let basenames = header.string_array(TAG_BASENAMES).unwrap_or_default();
+        let dirnames = header.string_array(TAG_DIRNAMES).unwrap_or_default();
+        let dirindexes = header.int32_array(TAG_DIRINDEXES).unwrap_or_default();
+        for ((basename, dirindex), digest) in basenames.iter().zip(dirindexes).zip(digests) {
+            // directories, symlinks and %ghost files have no digest
+            if digest.is_empty() {
+                continue;
+            }
+            let Some(dirname) = dirnames.get(dirindex as usize) else {
+                continue;
+            };
+            let Ok(checksum) = Checksum::new(algorithm, digest) else {
+                warn!("Invalid digest in {name:?} for {dirname}{basename}: {digest:?}");
+                continue;
+            };
+            files.push((format!("{dirname}{basename}"), checksum));
+        }
+    }
+
+    Ok(Installed {
+        pkg: Package {
+            name: name.to_string(),
+            version,
+            arch: arch.to_string(),
+        },
+        files,
+    })
+}
+
+/// Read all header blobs of a sqlite rpmdb, the database is opened immutable so nothing is written to the investigated system
+pub fn read_db(path: &Path) -> Result<Vec<Vec<u8>>> {
+    let mut uri = String::from("file:");
+    for c in path
+        .to_str()
+        .context("Path of rpmdb is not valid utf-8")?
+        .chars()
+    {
+        match c {
+            '?' | '#' | '%' => uri.push_str(&format!("%{:02X}", c as u32)),
+            c => uri.push(c),
+        }
+    }
+    uri.push_str("?immutable=1");
+    let db = Connection::open_with_flags(
+        &uri,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI,
+    )
+    .with_context(|| anyhow!("Failed to open rpmdb: {path:?}"))?;
+    let mut stmt = db
+        .prepare("SELECT blob FROM Packages")
+        .context("Failed to query rpmdb")?;
+    let blobs = stmt
+        .query_map([], |row| row.get::<_, Vec<u8>>(0))
+        .context("Failed to query rpmdb")?
+        .collect::<rusqlite::Result<Vec<_>>>()
+        .context("Failed to read package from rpmdb")?;
+    Ok(blobs)
+}
+
+/// Find the rpmdb, symlinks are not followed since they'd resolve on the investigating system
+fn find_db(root: &Path) -> Option<PathBuf> {
+    DB_PATHS
+        .iter()
+        .map(|path| root.join(path))
+        .find(|path| path.symlink_metadata().is_ok_and(|md| md.is_file()))
+}
+
+/// Read the installed packages from the rpmdb and send the trusted hashes of their files
+pub fn spawn_list_installed(
+    event_tx: mpsc::UnboundedSender<Event>,
+    root: PathBuf,
+    shutdown: CancellationToken,
+) {
+    tokio::spawn(async move {
+        let blobs = match find_db(&root) {
+            Some(path) => tokio::task::spawn_blocking(move || read_db(&path))
+                .await
+                .map_err(Error::from)
+                .and_then(|blobs| blobs),
+            None => Err(anyhow!(
+                "Failed to find sqlite rpmdb in {root:?}, the Berkeley DB format of older releases isn't supported"
+            )),
+        };
+        let blobs = blobs.unwrap_or_else(|err| {
+            warn!("Failed to read installed packages: {err:#}");
+            Vec::new()
+        });
+        for _ in &blobs {
+            if event_tx.send(Event::PkgQueued).is_err() {
+                return;
+            }
+        }
+
+        for blob in blobs {
+            if shutdown.is_cancelled() {
+                return;
+            }
+            match parse_header(&blob) {
+                Ok(installed) => {
+                    debug!(
+                        "Found installed package: {:?} {:?}",
+                        installed.pkg.name, installed.pkg.version
+                    );
+                    let pkg = Arc::new(installed.pkg);
+                    for (path, checksum) in installed.files {
+                        let path = resolve_merged_usr(&root, &path);
+                        let event =
+                            Event::TrustedFile(path, checksum.to_string(), Some(pkg.clone()));
+                        if event_tx.send(event).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(err) => warn!("Failed to parse package from rpmdb: {err:#}"),
+            }
+            if event_tx.send(Event::PkgCompleted).is_err() {
+                return;
+            }
+        }
+
+        event_tx.send(Event::CompletedListInstalled).ok();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_header(entries: &[(u32, u32, u32, &[u8])]) -> Vec<u8> {
+        let mut index = Vec::new();
+        let mut data = Vec::<u8>::new();
+        for (tag, kind, count, value) in entries {
+            index.extend(tag.to_be_bytes());
+            index.extend(kind.to_be_bytes());
+            index.extend((data.len() as u32).to_be_bytes());
+            index.extend(count.to_be_bytes());
+            data.extend(*value);
+        }
+        let mut blob = Vec::new();
+        blob.extend((entries.len() as u32).to_be_bytes());
+        blob.extend((data.len() as u32).to_be_bytes());
+        blob.extend(index);
+        blob.extend(data);
+        blob
+    }
+
+    #[test]
+    fn parse_rpm_header() {
+        let sha256 = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let digests = format!("{sha256}\0\0");
+        let blob = build_header(&[
+            (TAG_NAME, TYPE_STRING, 1, b"bash\0"),
+            (TAG_VERSION, TYPE_STRING, 1, b"5.2.26\0"),
+            (TAG_RELEASE, TYPE_STRING, 1, b"3.fc40\0"),
+            (TAG_ARCH, TYPE_STRING, 1, b"x86_64\0"),
+            (TAG_FILEDIGESTS, TYPE_STRING_ARRAY, 2, digests.as_bytes()),
+            (TAG_DIRINDEXES, TYPE_INT32, 2, &[0, 0, 0, 0, 0, 0, 0, 1]),
+            (TAG_BASENAMES, TYPE_STRING_ARRAY, 2, b"bash\0bash\0"),
+            (
+                TAG_DIRNAMES,
+                TYPE_STRING_ARRAY,
+                2,
+                b"/usr/bin/\0/usr/share/doc/\0",
+            ),
+            (TAG_FILEDIGESTALGO, TYPE_INT32, 1, &[0, 0, 0, 8]),
+        ]);
+        let installed = parse_header(&blob).unwrap();
+        assert_eq!(installed.pkg.name, "bash");
+        assert_eq!(installed.pkg.version, "5.2.26-3.fc40");
+        assert_eq!(installed.pkg.arch, "x86_64");
+        let files = installed
+            .files
+            .iter()
+            .map(|(path, checksum)| (path.as_str(), checksum.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(files, vec![("/usr/bin/bash", sha256.to_string())]);
+
+        assert!(Header::parse(&blob[..20]).is_err());
+    }
+}
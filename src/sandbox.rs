@@ -1,7 +1,17 @@
 use crate::errors::*;
 use caps::{CapSet, Capability};
+use std::fs;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+pub fn init(keep_read_cap: bool) -> Result<()> {
+    if keep_read_cap {
+        return keep_only_read_cap();
+    }
 
-pub fn init() -> Result<()> {
     debug!("Permanently clearing capability sets");
 
     for set in [CapSet::Effective, CapSet::Permitted] {
@@ -16,3 +26,567 @@ pub fn init() -> Result<()> {
 
     Ok(())
 }
+
+/// Clear all capabilities but `CAP_DAC_READ_SEARCH`, so all files under the scan root can be read
+/// without running as root (like with `setcap cap_dac_read_search+p` on the binary)
+fn keep_only_read_cap() -> Result<()> {
+    debug!("Clearing all capabilities but CAP_DAC_READ_SEARCH");
+
+    let permitted =
+        caps::read(None, CapSet::Permitted).context("Failed to read capability set (Permitted)")?;
+    if !permitted.contains(&Capability::CAP_DAC_READ_SEARCH) {
+        bail!("--keep-read-cap needs CAP_DAC_READ_SEARCH, run as root or grant it with `setcap cap_dac_read_search+p`");
+    }
+    let keep = caps::CapsHashSet::from([Capability::CAP_DAC_READ_SEARCH]);
+    for set in [CapSet::Effective, CapSet::Permitted] {
+        caps::set(None, set, &keep)
+            .with_context(|| anyhow!("Failed to apply capability set ({set:?})"))?;
+    }
+    for set in [CapSet::Inheritable, CapSet::Ambient] {
+        caps::clear(None, set)
+            .with_context(|| anyhow!("Failed to clear capability set ({set:?})"))?;
+    }
+
+    debug!("Sandbox has been setup successfully");
+
+    Ok(())
+}
+
+/// Move into a private mount namespace, mounts created by us (or our child
+/// processes) aren't visible to the rest of the system
+pub fn private_mount_namespace() -> Result<()> {
+    debug!("Moving into private mount namespace");
+
+    if unsafe { libc::unshare(libc::CLONE_NEWNS) } != 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(err).context("Failed to create mount namespace");
+    }
+    let ret = unsafe {
+        libc::mount(
+            std::ptr::null(),
+            c"/".as_ptr(),
+            std::ptr::null(),
+            libc::MS_REC | libc::MS_PRIVATE,
+            std::ptr::null(),
+        )
+    };
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(err).context("Failed to make mounts private");
+    }
+
+    Ok(())
+}
+
+/// Paths the scan may access once the sandbox is hardened
+#[derive(Debug, Default)]
+pub struct Policy {
+    /// Read-only access, like the investigated root and input files
+    pub read: Vec<PathBuf>,
+    /// Read and write access, like the state directory and the directories of output files
+    pub write: Vec<PathBuf>,
+    /// The only TCP port that can be connected to, like the one of the fetcher process.
+    /// Sockets other than unix and TCP ones can't be created either
+    pub connect_port: Option<u16>,
+    /// Fail if the kernel doesn't support Landlock, instead of continuing without it
+    pub require_landlock: bool,
+}
+
+/// Directories of the analysis host that are read for name resolution and TLS roots
+const HOST_READ: &[&str] = &[
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/host.conf",
+    "/etc/nsswitch.conf",
+    "/etc/gai.conf",
+    "/etc/localtime",
+    "/etc/ssl",
+    "/etc/ca-certificates",
+    "/etc/pki",
+    "/usr/share/ca-certificates",
+    "/usr/lib",
+    "/lib",
+    "/lib64",
+    "/proc/self",
+    "/sys/fs/cgroup",
+    "/sys/devices/system/cpu",
+];
+/// Devices of the analysis host that are written to
+const HOST_WRITE: &[&str] = &["/dev/null", "/dev/tty"];
+
+const LANDLOCK_CREATE_RULESET_VERSION: libc::c_uint = 1 << 0;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_uint = 1;
+const LANDLOCK_RULE_NET_PORT: libc::c_uint = 2;
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+/// Everything of the first Landlock ABI, from executing files to creating symlinks
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_NET_BIND_TCP: u64 = 1 << 0;
+const ACCESS_NET_CONNECT_TCP: u64 = 1 << 1;
+/// The rights that apply to files, all other rights are only valid for directories
+const ACCESS_FILE: u64 =
+    ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_TRUNCATE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+    /// Since ABI 4, kernels before that accept it if it's zero
+    handled_access_net: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: RawFd,
+}
+
+#[repr(C, packed)]
+struct NetPortAttr {
+    allowed_access: u64,
+    port: u64,
+}
+
+/// The filesystem rights that are restricted with a given Landlock ABI version
+fn handled_access(abi: i64) -> u64 {
+    let mut access = ACCESS_FS_V1;
+    if abi >= 2 {
+        access |= ACCESS_FS_REFER;
+    }
+    if abi >= 3 {
+        access |= ACCESS_FS_TRUNCATE;
+    }
+    access
+}
+
+/// Restrict filesystem access of this thread (and threads started later) to the paths of the policy,
+/// returns `false` if Landlock isn't supported by the kernel
+fn landlock(policy: &Policy) -> Result<bool> {
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            LANDLOCK_CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Ok(false);
+    }
+    let handled = handled_access(abi);
+    let restrict_net = policy.connect_port.is_some() && abi >= 4;
+    if policy.connect_port.is_some() && !restrict_net {
+        warn!("Landlock of this kernel can't restrict TCP connections, only other sockets are blocked");
+    }
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+        handled_access_net: if restrict_net {
+            ACCESS_NET_BIND_TCP | ACCESS_NET_CONNECT_TCP
+        } else {
+            0
+        },
+    };
+    let ruleset = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &attr,
+            mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if ruleset < 0 {
+        let err = io::Error::last_os_error();
+        return Err(err).context("Failed to create Landlock ruleset");
+    }
+    let ruleset = unsafe { OwnedFd::from_raw_fd(ruleset as RawFd) };
+
+    let read = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+    let write = handled & !ACCESS_FS_EXECUTE;
+    let paths = policy
+        .read
+        .iter()
+        .map(|path| (path.as_path(), read))
+        .chain(HOST_READ.iter().map(|path| (Path::new(path), read)))
+        .chain(policy.write.iter().map(|path| (path.as_path(), write)))
+        .chain(HOST_WRITE.iter().map(|path| (Path::new(path), write)));
+    for (path, access) in paths {
+        let file = match fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(err) => {
+                debug!("Skipping sandbox rule for {path:?}: {err:#}");
+                continue;
+            }
+        };
+        let is_dir = file.metadata().is_ok_and(|metadata| metadata.is_dir());
+        let rule = PathBeneathAttr {
+            allowed_access: if is_dir { access } else { access & ACCESS_FILE },
+            parent_fd: file.as_raw_fd(),
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_PATH_BENEATH,
+                &rule,
+                0,
+            )
+        };
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            return Err(err).with_context(|| anyhow!("Failed to add Landlock rule for {path:?}"));
+        }
+    }
+
+    if let Some(port) = policy.connect_port.filter(|_| restrict_net) {
+        let rule = NetPortAttr {
+            allowed_access: ACCESS_NET_CONNECT_TCP,
+            port: port.into(),
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                LANDLOCK_RULE_NET_PORT,
+                &rule,
+                0,
+            )
+        };
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            return Err(err)
+                .with_context(|| anyhow!("Failed to add Landlock rule for port {port}"));
+        }
+    }
+
+    if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) } != 0 {
+        let err = io::Error::last_os_error();
+        return Err(err).context("Failed to apply Landlock ruleset");
+    }
+    Ok(true)
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// The syscalls that are needed to walk and hash files, talk http and write the report
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const SYSCALLS: &[libc::c_long] = &[
+    // files
+    libc::SYS_openat,
+    libc::SYS_close,
+    libc::SYS_read,
+    libc::SYS_readv,
+    libc::SYS_pread64,
+    libc::SYS_preadv,
+    libc::SYS_write,
+    libc::SYS_writev,
+    libc::SYS_pwrite64,
+    libc::SYS_lseek,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_statfs,
+    libc::SYS_fstatfs,
+    libc::SYS_getdents64,
+    libc::SYS_readlinkat,
+    libc::SYS_faccessat,
+    libc::SYS_faccessat2,
+    libc::SYS_getcwd,
+    libc::SYS_fcntl,
+    libc::SYS_flock,
+    libc::SYS_ioctl,
+    libc::SYS_dup,
+    libc::SYS_dup3,
+    libc::SYS_pipe2,
+    libc::SYS_fadvise64,
+    libc::SYS_fsync,
+    libc::SYS_fdatasync,
+    libc::SYS_ftruncate,
+    libc::SYS_fchmod,
+    libc::SYS_fchown,
+    libc::SYS_mkdirat,
+    libc::SYS_unlinkat,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_copy_file_range,
+    libc::SYS_sendfile,
+    libc::SYS_getxattr,
+    libc::SYS_lgetxattr,
+    libc::SYS_fgetxattr,
+    libc::SYS_listxattr,
+    libc::SYS_llistxattr,
+    libc::SYS_flistxattr,
+    // memory and threads
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_futex,
+    libc::SYS_set_robust_list,
+    libc::SYS_set_tid_address,
+    libc::SYS_rseq,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_getcpu,
+    libc::SYS_prctl,
+    libc::SYS_prlimit64,
+    libc::SYS_sysinfo,
+    libc::SYS_uname,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_restart_syscall,
+    // signals and time
+    libc::SYS_rt_sigaction,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_sigaltstack,
+    libc::SYS_tgkill,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_getuid,
+    libc::SYS_geteuid,
+    libc::SYS_getgid,
+    libc::SYS_getegid,
+    libc::SYS_getrandom,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_getres,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_gettimeofday,
+    // event loop
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_epoll_pwait2,
+    libc::SYS_eventfd2,
+    libc::SYS_ppoll,
+    libc::SYS_pselect6,
+    // network
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    // the fetcher process
+    libc::SYS_accept4,
+    libc::SYS_bind,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockopt,
+    libc::SYS_sendto,
+    libc::SYS_recvfrom,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_shutdown,
+    // legacy variants that are still used by libc on x86_64
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_lstat,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_access,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_readlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_pipe,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_dup2,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_select,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_mkdir,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_getrlimit,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_arch_prctl,
+];
+
+fn bpf_stmt(code: u32, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn bpf_jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// A seccomp program that allows the given syscalls, all others fail with `ENOSYS`
+/// (so libc falls back to older syscalls where it can). With `restrict_sockets` only
+/// unix and TCP sockets can be created
+fn seccomp_program(
+    arch: u32,
+    syscalls: &[libc::c_long],
+    restrict_sockets: bool,
+) -> Vec<libc::sock_filter> {
+    use libc::{
+        BPF_ABS, BPF_ALU, BPF_AND, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W,
+    };
+    let deny = libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32;
+    let n = syscalls.len();
+    assert!(n < u8::MAX as usize, "Too many syscalls for a single jump");
+
+    let mut program = vec![
+        // the architecture of the syscall, everything else is killed
+        bpf_stmt(BPF_LD | BPF_W | BPF_ABS, 4),
+        bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, arch, 1, 0),
+        bpf_stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        // the syscall number, x32 syscalls on x86_64 are denied
+        bpf_stmt(BPF_LD | BPF_W | BPF_ABS, 0),
+        bpf_jump(BPF_JMP | BPF_JGE | BPF_K, 0x4000_0000, 0, 0),
+    ];
+    let x32 = program.len() - 1;
+    if restrict_sockets {
+        program.extend([
+            bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_socket as u32, 0, 8),
+            // the domain, the lower half of the first argument
+            bpf_stmt(BPF_LD | BPF_W | BPF_ABS, 16),
+            bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, libc::AF_UNIX as u32, 4, 0),
+            bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, libc::AF_INET as u32, 0, 4),
+            // the type without flags like SOCK_CLOEXEC
+            bpf_stmt(BPF_LD | BPF_W | BPF_ABS, 24),
+            bpf_stmt(BPF_ALU | BPF_AND | BPF_K, 0xf),
+            bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SOCK_STREAM as u32, 0, 1),
+            bpf_stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW),
+            bpf_stmt(BPF_RET | BPF_K, deny),
+        ]);
+    }
+    for (i, nr) in syscalls.iter().enumerate() {
+        // jump over the remaining checks and the deny
+        program.push(bpf_jump(
+            BPF_JMP | BPF_JEQ | BPF_K,
+            *nr as u32,
+            (n - i) as u8,
+            0,
+        ));
+    }
+    program.push(bpf_stmt(BPF_RET | BPF_K, deny));
+    program[x32].jt = (program.len() - 1 - (x32 + 1)) as u8;
+    program.push(bpf_stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    program
+}
+
+/// Limit the syscalls of all threads, returns `false` on unsupported architectures
+fn seccomp(restrict_sockets: bool) -> Result<bool> {
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    {
+        let program = seccomp_program(AUDIT_ARCH, SYSCALLS, restrict_sockets);
+        let prog = libc::sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_ptr() as *mut _,
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &prog,
+            )
+        };
+        if ret != 0 {
+            let err = io::Error::last_os_error();
+            return Err(err).context("Failed to apply seccomp filter");
+        }
+        Ok(true)
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        let _ = restrict_sockets;
+        Ok(false)
+    }
+}
+
+/// Restrict filesystem access with Landlock and syscalls with seccomp, nothing can be executed
+/// afterwards. This needs to happen before any threads are started, Landlock only applies to the
+/// calling thread and the ones it starts later
+pub fn harden(policy: &Policy) -> Result<()> {
+    debug!("Hardening sandbox with Landlock and seccomp");
+
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        let err = io::Error::last_os_error();
+        return Err(err).context("Failed to set no_new_privs");
+    }
+    if !landlock(policy)? {
+        if policy.require_landlock {
+            bail!("Landlock is not supported by the kernel, but the policy requires it");
+        }
+        warn!("Landlock is not supported by the kernel, filesystem access is not restricted");
+    }
+    if !seccomp(policy.connect_port.is_some())? {
+        warn!(
+            "Seccomp filters are not supported on this architecture, syscalls are not restricted"
+        );
+    }
+
+    debug!("Sandbox has been hardened successfully");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seccomp_jumps_to_allow() {
+        let syscalls = [libc::SYS_read, libc::SYS_write, libc::SYS_close];
+        let program = seccomp_program(0xc000_003e, &syscalls, false);
+        let allow = program.len() - 1;
+        let deny = program.len() - 2;
+        assert_eq!(program[allow].k, libc::SECCOMP_RET_ALLOW);
+        assert_eq!(
+            program[deny].k,
+            libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32
+        );
+        // x32 syscalls skip to the deny
+        assert_eq!(4 + 1 + program[4].jt as usize, deny);
+        for (i, nr) in syscalls.iter().enumerate() {
+            let pc = 5 + i;
+            assert_eq!(program[pc].k, *nr as u32);
+            assert_eq!(pc + 1 + program[pc].jt as usize, allow);
+        }
+
+        let program = seccomp_program(0xc000_003e, &syscalls, true);
+        let deny = program.len() - 2;
+        assert_eq!(4 + 1 + program[4].jt as usize, deny);
+        // everything but sockets skips the socket checks
+        assert_eq!(program[5].k, libc::SYS_socket as u32);
+        assert_eq!(5 + 1 + program[5].jf as usize, 14);
+        assert_eq!(program[14].k, libc::SYS_read as u32);
+
+        assert_eq!(handled_access(1) & ACCESS_FS_TRUNCATE, 0);
+        assert_ne!(handled_access(3) & ACCESS_FS_TRUNCATE, 0);
+    }
+}
@@ -0,0 +1,250 @@
+use crate::report::{Entry, Report};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// The schema that is referenced by the SARIF log
+pub const SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+/// The base that the locations of the results are relative to, the scan root
+const ROOT_BASE_ID: &str = "ROOT";
+
+/// The SARIF level of a finding kind, findings in sensitive paths are always errors
+pub fn level(kind: &str) -> &'static str {
+    if kind.starts_with("SENSITIVE ") {
+        return "error";
+    }
+    match kind {
+        "WRONG SHA256"
+        | "WRONG SIZE"
+        | "WRONG CONTENT"
+        | "TYPE CHANGED"
+        | "MTREE MISMATCH"
+        | "BOOT WRONG SHA256"
+        | "EFI WRONG SHA256"
+        | "INITRAMFS WRONG SHA256"
+        | "SYSTEMD WRONG SHA256"
+        | "MICROCODE INVALID"
+        | "LD PRELOAD"
+        | "PRIVILEGED UNTRACKED"
+        | "PRIVILEGED UNEXPECTED"
+        | "IO ERROR" => "error",
+        "NO SHA256"
+        | "MD5 ONLY"
+        | "KNOWN GOOD"
+        | "EXPECTED MUTATION"
+        | "MODIFIED CONFIG"
+        | "NO EXTRACT"
+        | "UNTRACKED ELSEWHERE"
+        | "MODULE DKMS"
+        | "RESTORED"
+        | "WOULD RESTORE"
+        | "PERMISSION DENIED"
+        | "VANISHED" => "note",
+        "PASSED" => "none",
+        _ => "warning",
+    }
+}
+
+/// The rule id of a finding kind, like `wrong-sha256` for `WRONG SHA256`
+pub fn rule_id(kind: &str) -> String {
+    kind.to_lowercase().replace(' ', "-")
+}
+
+fn description(kind: &str) -> String {
+    let kind = kind.strip_prefix("SENSITIVE ").unwrap_or(kind);
+    match kind {
+        "WRONG SHA256" => "The content of a file doesn't match its package".to_string(),
+        "WRONG SIZE" => "The size of a file doesn't match its package".to_string(),
+        "WRONG CONTENT" => {
+            "A file matches the .MTREE of its package, but not the file in the package".to_string()
+        }
+        "MTREE MISMATCH" => {
+            "The .MTREE of a package doesn't match the files in the package".to_string()
+        }
+        "MISSING FILE" => "A file of an installed package is missing".to_string(),
+        "NO EXTRACT" => {
+            "A file of an installed package is missing, it matches NoExtract of pacman.conf"
+                .to_string()
+        }
+        "PERMISSION DENIED" => "A file couldn't be read because access was denied".to_string(),
+        "VANISHED" => "A file was removed while it was scanned".to_string(),
+        "IO ERROR" => "A file couldn't be read, the media may be damaged".to_string(),
+        "UNREADABLE SYMLINK" => "The target of a symlink couldn't be read".to_string(),
+        "RESTORED" => "A file was replaced with the file of its package".to_string(),
+        "WOULD RESTORE" => "A file would be replaced with the file of its package".to_string(),
+        "RESTORE ERROR" => "A file couldn't be restored from its package".to_string(),
+        "PASSED" => "A file was verified against its package".to_string(),
+        "UNTRACKED" => "A file isn't owned by any installed package".to_string(),
+        "UNTRACKED ELSEWHERE" => {
+            "Files outside of package-managed directories aren't owned by any package".to_string()
+        }
+        "NO SHA256" => "A file has no trusted hash to compare against".to_string(),
+        "TYPE CHANGED" => {
+            "A packaged file was replaced with another type, like a symlink or directory"
+                .to_string()
+        }
+        "WRONG METADATA" => {
+            "The mode, owner or link target of a file doesn't match its package".to_string()
+        }
+        "NO TRUSTED SOURCE" => "No trusted source has the files of a package".to_string(),
+        _ => format!("Finding of kind {kind}"),
+    }
+}
+
+/// Percent-encode a relative path for a SARIF uri
+fn encode_uri(path: &Path) -> String {
+    use std::os::unix::ffi::OsStrExt;
+    let mut uri = String::new();
+    for &b in path.as_os_str().as_bytes() {
+        if b.is_ascii_alphanumeric() || b"/-._~".contains(&b) {
+            uri.push(b as char);
+        } else {
+            uri.push_str(&format!("%{b:02X}"));
+        }
+    }
+    uri
+}
+
+fn result(entry: &Entry, root: &Path, rule_index: usize) -> serde_json::Value {
+    let mut result = serde_json::json!({
+        "ruleId": rule_id(&entry.kind),
+        "ruleIndex": rule_index,
+        "level": level(&entry.kind),
+        "message": { "text": entry.to_string() },
+    });
+    if entry.kind == "PASSED" {
+        result["kind"] = "pass".into();
+    }
+    if let Some(path) = &entry.path {
+        let artifact = match path.strip_prefix(root) {
+            Ok(rel) => serde_json::json!({ "uri": encode_uri(rel), "uriBaseId": ROOT_BASE_ID }),
+            Err(_) => serde_json::json!({ "uri": format!("file://{}", encode_uri(path)) }),
+        };
+        result["locations"] = serde_json::json!([
+            { "physicalLocation": { "artifactLocation": artifact } }
+        ]);
+    }
+    let mut properties = serde_json::Map::new();
+    for (key, value) in [
+        ("sha256", &entry.sha256),
+        ("expected", &entry.expected),
+        ("package", &entry.package),
+        ("packageVersion", &entry.package_version),
+        ("diff", &entry.diff),
+    ] {
+        if let Some(value) = value {
+            properties.insert(key.to_string(), value.clone().into());
+        }
+    }
+    if !entry.details.is_empty() {
+        properties.insert("details".to_string(), entry.details.clone().into());
+    }
+    if !properties.is_empty() {
+        result["properties"] = properties.into();
+    }
+    result
+}
+
+/// Convert the report to a SARIF 2.1.0 log with a single run, every finding kind is a rule
+pub fn to_sarif(report: &Report) -> serde_json::Value {
+    let kinds = report
+        .entries
+        .iter()
+        .map(|entry| entry.kind.as_str())
+        .collect::<std::collections::BTreeSet<_>>()
+        .into_iter()
+        .enumerate()
+        .map(|(i, kind)| (kind, i))
+        .collect::<BTreeMap<_, _>>();
+    let rules = kinds
+        .keys()
+        .map(|kind| {
+            serde_json::json!({
+                "id": rule_id(kind),
+                "name": kind,
+                "shortDescription": { "text": description(kind) },
+                "defaultConfiguration": { "level": level(kind) },
+            })
+        })
+        .collect::<Vec<_>>();
+    let results = report
+        .entries
+        .iter()
+        .map(|entry| result(entry, &report.root, kinds[entry.kind.as_str()]))
+        .collect::<Vec<_>>();
+
+    let mut root = report.root.to_string_lossy().into_owned();
+    if !root.ends_with('/') {
+        root.push('/');
+    }
+    let mut run = serde_json::json!({
+        "tool": {
+            "driver": {
+                "name": env!("CARGO_PKG_NAME"),
+                "version": env!("CARGO_PKG_VERSION"),
+                "informationUri": "https://github.com/kpcyrd/archlinux-userland-fs-cmp",
+                "rules": rules,
+            },
+        },
+        "originalUriBaseIds": {
+            ROOT_BASE_ID: { "uri": format!("file://{}", encode_uri(Path::new(&root))) },
+        },
+        "results": results,
+    });
+    if let Some(summary) = &report.summary {
+        run["invocations"] = serde_json::json!([{
+            "commandLine": summary.command_line.join(" "),
+            "arguments": summary.command_line.get(1..).unwrap_or_default(),
+            "executionSuccessful": true,
+        }]);
+        run["properties"] = serde_json::json!({ "summary": summary });
+    }
+    serde_json::json!({
+        "$schema": SCHEMA,
+        "version": "2.1.0",
+        "runs": [run],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn sarif_log() {
+        let mut wrong = Entry::path("WRONG SHA256", "/mnt/usr/bin/my tool");
+        wrong.package = Some("tool".to_string());
+        wrong.sha256 = Some("aa".to_string());
+        let report = Report {
+            root: PathBuf::from("/mnt"),
+            entries: vec![
+                wrong,
+                Entry::path("UNTRACKED", "/mnt/usr/bin/evil"),
+                Entry::path("SENSITIVE UNTRACKED", "/mnt/etc/sudoers.d/x"),
+                Entry::new("UNTRACKED ELSEWHERE", None).detail("3 files"),
+            ],
+            ..Default::default()
+        };
+        let sarif = to_sarif(&report);
+        let run = &sarif["runs"][0];
+        assert_eq!(sarif["version"], "2.1.0");
+        assert_eq!(run["originalUriBaseIds"]["ROOT"]["uri"], "file:///mnt/");
+        assert_eq!(run["tool"]["driver"]["rules"].as_array().unwrap().len(), 4);
+
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(results[0]["ruleId"], "wrong-sha256");
+        assert_eq!(results[0]["level"], "error");
+        assert_eq!(results[0]["properties"]["package"], "tool");
+        assert_eq!(
+            results[0]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "usr/bin/my%20tool"
+        );
+        assert_eq!(results[1]["level"], "warning");
+        assert_eq!(results[2]["level"], "error");
+        assert_eq!(results[3]["level"], "note");
+        assert!(results[3].get("locations").is_none());
+        let rules = &run["tool"]["driver"]["rules"];
+        let index = results[1]["ruleIndex"].as_u64().unwrap() as usize;
+        assert_eq!(rules[index]["id"], "untracked");
+    }
+}
@@ -0,0 +1,1097 @@
+use crate::disk::{self, HashVerify};
+use crate::errors::*;
+use crate::filter::PathFilter;
+use crate::hashes::TrustedHashes;
+use crate::pkg::{self, Package};
+use crate::profile::Profile;
+use crate::report::{Entry, Phase, Report};
+use crate::throttle::Pause;
+use crate::{
+    digest, fetch, generated, mounts, mtree, pacman_conf, passlog, resolve_target_path, state,
+    trust, Event, MERGED_USR_DIRS,
+};
+use colored::{Color, Colorize};
+use indicatif::HumanBytes;
+use num_format::{Locale, ToFormattedString};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+
+const PATH_TRUNCATE: usize = 85;
+/// Maximum number of files handed to a hash worker at once
+const MAX_HASH_BATCH: usize = 64;
+/// Estimated memory of an entry in a map or queue, besides its path and hash
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Verify an Arch Linux system against the packages of the Arch Linux Archive, without
+/// the extra checks of the command line tool:
+///
+/// ```no_run
+/// use archlinux_userland_fs_cmp::scanner::Scanner;
+///
+/// # async fn example() -> anyhow::Result<()> {
+/// let report = Scanner::new("/mnt")
+///     .with_mirror("https://mirror.example.com/archive")
+///     .with_exclude("/home")
+///     .run()
+///     .await?;
+/// for entry in &report.entries {
+///     println!("{} {:?}", entry.kind, entry.path);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct Scanner {
+    root: PathBuf,
+    mirrors: Vec<String>,
+    bundle: Option<PathBuf>,
+    exclude: Vec<PathBuf>,
+    concurrency: usize,
+    hash_untracked: bool,
+    profile: Option<Profile>,
+}
+
+impl Scanner {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Scanner {
+            root: root.into(),
+            mirrors: Vec::new(),
+            bundle: None,
+            exclude: Vec::new(),
+            concurrency: num_cpus::get(),
+            hash_untracked: false,
+            profile: None,
+        }
+    }
+
+    /// Download packages from this mirror of the Arch Linux Archive (or a url template like
+    /// with `--archive-url`) instead of the official one, mirrors are tried in order
+    pub fn with_mirror(mut self, url: impl Into<String>) -> Self {
+        self.mirrors.push(url.into());
+        self
+    }
+
+    /// Read packages from a local folder before downloading them
+    pub fn with_bundle(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.bundle = Some(dir.into());
+        self
+    }
+
+    /// Don't scan this path of the investigated system
+    pub fn with_exclude(mut self, path: impl Into<PathBuf>) -> Self {
+        self.exclude.push(path.into());
+        self
+    }
+
+    /// How many files are hashed concurrently
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Also hash files that aren't owned by any package, for the `sha256` of their report entries
+    pub fn with_untracked_hashes(mut self, hash_untracked: bool) -> Self {
+        self.hash_untracked = hash_untracked;
+        self
+    }
+
+    /// Tag findings in the high-value locations of this profile as `SENSITIVE`
+    pub fn with_profile(mut self, profile: Profile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    /// Run the scan to completion, all workers are stopped if the future is dropped
+    pub async fn run(self) -> Result<Report> {
+        let root = self.root;
+        let conf = pacman_conf::load(&root, Path::new(pacman_conf::PATH)).await?;
+        let dbpath = resolve_target_path(
+            &root,
+            conf.dbpath.as_deref().unwrap_or(Path::new(pkg::DBPATH)),
+        );
+
+        let shutdown = CancellationToken::new();
+        let _shutdown = shutdown.clone().drop_guard();
+        let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let (pkg_tx, pkg_rx) = mpsc::unbounded_channel();
+
+        let decompress = fetch::Decompressors::default();
+        let mut sources = trust::Chain::default();
+        if let Some(dir) = self.bundle {
+            sources.push(Box::new(trust::Bundle {
+                dir,
+                decompress: decompress.clone(),
+            }));
+        }
+        let urls = if self.mirrors.is_empty() {
+            vec![pkg::ARCHIVE_URL.to_string()]
+        } else {
+            self.mirrors
+        };
+        sources.push(Box::new(trust::Archive {
+            client: reqwest::Client::new(),
+            urls,
+            decompress,
+            retry: fetch::Retry::default(),
+            limit_rate: None,
+            transferred: Default::default(),
+            snapshot: None,
+        }));
+        fetch::spawn_workers(
+            event_tx.clone(),
+            pkg_rx,
+            &root,
+            Arc::new(sources),
+            fetch::NUM_HTTP_WORKERS,
+            Pause::default(),
+            shutdown.clone(),
+        );
+        // pacman keeps modified config files on upgrades, they're expected to differ
+        let backup_files = pkg::list_backup_files(&dbpath)
+            .await
+            .unwrap_or_else(|err| {
+                warn!("Failed to read backup files from pacman database: {err:#}");
+                HashSet::new()
+            })
+            .into_iter()
+            .map(|path| root.join(path))
+            .collect();
+        pkg::spawn_list_installed(event_tx.clone(), pkg_tx, dbpath, None, shutdown.clone());
+
+        let excluded = self
+            .exclude
+            .iter()
+            .map(|p| resolve_target_path(&root, p))
+            .collect::<HashSet<_>>();
+        let filter = PathFilter::new(root.clone(), Vec::new(), Vec::new());
+        disk::spawn_scan(
+            event_tx,
+            vec![root.clone()],
+            excluded.clone(),
+            filter.clone(),
+            Vec::new(),
+            self.concurrency,
+            num_cpus::get(),
+            None,
+            Pause::default(),
+            shutdown.clone(),
+        );
+
+        let mut scan = Scan::new(self.concurrency, self.hash_untracked, HashMap::new());
+        scan.backup_files = backup_files;
+        scan.ignores = generated::Ignores::new(true);
+        scan.pacman_conf = conf;
+        scan.profile = self.profile;
+        // the channel closes once all workers are done
+        while let Some(event) = event_rx.recv().await {
+            scan.update(event);
+            scan.dispatch_hashers();
+        }
+        disk::forget_hard_links();
+
+        let trusted = mem::take(&mut scan.trusted_metadata);
+        let excluded_dirs = excluded.clone();
+        let events =
+            task::spawn_blocking(move || disk::verify_metadata(trusted, &excluded_dirs)).await?;
+        for event in events {
+            scan.update(event);
+        }
+        let files_missing = scan.files_missing(&excluded, &filter);
+        scan.classify_findings(&root);
+
+        Ok(scan.report(root, &files_missing))
+    }
+}
+
+/// Top-level directories that are managed by the package manager, untracked files in them are suspicious.
+/// Untracked files elsewhere (like `/home` or `/srv`) are only counted, unless all of them are shown
+pub const PACKAGE_PREFIXES: &[&str] = &["usr", "opt", "boot", "etc"];
+
+/// The report entry that counts the untracked files outside of the [`PACKAGE_PREFIXES`]
+pub fn untracked_elsewhere(count: usize) -> Entry {
+    Entry::new("UNTRACKED ELSEWHERE", None).detail(format!(
+        "{} files outside of package-managed directories, list them with --show-all-untracked",
+        count.to_formatted_string(&Locale::en)
+    ))
+}
+
+/// The report entry of a file with a different size than in its package
+pub fn wrong_size(path: &Path, expected: u64, actual: u64) -> Entry {
+    Entry::path("WRONG SIZE", path).detail(format!("size: {expected} -> {actual}"))
+}
+
+/// How a modified file is reported, see [`Scan::classify`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Class {
+    /// A config file in the backup list of its package or matching NoUpgrade, pacman keeps
+    /// these on upgrades
+    Config,
+    /// Expected to change after install, with the category
+    Generated(&'static str),
+    /// In a high-value location of the profile
+    Sensitive,
+    Modified,
+}
+
+/// The state of a running scan, updated with the [`Event`]s of the workers
+#[derive(Default)]
+pub struct Scan {
+    pub num_hash_worker: usize,
+    pub retired_hashers: usize,
+    /// Number of hash workers the pool is scaled to
+    pub hash_worker_target: usize,
+    pub files_hashed: u64,
+    /// Size of the files that were found on disk, and of the files that have been hashed so far
+    pub bytes_found: u64,
+    pub bytes_hashed: u64,
+
+    pub completed_pkgs: u64,
+    pub total_pkgs: u64,
+    /// Packages that none of the trust sources knows
+    pub untrusted_pkgs: Vec<Package>,
+    /// Trusted files are sent to the targeted scan instead of walking the filesystem
+    pub targets: Option<mpsc::UnboundedSender<PathBuf>>,
+    pub trusted_hashes: TrustedHashes,
+    pub trusted_metadata: HashMap<PathBuf, mtree::Metadata>,
+    /// The package each trusted hash was read from
+    pub trusted_owners: HashMap<PathBuf, Arc<Package>>,
+    /// Trusted files that have been found on disk
+    pub trusted_found: HashSet<PathBuf>,
+
+    pub running_list_installed: bool,
+    pub running_disk_scan: bool,
+
+    pub waiting_for_data: BTreeSet<PathBuf>,
+    pub waiting_for_hasher: VecDeque<(PathBuf, Option<String>)>,
+    pub available_hashers: VecDeque<oneshot::Sender<disk::HashBatch>>,
+
+    pub hash_untracked: bool,
+    pub queued_untracked: bool,
+    pub untracked_hashes: BTreeMap<PathBuf, String>,
+    pub allowlist: HashMap<PathBuf, String>,
+
+    pub files_passed: u64,
+    /// Files that passed verification, only kept for `--print-passed`
+    pub passed: Option<BTreeMap<PathBuf, passlog::Method>>,
+    /// Write every file that passed verification to this log, for `--log-passed`
+    pub passed_log: Option<passlog::PassLog>,
+    pub stamps: HashMap<PathBuf, state::Stamp>,
+    pub files_flagged: BTreeMap<PathBuf, String>,
+    /// Files in the backup list of their package, like configs in /etc
+    pub backup_files: HashSet<PathBuf>,
+    pub files_modified_config: BTreeMap<PathBuf, String>,
+    pub files_wrong_metadata: BTreeMap<PathBuf, String>,
+    /// Packaged files that were replaced with another type, like a symlink or directory
+    pub files_type_changed: BTreeMap<PathBuf, String>,
+    /// Files with a different size than in their package, as expected and actual size
+    pub files_wrong_size: BTreeMap<PathBuf, (u64, u64)>,
+    /// Config files matching NoUpgrade with a different size, as expected and actual size
+    pub files_no_upgrade_size: BTreeMap<PathBuf, (u64, u64)>,
+    /// Modified and untracked files that are expected to change after install, with their category
+    pub files_generated: Vec<(PathBuf, &'static str)>,
+    /// Files that are expected to change after install
+    pub ignores: generated::Ignores,
+    /// NoUpgrade and NoExtract of the investigated system, empty for a strict scan
+    pub pacman_conf: pacman_conf::PacmanConf,
+    /// Findings in the high-value locations of this profile are tagged as `SENSITIVE`
+    pub profile: Option<Profile>,
+    /// Report files that could only be verified with md5, pacman packages normally have sha256
+    pub report_md5_only: bool,
+    pub files_md5_only: BTreeSet<PathBuf>,
+
+    pub disk_errors: Vec<disk::ScanError>,
+    pub disk_pwd: Option<PathBuf>,
+
+    /// Findings that are waiting to be written with `--stream`
+    pub streamed: Option<Vec<Entry>>,
+    /// Trusted paths are resolved to the separately mounted directories
+    pub mounts: mounts::Mounts,
+    /// Report untracked files outside of the [`PACKAGE_PREFIXES`] too, instead of only counting them
+    pub show_all_untracked: bool,
+    /// Bytes downloaded by the trust sources
+    pub transferred: fetch::Transferred,
+    pub started: Option<Instant>,
+    /// The phases that completed so far, for the summary of the report
+    pub phases: Vec<Phase>,
+}
+
+impl Scan {
+    pub fn new(
+        num_hash_worker: usize,
+        hash_untracked: bool,
+        allowlist: HashMap<PathBuf, String>,
+    ) -> Self {
+        Self {
+            num_hash_worker,
+            hash_worker_target: num_hash_worker,
+            hash_untracked,
+            allowlist,
+            running_list_installed: true,
+            running_disk_scan: true,
+            started: Some(Instant::now()),
+            ..Default::default()
+        }
+    }
+
+    /// Record that a phase completed, phases are only recorded once
+    pub fn complete_phase(&mut self, name: &str) {
+        if self.phases.iter().any(|phase| phase.name == name) {
+            return;
+        }
+        let seconds = self
+            .started
+            .map(|started| started.elapsed().as_secs_f64())
+            .unwrap_or_default();
+        self.phases.push(Phase {
+            name: name.to_string(),
+            seconds,
+        });
+    }
+
+    pub fn update(&mut self, event: Event) -> bool {
+        match event {
+            Event::PkgQueued => self.total_pkgs += 1,
+            Event::PkgCompleted => {
+                self.completed_pkgs += 1;
+                if self.trust_complete() {
+                    self.complete_phase("trusted hashes");
+                }
+                return true;
+            }
+            Event::NoTrustedSource(pkg) => {
+                self.untrusted_pkgs.push(pkg);
+            }
+            Event::TrustedFile(path, sha256, pkg) => {
+                let path = self.mounts.resolve(path);
+                if let Some(old) = self.trusted_hashes.get(&path) {
+                    warn!("Unexpected duplicate for {path:?} ({sha256:?} vs {old:?})");
+                } else {
+                    if self.waiting_for_data.remove(&path) {
+                        self.trusted_found.insert(path.clone());
+                        if let Some(calculated) = self.untracked_hashes.remove(&path) {
+                            self.verify_hash(path.clone(), &sha256, calculated);
+                        } else {
+                            self.waiting_for_hasher
+                                .push_back((path.clone(), Some(sha256.clone())));
+                        }
+                    }
+                    if let Some(pkg) = pkg {
+                        self.trusted_owners.insert(path.clone(), pkg);
+                    }
+                    if let Some(targets) = &self.targets {
+                        targets.send(path.clone()).ok();
+                    }
+                    self.trusted_hashes.insert(&path, &sha256);
+                }
+            }
+            Event::TrustedMetadata(path, metadata) => {
+                self.trusted_metadata
+                    .insert(self.mounts.resolve(path), metadata);
+            }
+            Event::WrongMetadata(path, diff) => {
+                self.files_wrong_metadata.insert(path, diff);
+            }
+            Event::TypeChanged(path, diff) => {
+                self.files_type_changed.insert(path, diff);
+            }
+            Event::DiskFile(path, size) => {
+                self.bytes_found += size;
+                if let Some(sha256) = self.trusted_hashes.get(&path) {
+                    self.trusted_found.insert(path.clone());
+                    self.waiting_for_hasher
+                        .push_back((path, Some(sha256.clone())));
+                } else {
+                    self.waiting_for_data.insert(path);
+                }
+            }
+            Event::DiskFileHashed(path, calculated) => {
+                if let Some(sha256) = self.trusted_hashes.get(&path) {
+                    let sha256 = sha256.clone();
+                    self.trusted_found.insert(path.clone());
+                    self.verify_hash(path, &sha256, calculated);
+                } else {
+                    self.waiting_for_data.insert(path.clone());
+                    self.untracked_hashes.insert(path, calculated);
+                }
+            }
+            Event::DiskPwd(path) => {
+                self.disk_pwd = Some(path);
+            }
+            Event::DiskError(err) => self.disk_error(err),
+            Event::CompletedListInstalled => {
+                self.running_list_installed = false;
+                self.complete_phase("list installed");
+                if self.trust_complete() {
+                    self.complete_phase("trusted hashes");
+                }
+                return true;
+            }
+            Event::CompletedDiskScan => {
+                self.running_disk_scan = false;
+                self.disk_pwd = None;
+                self.complete_phase("disk scan");
+                return true;
+            }
+            Event::AvailableHasher(hasher) => {
+                self.available_hashers.push_back(hasher);
+            }
+            Event::CompletedHashing(hashed, size) => {
+                self.files_hashed += 1;
+                self.bytes_hashed += size;
+                match hashed {
+                    HashVerify::Passed(path, stamp, sha256) => {
+                        self.pass(&path, sha256.as_deref(), passlog::Method::Hashed);
+                        if self.report_md5_only
+                            && self
+                                .trusted_hashes
+                                .get(&path)
+                                .is_some_and(|hash| hash.starts_with("md5:"))
+                        {
+                            self.files_md5_only.insert(path.clone());
+                        }
+                        // keep the sha256 that was computed in the same pass for exports
+                        if let Some(sha256) = sha256 {
+                            self.trusted_hashes.insert(&path, &sha256);
+                        }
+                        if let Some(stamp) = stamp {
+                            self.stamps.insert(path, stamp);
+                        }
+                    }
+                    HashVerify::Unchanged(path, stamp) => {
+                        self.pass(&path, None, passlog::Method::Unchanged);
+                        self.stamps.insert(path, stamp);
+                    }
+                    HashVerify::Flagged(path, sha256) => self.flag(path, sha256),
+                    HashVerify::Computed(path, sha256) => {
+                        self.untracked_hashes.insert(path, sha256);
+                    }
+                    HashVerify::WrongSize(path, expected, actual) => {
+                        // the file won't be hashed
+                        self.bytes_found = self.bytes_found.saturating_sub(actual);
+                        if let Some(streamed) = &mut self.streamed {
+                            let mut entry = wrong_size(&path, expected, actual);
+                            entry.expected = self.trusted_hashes.get(&path);
+                            entry.set_package(self.trusted_owners.get(&path).map(|pkg| &**pkg));
+                            streamed.push(entry);
+                        }
+                        self.files_wrong_size.insert(path, (expected, actual));
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    fn verify_hash(&mut self, path: PathBuf, expected: &str, calculated: String) {
+        match digest::Checksum::parse(expected) {
+            Ok(checksum) if checksum.algorithm == digest::Algorithm::Sha256 => (),
+            Ok(checksum) => {
+                let err = anyhow!(
+                    "Can't verify with a {} hash, only sha256 is computed while reading archives",
+                    checksum.algorithm.name()
+                );
+                self.disk_error(disk::ScanError::Other(Some(path), err));
+                return;
+            }
+            Err(err) => {
+                self.disk_error(disk::ScanError::HashDecode(path, err));
+                return;
+            }
+        }
+        if expected.eq_ignore_ascii_case(&calculated) {
+            self.pass(&path, Some(&calculated), passlog::Method::Hashed);
+        } else {
+            self.flag(path, calculated);
+        }
+    }
+
+    /// Count a file that passed verification, `sha256` is only needed if it's not the trusted hash
+    fn pass(&mut self, path: &Path, sha256: Option<&str>, method: passlog::Method) {
+        self.files_passed += 1;
+        if let Some(passed) = &mut self.passed {
+            passed.insert(path.to_owned(), method);
+        }
+        if let Some(log) = &mut self.passed_log {
+            let expected = self.trusted_hashes.get(path);
+            // the trusted hash was matched if it's a sha256
+            let sha256 = sha256.map(str::to_owned).or_else(|| {
+                expected
+                    .clone()
+                    .filter(|_| method == passlog::Method::Hashed)
+                    .filter(|hash| !hash.contains(':'))
+            });
+            let pkg = self.trusted_owners.get(path).map(|pkg| &**pkg);
+            if let Err(err) = log.write(path, pkg, sha256.as_deref(), expected.as_deref(), method) {
+                error!("{err:#}");
+                self.passed_log = None;
+            }
+        }
+    }
+
+    fn flag(&mut self, path: PathBuf, sha256: String) {
+        if self.backup_files.contains(&path) {
+            debug!("Modified file is a config file: {path:?}");
+            self.files_modified_config.insert(path, sha256);
+            return;
+        }
+        if let Some(streamed) = &mut self.streamed {
+            if self.allowlist.get(&path) != Some(&sha256) {
+                let mut entry = Entry::path("WRONG SHA256", &path);
+                entry.sha256 = Some(sha256.clone());
+                entry.expected = self.trusted_hashes.get(&path);
+                entry.set_package(self.trusted_owners.get(&path).map(|pkg| &**pkg));
+                streamed.push(entry);
+            }
+        }
+        self.files_flagged.insert(path, sha256);
+    }
+
+    fn disk_error(&mut self, err: disk::ScanError) {
+        if let Some(streamed) = &mut self.streamed {
+            let mut entry = Entry::new(err.kind(), err.path().map(Path::to_owned));
+            entry.set_package(
+                err.path()
+                    .and_then(|path| self.trusted_owners.get(path))
+                    .map(|pkg| &**pkg),
+            );
+            entry.details.extend(err.detail());
+            streamed.push(entry);
+        }
+        self.disk_errors.push(err);
+    }
+
+    /// Trusted files that were never found on disk, excluded (or unreadable) directories are skipped.
+    /// Files that were replaced with another type are reported as such
+    pub fn files_missing(&self, excluded: &HashSet<PathBuf>, filter: &PathFilter) -> Vec<PathBuf> {
+        let unreadable = self
+            .disk_errors
+            .iter()
+            .filter_map(|err| err.path())
+            .collect::<HashSet<_>>();
+        let mut missing = self
+            .trusted_hashes
+            .keys()
+            .filter(|path| !self.trusted_found.contains(path) && filter.is_included(path))
+            .filter(|path| !self.files_type_changed.contains_key(path))
+            .filter(|path| {
+                !path
+                    .ancestors()
+                    .any(|dir| excluded.contains(dir) || unreadable.contains(dir))
+            })
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing
+    }
+
+    /// Write the files that passed so far to the `--state-file`
+    pub async fn save_checkpoint(&mut self, path: &Path, root: &Path, started: i64) -> Result<()> {
+        debug!(
+            "Saving progress of {} verified files to {path:?}",
+            self.stamps.len()
+        );
+        let checkpoint = state::Checkpoint {
+            root: root.to_owned(),
+            started,
+            verified: mem::take(&mut self.stamps),
+        };
+        let result = checkpoint.save(path).await;
+        self.stamps = checkpoint.verified;
+        result
+    }
+
+    pub fn trust_complete(&self) -> bool {
+        !self.running_list_installed && self.completed_pkgs == self.total_pkgs
+    }
+
+    /// Hash workers that haven't been retired
+    pub fn active_hashers(&self) -> usize {
+        self.num_hash_worker - self.retired_hashers
+    }
+
+    /// Hash workers that are currently reading a file
+    pub fn busy_hashers(&self) -> usize {
+        self.active_hashers() - self.available_hashers.len()
+    }
+
+    /// Scale the pool of hash workers, new workers are started with `spawn`
+    /// and idle workers are retired until the target is reached
+    pub fn scale_hashers(&mut self, target: usize, spawn: impl FnOnce(usize)) {
+        let active = self.active_hashers();
+        if target > active {
+            spawn(target - active);
+            self.num_hash_worker += target - active;
+        }
+        self.hash_worker_target = target;
+        self.retire_scaled_hashers();
+    }
+
+    fn retire_scaled_hashers(&mut self) {
+        while self.active_hashers() > self.hash_worker_target {
+            // dropping the channel stops the worker
+            if self.available_hashers.pop_front().is_none() {
+                break;
+            }
+            self.num_hash_worker -= 1;
+        }
+    }
+
+    /// Estimated memory of the files waiting for their trusted hash (or a hasher), and of the trusted hashes
+    pub fn memory_usage(&self) -> (u64, u64) {
+        let size = |path: &PathBuf, hash: Option<&String>| {
+            (path.capacity() + hash.map(String::capacity).unwrap_or_default() + ENTRY_OVERHEAD)
+                as u64
+        };
+        let pending = self
+            .waiting_for_data
+            .iter()
+            .map(|path| size(path, None))
+            .chain(
+                self.waiting_for_hasher
+                    .iter()
+                    .map(|(path, hash)| size(path, hash.as_ref())),
+            )
+            .chain(
+                self.untracked_hashes
+                    .iter()
+                    .map(|(path, hash)| size(path, Some(hash))),
+            )
+            .sum();
+        let trusted = self.trusted_hashes.memory_size()
+            + self
+                .trusted_metadata
+                .iter()
+                .map(|(path, metadata)| size(path, metadata.link.as_ref()))
+                .chain(self.trusted_found.iter().map(|path| size(path, None)))
+                .chain(self.trusted_owners.keys().map(|path| size(path, None)))
+                .sum::<u64>();
+        (pending, trusted)
+    }
+
+    /// Once all trusted hashes are known, the remaining files are untracked
+    fn queue_untracked(&mut self) {
+        if self.queued_untracked || self.running_disk_scan || !self.trust_complete() {
+            return;
+        }
+        for path in &self.waiting_for_data {
+            if self.untracked_hashes.contains_key(path) {
+                continue;
+            }
+            if self.hash_untracked || self.allowlist.contains_key(path) {
+                debug!("Queueing untracked file for hashing: {path:?}");
+                self.waiting_for_hasher.push_back((path.clone(), None));
+            }
+        }
+        self.queued_untracked = true;
+    }
+
+    /// Check for modified files that aren't in the allowlist, config files and expected
+    /// mutations don't count
+    pub fn has_flagged(&self, root: &Path) -> bool {
+        let is_modified = |path: &Path| {
+            matches!(
+                self.classify(root, path),
+                Class::Sensitive | Class::Modified
+            )
+        };
+        self.files_wrong_size.keys().any(|path| is_modified(path))
+            || self
+                .files_flagged
+                .iter()
+                .any(|(path, sha256)| self.allowlist.get(path) != Some(sha256) && is_modified(path))
+    }
+
+    /// How a modified file is reported, the same for `--stream` and the final report
+    pub fn classify(&self, root: &Path, path: &Path) -> Class {
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        if self.backup_files.contains(path) {
+            Class::Config
+        } else if let Some(category) = self.ignores.classify(rel) {
+            Class::Generated(category)
+        } else if self.pacman_conf.is_no_upgrade(rel) {
+            Class::Config
+        } else if self.is_sensitive(root, path) {
+            Class::Sensitive
+        } else {
+            Class::Modified
+        }
+    }
+
+    /// Check if a file is in a high-value location of the [`Scan::profile`]
+    pub fn is_sensitive(&self, root: &Path, path: &Path) -> bool {
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        self.profile.is_some_and(|profile| profile.matches(rel))
+    }
+
+    /// The kind of a report entry, findings in high-value locations are tagged as `SENSITIVE`
+    pub fn tag(&self, root: &Path, path: &Path, kind: &str) -> String {
+        if self.is_sensitive(root, path) {
+            format!("SENSITIVE {kind}")
+        } else {
+            kind.to_string()
+        }
+    }
+
+    /// Check if pacman doesn't extract a file (like trimmed locales), it's not missing then
+    pub fn is_no_extract(&self, root: &Path, path: &Path) -> bool {
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        self.pacman_conf.is_no_extract(rel)
+    }
+
+    /// Move modified and untracked files that are expected to change out of the findings,
+    /// and treat modified files matching NoUpgrade like config files
+    pub fn classify_findings(&mut self, root: &Path) {
+        let mut generated = Vec::new();
+        let mut config = BTreeMap::new();
+        let mut config_size = BTreeMap::new();
+        for (path, sha256) in mem::take(&mut self.files_flagged) {
+            match self.classify(root, &path) {
+                Class::Config => {
+                    config.insert(path, sha256);
+                }
+                Class::Generated(category) => generated.push((path, category)),
+                Class::Sensitive | Class::Modified => {
+                    self.files_flagged.insert(path, sha256);
+                }
+            }
+        }
+        for (path, size) in mem::take(&mut self.files_wrong_size) {
+            match self.classify(root, &path) {
+                Class::Config => {
+                    config_size.insert(path, size);
+                }
+                Class::Generated(category) => generated.push((path, category)),
+                Class::Sensitive | Class::Modified => {
+                    self.files_wrong_size.insert(path, size);
+                }
+            }
+        }
+        self.waiting_for_data.retain(|path| {
+            let rel = path.strip_prefix(root).unwrap_or(path);
+            let category = self.ignores.classify(rel);
+            generated.extend(category.map(|category| (path.clone(), category)));
+            category.is_none()
+        });
+        self.files_modified_config.extend(config);
+        self.files_no_upgrade_size.extend(config_size);
+        self.files_generated.extend(generated);
+        self.files_generated.sort();
+    }
+
+    /// Remove findings for files that still match their pinned hash
+    pub fn apply_allowlist(&mut self) {
+        let allowlist = &self.allowlist;
+        let is_pinned = |path: &PathBuf, sha256: &String| allowlist.get(path) == Some(sha256);
+
+        self.files_flagged.retain(|path, sha256| {
+            let pinned = is_pinned(path, sha256);
+            if pinned {
+                debug!("Modified file matches pinned hash: {path:?}");
+            }
+            !pinned
+        });
+
+        let untracked_hashes = &self.untracked_hashes;
+        self.waiting_for_data.retain(|path| {
+            let pinned = untracked_hashes
+                .get(path)
+                .is_some_and(|sha256| is_pinned(path, sha256));
+            if pinned {
+                debug!("Untracked file matches pinned hash: {path:?}");
+            }
+            !pinned
+        });
+    }
+
+    /// Hand the queued files to idle hash workers, workers are retired once nothing is left to hash
+    pub fn dispatch_hashers(&mut self) {
+        self.retire_scaled_hashers();
+        while !self.waiting_for_hasher.is_empty() && !self.available_hashers.is_empty() {
+            let hasher = self.available_hashers.pop_front().unwrap();
+            // long queues (like many small files) are handed out in batches, while still
+            // leaving enough for the other workers
+            let size = (self.waiting_for_hasher.len() / (self.available_hashers.len() + 1) / 4)
+                .clamp(1, MAX_HASH_BATCH);
+            // config files and pinned files are hashed anyway, they're reported (or allowed)
+            // by their hash if modified
+            let batch = self
+                .waiting_for_hasher
+                .drain(..size)
+                .map(|(path, sha256)| {
+                    let metadata = self
+                        .trusted_metadata
+                        .get(&path)
+                        .filter(|_| !self.backup_files.contains(&path))
+                        .filter(|_| !self.allowlist.contains_key(&path))
+                        .cloned();
+                    (path, sha256, metadata)
+                })
+                .collect::<Vec<_>>();
+            if let Err(batch) = hasher.send(batch) {
+                // the worker is gone, hand the files to the next one
+                for (path, sha256, _) in batch.into_iter().rev() {
+                    self.waiting_for_hasher.push_front((path, sha256));
+                }
+            }
+        }
+
+        self.queue_untracked();
+
+        while !self.available_hashers.is_empty()
+            && self.waiting_for_hasher.is_empty()
+            && !self.running_disk_scan
+            && self.trust_complete()
+            && self.queued_untracked
+        {
+            self.available_hashers.pop_front();
+            self.retired_hashers += 1;
+        }
+    }
+
+    /// Check if a file is below one of the [`PACKAGE_PREFIXES`] (or a top-level directory of a
+    /// system without merged /usr), untracked files there are suspicious
+    pub fn is_package_managed(&self, root: &Path, path: &Path) -> bool {
+        let path = self.mounts.unresolve(path);
+        let Some(dir) = path
+            .strip_prefix(root)
+            .ok()
+            .and_then(|rel| rel.components().next())
+        else {
+            return false;
+        };
+        let dir = dir.as_os_str();
+        PACKAGE_PREFIXES
+            .iter()
+            .chain(MERGED_USR_DIRS)
+            .any(|prefix| dir == *prefix)
+    }
+
+    /// The findings of a completed scan, without the extra analysis of the command line tool.
+    /// Expected changes are only reported separately after [`Scan::classify_findings`]
+    pub fn report(&self, root: PathBuf, files_missing: &[PathBuf]) -> Report {
+        let owner = |path: &Path| self.trusted_owners.get(path).map(|pkg| &**pkg);
+        let tag = |path: &Path, kind: &str| self.tag(&root, path, kind);
+        let (files_no_extract, files_missing) = files_missing
+            .iter()
+            .partition::<Vec<_>, _>(|path| self.is_no_extract(&root, path));
+        let mut entries = Vec::new();
+        for pkg in &self.untrusted_pkgs {
+            let mut entry = Entry::new("NO TRUSTED SOURCE", None)
+                .detail(format!("{}-{}-{}", pkg.name, pkg.version, pkg.arch));
+            entry.set_package(Some(pkg));
+            entries.push(entry);
+        }
+        for (path, sha256) in &self.files_modified_config {
+            let mut entry = Entry::path("MODIFIED CONFIG", path);
+            entry.sha256 = Some(sha256.clone());
+            entry.expected = self.trusted_hashes.get(path);
+            entry.set_package(owner(path));
+            entries.push(entry);
+        }
+        for (path, (expected, actual)) in &self.files_no_upgrade_size {
+            let mut entry = Entry::path("MODIFIED CONFIG", path)
+                .detail(format!("size: {expected} -> {actual}"));
+            entry.expected = self.trusted_hashes.get(path);
+            entry.set_package(owner(path));
+            entries.push(entry);
+        }
+        for path in files_no_extract {
+            let mut entry = Entry::path("NO EXTRACT", path);
+            entry.set_package(owner(path));
+            entries.push(entry);
+        }
+        for (path, category) in &self.files_generated {
+            entries.push(Entry::path("EXPECTED MUTATION", path).detail(*category));
+        }
+        let mut elsewhere = 0;
+        for path in &self.waiting_for_data {
+            let kind = if self.is_package_managed(&root, path) {
+                "UNTRACKED"
+            } else if self.show_all_untracked || self.is_sensitive(&root, path) {
+                "NO SHA256"
+            } else {
+                elsewhere += 1;
+                continue;
+            };
+            let mut entry = Entry::path(tag(path, kind), path);
+            entry.sha256 = self.untracked_hashes.get(path).cloned();
+            entries.push(entry);
+        }
+        if elsewhere > 0 {
+            entries.push(untracked_elsewhere(elsewhere));
+        }
+        for err in &self.disk_errors {
+            let mut entry = Entry::new(err.kind(), err.path().map(Path::to_owned));
+            entry.set_package(err.path().and_then(owner));
+            entry.details.extend(err.detail());
+            entries.push(entry);
+        }
+        for (path, sha256) in &self.files_flagged {
+            let mut entry = Entry::path(tag(path, "WRONG SHA256"), path);
+            entry.sha256 = Some(sha256.clone());
+            entry.expected = self.trusted_hashes.get(path);
+            entry.set_package(owner(path));
+            entries.push(entry);
+        }
+        for (path, (expected, actual)) in &self.files_wrong_size {
+            let mut entry = wrong_size(path, *expected, *actual);
+            entry.kind = tag(path, &entry.kind);
+            entry.expected = self.trusted_hashes.get(path);
+            entry.set_package(owner(path));
+            entries.push(entry);
+        }
+        for path in files_missing {
+            let mut entry = Entry::path(tag(path, "MISSING FILE"), path);
+            entry.set_package(owner(path));
+            entries.push(entry);
+        }
+        for (path, diff) in &self.files_type_changed {
+            let mut entry = Entry::path(tag(path, "TYPE CHANGED"), path).detail(diff);
+            entry.set_package(owner(path));
+            entries.push(entry);
+        }
+        for (path, diff) in &self.files_wrong_metadata {
+            let mut entry = Entry::path("WRONG METADATA", path).detail(diff);
+            entry.set_package(owner(path));
+            entries.push(entry);
+        }
+        Report {
+            root,
+            entries,
+            ..Default::default()
+        }
+    }
+
+    /// The progress line of the scan, for the terminal
+    pub fn status(&self) -> String {
+        let mut status = "packages: ".bold().to_string();
+        status.push_str(
+            &format!(
+                "{:>7}",
+                self.completed_pkgs.to_formatted_string(&Locale::en)
+            )
+            .color(if self.completed_pkgs < self.total_pkgs {
+                Color::Yellow
+            } else {
+                Color::Green
+            })
+            .to_string(),
+        );
+        status.push_str(&"/".bold().to_string());
+        status.push_str(
+            &self
+                .total_pkgs
+                .to_formatted_string(&Locale::en)
+                .bold()
+                .to_string(),
+        );
+        if self.running_list_installed {
+            status.push_str("...");
+        }
+
+        if !self.trusted_hashes.is_empty() {
+            status.push_str(
+                &format!(
+                    " (files: {:>7})",
+                    self.trusted_hashes.len().to_formatted_string(&Locale::en)
+                )
+                .bright_black()
+                .to_string(),
+            );
+        }
+        let transferred = self.transferred.get();
+        if transferred > 0 {
+            status.push_str(
+                &format!(" (downloaded: {})", HumanBytes(transferred))
+                    .bright_black()
+                    .to_string(),
+            );
+        }
+
+        status.push_str(&" | scanned disk: ".bold().to_string());
+        status.push_str(
+            &format!(
+                "{:>8}",
+                self.waiting_for_data.len().to_formatted_string(&Locale::en)
+            )
+            .yellow()
+            .to_string(),
+        );
+        if self.running_disk_scan {
+            status.push_str("...");
+        }
+        status.push('/');
+        status.push_str(&format!(
+            "{:>7}",
+            self.waiting_for_hasher
+                .len()
+                .to_formatted_string(&Locale::en)
+        ));
+
+        status.push_str(&" | hashing ".bold().to_string());
+        {
+            let running_hash_workers = self.busy_hashers();
+            let s = format!("{running_hash_workers}/{}", self.num_hash_worker);
+            let s = if running_hash_workers == self.num_hash_worker {
+                s.cyan()
+            } else if running_hash_workers == 0 {
+                s.bright_black()
+            } else {
+                s.normal()
+            }
+            .to_string();
+            status.push('[');
+            status.push_str(&s);
+            status.push(']');
+        }
+
+        status.push_str(&" | passed".bold().to_string());
+        status.push('=');
+        status.push_str(
+            &self
+                .files_passed
+                .to_formatted_string(&Locale::en)
+                .green()
+                .to_string(),
+        );
+        status.push_str(&" failed".bold().to_string());
+        status.push('=');
+        status.push_str(
+            &self
+                .files_flagged
+                .len()
+                .to_formatted_string(&Locale::en)
+                .red()
+                .to_string(),
+        );
+
+        if let Some(path) = &self.disk_pwd {
+            let path = format!("{:?}", path.display());
+
+            let mut offset = 0;
+            let mut width = 0;
+            let mut truncated = false;
+            for c in path.chars() {
+                width += unicode_width::UnicodeWidthChar::width(c).unwrap_or(1);
+                if width < PATH_TRUNCATE {
+                    offset += c.len_utf8();
+                } else {
+                    truncated = true;
+                    break;
+                }
+            }
+
+            let path = &path[..offset];
+            let truncated = if truncated { "..." } else { "" };
+            status.push_str(&format!(" ({path}{truncated})").bright_black().to_string());
+        }
+
+        status
+    }
+}
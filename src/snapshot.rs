@@ -0,0 +1,70 @@
+use crate::errors::*;
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const BTRFS_SUPER_MAGIC: i64 = 0x9123683e;
+
+/// Check if a path is located on a btrfs filesystem
+pub fn is_btrfs(path: &Path) -> Result<bool> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mut buf = MaybeUninit::<libc::statfs>::uninit();
+    let ret = unsafe { libc::statfs(c_path.as_ptr(), buf.as_mut_ptr()) };
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        return Err(err).with_context(|| anyhow!("Failed to statfs {path:?}"));
+    }
+    let buf = unsafe { buf.assume_init() };
+    // the type of f_type differs between architectures
+    #[allow(clippy::unnecessary_cast)]
+    Ok(buf.f_type as i64 == BTRFS_SUPER_MAGIC)
+}
+
+fn btrfs(args: &[&std::ffi::OsStr]) -> Result<()> {
+    let status = Command::new("btrfs")
+        .args(args)
+        .status()
+        .context("Failed to execute btrfs")?;
+    if !status.success() {
+        bail!("btrfs exited with error: {status}");
+    }
+    Ok(())
+}
+
+/// A read-only btrfs snapshot that is deleted again when dropped
+#[derive(Debug)]
+pub struct Snapshot {
+    pub path: PathBuf,
+}
+
+impl Snapshot {
+    /// Create a read-only snapshot of the subvolume at `root`, it's placed inside of it
+    pub fn create(root: &Path) -> Result<Self> {
+        let path = root.join(format!(".archlinux-userland-fs-cmp-{}", std::process::id()));
+        info!("Creating read-only btrfs snapshot of {root:?} at {path:?}");
+        btrfs(&[
+            "subvolume".as_ref(),
+            "snapshot".as_ref(),
+            "-r".as_ref(),
+            root.as_os_str(),
+            path.as_os_str(),
+        ])
+        .with_context(|| anyhow!("Failed to create btrfs snapshot of {root:?}"))?;
+        Ok(Snapshot { path })
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        info!("Deleting btrfs snapshot {:?}", self.path);
+        if let Err(err) = btrfs(&[
+            "subvolume".as_ref(),
+            "delete".as_ref(),
+            self.path.as_os_str(),
+        ]) {
+            warn!("Failed to delete btrfs snapshot {:?}: {err:#}", self.path);
+        }
+    }
+}
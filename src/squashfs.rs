@@ -0,0 +1,99 @@
+use crate::disk::ScanError;
+use crate::errors::*;
+use crate::pkg::{self, Package};
+use crate::Event;
+use backhand::{FilesystemReader, InnerNode};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+
+fn scan(
+    event_tx: &mpsc::UnboundedSender<Event>,
+    image: &Path,
+    root: &Path,
+    dbpath: &Path,
+    excluded: &HashSet<PathBuf>,
+    pkg_tx: Option<&mpsc::UnboundedSender<Package>>,
+    shutdown: &CancellationToken,
+) -> Result<()> {
+    let file = File::open(image).with_context(|| anyhow!("Failed to open squashfs: {image:?}"))?;
+    let fs = FilesystemReader::from_reader(BufReader::new(file))
+        .with_context(|| anyhow!("Failed to read squashfs: {image:?}"))?;
+
+    for node in fs.files() {
+        if shutdown.is_cancelled() {
+            break;
+        }
+        let rel = node.fullpath.strip_prefix("/").unwrap_or(&node.fullpath);
+        let path = root.join(rel);
+        if excluded.iter().any(|excluded| path.starts_with(excluded)) {
+            continue;
+        }
+
+        let event = match &node.inner {
+            InnerNode::Dir(_) => Event::DiskPwd(path),
+            InnerNode::File(file) => {
+                let mut reader = fs.file(file).reader();
+                if let Some(pkg_tx) = pkg_tx.filter(|_| pkg::is_local_desc(dbpath, rel)) {
+                    let mut desc = String::new();
+                    reader
+                        .read_to_string(&mut desc)
+                        .with_context(|| anyhow!("Failed to read pacman database: {rel:?}"))?;
+                    if let Some(pkg) = pkg::parse_desc(&desc) {
+                        debug!("Found installed package: {:?} {:?}", pkg.name, pkg.version);
+                        event_tx.send(Event::PkgQueued)?;
+                        pkg_tx.send(pkg)?;
+                    }
+                    Event::DiskFileHashed(path, crate::disk::sha256(desc.as_bytes()))
+                } else {
+                    let mut hasher = Sha256::new();
+                    match io::copy(&mut reader, &mut hasher) {
+                        Ok(_) => Event::DiskFileHashed(path, hex::encode(hasher.finalize())),
+                        Err(err) => Event::DiskError(ScanError::from_io(path, err)),
+                    }
+                }
+            }
+            // ignore symlinks and special files for now
+            _ => continue,
+        };
+        event_tx.send(event)?;
+    }
+
+    Ok(())
+}
+
+/// Read files from a squashfs image without mounting it, the pacman
+/// database is read from the image too unless `pkg_tx` is `None`
+pub fn spawn_scan(
+    event_tx: mpsc::UnboundedSender<Event>,
+    image: PathBuf,
+    root: PathBuf,
+    dbpath: PathBuf,
+    excluded: HashSet<PathBuf>,
+    pkg_tx: Option<mpsc::UnboundedSender<Package>>,
+    shutdown: CancellationToken,
+) {
+    task::spawn_blocking(move || {
+        if let Err(err) = scan(
+            &event_tx,
+            &image,
+            &root,
+            &dbpath,
+            &excluded,
+            pkg_tx.as_ref(),
+            &shutdown,
+        ) {
+            event_tx.send(Event::DiskError(err.into())).ok();
+        }
+
+        if pkg_tx.is_some() {
+            event_tx.send(Event::CompletedListInstalled).ok();
+        }
+        event_tx.send(Event::CompletedDiskScan).ok();
+    });
+}
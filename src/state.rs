@@ -0,0 +1,341 @@
+use crate::errors::*;
+use crate::mtree;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// The directory to keep state between runs in
+pub fn default_dir() -> Result<PathBuf> {
+    let base = if let Some(dir) = std::env::var_os("XDG_STATE_HOME") {
+        PathBuf::from(dir)
+    } else if let Some(home) = std::env::var_os("HOME") {
+        PathBuf::from(home).join(".local/state")
+    } else {
+        bail!("Failed to determine state directory, neither $XDG_STATE_HOME nor $HOME are set");
+    };
+    Ok(base.join("archlinux-userland-fs-cmp"))
+}
+
+/// The different kinds of data kept in the state directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Kind {
+    /// Trusted hashes of packages, read from their .MTREE
+    Mtree,
+    /// Hashes that weren't known to a threat intel service
+    Lookups,
+    /// Signed baselines of files not owned by any package
+    Baselines,
+    /// File timestamps of the previous --incremental run
+    Incremental,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Mtree => "mtree",
+            Kind::Lookups => "lookups",
+            Kind::Baselines => "baselines",
+            Kind::Incremental => "incremental",
+        }
+    }
+}
+
+/// Managed directory for caches and state between runs
+#[derive(Debug, Clone)]
+pub struct StateDir {
+    pub path: PathBuf,
+}
+
+impl StateDir {
+    /// Use the given path or fallback to the default location
+    pub fn new(path: Option<PathBuf>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path,
+            None => default_dir()?,
+        };
+        Ok(StateDir { path })
+    }
+
+    pub fn dir(&self, kind: Kind) -> PathBuf {
+        self.path.join(kind.name())
+    }
+
+    /// An allowlist that is used if none was given explicitly
+    pub fn allowlist(&self) -> PathBuf {
+        self.path.join("allowlist")
+    }
+
+    /// Number of files and their total size for each kind
+    pub fn stats(&self) -> Result<Vec<(Kind, u64, u64)>> {
+        let mut stats = Vec::new();
+        for kind in Kind::value_variants() {
+            let (mut files, mut size) = (0, 0);
+            let dir = self.dir(*kind);
+            if dir.exists() {
+                for entry in walkdir::WalkDir::new(&dir) {
+                    let entry = entry.with_context(|| anyhow!("Failed to read {dir:?}"))?;
+                    if entry.file_type().is_file() {
+                        files += 1;
+                        size += entry.metadata().map(|m| m.len()).unwrap_or(0);
+                    }
+                }
+            }
+            stats.push((*kind, files, size));
+        }
+        Ok(stats)
+    }
+
+    /// Delete the given kinds of data, or everything if none are given
+    pub async fn clear(&self, kinds: &[Kind]) -> Result<()> {
+        let kinds = if kinds.is_empty() {
+            Kind::value_variants()
+        } else {
+            kinds
+        };
+        for kind in kinds {
+            let dir = self.dir(*kind);
+            match fs::remove_dir_all(&dir).await {
+                Ok(()) => info!("Removed {dir:?}"),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                Err(err) => {
+                    return Err(err).with_context(|| anyhow!("Failed to remove {dir:?}"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Modification and change time of a file, with nanosecond precision
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Stamp {
+    pub mtime: (i64, i64),
+    pub ctime: (i64, i64),
+}
+
+impl Stamp {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        Stamp {
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+        }
+    }
+}
+
+/// The cutoff of `--since`, a unix timestamp or the start of the previous `--since` run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Since {
+    Timestamp(i64),
+    LastRun,
+}
+
+impl Since {
+    /// Parse `@last-run`, a unix timestamp like `@1704931316` or a date like `2024-01-31` (UTC)
+    pub fn parse(s: &str) -> Result<Self> {
+        if s == "@last-run" {
+            return Ok(Since::LastRun);
+        }
+        if let Some(timestamp) = s.strip_prefix('@') {
+            let timestamp = timestamp
+                .parse()
+                .with_context(|| anyhow!("Invalid unix timestamp: {s:?}"))?;
+            return Ok(Since::Timestamp(timestamp));
+        }
+        let parts = s
+            .split('-')
+            .map(|part| part.parse::<i64>().ok())
+            .collect::<Option<Vec<_>>>();
+        let Some([year, month, day]) = parts.as_deref() else {
+            bail!("Expected `@last-run`, `@<unix timestamp>` or a date like `2024-01-31`: {s:?}");
+        };
+        if !(1..=12).contains(month) || !(1..=31).contains(day) {
+            bail!("Invalid date: {s:?}");
+        }
+        Ok(Since::Timestamp(
+            days_from_civil(*year, *month, *day) * 86400,
+        ))
+    }
+}
+
+/// Days since 1970-01-01 of a date in the proleptic gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// The file with the start of the previous `--since` run of a scan root, for `--since @last-run`
+pub fn last_run_path(state: &StateDir, root: &Path) -> PathBuf {
+    Incremental::path_for(state, root).with_extension("last-run")
+}
+
+pub async fn load_last_run(path: &Path) -> Result<Option<i64>> {
+    match fs::read_to_string(path).await {
+        Ok(started) => {
+            let started = started
+                .trim()
+                .parse()
+                .with_context(|| anyhow!("Failed to parse last run: {path:?}"))?;
+            Ok(Some(started))
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| anyhow!("Failed to read last run: {path:?}")),
+    }
+}
+
+pub async fn save_last_run(path: &Path, started: i64) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| anyhow!("Failed to create directory: {parent:?}"))?;
+    }
+    fs::write(path, format!("{started}\n"))
+        .await
+        .with_context(|| anyhow!("Failed to write last run: {path:?}"))
+}
+
+/// Files that passed verification in a previous run
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Incremental {
+    /// Unix timestamp of when the previous run has started
+    pub started: i64,
+    pub files: HashMap<PathBuf, Stamp>,
+    /// Share of unchanged files that are verified anyway
+    #[serde(skip)]
+    pub sample: f64,
+    /// Files that have the size and modification time of their package are skipped if they
+    /// didn't change since this unix timestamp, with `--since`
+    #[serde(skip)]
+    pub since: Option<i64>,
+}
+
+impl Incremental {
+    /// The state file for a given scan root
+    pub fn path_for(state: &StateDir, root: &Path) -> PathBuf {
+        let id = crate::disk::sha256(root.as_os_str().as_encoded_bytes());
+        state
+            .dir(Kind::Incremental)
+            .join(format!("{}.json", &id[..16]))
+    }
+
+    pub async fn load(path: &Path) -> Result<Self> {
+        match fs::read(path).await {
+            Ok(buf) => serde_json::from_slice(&buf)
+                .with_context(|| anyhow!("Failed to parse incremental state: {path:?}")),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                info!("No previous incremental state found, verifying all files");
+                Ok(Self::default())
+            }
+            Err(err) => {
+                Err(err).with_context(|| anyhow!("Failed to read incremental state: {path:?}"))
+            }
+        }
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| anyhow!("Failed to create directory: {parent:?}"))?;
+        }
+        let buf = serde_json::to_vec(self)?;
+        fs::write(path, buf)
+            .await
+            .with_context(|| anyhow!("Failed to write incremental state: {path:?}"))?;
+        Ok(())
+    }
+
+    /// Check if a file can be skipped, files that were changed after the
+    /// previous run has started are always verified, like a random sample
+    pub fn is_unchanged(&self, path: &Path, stamp: &Stamp) -> bool {
+        self.files.get(path) == Some(stamp)
+            && stamp.mtime.0 < self.started
+            && stamp.ctime.0 < self.started
+            && rand::random::<f64>() >= self.sample
+    }
+
+    /// Check if a file can be skipped because it has the size and modification time of its
+    /// package and neither its content nor its inode changed since the `--since` cutoff
+    pub fn is_older(&self, stamp: &Stamp, size: u64, expected: &mtree::Metadata) -> bool {
+        let Some(since) = self.since else {
+            return false;
+        };
+        expected.size == Some(size)
+            && expected.mtime == Some(stamp.mtime.0)
+            && stamp.mtime.0 < since
+            && stamp.ctime.0 < since
+            && rand::random::<f64>() >= self.sample
+    }
+}
+
+/// Progress of a scan that is written periodically with `--state-file`,
+/// so an interrupted scan can be continued with `--resume`
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub root: PathBuf,
+    /// Unix timestamp of when the interrupted run has started
+    pub started: i64,
+    /// Files that passed verification
+    pub verified: HashMap<PathBuf, Stamp>,
+}
+
+impl Checkpoint {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let buf = fs::read(path)
+            .await
+            .with_context(|| anyhow!("Failed to read state file: {path:?}"))?;
+        serde_json::from_slice(&buf)
+            .with_context(|| anyhow!("Failed to parse state file: {path:?}"))
+    }
+
+    /// Write the state file, the previous checkpoint is only replaced once the new one is complete
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let buf = serde_json::to_vec(self)?;
+        fs::write(&tmp, buf)
+            .await
+            .with_context(|| anyhow!("Failed to write state file: {tmp:?}"))?;
+        fs::rename(&tmp, path)
+            .await
+            .with_context(|| anyhow!("Failed to replace state file: {path:?}"))?;
+        Ok(())
+    }
+
+    /// Files that passed in the interrupted run and didn't change since are skipped
+    pub fn into_incremental(self) -> Incremental {
+        Incremental {
+            started: self.started,
+            files: self.verified,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_since() {
+        assert_eq!(Since::parse("@last-run").unwrap(), Since::LastRun);
+        assert_eq!(
+            Since::parse("@1704931316").unwrap(),
+            Since::Timestamp(1704931316)
+        );
+        assert_eq!(Since::parse("1970-01-01").unwrap(), Since::Timestamp(0));
+        assert_eq!(
+            Since::parse("2024-01-31").unwrap(),
+            Since::Timestamp(1706659200)
+        );
+        assert!(Since::parse("2024-13-01").is_err());
+        assert!(Since::parse("yesterday").is_err());
+    }
+}
@@ -0,0 +1,184 @@
+use crate::errors::*;
+use crate::hashes::TrustedHashes;
+use crate::report::Entry;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// Directories that contain system and user units, relative to the root
+const UNIT_DIRS: &[&str] = &[
+    "usr/lib/systemd/system",
+    "usr/lib/systemd/user",
+    "etc/systemd/system",
+    "etc/systemd/user",
+];
+const UNIT_EXTS: &[&str] = &[
+    "service",
+    "socket",
+    "timer",
+    "path",
+    "mount",
+    "automount",
+    "swap",
+    "target",
+    "slice",
+    "scope",
+    "device",
+];
+
+#[derive(Debug)]
+pub enum Finding {
+    /// A packaged unit or drop-in that doesn't match the packaged file
+    Mismatch(PathBuf),
+    /// A unit that isn't owned by any package
+    UntrackedUnit(PathBuf),
+    /// A drop-in that isn't owned by any package
+    UntrackedDropIn(PathBuf),
+    /// An untracked unit or drop-in that replaces the command of a service
+    ExecStart(PathBuf, String),
+    Error(PathBuf, Error),
+}
+
+impl From<Finding> for Entry {
+    fn from(finding: Finding) -> Self {
+        match finding {
+            Finding::Mismatch(path) => Entry::path("SYSTEMD WRONG SHA256", path),
+            Finding::UntrackedUnit(path) => Entry::path("SYSTEMD UNTRACKED UNIT", path),
+            Finding::UntrackedDropIn(path) => Entry::path("SYSTEMD UNTRACKED DROP-IN", path),
+            Finding::ExecStart(path, cmd) => Entry::path("SYSTEMD EXECSTART", path).detail(cmd),
+            Finding::Error(path, err) => {
+                Entry::path("SYSTEMD ERROR", path).detail(format!("{err:#}"))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Unit files and drop-ins that have been covered by this pass
+    pub covered: Vec<PathBuf>,
+    pub findings: Vec<Finding>,
+}
+
+fn is_unit(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| UNIT_EXTS.contains(&ext))
+}
+
+fn is_drop_in(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "conf")
+        && path
+            .parent()
+            .and_then(|dir| dir.extension())
+            .is_some_and(|ext| ext == "d")
+}
+
+/// The commands configured with `ExecStart=` in the `[Service]` section,
+/// an empty assignment resets the list
+pub fn exec_start(content: &str) -> Vec<String> {
+    let mut section = "";
+    let mut cmds = Vec::new();
+    for line in content.lines() {
+        let line = line.trim();
+        if line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name;
+            continue;
+        }
+        if section != "Service" {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if key.trim() != "ExecStart" {
+            continue;
+        }
+        let value = value.trim();
+        if value.is_empty() {
+            cmds.clear();
+        } else {
+            cmds.push(value.to_string());
+        }
+    }
+    cmds
+}
+
+fn check_untracked(path: PathBuf, report: &mut Report) {
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(err) => {
+            report.findings.push(Finding::Error(path, err.into()));
+            return;
+        }
+    };
+    let cmds = exec_start(&content);
+    if is_unit(&path) {
+        report.findings.push(Finding::UntrackedUnit(path.clone()));
+    } else {
+        report.findings.push(Finding::UntrackedDropIn(path.clone()));
+    }
+    for cmd in cmds {
+        report.findings.push(Finding::ExecStart(path.clone(), cmd));
+    }
+}
+
+/// Verify systemd units and drop-ins against their packages
+pub fn check(root: &Path, trusted: &TrustedHashes, flagged: &BTreeMap<PathBuf, String>) -> Report {
+    let mut report = Report::default();
+
+    for dir in UNIT_DIRS {
+        let dir = root.join(dir);
+        for entry in WalkDir::new(&dir).sort_by_file_name() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) if entry_not_found(&err) => continue,
+                Err(err) => {
+                    report
+                        .findings
+                        .push(Finding::Error(dir.clone(), err.into()));
+                    continue;
+                }
+            };
+            // symlinks enable or mask units, they aren't hashed
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = entry.into_path();
+            if !is_unit(&path) && !is_drop_in(&path) {
+                continue;
+            }
+
+            report.covered.push(path.clone());
+            if flagged.contains_key(&path) {
+                report.findings.push(Finding::Mismatch(path));
+            } else if !trusted.contains_key(&path) {
+                check_untracked(path, &mut report);
+            }
+        }
+    }
+
+    report
+}
+
+fn entry_not_found(err: &walkdir::Error) -> bool {
+    err.io_error()
+        .is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_exec_start() {
+        let cmds = exec_start(
+            "[Unit]\nExecStart=/bin/nope\n\n[Service]\n# ExecStart=/bin/comment\nExecStart=\nExecStart=/usr/bin/evil --daemon\n",
+        );
+        assert_eq!(cmds, vec!["/usr/bin/evil --daemon"]);
+    }
+}
@@ -0,0 +1,134 @@
+use crate::disk::{self, ScanError};
+use crate::errors::*;
+use crate::pkg::{self, Package};
+use crate::Event;
+use async_compression::tokio::bufread::{GzipDecoder, XzDecoder, ZstdDecoder};
+use futures_util::StreamExt;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio_tar as tar;
+use tokio_util::sync::CancellationToken;
+
+type Reader = Box<dyn AsyncRead + Unpin + Send>;
+
+/// Open a (possibly compressed) tarball, the compression is detected by magic bytes
+async fn open(path: &Path) -> Result<Reader> {
+    let file = File::open(path)
+        .await
+        .with_context(|| anyhow!("Failed to open tarball: {path:?}"))?;
+    let mut reader = BufReader::new(file);
+    let magic = reader
+        .fill_buf()
+        .await
+        .with_context(|| anyhow!("Failed to read from tarball: {path:?}"))?;
+
+    let reader: Reader = if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Box::new(ZstdDecoder::new(reader))
+    } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+        Box::new(XzDecoder::new(reader))
+    } else if magic.starts_with(&[0x1f, 0x8b]) {
+        Box::new(GzipDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+    Ok(reader)
+}
+
+fn normalize(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|c| matches!(c, std::path::Component::Normal(_)))
+        .collect()
+}
+
+async fn scan(
+    event_tx: &mpsc::UnboundedSender<Event>,
+    tarball: &Path,
+    root: &Path,
+    dbpath: &Path,
+    excluded: &HashSet<PathBuf>,
+    pkg_tx: Option<&mpsc::UnboundedSender<Package>>,
+    shutdown: &CancellationToken,
+) -> Result<()> {
+    let reader = open(tarball).await?;
+    let mut archive = tar::Archive::new(reader);
+    let mut entries = archive
+        .entries()
+        .with_context(|| anyhow!("Failed to read tarball: {tarball:?}"))?;
+
+    while let Some(entry) = entries.next().await {
+        if shutdown.is_cancelled() {
+            break;
+        }
+        let mut entry = entry.context("Failed to read entry from tarball")?;
+        let rel = normalize(&entry.path().context("Failed to read path from tarball")?);
+        let path = root.join(&rel);
+        if excluded.iter().any(|excluded| path.starts_with(excluded)) {
+            continue;
+        }
+
+        let event = match entry.header().entry_type() {
+            tar::EntryType::Directory => Event::DiskPwd(path),
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                if let Some(pkg_tx) = pkg_tx.filter(|_| pkg::is_local_desc(dbpath, &rel)) {
+                    let mut desc = String::new();
+                    entry
+                        .read_to_string(&mut desc)
+                        .await
+                        .with_context(|| anyhow!("Failed to read pacman database: {rel:?}"))?;
+                    if let Some(pkg) = pkg::parse_desc(&desc) {
+                        debug!("Found installed package: {:?} {:?}", pkg.name, pkg.version);
+                        event_tx.send(Event::PkgQueued)?;
+                        pkg_tx.send(pkg)?;
+                    }
+                    Event::DiskFileHashed(path, disk::sha256(desc.as_bytes()))
+                } else {
+                    match disk::hash_reader(&mut entry).await {
+                        Ok(sha256) => Event::DiskFileHashed(path, hex::encode(sha256)),
+                        Err(err) => Event::DiskError(ScanError::read(path, err)),
+                    }
+                }
+            }
+            // ignore symlinks and special files for now
+            _ => continue,
+        };
+        event_tx.send(event)?;
+    }
+
+    Ok(())
+}
+
+/// Read files from a tarball instead of a mounted filesystem, the pacman
+/// database is read from the tarball too unless `pkg_tx` is `None`
+pub fn spawn_scan(
+    event_tx: mpsc::UnboundedSender<Event>,
+    tarball: PathBuf,
+    root: PathBuf,
+    dbpath: PathBuf,
+    excluded: HashSet<PathBuf>,
+    pkg_tx: Option<mpsc::UnboundedSender<Package>>,
+    shutdown: CancellationToken,
+) {
+    tokio::spawn(async move {
+        if let Err(err) = scan(
+            &event_tx,
+            &tarball,
+            &root,
+            &dbpath,
+            &excluded,
+            pkg_tx.as_ref(),
+            &shutdown,
+        )
+        .await
+        {
+            event_tx.send(Event::DiskError(err.into())).ok();
+        }
+
+        if pkg_tx.is_some() {
+            event_tx.send(Event::CompletedListInstalled).ok();
+        }
+        event_tx.send(Event::CompletedDiskScan).ok();
+    });
+}
@@ -0,0 +1,209 @@
+use crate::errors::*;
+use crate::fetch;
+use crate::pkg::Package;
+use futures_util::StreamExt;
+use similar::TextDiff;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_tar as tar;
+
+/// Default size up to which flagged text files are compared
+pub const MAX_TEXT_SIZE: u64 = 64 * 1024;
+
+/// Files with a NUL byte or that aren't utf-8 are considered binary
+pub fn as_text(data: &[u8]) -> Option<&str> {
+    if data.contains(&0) {
+        return None;
+    }
+    std::str::from_utf8(data).ok()
+}
+
+/// A unified diff between the packaged and the installed content of a file,
+/// `None` if one of them isn't text
+pub fn unified(rel: &Path, original: &[u8], installed: &[u8]) -> Option<String> {
+    let original = as_text(original)?;
+    let installed = as_text(installed)?;
+    let diff = TextDiff::from_lines(original, installed)
+        .unified_diff()
+        .header(
+            &format!("a/{}", rel.display()),
+            &format!("b/{}", rel.display()),
+        )
+        .to_string();
+    Some(diff)
+}
+
+/// Read the files of a package that are in `paths` (relative to the root), files
+/// larger than `max_size` are skipped
+async fn read_members<R: AsyncRead + Unpin>(
+    reader: R,
+    paths: &HashSet<&Path>,
+    max_size: u64,
+) -> Result<HashMap<PathBuf, Vec<u8>>> {
+    let mut files = HashMap::new();
+    let mut tar = tar::Archive::new(reader);
+    let mut entries = tar.entries()?;
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry.context("Failed to read entry from package")?;
+        let path = entry.path()?.into_owned();
+        if !paths.contains(path.as_path()) || entry.header().size()? > max_size {
+            continue;
+        }
+        let mut buf = Vec::new();
+        entry
+            .read_to_end(&mut buf)
+            .await
+            .with_context(|| anyhow!("Failed to read {path:?} from package"))?;
+        files.insert(path, buf);
+        if files.len() == paths.len() {
+            break;
+        }
+    }
+    Ok(files)
+}
+
+/// Diff flagged text files against the pristine files of their packages. Every package
+/// is only read once, even if several of its files were flagged
+pub async fn diff_flagged<'a>(
+    client: &reqwest::Client,
+    dirs: &[PathBuf],
+    mirrors: &[String],
+    root: &Path,
+    flagged: impl Iterator<Item = &'a Path>,
+    owner: impl Fn(&Path) -> Option<&'a Package>,
+    max_size: u64,
+) -> HashMap<PathBuf, String> {
+    let mut by_pkg = BTreeMap::<_, (&Package, Vec<&Path>)>::new();
+    for path in flagged {
+        let Some(pkg) = owner(path) else {
+            debug!("No package owns {path:?}, skipping text diff");
+            continue;
+        };
+        let size = fs::symlink_metadata(path).await.map(|m| m.len());
+        if size.map_or(true, |size| size > max_size) {
+            continue;
+        }
+        by_pkg
+            .entry((&pkg.name, &pkg.version))
+            .or_insert_with(|| (pkg, Vec::new()))
+            .1
+            .push(path);
+    }
+
+    let mut diffs = HashMap::new();
+    for (pkg, paths) in by_pkg.into_values() {
+        let rel = paths
+            .iter()
+            .filter_map(|path| path.strip_prefix(root).ok())
+            .collect::<HashSet<_>>();
+        let originals = match fetch::open_package(client, dirs, mirrors, pkg).await {
+            Ok(Some((source, reader))) => read_members(reader, &rel, max_size)
+                .await
+                .with_context(|| anyhow!("Failed to read package: {source}")),
+            Ok(None) => Err(anyhow!(
+                "Failed to find package {}-{}",
+                pkg.name,
+                pkg.version
+            )),
+            Err(err) => Err(err),
+        };
+        let originals = match originals {
+            Ok(originals) => originals,
+            Err(err) => {
+                warn!("Failed to diff files of {}: {err:#}", pkg.name);
+                continue;
+            }
+        };
+
+        for path in paths {
+            let Ok(rel) = path.strip_prefix(root) else {
+                continue;
+            };
+            let Some(original) = originals.get(rel) else {
+                continue;
+            };
+            let installed = match fs::read(path).await {
+                Ok(installed) => installed,
+                Err(err) => {
+                    warn!("Failed to read {path:?} for text diff: {err:#}");
+                    continue;
+                }
+            };
+            if let Some(diff) = unified(rel, original, &installed) {
+                info!("Diffed text file {path:?}");
+                diffs.insert(path.to_owned(), diff);
+            }
+        }
+    }
+    diffs
+}
+
+/// The name of the file a diff is written to in `--diff-dir`, like `etc_ssh_sshd_config.diff`
+pub fn diff_file_name(rel: &Path) -> String {
+    let name = rel.to_string_lossy().replace('/', "_");
+    format!("{}.diff", name.trim_start_matches('_'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn diff_text_files() {
+        let dir = std::env::temp_dir().join(format!("textdiff-test-{}", std::process::id()));
+        let root = dir.join("root");
+        std::fs::create_dir_all(root.join("etc/systemd/system")).unwrap();
+        let unit = root.join("etc/systemd/system/sshd.service");
+        std::fs::write(
+            &unit,
+            b"[Service]\nExecStart=/usr/bin/sshd -D\nExecStartPost=/tmp/x\n",
+        )
+        .unwrap();
+        let binary = root.join("etc/ld.so.cache");
+        std::fs::write(&binary, b"\0\x01").unwrap();
+
+        let mut tar = ::tar::Builder::new(Vec::new());
+        for (path, data) in [
+            (
+                "etc/systemd/system/sshd.service",
+                &b"[Service]\nExecStart=/usr/bin/sshd -D\n"[..],
+            ),
+            ("etc/ld.so.cache", b"\0\x02"),
+        ] {
+            let mut header = ::tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            tar.append_data(&mut header, path, data).unwrap();
+        }
+        let pkg = Package {
+            name: "openssh".to_string(),
+            version: "9.8p1-1".to_string(),
+            arch: "x86_64".to_string(),
+        };
+        std::fs::write(dir.join(pkg.file_name("")), tar.into_inner().unwrap()).unwrap();
+
+        let diffs = diff_flagged(
+            &reqwest::Client::new(),
+            std::slice::from_ref(&dir),
+            &[],
+            &root,
+            [unit.as_path(), binary.as_path()].into_iter(),
+            |_| Some(&pkg),
+            MAX_TEXT_SIZE,
+        )
+        .await;
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(
+            diffs[&unit],
+            "--- a/etc/systemd/system/sshd.service\n+++ b/etc/systemd/system/sshd.service\n@@ -1,2 +1,3 @@\n [Service]\n ExecStart=/usr/bin/sshd -D\n+ExecStartPost=/tmp/x\n"
+        );
+        assert_eq!(
+            diff_file_name(Path::new("etc/systemd/system/sshd.service")),
+            "etc_systemd_system_sshd.service.diff"
+        );
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
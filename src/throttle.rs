@@ -0,0 +1,208 @@
+use crate::errors::*;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{watch, Mutex};
+use tokio::time::{self, Instant};
+
+/// Pause when this share of the memory cap is used
+const PAUSE_AT: f64 = 0.9;
+/// Resume once the usage dropped below this share of the memory cap
+const RESUME_AT: f64 = 0.75;
+
+/// Parse a size like `512M` or `1G` (powers of 1024), a plain number is in bytes
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => s.split_at(idx),
+        None => (s, ""),
+    };
+    let num = num
+        .parse::<u64>()
+        .with_context(|| anyhow!("Invalid size: {s:?}"))?;
+    let shift = match unit.trim_end_matches(['B', 'b', 'i']) {
+        "" => 0,
+        "K" | "k" => 10,
+        "M" | "m" => 20,
+        "G" | "g" => 30,
+        "T" | "t" => 40,
+        _ => bail!("Unknown unit in size: {s:?}"),
+    };
+    num.checked_mul(1 << shift)
+        .with_context(|| anyhow!("Size is too large: {s:?}"))
+}
+
+/// Pauses a producer of the scan (like the disk walker), all clones share the same state
+#[derive(Debug, Clone)]
+pub struct Pause {
+    paused: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Pause {
+    fn default() -> Self {
+        let (paused, _) = watch::channel(false);
+        Pause {
+            paused: Arc::new(paused),
+        }
+    }
+}
+
+impl Pause {
+    pub fn set(&self, paused: bool) {
+        self.paused.send_if_modified(|current| {
+            let changed = *current != paused;
+            *current = paused;
+            changed
+        });
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Wait until the producer is allowed to continue
+    pub async fn wait(&self) {
+        let mut rx = self.paused.subscribe();
+        rx.wait_for(|paused| !paused).await.ok();
+    }
+}
+
+/// A token bucket that limits the aggregate rate of all clones to `bytes_per_sec`,
+/// with bursts of up to one second worth of bytes
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    bytes_per_sec: u64,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative while the bytes that were already taken are paid off
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        RateLimit {
+            bytes_per_sec,
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                refilled: Instant::now(),
+            })),
+        }
+    }
+
+    /// Take `bytes` from the bucket and wait until the rate allows them
+    pub async fn acquire(&self, bytes: u64) {
+        let rate = self.bytes_per_sec as f64;
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - bytes as f64;
+            bucket.refilled = now;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            time::sleep(wait).await;
+        }
+    }
+}
+
+/// Keeps the memory used by the state of the scan below a cap by pausing the producers
+#[derive(Debug)]
+pub struct MemoryCap {
+    pub limit: u64,
+    /// The disk walker, it produces files that wait for their trusted hashes
+    pub walker: Pause,
+    /// The trust source workers, they produce trusted hashes that wait for their files
+    pub fetch: Pause,
+}
+
+impl MemoryCap {
+    pub fn new(limit: u64) -> Self {
+        MemoryCap {
+            limit,
+            walker: Pause::default(),
+            fetch: Pause::default(),
+        }
+    }
+
+    /// Pause the producer that is ahead, `pending` is the memory of files waiting for
+    /// trusted hashes (or a hasher) and `trusted` the memory of the trusted hashes.
+    /// A producer is only paused while the other one is still running, so the scan
+    /// can't get stuck.
+    pub fn update(&self, pending: u64, trusted: u64, walker_running: bool, fetch_running: bool) {
+        let usage = pending + trusted;
+        if (usage as f64) < self.limit as f64 * RESUME_AT {
+            if self.walker.is_paused() || self.fetch.is_paused() {
+                debug!("Memory usage is at {usage} bytes, resuming");
+            }
+            self.walker.set(false);
+            self.fetch.set(false);
+        } else if (usage as f64) >= self.limit as f64 * PAUSE_AT {
+            let walker_ahead = pending > trusted;
+            let pause_walker = walker_ahead && fetch_running;
+            let pause_fetch = !walker_ahead && walker_running;
+            if pause_walker != self.walker.is_paused() || pause_fetch != self.fetch.is_paused() {
+                debug!(
+                    "Memory usage is at {usage} of {} bytes (pause walker={pause_walker}, pause fetch={pause_fetch})",
+                    self.limit
+                );
+            }
+            self.walker.set(pause_walker);
+            self.fetch.set(pause_fetch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pause_producer_ahead() {
+        assert_eq!(parse_size("1G").unwrap(), 1 << 30);
+        assert_eq!(parse_size("512MiB").unwrap(), 512 << 20);
+        assert_eq!(parse_size("4096").unwrap(), 4096);
+        assert!(parse_size("1X").is_err());
+
+        let cap = MemoryCap::new(1000);
+        cap.update(800, 150, true, true);
+        assert!(cap.walker.is_paused());
+        assert!(!cap.fetch.is_paused());
+
+        // nothing would drain the pending files anymore
+        cap.update(800, 150, true, false);
+        assert!(!cap.walker.is_paused());
+
+        cap.update(100, 850, true, true);
+        assert!(!cap.walker.is_paused());
+        assert!(cap.fetch.is_paused());
+
+        // between the thresholds the state is kept
+        cap.update(100, 700, true, true);
+        assert!(cap.fetch.is_paused());
+        cap.update(100, 600, true, true);
+        assert!(!cap.fetch.is_paused());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_token_bucket() {
+        let limit = RateLimit::new(1000);
+        let start = Instant::now();
+        // the first second worth of bytes is a burst
+        limit.acquire(1000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        // clones share the bucket
+        limit.clone().acquire(500).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+        limit.acquire(2000).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(2500));
+    }
+}
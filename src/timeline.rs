@@ -0,0 +1,90 @@
+use crate::digest::Checksum;
+use crate::disk;
+use crate::errors::*;
+use crate::hashes::TrustedHashes;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// Describe when the content changed, given the snapshots (in chronological order)
+/// and whether the file was already modified in each of them
+pub fn describe(roots: &[PathBuf], changed: &[bool]) -> Option<String> {
+    let idx = changed.iter().position(|changed| *changed)?;
+    let detail = if idx == 0 {
+        format!("changed before {:?}", roots[0])
+    } else {
+        format!("changed between {:?} and {:?}", roots[idx - 1], roots[idx])
+    };
+    Some(detail)
+}
+
+/// Check if the file differs from its trusted hash, or if untracked, exists at all
+async fn is_changed(path: &Path, expected: Option<&str>) -> bool {
+    if fs::symlink_metadata(path).await.is_err() {
+        return false;
+    }
+    let Some(expected) = expected else {
+        return true;
+    };
+    let expected = match Checksum::parse(expected) {
+        Ok(expected) => expected,
+        Err(err) => {
+            warn!("Failed to parse trusted hash for {path:?}: {err:#}");
+            return false;
+        }
+    };
+    match disk::hash_file_with(path, &[expected.algorithm]).await {
+        Ok(checksums) => !checksums.iter().any(|c| c.matches(&expected)),
+        Err(err) => {
+            warn!("Failed to hash file {path:?}: {err:#}");
+            false
+        }
+    }
+}
+
+/// Look up flagged and untracked files in each snapshot to find the interval they first changed in
+pub async fn trace(
+    root: &Path,
+    roots: &[PathBuf],
+    trusted: &TrustedHashes,
+    paths: &[&PathBuf],
+) -> HashMap<PathBuf, String> {
+    let mut timeline = HashMap::new();
+    for path in paths {
+        let rel = path.strip_prefix(root).unwrap_or(path);
+        let expected = trusted.get(path);
+        let expected = expected.as_deref();
+
+        let mut changed = Vec::new();
+        for snapshot in roots {
+            changed.push(is_changed(&snapshot.join(rel), expected).await);
+        }
+        if let Some(detail) = describe(roots, &changed) {
+            timeline.insert((*path).clone(), detail);
+        }
+    }
+    timeline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describe_interval() {
+        let roots = vec![
+            PathBuf::from("snap-01"),
+            PathBuf::from("snap-02"),
+            PathBuf::from("snap-03"),
+        ];
+        assert_eq!(
+            describe(&roots, &[false, true, true]).as_deref(),
+            Some("changed between \"snap-01\" and \"snap-02\"")
+        );
+        assert_eq!(
+            describe(&roots, &[true, true, true]).as_deref(),
+            Some("changed before \"snap-01\"")
+        );
+        assert_eq!(describe(&roots, &[false, false, false]), None);
+    }
+}
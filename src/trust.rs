@@ -0,0 +1,452 @@
+use crate::digest::Algorithm;
+use crate::errors::*;
+use crate::fetch::{self, PKG_COMPRESSION_EXTS};
+use crate::manifest;
+use crate::mtree;
+use crate::pkg::{self, Package};
+use crate::repodb;
+use crate::throttle::RateLimit;
+use async_compression::tokio::bufread::GzipDecoder;
+use clap::ValueEnum;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader};
+
+/// Trusted hashes of the files of a package, as `.MTREE` path (like `./usr/bin/foo`) and sha256
+/// (or `md5:` for packages that are too old to have sha256)
+pub type Hashes = Vec<(String, String)>;
+/// Trusted permissions, ownership and symlink targets of a package, by `.MTREE` path
+pub type Metadata = Vec<(String, mtree::Metadata)>;
+
+/// What a trust source knows about the files of a package
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Trusted {
+    pub hashes: Hashes,
+    /// Only known by sources that read the `.MTREE`
+    pub metadata: Metadata,
+}
+
+impl Trusted {
+    /// Add an entry of a `.MTREE`, only the type of directories is kept
+    pub fn push_mtree(&mut self, entry: mtree::Entry) {
+        match entry.content {
+            mtree::EntryType::File(file) => {
+                if let Some(digest) = file.digest() {
+                    self.hashes.push((entry.path.clone(), digest));
+                }
+            }
+            mtree::EntryType::Link(_) => (),
+            // the permissions and ownership of directories are often changed by
+            // systemd-tmpfiles or install scripts
+            mtree::EntryType::Directory(_) => {
+                let metadata = mtree::Metadata {
+                    kind: Some(mtree::Kind::Dir),
+                    ..Default::default()
+                };
+                self.metadata.push((entry.path, metadata));
+                return;
+            }
+        }
+        if !entry.metadata.is_empty() {
+            self.metadata.push((entry.path, entry.metadata));
+        }
+    }
+}
+
+/// The kinds of trust sources that can be selected on the command line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Kind {
+    /// Hashes of previous runs, kept in the state directory
+    Cache,
+    /// The .MTREE of packages downloaded from archive.archlinux.org
+    Archive,
+    /// A directory of package files, like an offline bundle or a pacman cache
+    Bundle,
+    /// The .MTREE copies in the local pacman database (stored on the investigated system!)
+    LocalDb,
+    /// A `sha256sum` manifest, matched against the file lists of the local pacman database
+    Manifest,
+}
+
+/// A source of trusted hashes for the files of a package
+pub trait TrustSource: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// The trusted hashes of a package, `None` if this source doesn't know about it
+    fn hashes<'a>(&'a self, pkg: &'a Package) -> BoxFuture<'a, Result<Option<Trusted>>>;
+
+    /// Remember hashes that were found in a source of lower priority
+    fn store<'a>(&'a self, _pkg: &'a Package, _trusted: &'a Trusted) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Read the file entries of a (gzip compressed) `.MTREE`
+async fn read_mtree<R: AsyncBufRead + Unpin>(reader: R) -> Result<Trusted> {
+    let mut lines = BufReader::new(GzipDecoder::new(reader)).lines();
+    let mut parser = mtree::Parser::default();
+    let mut trusted = Trusted::default();
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .context("Failed to read line from .MTREE")?
+    {
+        if let Some(entry) = parser.parse(&line) {
+            trusted.push_mtree(entry);
+        }
+    }
+    Ok(trusted)
+}
+
+/// Hashes of previous runs, packages are immutable so they never get outdated
+pub struct Cache {
+    pub dir: PathBuf,
+}
+
+impl Cache {
+    fn path(&self, pkg: &Package, ext: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}-{}-{}.{ext}", pkg.name, pkg.version, pkg.arch))
+    }
+}
+
+impl TrustSource for Cache {
+    fn name(&self) -> &'static str {
+        "cache"
+    }
+
+    fn hashes<'a>(&'a self, pkg: &'a Package) -> BoxFuture<'a, Result<Option<Trusted>>> {
+        Box::pin(async move {
+            let Ok(content) = fs::read_to_string(self.path(pkg, "sha256")).await else {
+                return Ok(None);
+            };
+            let hashes = content
+                .lines()
+                .filter_map(manifest::parse_line)
+                .map(|(sha256, path)| (path.to_string_lossy().into_owned(), sha256))
+                .collect();
+            // caches of older versions don't have the metadata
+            let metadata = fs::read_to_string(self.path(pkg, "meta"))
+                .await
+                .unwrap_or_default()
+                .lines()
+                .filter_map(|line| {
+                    let (path, keywords) = line.split_once(' ')?;
+                    let keywords = keywords.split(' ').filter_map(|kw| kw.split_once('='));
+                    Some((path.to_string(), mtree::Metadata::from_keywords(keywords)))
+                })
+                .collect();
+            Ok(Some(Trusted { hashes, metadata }))
+        })
+    }
+
+    fn store<'a>(&'a self, pkg: &'a Package, trusted: &'a Trusted) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            let metadata = trusted
+                .metadata
+                .iter()
+                .map(|(path, metadata)| format!("{path} {}\n", metadata.to_keywords()))
+                .collect::<String>();
+            fetch::write_cache(&self.path(pkg, "meta"), &metadata).await?;
+            // the hashes are written last, they mark the cache entry as complete
+            let hashes = trusted
+                .hashes
+                .iter()
+                .map(|(path, sha256)| {
+                    // .MTREE paths are utf-8 already
+                    String::from_utf8_lossy(&manifest::format_line(sha256, Path::new(path)))
+                        .into_owned()
+                })
+                .collect::<String>();
+            fetch::write_cache(&self.path(pkg, "sha256"), &hashes).await
+        })
+    }
+}
+
+/// Download the `.MTREE` from the package archive, the download is aborted once it's been read
+pub struct Archive {
+    pub client: reqwest::Client,
+    /// Base urls (or url templates) of the archive and its mirrors, like [`pkg::ARCHIVE_URL`]
+    pub urls: Vec<String>,
+    pub decompress: fetch::Decompressors,
+    pub retry: fetch::Retry,
+    /// Shared by all workers, so it limits the aggregate download rate
+    pub limit_rate: Option<RateLimit>,
+    /// Bytes that were actually downloaded, shared with the scan for its status
+    pub transferred: fetch::Transferred,
+    /// Resolve packages through the repository databases of a date snapshot, instead of per-package urls
+    pub snapshot: Option<repodb::Index>,
+}
+
+impl TrustSource for Archive {
+    fn name(&self) -> &'static str {
+        "archive"
+    }
+
+    fn hashes<'a>(&'a self, pkg: &'a Package) -> BoxFuture<'a, Result<Option<Trusted>>> {
+        Box::pin(async move {
+            // a mirror that fails falls back to the next one
+            let mut result = Ok(None);
+            'mirrors: for archive in &self.urls {
+                let candidates = match &self.snapshot {
+                    Some(index) => match index.url(archive, pkg) {
+                        Some(candidate) => vec![candidate],
+                        // the package wasn't in the repositories at that date
+                        None => return Ok(None),
+                    },
+                    None => PKG_COMPRESSION_EXTS
+                        .iter()
+                        .map(|ext| Ok((pkg.to_url(archive, ext)?, *ext)))
+                        .collect::<Result<Vec<_>>>()?,
+                };
+                for (url, ext) in candidates {
+                    let body = match fetch::download_package(&self.client, &url, &self.retry).await
+                    {
+                        Ok(Some(body)) => body,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            debug!("Failed to download from mirror {archive:?}: {err:#}");
+                            result = Err(err);
+                            continue 'mirrors;
+                        }
+                    };
+                    let hashes = match &self.limit_rate {
+                        Some(limit) => {
+                            let body = fetch::limit_rate(body, limit.clone());
+                            self.decompress
+                                .read_mtree_download(body, ext, &self.transferred)
+                                .await
+                        }
+                        None => {
+                            self.decompress
+                                .read_mtree_download(body, ext, &self.transferred)
+                                .await
+                        }
+                    };
+                    return hashes.map(Some);
+                }
+            }
+            result
+        })
+    }
+}
+
+/// A directory of package files, like an offline bundle or `/var/cache/pacman/pkg`
+pub struct Bundle {
+    pub dir: PathBuf,
+    pub decompress: fetch::Decompressors,
+}
+
+impl TrustSource for Bundle {
+    fn name(&self) -> &'static str {
+        "bundle"
+    }
+
+    fn hashes<'a>(&'a self, pkg: &'a Package) -> BoxFuture<'a, Result<Option<Trusted>>> {
+        Box::pin(async move {
+            for ext in PKG_COMPRESSION_EXTS {
+                let path = self.dir.join(pkg.file_name(ext));
+                let Ok(file) = File::open(&path).await else {
+                    continue;
+                };
+                let hashes = self
+                    .decompress
+                    .read_mtree(file.into_std().await, ext)
+                    .await
+                    .with_context(|| anyhow!("Failed to read package file: {path:?}"))?;
+                return Ok(Some(hashes));
+            }
+            Ok(None)
+        })
+    }
+}
+
+/// The `.MTREE` that pacman keeps in its local database, this is stored on the
+/// investigated system and only as trustworthy as the system itself
+pub struct LocalDb {
+    pub dbpath: PathBuf,
+}
+
+impl TrustSource for LocalDb {
+    fn name(&self) -> &'static str {
+        "local-db"
+    }
+
+    fn hashes<'a>(&'a self, pkg: &'a Package) -> BoxFuture<'a, Result<Option<Trusted>>> {
+        Box::pin(async move {
+            let path = self
+                .dbpath
+                .join("local")
+                .join(format!("{}-{}", pkg.name, pkg.version))
+                .join("mtree");
+            let Ok(file) = File::open(&path).await else {
+                return Ok(None);
+            };
+            let hashes = read_mtree(BufReader::new(file))
+                .await
+                .with_context(|| anyhow!("Failed to read mtree: {path:?}"))?;
+            Ok(Some(hashes))
+        })
+    }
+}
+
+/// A `sha256sum` manifest, the files of a package are looked up with the file list of the local pacman database
+pub struct Manifest {
+    pub dbpath: PathBuf,
+    /// Trusted hashes (sha256, or prefixed with their algorithm) by path relative to the root
+    pub hashes: HashMap<PathBuf, String>,
+}
+
+impl Manifest {
+    pub async fn load(path: &Path, dbpath: PathBuf, default: Algorithm) -> Result<Self> {
+        let hashes = manifest::load(path, Path::new(""), default).await?;
+        Ok(Manifest {
+            dbpath,
+            hashes: hashes
+                .into_iter()
+                .map(|(path, hash, _)| (path, hash))
+                .collect(),
+        })
+    }
+}
+
+impl TrustSource for Manifest {
+    fn name(&self) -> &'static str {
+        "manifest"
+    }
+
+    fn hashes<'a>(&'a self, pkg: &'a Package) -> BoxFuture<'a, Result<Option<Trusted>>> {
+        Box::pin(async move {
+            let path = self
+                .dbpath
+                .join("local")
+                .join(format!("{}-{}", pkg.name, pkg.version))
+                .join("files");
+            let Ok(files) = fs::read_to_string(&path).await else {
+                return Ok(None);
+            };
+            let hashes = pkg::parse_files(&files)
+                .into_iter()
+                .filter_map(|path| {
+                    let sha256 = self.hashes.get(&path)?;
+                    Some((format!("./{}", path.display()), sha256.clone()))
+                })
+                .collect::<Vec<_>>();
+            if hashes.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(Trusted {
+                hashes,
+                ..Default::default()
+            }))
+        })
+    }
+}
+
+/// Trust sources in order of priority, the first source that knows a package is used
+#[derive(Default)]
+pub struct Chain {
+    sources: Vec<Box<dyn TrustSource>>,
+}
+
+impl Chain {
+    pub fn new(sources: Vec<Box<dyn TrustSource>>) -> Self {
+        Chain { sources }
+    }
+
+    pub fn push(&mut self, source: Box<dyn TrustSource>) {
+        self.sources.push(source);
+    }
+
+    /// Query the sources in order, the hashes are stored in the sources that came before
+    pub async fn hashes(&self, pkg: &Package) -> Option<Trusted> {
+        for (idx, source) in self.sources.iter().enumerate() {
+            match source.hashes(pkg).await {
+                Ok(Some(trusted)) => {
+                    debug!(
+                        "Using trust source {:?} for {:?} {:?}",
+                        source.name(),
+                        pkg.name,
+                        pkg.version
+                    );
+                    for previous in &self.sources[..idx] {
+                        if let Err(err) = previous.store(pkg, &trusted).await {
+                            warn!("Failed to store hashes in {:?}: {err:#}", previous.name());
+                        }
+                    }
+                    return Some(trusted);
+                }
+                Ok(None) => (),
+                Err(err) => warn!(
+                    "Failed to read {:?} {:?} from {:?}: {err:#}",
+                    pkg.name,
+                    pkg.version,
+                    source.name()
+                ),
+            }
+        }
+        warn!(
+            "No trust source has hashes for {:?} {:?}",
+            pkg.name, pkg.version
+        );
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct Mock {
+        hashes: Option<Trusted>,
+        stored: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TrustSource for Mock {
+        fn name(&self) -> &'static str {
+            "mock"
+        }
+
+        fn hashes<'a>(&'a self, _pkg: &'a Package) -> BoxFuture<'a, Result<Option<Trusted>>> {
+            Box::pin(async move { Ok(self.hashes.clone()) })
+        }
+
+        fn store<'a>(&'a self, pkg: &'a Package, _: &'a Trusted) -> BoxFuture<'a, Result<()>> {
+            self.stored.lock().unwrap().push(pkg.name.clone());
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn chain_priority() {
+        let hashes = Trusted {
+            hashes: vec![("./usr/bin/foo".to_string(), "abcd".to_string())],
+            ..Default::default()
+        };
+        let stored = Arc::new(Mutex::new(Vec::new()));
+        let chain = Chain::new(vec![
+            Box::new(Mock {
+                hashes: None,
+                stored: stored.clone(),
+            }),
+            Box::new(Mock {
+                hashes: Some(hashes.clone()),
+                stored: Arc::default(),
+            }),
+            Box::new(Mock {
+                hashes: Some(Trusted::default()),
+                stored: Arc::default(),
+            }),
+        ]);
+        let pkg = Package {
+            name: "foo".to_string(),
+            version: "1.0-1".to_string(),
+            arch: "x86_64".to_string(),
+        };
+        assert_eq!(chain.hashes(&pkg).await, Some(hashes));
+        assert_eq!(*stored.lock().unwrap(), vec!["foo".to_string()]);
+    }
+}
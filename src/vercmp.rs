@@ -0,0 +1,145 @@
+use std::cmp::Ordering;
+
+/// Compare version segments, a port of `rpmvercmp` from libalpm
+fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let one = a.as_bytes();
+    let two = b.as_bytes();
+    let (mut i, mut j) = (0, 0);
+    let (mut ptr1, mut ptr2) = (0, 0);
+
+    while i < one.len() && j < two.len() {
+        while i < one.len() && !one[i].is_ascii_alphanumeric() {
+            i += 1;
+        }
+        while j < two.len() && !two[j].is_ascii_alphanumeric() {
+            j += 1;
+        }
+        if i >= one.len() || j >= two.len() {
+            break;
+        }
+
+        // separators of different length
+        if i - ptr1 != j - ptr2 {
+            return (i - ptr1).cmp(&(j - ptr2));
+        }
+
+        ptr1 = i;
+        ptr2 = j;
+        let isnum = one[ptr1].is_ascii_digit();
+        let matches = |c: u8| {
+            if isnum {
+                c.is_ascii_digit()
+            } else {
+                c.is_ascii_alphabetic()
+            }
+        };
+        while ptr1 < one.len() && matches(one[ptr1]) {
+            ptr1 += 1;
+        }
+        while ptr2 < two.len() && matches(two[ptr2]) {
+            ptr2 += 1;
+        }
+
+        let mut seg1 = &one[i..ptr1];
+        let mut seg2 = &two[j..ptr2];
+        if seg1.is_empty() {
+            return Ordering::Less;
+        }
+        if seg2.is_empty() {
+            // numeric segments are always newer than alpha segments
+            return if isnum {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+
+        if isnum {
+            while seg1.first() == Some(&b'0') {
+                seg1 = &seg1[1..];
+            }
+            while seg2.first() == Some(&b'0') {
+                seg2 = &seg2[1..];
+            }
+            match seg1.len().cmp(&seg2.len()) {
+                Ordering::Equal => (),
+                other => return other,
+            }
+        }
+
+        match seg1.cmp(seg2) {
+            Ordering::Equal => (),
+            other => return other,
+        }
+
+        i = ptr1;
+        j = ptr2;
+    }
+
+    if i >= one.len() && j >= two.len() {
+        return Ordering::Equal;
+    }
+
+    // the remaining version is older if it starts with an alpha segment
+    if (i >= one.len() && !two[j].is_ascii_alphabetic())
+        || (i < one.len() && one[i].is_ascii_alphabetic())
+    {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+/// Split `epoch:version-release` into its parts
+fn parse_evr(evr: &str) -> (&str, &str, Option<&str>) {
+    let (epoch, rest) = match evr.split_once(':') {
+        Some((epoch, rest)) if epoch.bytes().all(|b| b.is_ascii_digit()) => {
+            (if epoch.is_empty() { "0" } else { epoch }, rest)
+        }
+        _ => ("0", evr),
+    };
+    match rest.rsplit_once('-') {
+        Some((version, release)) => (epoch, version, Some(release)),
+        None => (epoch, rest, None),
+    }
+}
+
+/// Compare two package versions like `vercmp(8)` from pacman
+pub fn vercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let (epoch1, version1, release1) = parse_evr(a);
+    let (epoch2, version2, release2) = parse_evr(b);
+
+    rpmvercmp(epoch1, epoch2)
+        .then_with(|| rpmvercmp(version1, version2))
+        .then_with(|| match (release1, release2) {
+            (Some(release1), Some(release2)) => rpmvercmp(release1, release2),
+            _ => Ordering::Equal,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compare_versions() {
+        assert_eq!(vercmp("1.0-1", "1.0-1"), Ordering::Equal);
+        assert_eq!(vercmp("1.0-1", "1.0-2"), Ordering::Less);
+        assert_eq!(vercmp("1.0", "1.0-2"), Ordering::Equal);
+        assert_eq!(vercmp("1.10-1", "1.9-1"), Ordering::Greater);
+        assert_eq!(vercmp("1.0a-1", "1.0-1"), Ordering::Less);
+        assert_eq!(vercmp("1.0.1-1", "1.0-1"), Ordering::Greater);
+        assert_eq!(vercmp("1:1.0-1", "2.0-1"), Ordering::Greater);
+        assert_eq!(vercmp("1.0rc1", "1.0"), Ordering::Less);
+        assert_eq!(vercmp("1.001", "1.1"), Ordering::Equal);
+        assert_eq!(vercmp("1..0", "1.0"), Ordering::Greater);
+    }
+}
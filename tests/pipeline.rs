@@ -0,0 +1,411 @@
+//! End-to-end tests of the scan pipeline, packages are served by a local mock of the Arch Linux Archive
+
+use archlinux_userland_fs_cmp::report::{Entry, Report};
+use archlinux_userland_fs_cmp::scanner::Scanner;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+use std::thread;
+
+/// Serve static files over http until the test process exits
+fn spawn_archive(files: HashMap<String, Vec<u8>>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let files = Arc::new(files);
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            let files = files.clone();
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                // skip the headers
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+
+                let mut parts = request.split(' ');
+                let method = parts.next().unwrap_or_default();
+                let path = parts.next().unwrap_or_default();
+                let (status, body) = match files.get(path) {
+                    Some(body) => ("200 OK", body.as_slice()),
+                    None => ("404 Not Found", &b""[..]),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                if method != "HEAD" {
+                    stream.write_all(body).ok();
+                }
+            });
+        }
+    });
+
+    format!("http://{addr}")
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn append(builder: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, path, data).unwrap();
+}
+
+/// Build a .pkg.tar.zst with a .MTREE for the given files
+fn build_package(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let mut mtree = String::from("#mtree\n/set type=file uid=0 gid=0 mode=644\n");
+    for (path, data) in files {
+        let sha256 = hex::encode(Sha256::digest(data));
+        mtree.push_str(&format!(
+            "./{path} time=1700000000.0 size={} sha256digest={sha256}\n",
+            data.len()
+        ));
+    }
+
+    let mut builder = tar::Builder::new(Vec::new());
+    append(&mut builder, ".PKGINFO", b"pkgname = foo\n");
+    append(&mut builder, ".MTREE", &gzip(mtree.as_bytes()));
+    for (path, data) in files {
+        append(&mut builder, path, data);
+    }
+    let tar = builder.into_inner().unwrap();
+    zstd::encode_all(&tar[..], 0).unwrap()
+}
+
+fn write(root: &Path, path: &str, data: &[u8]) {
+    let path = root.join(path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, data).unwrap();
+}
+
+fn tempdir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "archlinux-userland-fs-cmp-{name}-{}",
+        std::process::id()
+    ));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn scan_against_mock_archive() {
+    let package = build_package(&[
+        ("usr/bin/foo", b"#!/bin/sh\necho foo\n"),
+        ("usr/bin/bar", b"#!/bin/sh\necho bar\n"),
+        ("usr/bin/baz", b"#!/bin/sh\necho baz\n"),
+    ]);
+    let archive = spawn_archive(HashMap::from([(
+        "/packages/f/foo/foo-1.0-1-x86_64.pkg.tar.zst".to_string(),
+        package,
+    )]));
+
+    let dir = tempdir("pipeline");
+    let root = dir.join("root");
+    write(
+        &root,
+        "var/lib/pacman/local/foo-1.0-1/desc",
+        b"%NAME%\nfoo\n\n%VERSION%\n1.0-1\n\n%ARCH%\nx86_64\n\n",
+    );
+    write(&root, "usr/bin/foo", b"#!/bin/sh\necho foo\n");
+    // same size, only the content differs
+    write(&root, "usr/bin/bar", b"#!/bin/sh\necho pwn\n");
+    write(&root, "usr/bin/baz", b"#!/bin/sh\n");
+    write(&root, "usr/bin/backdoor", b"#!/bin/sh\nnc -l 1337\n");
+
+    let output = dir.join("report.json");
+    let status = Command::new(env!("CARGO_BIN_EXE_archlinux-userland-fs-cmp"))
+        .arg(&root)
+        .args(["-x", "/var", "--format", "json", "--archive-url", &archive])
+        .arg("--state-dir")
+        .arg(dir.join("state"))
+        .arg("-o")
+        .arg(&output)
+        .status()
+        .unwrap();
+    // files were flagged
+    assert_eq!(status.code(), Some(1));
+
+    let report = serde_json::from_slice::<Report>(&fs::read(&output).unwrap()).unwrap();
+    let mut findings = report
+        .entries
+        .iter()
+        .map(|entry| {
+            let path = entry.path.as_ref().unwrap();
+            (entry.kind.as_str(), path.strip_prefix(&root).unwrap())
+        })
+        .collect::<Vec<_>>();
+    findings.sort();
+    assert_eq!(
+        findings,
+        vec![
+            ("UNTRACKED", Path::new("usr/bin/backdoor")),
+            ("WRONG SHA256", Path::new("usr/bin/bar")),
+            ("WRONG SIZE", Path::new("usr/bin/baz")),
+        ]
+    );
+
+    // the .MTREE was cached for the next run
+    assert!(dir.join("state/mtree/foo-1.0-1-x86_64.sha256").exists());
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn allowlisted_file_with_wrong_size() {
+    let package = build_package(&[("etc/foo.conf", b"enabled=false\n")]);
+    let archive = spawn_archive(HashMap::from([(
+        "/packages/f/foo/foo-1.0-1-x86_64.pkg.tar.zst".to_string(),
+        package,
+    )]));
+
+    let dir = tempdir("allowlist");
+    let root = dir.join("root");
+    write(
+        &root,
+        "var/lib/pacman/local/foo-1.0-1/desc",
+        b"%NAME%\nfoo\n\n%VERSION%\n1.0-1\n\n%ARCH%\nx86_64\n\n",
+    );
+    // a local change with a different size, pinned in the allowlist
+    let modified = b"enabled=true\n";
+    write(&root, "etc/foo.conf", modified);
+    let allowlist = dir.join("allowlist");
+    fs::write(
+        &allowlist,
+        format!("/etc/foo.conf {}\n", hex::encode(Sha256::digest(modified))),
+    )
+    .unwrap();
+
+    let output = dir.join("report.json");
+    let status = Command::new(env!("CARGO_BIN_EXE_archlinux-userland-fs-cmp"))
+        .arg(&root)
+        .args(["-x", "/var", "--format", "json", "--archive-url", &archive])
+        .arg("--allowlist")
+        .arg(&allowlist)
+        .arg("--state-dir")
+        .arg(dir.join("state"))
+        .arg("-o")
+        .arg(&output)
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(0));
+
+    let report = serde_json::from_slice::<Report>(&fs::read(&output).unwrap()).unwrap();
+    assert!(report.entries.is_empty(), "{:?}", report.entries);
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn aide_export_with_wrong_size() {
+    let package = build_package(&[
+        ("usr/bin/foo", b"#!/bin/sh\necho foo\n"),
+        ("usr/bin/bar", b"#!/bin/sh\necho bar\n"),
+    ]);
+    let archive = spawn_archive(HashMap::from([(
+        "/packages/f/foo/foo-1.0-1-x86_64.pkg.tar.zst".to_string(),
+        package,
+    )]));
+
+    let dir = tempdir("aide");
+    let root = dir.join("root");
+    write(
+        &root,
+        "var/lib/pacman/local/foo-1.0-1/desc",
+        b"%NAME%\nfoo\n\n%VERSION%\n1.0-1\n\n%ARCH%\nx86_64\n\n",
+    );
+    write(&root, "usr/bin/foo", b"#!/bin/sh\necho foo\n");
+    write(&root, "usr/bin/bar", b"#!/bin/sh\nnc -l 1337\n");
+
+    let aide = dir.join("aide.db");
+    let status = Command::new(env!("CARGO_BIN_EXE_archlinux-userland-fs-cmp"))
+        .arg(&root)
+        .args(["-x", "/var", "--format", "json", "--archive-url", &archive])
+        .arg("--export-aide")
+        .arg(&aide)
+        .arg("--state-dir")
+        .arg(dir.join("state"))
+        .arg("-o")
+        .arg(dir.join("report.json"))
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+
+    // the stat of the modified file isn't exported as trusted
+    let db = fs::read_to_string(&aide).unwrap();
+    let entry = |path: &str| {
+        db.lines()
+            .find(|line| line.starts_with(&format!("{path} ")))
+            .unwrap()
+            .split(' ')
+            .skip(1)
+            .take(5)
+            .collect::<Vec<_>>()
+    };
+    let foo = entry("/usr/bin/foo");
+    assert_eq!(foo[0], "1073741885");
+    assert_eq!(foo[4], b"#!/bin/sh\necho foo\n".len().to_string());
+    assert_eq!(entry("/usr/bin/bar"), ["1073741825", "0", "0", "0", "0"]);
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn stream_no_upgrade() {
+    let package = build_package(&[
+        ("etc/hosts", b"127.0.0.1 localhost\n"),
+        ("etc/motd", b"welcome\n"),
+    ]);
+    let archive = spawn_archive(HashMap::from([(
+        "/packages/f/foo/foo-1.0-1-x86_64.pkg.tar.zst".to_string(),
+        package,
+    )]));
+
+    let dir = tempdir("stream");
+    let root = dir.join("root");
+    write(
+        &root,
+        "var/lib/pacman/local/foo-1.0-1/desc",
+        b"%NAME%\nfoo\n\n%VERSION%\n1.0-1\n\n%ARCH%\nx86_64\n\n",
+    );
+    write(
+        &root,
+        "etc/pacman.conf",
+        b"[options]\nNoUpgrade = etc/hosts etc/motd\n",
+    );
+    // a different size and the same size with other content
+    write(
+        &root,
+        "etc/hosts",
+        b"127.0.0.1 localhost\n10.0.0.1 intranet\n",
+    );
+    write(&root, "etc/motd", b"hacked!\n");
+
+    let output = dir.join("report.jsonl");
+    let status = Command::new(env!("CARGO_BIN_EXE_archlinux-userland-fs-cmp"))
+        .arg(&root)
+        .args(["-x", "/var", "--stream", "--no-summary"])
+        .args(["--format", "jsonl", "--archive-url", &archive])
+        .arg("--state-dir")
+        .arg(dir.join("state"))
+        .arg("-o")
+        .arg(&output)
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(1));
+
+    // the streamed findings agree with the final report
+    let mut findings = fs::read_to_string(&output)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<Entry>(line).unwrap())
+        .map(|entry| {
+            let path = entry.path.unwrap();
+            (entry.kind, path.strip_prefix(&root).unwrap().to_owned())
+        })
+        .collect::<Vec<_>>();
+    findings.sort();
+    assert_eq!(
+        findings,
+        vec![
+            ("MODIFIED CONFIG".to_string(), PathBuf::from("etc/hosts")),
+            ("MODIFIED CONFIG".to_string(), PathBuf::from("etc/motd")),
+            ("UNTRACKED".to_string(), PathBuf::from("etc/pacman.conf")),
+        ]
+    );
+
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn embedded_scanner() {
+    let package = build_package(&[
+        ("usr/bin/foo", b"#!/bin/sh\necho foo\n"),
+        ("etc/foo.conf", b"enabled=false\n"),
+        ("etc/hosts", b"127.0.0.1 localhost\n"),
+    ]);
+    let archive = spawn_archive(HashMap::from([(
+        "/packages/f/foo/foo-1.0-1-x86_64.pkg.tar.zst".to_string(),
+        package,
+    )]));
+
+    let dir = tempdir("scanner");
+    let root = dir.join("root");
+    write(
+        &root,
+        "var/lib/pacman/local/foo-1.0-1/desc",
+        b"%NAME%\nfoo\n\n%VERSION%\n1.0-1\n\n%ARCH%\nx86_64\n\n",
+    );
+    // config files are expected to be modified, from the backup list and NoUpgrade
+    write(
+        &root,
+        "var/lib/pacman/local/foo-1.0-1/files",
+        b"%BACKUP%\netc/foo.conf\t0123456789abcdef0123456789abcdef\n\n",
+    );
+    write(
+        &root,
+        "etc/pacman.conf",
+        b"[options]\nNoUpgrade = etc/hosts\n",
+    );
+    write(&root, "etc/foo.conf", b"enabled=true!\n");
+    write(
+        &root,
+        "etc/hosts",
+        b"127.0.0.1 localhost\n10.0.0.1 intranet\n",
+    );
+    write(&root, "usr/bin/foo", b"#!/bin/sh\necho pwned\n");
+    write(&root, "usr/bin/backdoor", b"#!/bin/sh\nnc -l 1337\n");
+    write(&root, "home/user/notes.txt", b"todo\n");
+
+    let report = Scanner::new(&root)
+        .with_mirror(archive)
+        .with_exclude("/var")
+        .with_concurrency(2)
+        .run()
+        .await
+        .unwrap();
+    let mut findings = report
+        .entries
+        .iter()
+        .map(|entry| {
+            let path = entry
+                .path
+                .as_ref()
+                .map(|path| path.strip_prefix(&root).unwrap());
+            (entry.kind.as_str(), path)
+        })
+        .collect::<Vec<_>>();
+    findings.sort();
+    assert_eq!(
+        findings,
+        vec![
+            ("MODIFIED CONFIG", Some(Path::new("etc/foo.conf"))),
+            ("MODIFIED CONFIG", Some(Path::new("etc/hosts"))),
+            ("UNTRACKED", Some(Path::new("etc/pacman.conf"))),
+            // the file in /home is only counted
+            ("UNTRACKED", Some(Path::new("usr/bin/backdoor"))),
+            ("UNTRACKED ELSEWHERE", None),
+            ("WRONG SIZE", Some(Path::new("usr/bin/foo"))),
+        ]
+    );
+
+    fs::remove_dir_all(&dir).ok();
+}